@@ -0,0 +1,872 @@
+//! プロジェクトを組み立てるためのビルダー
+//!
+//! `Vsqx4`を手で組み立てる場合、
+//!
+//! * `VsTrack`ごとに対応する`Mixer::vs_unit`が必要
+//! * `Singer::pc`は`VoiceTable`のインデックスでなければならない
+//! * パートの位置はプリメジャー分だけずらさなければならない
+//!
+//! などの暗黙の規則がある。`ProjectBuilder`は小節・拍・音価といった音楽的な単位で
+//! ボイス・トラック・パート・ノートを受け取り、これらの規則を満たした`Vsqx4`や`Vpr`を出力する。
+//!
+//! ```
+//! use vsqx::builder::{Length, NoteBuilder, PartBuilder, ProjectBuilder, TrackBuilder};
+//!
+//! let vsqx = ProjectBuilder::new("Song")
+//!     .tempo(0, 120.0)
+//!     .time_signature(0, 4, 4)
+//!     .voice("BHHN4EF9BRWTNHAB", "Miku(V2)")
+//!     .track(
+//!         TrackBuilder::new("Vocal").part(
+//!             PartBuilder::new(0)
+//!                 .note(NoteBuilder::new(0, Length::new(1, 4), 60, "ど"))
+//!                 .note(NoteBuilder::new((0, 1), Length::new(1, 4), 62, "れ")),
+//!         ),
+//!     )
+//!     .build_vsqx4()
+//!     .unwrap();
+//!
+//! assert_eq!(vsqx.vs_track.len(), vsqx.mixer.vs_unit.len());
+//! ```
+
+use crate::vpr::{self, Vpr};
+use crate::vsqx4::{self, Vsqx4};
+use crate::{Error, Result};
+
+/// 音楽的な位置（小節・拍・ティック）。
+///
+/// 小節は0始まりで、プリメジャーを含まない。
+/// 拍の長さはその小節の拍子の分母で決まる。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MusicalTime {
+    pub bar: i64,
+    pub beat: i64,
+    pub tick: i64,
+}
+
+impl MusicalTime {
+    pub fn new(bar: i64, beat: i64, tick: i64) -> Self {
+        Self { bar, beat, tick }
+    }
+}
+
+impl From<i64> for MusicalTime {
+    fn from(bar: i64) -> Self {
+        Self::new(bar, 0, 0)
+    }
+}
+
+impl From<(i64, i64)> for MusicalTime {
+    fn from((bar, beat): (i64, i64)) -> Self {
+        Self::new(bar, beat, 0)
+    }
+}
+
+impl From<(i64, i64, i64)> for MusicalTime {
+    fn from((bar, beat, tick): (i64, i64, i64)) -> Self {
+        Self::new(bar, beat, tick)
+    }
+}
+
+/// 音価。全音符に対する比か、ティック数で与える。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Length {
+    /// 全音符に対する比（`Fraction(1, 4)`で四分音符）
+    Fraction(i64, i64),
+    /// ティック数
+    Ticks(i64),
+}
+
+impl Length {
+    /// `numerator / denominator`分音符。
+    pub fn new(numerator: i64, denominator: i64) -> Self {
+        Length::Fraction(numerator, denominator)
+    }
+
+    /// 付点音符。
+    pub fn dotted(numerator: i64, denominator: i64) -> Self {
+        Length::Fraction(numerator * 3, denominator * 2)
+    }
+
+    /// ティック数で指定する。
+    pub fn ticks(ticks: i64) -> Self {
+        Length::Ticks(ticks)
+    }
+
+    /// 四分音符あたり`resolution`ティックとしてティック数に変換する。分母が正でなければエラーになる。
+    pub fn to_ticks(self, resolution: i64) -> Result<i64> {
+        match self {
+            Length::Fraction(_, d) if d <= 0 => Err(invalid(&format!(
+                "length denominator {} must be positive",
+                d
+            ))),
+            Length::Fraction(n, d) => Ok(resolution * 4 * n / d),
+            Length::Ticks(t) => Ok(t),
+        }
+    }
+}
+
+/// ノート
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoteBuilder {
+    at: MusicalTime,
+    length: Length,
+    number: i64,
+    velocity: i64,
+    lyric: String,
+    phoneme: String,
+}
+
+impl NoteBuilder {
+    /// `at`の位置（曲の先頭基準）に、長さ`length`、ノート番号`number`のノートを作る。
+    pub fn new<T: Into<MusicalTime>, S: Into<String>>(
+        at: T,
+        length: Length,
+        number: i64,
+        lyric: S,
+    ) -> Self {
+        Self {
+            at: at.into(),
+            length,
+            number,
+            velocity: 64,
+            lyric: lyric.into(),
+            phoneme: String::new(),
+        }
+    }
+
+    /// 発音記号。省略時は空になる。
+    pub fn phoneme<S: Into<String>>(mut self, phoneme: S) -> Self {
+        self.phoneme = phoneme.into();
+        self
+    }
+
+    /// ベロシティ（0〜127）。省略時は64。
+    pub fn velocity(mut self, velocity: i64) -> Self {
+        self.velocity = velocity;
+        self
+    }
+}
+
+/// パート
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartBuilder {
+    at: MusicalTime,
+    length: Option<Length>,
+    name: Option<String>,
+    voice: usize,
+    notes: Vec<NoteBuilder>,
+}
+
+impl PartBuilder {
+    /// `at`の位置（曲の先頭基準）から始まるパートを作る。
+    pub fn new<T: Into<MusicalTime>>(at: T) -> Self {
+        Self {
+            at: at.into(),
+            length: None,
+            name: None,
+            voice: 0,
+            notes: vec![],
+        }
+    }
+
+    /// パート名
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// パートの長さ。省略時は最後のノートの終わりまで。
+    pub fn length(mut self, length: Length) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// 歌声。`ProjectBuilder::voice`で追加した順番のインデックスで指定する。
+    pub fn voice(mut self, index: usize) -> Self {
+        self.voice = index;
+        self
+    }
+
+    /// ノートを追加する。
+    pub fn note(mut self, note: NoteBuilder) -> Self {
+        self.notes.push(note);
+        self
+    }
+}
+
+/// トラック
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackBuilder {
+    name: String,
+    parts: Vec<PartBuilder>,
+}
+
+impl TrackBuilder {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            parts: vec![],
+        }
+    }
+
+    /// パートを追加する。
+    pub fn part(mut self, part: PartBuilder) -> Self {
+        self.parts.push(part);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct VoiceEntry {
+    comp_id: String,
+    name: String,
+    bs: i64,
+}
+
+/// プロジェクト
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProjectBuilder {
+    title: String,
+    resolution: i64,
+    pre_measure: i64,
+    tempos: Vec<(MusicalTime, f64)>,
+    time_signatures: Vec<(i64, i64, i64)>,
+    voices: Vec<VoiceEntry>,
+    tracks: Vec<TrackBuilder>,
+}
+
+impl ProjectBuilder {
+    pub fn new<S: Into<String>>(title: S) -> Self {
+        Self {
+            title: title.into(),
+            resolution: 480,
+            pre_measure: 4,
+            tempos: vec![],
+            time_signatures: vec![],
+            voices: vec![],
+            tracks: vec![],
+        }
+    }
+
+    /// 四分音符あたりのティック数。省略時は480。
+    pub fn resolution(mut self, resolution: i64) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// プリメジャーの小節数。省略時は4（VOCALOID4 Editorの既定値）。
+    pub fn pre_measure(mut self, pre_measure: i64) -> Self {
+        self.pre_measure = pre_measure;
+        self
+    }
+
+    /// `at`の位置のテンポをBPMで指定する。省略時は120。
+    pub fn tempo<T: Into<MusicalTime>>(mut self, at: T, bpm: f64) -> Self {
+        self.tempos.push((at.into(), bpm));
+        self
+    }
+
+    /// `bar`小節目からの拍子を指定する。省略時は4/4。
+    pub fn time_signature(mut self, bar: i64, numerator: i64, denominator: i64) -> Self {
+        self.time_signatures.push((bar, numerator, denominator));
+        self
+    }
+
+    /// 歌声を追加する（言語は0）。
+    pub fn voice<S: Into<String>, T: Into<String>>(self, comp_id: S, name: T) -> Self {
+        self.voice_with_language(comp_id, name, 0)
+    }
+
+    /// 言語（`bs`）を指定して歌声を追加する。
+    pub fn voice_with_language<S: Into<String>, T: Into<String>>(
+        mut self,
+        comp_id: S,
+        name: T,
+        bs: i64,
+    ) -> Self {
+        self.voices.push(VoiceEntry {
+            comp_id: comp_id.into(),
+            name: name.into(),
+            bs,
+        });
+        self
+    }
+
+    /// トラックを追加する。
+    pub fn track(mut self, track: TrackBuilder) -> Self {
+        self.tracks.push(track);
+        self
+    }
+
+    /// `Vsqx4`を出力する。
+    pub fn build_vsqx4(self) -> Result<Vsqx4> {
+        let project = self.resolve()?;
+        let offset = project.pre_measure_ticks();
+
+        let mut v = Vsqx4::default();
+
+        v.master_track.name = project.title.clone();
+        v.master_track.resolution = project.resolution;
+        v.master_track.pre_measure = project.pre_measure;
+        v.master_track.time_signatures = project
+            .time_signatures
+            .iter()
+            .enumerate()
+            .map(|(i, ts)| vsqx4::TimeSignature {
+                // 先頭の拍子はプリメジャーにも適用される
+                position: if i == 0 {
+                    0
+                } else {
+                    ts.bar + project.pre_measure
+                },
+                numerator: ts.numerator,
+                denominator: ts.denominator,
+            })
+            .collect();
+        v.master_track.tempos = project
+            .tempos
+            .iter()
+            .enumerate()
+            .map(|(i, &(pos, value))| vsqx4::Tempo {
+                // 先頭のテンポはプリメジャーも含めて有効にする
+                position: if i == 0 { 0 } else { pos + offset },
+                value,
+            })
+            .collect();
+
+        v.voice_table.voices = project
+            .voices
+            .iter()
+            .enumerate()
+            .map(|(i, voice)| vsqx4::Voice {
+                bs: voice.bs,
+                pc: i as i64,
+                id: voice.comp_id.clone(),
+                name: voice.name.clone(),
+                ..Default::default()
+            })
+            .collect();
+
+        v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+        v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+        for (i, track) in project.tracks.iter().enumerate() {
+            let mut t = vsqx4::VsTrack {
+                track_no: i as i64,
+                name: track.name.clone(),
+                ..Default::default()
+            };
+
+            v.mixer.vs_unit.push(vsqx4::VsUnit {
+                track_no: t.track_no,
+                ..Default::default()
+            });
+
+            for part in &track.parts {
+                let mut p = vsqx4::VsPart {
+                    position: part.pos + offset,
+                    play_time: Some(part.duration as u64),
+                    name: part.name.clone(),
+                    ..Default::default()
+                };
+
+                p.singers.push(vsqx4::Singer {
+                    position: 0,
+                    bs: project.voices[part.voice].bs,
+                    pc: part.voice as i64,
+                });
+
+                for note in &part.notes {
+                    p.notes.push(vsqx4::Note {
+                        position: note.pos,
+                        duration: note.duration,
+                        note_num: note.number,
+                        velocity: note.velocity,
+                        lyric: note.lyric.clone(),
+                        phoneme: note.phoneme.clone(),
+                        ..Default::default()
+                    });
+                }
+
+                t.parts.push(p);
+            }
+
+            v.vs_track.push(t);
+        }
+
+        Ok(v)
+    }
+
+    /// `Vpr`を出力する。
+    pub fn build_vpr(self) -> Result<Vpr> {
+        let project = self.resolve()?;
+
+        let master_track = vpr::MasterTrack {
+            sampling_rate: 44100,
+            loop_info: vpr::Loop::default(),
+            tempo: vpr::Tempo {
+                is_folded: false,
+                height: 0.0,
+                global: vpr::GlobalTempo {
+                    is_enabled: false,
+                    value: project.tempos[0].1 as u64,
+                },
+                events: project
+                    .tempos
+                    .iter()
+                    .map(|&(pos, value)| vpr::ControlChange { pos, value })
+                    .collect(),
+            },
+            time_sig: vpr::TimeSignature {
+                is_folded: false,
+                events: project
+                    .time_signatures
+                    .iter()
+                    .map(|ts| vpr::TimeSignatureEvent {
+                        bar: ts.bar,
+                        numerator: ts.numerator,
+                        denominator: ts.denominator,
+                    })
+                    .collect(),
+            },
+            volume: vpr::Volume::default(),
+        };
+
+        let voices: Vec<vpr::Voice> = project
+            .voices
+            .iter()
+            .map(|voice| vpr::Voice {
+                comp_id: voice.comp_id.clone(),
+                lang_id: Some(voice.bs),
+                name: Some(voice.name.clone()),
+            })
+            .collect();
+
+        let tracks = project
+            .tracks
+            .iter()
+            .map(|track| vpr::Track {
+                track_type: 0,
+                name: Some(track.name.clone()),
+                color: 0,
+                bus_no: 0,
+                is_folded: true,
+                height: 0.0,
+                volume: vpr::Volume::default(),
+                panpot: vpr::Panpot::default(),
+                is_muted: false,
+                is_solo_mode: false,
+                parts: track
+                    .parts
+                    .iter()
+                    .map(|part| {
+                        let mut voice = voices[part.voice].clone();
+                        voice.name = None;
+
+                        vpr::Part {
+                            name: part.name.clone(),
+                            pos: part.pos as u64,
+                            duration: part.duration as u64,
+                            style_name: "No Effect".into(),
+                            voice,
+                            midi_effects: vec![],
+                            controllers: vec![],
+                            notes: part
+                                .notes
+                                .iter()
+                                .map(|note| vpr::Note {
+                                    lyric: note.lyric.clone(),
+                                    phoneme: note.phoneme.clone(),
+                                    is_protected: false,
+                                    pos: note.pos,
+                                    duration: note.duration as u64,
+                                    number: note.number,
+                                    velocity: note.velocity as u8,
+                                    exp: Default::default(),
+                                    singing_skill: Some(vpr::SingingSkill {
+                                        duration: 0,
+                                        weight: vpr::SkillWeight { pre: 64, post: 64 },
+                                    }),
+                                    vibrato: vpr::Vibrato {
+                                        vibrato_type: 0,
+                                        duration: 0,
+                                    },
+                                })
+                                .collect(),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Vpr {
+            version: vpr::Version::new(5, 0, 0),
+            vender: vpr::vpr_vender(),
+            title: project.title,
+            master_track,
+            voices,
+            tracks,
+        })
+    }
+
+    /// 音楽的な単位をすべてティックに直す。
+    /// ここで得られる位置はプリメジャーを含まない。
+    fn resolve(self) -> Result<ResolvedProject> {
+        if self.resolution <= 0 {
+            return Err(invalid("resolution must be positive"));
+        }
+        if self.pre_measure < 0 {
+            return Err(invalid("pre-measure must not be negative"));
+        }
+        if self.voices.is_empty() {
+            return Err(invalid("at least one voice is required"));
+        }
+
+        // 拍子
+        let mut time_signatures: Vec<ResolvedTimeSignature> = self
+            .time_signatures
+            .iter()
+            .map(|&(bar, numerator, denominator)| ResolvedTimeSignature {
+                bar,
+                numerator,
+                denominator,
+            })
+            .collect();
+        time_signatures.sort_by_key(|ts| ts.bar);
+        time_signatures.dedup_by(|later, earlier| {
+            let same = later.bar == earlier.bar;
+            if same {
+                *earlier = *later;
+            }
+            same
+        });
+
+        for ts in &time_signatures {
+            if ts.bar < 0 {
+                return Err(invalid("time signature must not be placed before bar 0"));
+            }
+            if ts.numerator <= 0 || ts.denominator <= 0 || !is_power_of_two(ts.denominator) {
+                return Err(invalid(&format!(
+                    "invalid time signature {}/{}",
+                    ts.numerator, ts.denominator
+                )));
+            }
+        }
+
+        if time_signatures.first().map(|ts| ts.bar) != Some(0) {
+            time_signatures.insert(
+                0,
+                ResolvedTimeSignature {
+                    bar: 0,
+                    numerator: 4,
+                    denominator: 4,
+                },
+            );
+        }
+
+        let mut project = ResolvedProject {
+            title: self.title,
+            resolution: self.resolution,
+            pre_measure: self.pre_measure,
+            time_signatures,
+            tempos: vec![],
+            voices: self.voices,
+            tracks: vec![],
+        };
+
+        // テンポ
+        let mut tempos = vec![];
+        for (at, bpm) in &self.tempos {
+            if !(bpm.is_finite() && *bpm > 0.0) {
+                return Err(invalid(&format!("invalid tempo {}", bpm)));
+            }
+            tempos.push((project.ticks(*at)?, (bpm * 100.0).round() as i64));
+        }
+        tempos.sort_by_key(|&(pos, _)| pos);
+        // 同じ位置のテンポは後から指定したものを使う
+        tempos.dedup_by(|later, earlier| {
+            let same = later.0 == earlier.0;
+            if same {
+                *earlier = *later;
+            }
+            same
+        });
+
+        if tempos.first().map(|&(pos, _)| pos) != Some(0) {
+            tempos.insert(0, (0, 12000));
+        }
+        project.tempos = tempos;
+
+        // トラック
+        for track in self.tracks {
+            let mut parts = vec![];
+
+            for part in track.parts {
+                if part.voice >= project.voices.len() {
+                    return Err(invalid(&format!(
+                        "voice index {} is out of range",
+                        part.voice
+                    )));
+                }
+
+                let pos = project.ticks(part.at)?;
+                let mut notes = vec![];
+
+                for note in part.notes {
+                    let note_pos = project.ticks(note.at)? - pos;
+                    let duration = note.length.to_ticks(project.resolution)?;
+
+                    if note_pos < 0 {
+                        return Err(invalid("note must not start before its part"));
+                    }
+                    if duration <= 0 {
+                        return Err(invalid("note length must be positive"));
+                    }
+                    if !(0..=127).contains(&note.number) {
+                        return Err(invalid(&format!(
+                            "note number {} is out of range",
+                            note.number
+                        )));
+                    }
+                    if !(0..=127).contains(&note.velocity) {
+                        return Err(invalid(&format!(
+                            "velocity {} is out of range",
+                            note.velocity
+                        )));
+                    }
+
+                    notes.push(ResolvedNote {
+                        pos: note_pos,
+                        duration,
+                        number: note.number,
+                        velocity: note.velocity,
+                        lyric: note.lyric,
+                        phoneme: note.phoneme,
+                    });
+                }
+
+                notes.sort_by_key(|n| n.pos);
+
+                let end = notes.iter().map(|n| n.pos + n.duration).max().unwrap_or(0);
+                let duration = match part.length {
+                    Some(length) => length.to_ticks(project.resolution)?,
+                    None => end,
+                };
+                if duration < end {
+                    return Err(invalid("part length must cover all of its notes"));
+                }
+
+                parts.push(ResolvedPart {
+                    pos,
+                    duration,
+                    name: part.name,
+                    voice: part.voice,
+                    notes,
+                });
+            }
+
+            parts.sort_by_key(|p| p.pos);
+
+            project.tracks.push(ResolvedTrack {
+                name: track.name,
+                parts,
+            });
+        }
+
+        Ok(project)
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidProject(message.into())
+}
+
+fn is_power_of_two(n: i64) -> bool {
+    n > 0 && n & (n - 1) == 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ResolvedTimeSignature {
+    bar: i64,
+    numerator: i64,
+    denominator: i64,
+}
+
+struct ResolvedNote {
+    pos: i64,
+    duration: i64,
+    number: i64,
+    velocity: i64,
+    lyric: String,
+    phoneme: String,
+}
+
+struct ResolvedPart {
+    pos: i64,
+    duration: i64,
+    name: Option<String>,
+    voice: usize,
+    notes: Vec<ResolvedNote>,
+}
+
+struct ResolvedTrack {
+    name: String,
+    parts: Vec<ResolvedPart>,
+}
+
+struct ResolvedProject {
+    title: String,
+    resolution: i64,
+    pre_measure: i64,
+    time_signatures: Vec<ResolvedTimeSignature>,
+    tempos: Vec<(i64, i64)>,
+    voices: Vec<VoiceEntry>,
+    tracks: Vec<ResolvedTrack>,
+}
+
+impl ResolvedProject {
+    fn bar_ticks(&self, ts: &ResolvedTimeSignature) -> i64 {
+        self.resolution * 4 * ts.numerator / ts.denominator
+    }
+
+    /// プリメジャーの長さ（最初の拍子で数える）
+    fn pre_measure_ticks(&self) -> i64 {
+        self.pre_measure * self.bar_ticks(&self.time_signatures[0])
+    }
+
+    /// 音楽的な位置をティックに変換する。
+    fn ticks(&self, at: MusicalTime) -> Result<i64> {
+        if at.bar < 0 {
+            return Err(invalid("position must not be placed before bar 0"));
+        }
+
+        let mut ticks = 0;
+        let mut current = self.time_signatures[0];
+
+        for ts in &self.time_signatures[1..] {
+            if ts.bar > at.bar {
+                break;
+            }
+            ticks += (ts.bar - current.bar) * self.bar_ticks(&current);
+            current = *ts;
+        }
+
+        let beat_ticks = self.resolution * 4 / current.denominator;
+        Ok(ticks
+            + (at.bar - current.bar) * self.bar_ticks(&current)
+            + at.beat * beat_ticks
+            + at.tick)
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// ビルダーで作ったVsqx4が暗黙の規則を満たしているか確認する。
+fn test_builder_vsqx4() {
+    let v = ProjectBuilder::new("Test")
+        .tempo(0, 150.0)
+        .time_signature(0, 3, 4)
+        .time_signature(2, 4, 4)
+        .voice("BNGW7FG7E5TRSNC3", "KAITO_V3_English")
+        .track(
+            TrackBuilder::new("Vocal").part(
+                PartBuilder::new(1)
+                    .note(NoteBuilder::new(1, Length::new(1, 4), 55, "god"))
+                    .note(NoteBuilder::new(2, Length::dotted(1, 2), 55, "save")),
+            ),
+        )
+        .track(TrackBuilder::new("Chorus"))
+        .build_vsqx4()
+        .unwrap();
+
+    assert_eq!(v.master_track.pre_measure, 4);
+    assert_eq!(v.master_track.tempos[0].value, 15000);
+
+    // トラックとミキサーの対応
+    let track_nos: Vec<_> = v.vs_track.iter().map(|t| t.track_no).collect();
+    let unit_nos: Vec<_> = v.mixer.vs_unit.iter().map(|u| u.track_no).collect();
+    assert_eq!(track_nos, unit_nos);
+
+    // パート位置はプリメジャー（3/4を4小節）+ 1小節
+    let part = &v.vs_track[0].parts[0];
+    assert_eq!(part.position, 1440 * 5);
+    assert_eq!(part.notes[1].position, 1440);
+    assert_eq!(part.play_time, Some(1440 + 1440));
+
+    // 書き出したものが読み込めること
+    let v2: Vsqx4 = v.to_string().unwrap().parse().unwrap();
+    assert_eq!(v2.vs_track[0].parts[0].notes.len(), 2);
+}
+
+#[test]
+#[cfg(test)]
+/// 存在しない歌声を参照した場合にエラーになるか確認する。
+fn test_builder_invalid_voice() {
+    let res = ProjectBuilder::new("Test")
+        .voice("BHHN4EF9BRWTNHAB", "Miku(V2)")
+        .track(TrackBuilder::new("Vocal").part(PartBuilder::new(0).voice(1)))
+        .build_vpr();
+
+    assert!(res.is_err());
+}
+
+#[test]
+#[cfg(test)]
+/// 範囲外のノート番号・ベロシティがエラーになり、同じ位置のテンポが1つにまとまるか確認する。
+fn test_builder_note_range() {
+    let project =
+        |number, velocity| {
+            ProjectBuilder::new("Test")
+                .voice("BHHN4EF9BRWTNHAB", "Miku(V2)")
+                .track(TrackBuilder::new("Vocal").part(
+                    PartBuilder::new(0).note(
+                        NoteBuilder::new(0, Length::new(1, 4), number, "a").velocity(velocity),
+                    ),
+                ))
+        };
+
+    assert!(project(60, 300).build_vpr().is_err());
+    assert!(project(128, 64).build_vsqx4().is_err());
+    assert!(project(-1, 64).build_vpr().is_err());
+
+    let vpr = project(60, 127)
+        .tempo(0, 120.0)
+        .tempo(0, 150.0)
+        .build_vpr()
+        .unwrap();
+    assert_eq!(vpr.master_track.tempo.events.len(), 1);
+    assert_eq!(vpr.master_track.tempo.events[0].value, 15000);
+    assert_eq!(vpr.tracks[0].parts[0].notes[0].velocity, 127);
+}
+
+#[test]
+#[cfg(test)]
+/// 分母が正でない音価と、ノートより短いパートがエラーになるか確認する。
+fn test_builder_lengths() {
+    assert!(Length::new(1, 0).to_ticks(480).is_err());
+    assert!(Length::new(1, -4).to_ticks(480).is_err());
+    assert_eq!(Length::dotted(1, 4).to_ticks(480).unwrap(), 720);
+
+    let project = |length: Length, part: Option<Length>| {
+        let mut part_builder = PartBuilder::new(0).note(NoteBuilder::new(0, length, 60, "a"));
+        if let Some(part) = part {
+            part_builder = part_builder.length(part);
+        }
+        ProjectBuilder::new("Test")
+            .voice("BHHN4EF9BRWTNHAB", "Miku(V2)")
+            .track(TrackBuilder::new("Vocal").part(part_builder))
+    };
+
+    assert!(project(Length::new(1, 0), None).build_vsqx4().is_err());
+    assert!(project(Length::new(1, 4), Some(Length::new(1, 0)))
+        .build_vpr()
+        .is_err());
+    assert!(project(Length::new(1, 2), Some(Length::new(1, 4)))
+        .build_vsqx4()
+        .is_err());
+    let v = project(Length::new(1, 4), Some(Length::new(1, 1)))
+        .build_vsqx4()
+        .unwrap();
+    assert_eq!(v.vs_track[0].parts[0].play_time, Some(1920));
+}
@@ -0,0 +1,570 @@
+//! .ccsとVsqx4の相互変換（Vprとの変換はVsqx4を経由する）
+
+use super::{Ccs, Curve, Group, Note, Unit, CLOCKS_PER_QUARTER, FRAME_SECONDS};
+use crate::conversion::{self, Conversion, NoteLosses, Range, Unmapped};
+use crate::edit::{self, EditPart};
+use crate::midi::import::to_bars;
+use crate::timeline::TempoMap;
+use crate::ust::convert::{bpm_to_value, semitones_to_pit};
+use crate::vsqx4::{self, Vsqx4};
+
+/// 1ティック（四分音符 = 480）あたりのClock数
+const CLOCKS_PER_TICK: i64 = CLOCKS_PER_QUARTER / 480;
+/// 読み込むときに設定するピッチベンドセンシティビティ（半音）
+const PITCH_BEND_SENS: i64 = 12;
+
+/// `(.ccsのパラメーター, VOCALOIDのCC, .ccsの値域, VOCALOIDの値域)`
+///
+/// 値域はおおよそのもの。`LogF0`はPIT・PBSから合成し、`C0`に相当するものはない。
+const PARAMETER_MAPPINGS: &[(&str, &str, Range, Range)] = &[
+    ("Volume", "D", (-24.0, 0.0, 6.0), (0.0, 64.0, 127.0)),
+    ("Alpha", "G", (-0.5, 0.0, 0.5), (0.0, 64.0, 127.0)),
+];
+
+/// `00:00:00.0000000`の形式の時刻を秒にする。
+fn parse_time(time: &str) -> f64 {
+    time.split(':').fold(0.0, |acc, t| {
+        acc * 60.0 + t.trim().parse::<f64>().unwrap_or(0.0)
+    })
+}
+
+/// 秒を`00:00:00.0000000`の形式にする。
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0);
+    let hours = (seconds / 3600.0).floor();
+    let minutes = ((seconds - hours * 3600.0) / 60.0).floor();
+
+    format!(
+        "{:02}:{:02}:{:010.7}",
+        hours,
+        minutes,
+        seconds - hours * 3600.0 - minutes * 60.0
+    )
+}
+
+/// ノート番号の周波数（Hz）の自然対数
+fn log_frequency(note_num: i64) -> f64 {
+    (440.0 * 2f64.powf((note_num - 69) as f64 / 12.0)).ln()
+}
+
+/// .ccsからVsqx4への変換。
+/// トラック（`Group`）ごとに1つのトラックを、ユニットごとに1つのパートを作る。
+/// テンポと拍子は最初のユニットから取る。ノートの位置は曲の先頭から、
+/// パラメーターのフレームはユニットの開始時刻からとみなす。
+pub(crate) fn convert_ccs_to_vsqx4(ccs: &Ccs) -> Conversion<Vsqx4> {
+    let mut unmapped = vec![];
+    let mut v = Vsqx4::default();
+    v.master_track.pre_measure = 4;
+    let resolution = v.master_track.resolution;
+
+    let first = ccs.units.first();
+
+    // 拍子（先頭の拍子はプリメジャーにも適用される）
+    let mut beats: Vec<(i64, i64, i64)> = first
+        .map(|u| {
+            u.beats
+                .iter()
+                .map(|&(clock, n, d)| (clock / CLOCKS_PER_TICK, n.max(1), d.max(1)))
+                .collect()
+        })
+        .unwrap_or_default();
+    beats.sort_by_key(|b| b.0);
+    let bars = to_bars(&beats, resolution);
+    if !bars.is_empty() {
+        let pre_measure = v.master_track.pre_measure;
+        v.master_track.time_signatures = bars
+            .into_iter()
+            .map(|(bar, numerator, denominator)| vsqx4::TimeSignature {
+                position: if bar == 0 { 0 } else { bar + pre_measure },
+                numerator,
+                denominator,
+            })
+            .collect();
+    }
+    let offset = v.master_track.pre_measure_ticks();
+
+    // テンポ
+    let mut tempos: Vec<(i64, i64)> = first
+        .map(|u| {
+            u.tempos
+                .iter()
+                .map(|&(clock, bpm)| (clock / CLOCKS_PER_TICK, bpm_to_value(bpm)))
+                .collect()
+        })
+        .unwrap_or_default();
+    tempos.sort_by_key(|t| t.0);
+    if !tempos.is_empty() {
+        if tempos[0].0 != 0 {
+            tempos.insert(0, (0, 12000));
+        }
+        v.master_track.tempos = tempos
+            .iter()
+            .map(|&(tick, value)| vsqx4::Tempo {
+                position: if tick == 0 { 0 } else { tick + offset },
+                value,
+            })
+            .collect();
+    }
+    let tempo_map = TempoMap::new(resolution, tempos);
+
+    let voice = &v.voice_table.voices[0];
+    let singer = vsqx4::Singer {
+        position: 0,
+        bs: voice.bs,
+        pc: voice.pc,
+    };
+
+    // トラックの一覧（どのトラックにも属さないユニットは最後のトラックにまとめる）
+    let mut groups: Vec<Option<&Group>> = ccs.groups.iter().map(Some).collect();
+    if ccs
+        .units
+        .iter()
+        .any(|u| !ccs.groups.iter().any(|g| g.id == u.group))
+    {
+        groups.push(None);
+    }
+
+    for (i, group) in groups.into_iter().enumerate() {
+        let mut vs_track = vsqx4::VsTrack {
+            track_no: i as i64,
+            name: group.map_or_else(|| "Song".into(), |g| g.name.clone()),
+            ..Default::default()
+        };
+        let mut vs_unit = vsqx4::VsUnit {
+            track_no: i as i64,
+            ..Default::default()
+        };
+
+        if let Some(g) = group {
+            vs_unit.volume = (g.volume * 10.0).round() as i64;
+            vs_unit.pan = (g.pan * 64.0 / 100.0).round() as i64 + 64;
+            vs_unit.mute = g.is_muted as i64;
+            vs_unit.solo = g.is_solo as i64;
+            if !g.cast_id.is_empty() {
+                unmapped.push(Unmapped::track(i, format!("singer {:?}", g.cast_id)));
+            }
+        }
+
+        let units = ccs.units.iter().filter(|u| match group {
+            Some(g) => u.group == g.id,
+            None => !ccs.groups.iter().any(|g| g.id == u.group),
+        });
+        for unit in units {
+            if unit.notes.is_empty() {
+                continue;
+            }
+
+            let index = vs_track.parts.len();
+            let mut part = convert_unit(unit, &tempo_map, i, index, &mut unmapped);
+            part.position += offset;
+            part.singers.push(singer.clone());
+            vs_track.parts.push(part);
+        }
+
+        v.vs_track.push(vs_track);
+        v.mixer.vs_unit.push(vs_unit);
+    }
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    Conversion::new(v, unmapped)
+}
+
+/// ユニットをパートにする。位置はプリメジャーを含まない。
+fn convert_unit(
+    unit: &Unit,
+    tempo_map: &TempoMap,
+    track: usize,
+    index: usize,
+    unmapped: &mut Vec<Unmapped>,
+) -> vsqx4::VsPart {
+    let mut notes: Vec<&Note> = unit.notes.iter().collect();
+    notes.sort_by_key(|n| n.clock);
+    let start = notes[0].clock / CLOCKS_PER_TICK;
+
+    let notes: Vec<vsqx4::Note> = notes
+        .into_iter()
+        .map(|n| vsqx4::Note {
+            position: n.clock / CLOCKS_PER_TICK - start,
+            duration: (n.duration / CLOCKS_PER_TICK).max(1),
+            note_num: n.note_num(),
+            lyric: n.lyric.clone(),
+            phoneme: String::new(),
+            ..Default::default()
+        })
+        .collect();
+
+    // フレームをパートの先頭からのティックにする
+    let origin = parse_time(&unit.start_time);
+    let to_tick = |frame: i64| {
+        tempo_map
+            .ticks(origin + frame as f64 * FRAME_SECONDS)
+            .round() as i64
+            - start
+    };
+
+    let mut curves = vec![];
+    let parameters = &unit.parameters;
+
+    if let Some(log_f0) = &parameters.log_f0 {
+        let events = frames_to_events(log_f0, to_tick, 0, |tick, value| {
+            // そのときに鳴っている（なければ直前の）ノートからの差
+            let note = notes
+                .iter()
+                .rev()
+                .find(|n| n.position <= tick)
+                .or_else(|| notes.first())?;
+            let semitones = (value - log_frequency(note.note_num)) / 2f64.ln() * 12.0;
+            Some(semitones_to_pit(semitones, PITCH_BEND_SENS))
+        });
+
+        if !events.is_empty() {
+            curves.push(edit::Curve {
+                name: "S".into(),
+                events: vec![(0, PITCH_BEND_SENS)],
+            });
+            curves.push(edit::Curve {
+                name: "P".into(),
+                events,
+            });
+        }
+    }
+
+    for &(name, id, from, to) in PARAMETER_MAPPINGS {
+        if let Some(curve) = parameters.get(name) {
+            let default = to.1 as i64;
+            let events = frames_to_events(curve, to_tick, default, |_, value| {
+                Some(conversion::map_range(value, from, to).round() as i64)
+            });
+            if !events.is_empty() {
+                curves.push(edit::Curve {
+                    name: id.into(),
+                    events,
+                });
+            }
+        }
+    }
+
+    if matches!(&parameters.c0, Some(c0) if !c0.data.is_empty()) {
+        unmapped.push(Unmapped::part(track, index, "parameter \"C0\""));
+    }
+
+    let play_time = notes
+        .iter()
+        .map(|n| n.position + n.duration)
+        .max()
+        .unwrap_or(0);
+    let mut part = vsqx4::VsPart {
+        position: start,
+        play_time: Some(play_time as u64),
+        notes,
+        ..Default::default()
+    };
+    part.set_curves(curves);
+
+    part
+}
+
+/// フレームの値を`(位置, 値)`のイベントにする。値のないフレームでは`default`に戻す。
+fn frames_to_events<T: Fn(i64) -> i64, F: Fn(i64, f64) -> Option<i64>>(
+    curve: &Curve,
+    to_tick: T,
+    default: i64,
+    convert: F,
+) -> Vec<(i64, i64)> {
+    let mut events: Vec<(i64, i64)> = vec![];
+    let mut push = |tick: i64, value: i64| {
+        let tick = tick.max(0);
+        match events.last_mut() {
+            Some(last) if last.0 == tick => last.1 = value,
+            Some(last) if last.1 == value => {}
+            _ => events.push((tick, value)),
+        }
+    };
+
+    let mut prev = None;
+    for (frame, value) in curve.frames() {
+        if let Some(prev) = prev {
+            if frame != prev + 1 {
+                push(to_tick(prev + 1), default);
+            }
+        }
+
+        let tick = to_tick(frame);
+        if let Some(value) = convert(tick, value) {
+            push(tick, value);
+        }
+        prev = Some(frame);
+    }
+    if let Some(prev) = prev {
+        push(to_tick(prev + 1), default);
+    }
+
+    // 先頭の既定値は不要
+    if matches!(events.first(), Some(&(_, value)) if value == default) {
+        events.remove(0);
+    }
+
+    events
+}
+
+/// 小節の先頭の位置（プリメジャーを含まないティック）と拍子
+fn bar_positions(v: &Vsqx4) -> Vec<(i64, i64, i64)> {
+    let master = &v.master_track;
+    let mut time_signatures = master.time_signatures.clone();
+    time_signatures.sort_by_key(|ts| ts.position);
+
+    let mut positions = vec![];
+    let mut tick = 0;
+    let mut bar = 0;
+    for (i, ts) in time_signatures.iter().enumerate() {
+        if i > 0 {
+            let prev = &time_signatures[i - 1];
+            tick += (ts.position - bar) * master.resolution * 4 * prev.numerator / prev.denominator;
+        }
+        bar = ts.position;
+        positions.push((tick, ts.numerator, ts.denominator));
+    }
+
+    let offset = master.pre_measure_ticks();
+    positions
+        .into_iter()
+        .map(|(tick, n, d)| ((tick - offset).max(0), n, d))
+        .collect()
+}
+
+/// Vsqx4から.ccsへの変換。
+/// トラックごとに1つのトラック（`Group`）を、パートごとに1つのユニットを作る。
+/// パラメーターのフレームは曲の先頭から数える（ユニットの開始時刻は常に0）。
+pub(crate) fn convert_vsqx4_to_ccs(v: &Vsqx4) -> Conversion<Ccs> {
+    let mut unmapped = vec![];
+    let mut ccs = Ccs::default();
+    let offset = v.master_track.pre_measure_ticks();
+    let tempo_map = TempoMap::from_vsqx4(v);
+
+    let tempos: Vec<(i64, f64)> = tempo_map
+        .tempos()
+        .iter()
+        .map(|&(tick, value)| (tick.max(0) * CLOCKS_PER_TICK, value as f64 / 100.0))
+        .collect();
+    let mut beats: Vec<(i64, i64, i64)> = vec![];
+    for (tick, n, d) in bar_positions(v) {
+        let clock = tick * CLOCKS_PER_TICK;
+        beats.retain(|b| b.0 != clock);
+        beats.push((clock, n, d));
+    }
+
+    for (i, track) in v.vs_track.iter().enumerate() {
+        let id = format!("00000000-0000-0000-0000-{:012x}", i);
+        let vs_unit = v.mixer.vs_unit.iter().find(|u| u.track_no == i as i64);
+        ccs.groups.push(Group {
+            id: id.clone(),
+            name: track.name.clone(),
+            cast_id: String::new(),
+            language: "Japanese".into(),
+            volume: vs_unit.map_or(0.0, |u| u.volume as f64 / 10.0),
+            pan: vs_unit.map_or(0.0, |u| ((u.pan - 64) * 100) as f64 / 64.0),
+            is_solo: matches!(vs_unit, Some(u) if u.solo != 0),
+            is_muted: matches!(vs_unit, Some(u) if u.mute != 0),
+        });
+
+        let singer = track
+            .parts
+            .iter()
+            .flat_map(|p| p.singers.first())
+            .next()
+            .and_then(|s| v.voice_table.voices.get(s.pc as usize));
+        if let Some(voice) = singer {
+            unmapped.push(Unmapped::track(i, format!("singer {:?}", voice.name)));
+        }
+
+        for (j, part) in track.parts.iter().enumerate() {
+            let start = part.position - offset;
+            let mut lost = NoteLosses::default();
+
+            let notes: Vec<Note> = part
+                .notes
+                .iter()
+                .filter(|n| {
+                    let keep = start + n.position >= 0;
+                    if !keep {
+                        lost.add("pre-measure");
+                    }
+                    keep
+                })
+                .map(|n| {
+                    let mut note = Note {
+                        clock: (start + n.position) * CLOCKS_PER_TICK,
+                        duration: n.duration * CLOCKS_PER_TICK,
+                        lyric: n.lyric.clone(),
+                        ..Default::default()
+                    };
+                    note.set_note_num(n.note_num);
+                    note
+                })
+                .collect();
+            unmapped.extend(lost.into_unmapped(i, j));
+
+            let end = start + part.effective_length();
+            let mut unit = Unit {
+                group: id.clone(),
+                start_time: format_time(0.0),
+                duration: format_time(tempo_map.seconds(end)),
+                cast_id: String::new(),
+                language: "Japanese".into(),
+                tempos: tempos.clone(),
+                beats: beats.clone(),
+                notes,
+                ..Default::default()
+            };
+
+            append_parameters(&mut unit, part, start, &tempo_map, i, j, &mut unmapped);
+            ccs.units.push(unit);
+        }
+    }
+
+    Conversion::new(ccs, unmapped)
+}
+
+/// パートのカーブを.ccsのパラメーターにする。
+fn append_parameters(
+    unit: &mut Unit,
+    part: &vsqx4::VsPart,
+    start: i64,
+    tempo_map: &TempoMap,
+    track: usize,
+    index: usize,
+    unmapped: &mut Vec<Unmapped>,
+) {
+    let curves = part.curves();
+    let curve = |name: &str| curves.iter().find(|c| c.name == name);
+    // 曲の先頭からのフレームとパートの先頭からのティック
+    let frames = |from: i64, to: i64| {
+        let first = (tempo_map.seconds(start + from.max(-start)) / FRAME_SECONDS).ceil() as i64;
+        let last = (tempo_map.seconds(start + to) / FRAME_SECONDS).ceil() as i64;
+        (first..last).map(move |frame| {
+            let tick = tempo_map.ticks(frame as f64 * FRAME_SECONDS).round() as i64 - start;
+            (frame, tick)
+        })
+    };
+
+    let pit = curve("P");
+    if pit.is_some() {
+        let pbs = curve("S");
+        let mut values = vec![];
+
+        for n in &part.notes {
+            for (frame, tick) in frames(n.position, n.position + n.duration) {
+                let pit = pit.and_then(|c| c.value_at(tick)).unwrap_or(0);
+                let pbs = pbs.and_then(|c| c.value_at(tick)).unwrap_or(2);
+                let semitones = pit as f64 / 8192.0 * pbs as f64;
+                let value = log_frequency(n.note_num) + semitones / 12.0 * 2f64.ln();
+                values.push((frame, (value * 10000.0).round() / 10000.0));
+            }
+        }
+        values.sort_by_key(|v| v.0);
+        values.dedup_by_key(|v| v.0);
+        unit.parameters.log_f0 = Some(Curve::from_frames(&values));
+    }
+
+    for c in &curves {
+        if c.name == "P" || c.name == "S" {
+            continue;
+        }
+
+        let &(name, _, from, to) = match PARAMETER_MAPPINGS.iter().find(|m| m.1 == c.name) {
+            Some(mapping) => mapping,
+            None => {
+                unmapped.push(Unmapped::part(
+                    track,
+                    index,
+                    format!("control change {:?}", c.name),
+                ));
+                continue;
+            }
+        };
+
+        let values: Vec<(i64, f64)> = frames(0, part.effective_length())
+            .map(|(frame, tick)| {
+                let value = c.value_at(tick).unwrap_or(to.1 as i64) as f64;
+                let value = conversion::map_range(value, to, from);
+                (frame, (value * 1000.0).round() / 1000.0)
+            })
+            .collect();
+        *unit.parameters.get_mut(name).unwrap() = Some(Curve::from_frames(&values));
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// .ccsをVsqx4に変換し、ノート・テンポ・パラメーターが反映されるか確認する。
+fn test_ccs_to_vsqx4() {
+    let ccs = Ccs::from_bytes(include_bytes!("../test/test.ccs")).unwrap();
+    let conversion = ccs.to_vsqx4();
+    let v = &conversion.project;
+
+    assert_eq!(v.master_track.tempos[0].value, 12000);
+    assert_eq!(v.vs_track[0].name, "Song 1");
+
+    let part = &v.vs_track[0].parts[0];
+    assert_eq!(part.position, v.master_track.pre_measure_ticks());
+    assert_eq!(part.notes.len(), 2);
+    assert_eq!(part.notes[1].position, 480);
+    assert_eq!(part.notes[1].note_num, 62);
+
+    let curves = part.curves();
+    // 100フレーム目（0.5秒）は480ティック
+    let dynamics = curves.iter().find(|c| c.name == "D").unwrap();
+    assert_eq!(dynamics.value_at(480), Some(56));
+    // E4の周波数のLogF0は、D4のノートから2半音上
+    let pit = curves.iter().find(|c| c.name == "P").unwrap();
+    assert_eq!(pit.value_at(500), Some(8192 * 2 / 12));
+
+    assert!(conversion
+        .unmapped
+        .iter()
+        .any(|u| u.description == "parameter \"C0\""));
+}
+
+#[test]
+#[cfg(test)]
+/// Vsqx4を.ccsに変換し、読み直して戻すとノートとピッチが保たれるか確認する。
+fn test_vsqx4_to_ccs() {
+    let mut v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+    v.vs_track[0].parts[0].set_curves(vec![
+        edit::Curve {
+            name: "S".into(),
+            events: vec![(0, 2)],
+        },
+        edit::Curve {
+            name: "P".into(),
+            events: vec![(0, 4096)],
+        },
+    ]);
+
+    let ccs = Ccs::from_vsqx4(&v).project;
+    let ccs: Ccs = ccs.to_string().unwrap().parse().unwrap();
+    let v2 = ccs.to_vsqx4().project;
+
+    let notes = |v: &Vsqx4| {
+        let offset = v.master_track.pre_measure_ticks();
+        v.vs_track[0].parts[0]
+            .notes
+            .iter()
+            .map(|n| {
+                (
+                    v.vs_track[0].parts[0].position - offset + n.position,
+                    n.note_num,
+                    n.lyric.clone(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(notes(&v), notes(&v2));
+
+    // 1半音上げたピッチは、PBS 12のPITで1/12になる
+    let part = &v2.vs_track[0].parts[0];
+    let pit = part.curves().into_iter().find(|c| c.name == "P").unwrap();
+    let first = part.notes[0].position + 10;
+    assert!((pit.value_at(first).unwrap() - 8192 / 12).abs() <= 2);
+}
@@ -0,0 +1,520 @@
+//! CeVIO Creative Studioの.ccs形式（ソングトラック）
+//!
+//! 位置（`Clock`）は四分音符が`CLOCKS_PER_QUARTER`で、曲の先頭が0。
+//! パラメーター（`LogF0`など）は`FRAME_SECONDS`秒ごとのフレームの値の列で、
+//! `Index`（省略時は直前の続き）から`Repeat`個のフレームが同じ値を持つ。
+//!
+//! トークトラックや音源の設定などは扱わず、書き出すときはソングトラックのみを出力する。
+
+use crate::conversion::Conversion;
+use crate::read_xml::Element;
+use crate::write_xml::{write_empty, write_end, write_start};
+use crate::{vpr, vsqx4, Error, Result};
+use quick_xml::events::{BytesText, Event};
+use quick_xml::Writer;
+
+pub(crate) mod convert;
+
+/// 四分音符あたりのClock数
+pub const CLOCKS_PER_QUARTER: i64 = 960;
+/// パラメーターの1フレームの長さ（秒）
+pub const FRAME_SECONDS: f64 = 0.005;
+
+/// .ccsのプロジェクト
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Ccs {
+    /// ソングのユニット
+    pub units: Vec<Unit>,
+    /// トラック（ユニットは`Group`の`Id`でトラックを参照する）
+    pub groups: Vec<Group>,
+}
+
+/// トラック
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    /// キャスト（歌声）のID
+    pub cast_id: String,
+    pub language: String,
+    /// 音量（dB）
+    pub volume: f64,
+    pub pan: f64,
+    pub is_solo: bool,
+    pub is_muted: bool,
+}
+
+/// ソングのユニット（VOCALOIDのパートに相当する）
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Unit {
+    /// トラック（`Group`）のID
+    pub group: String,
+    /// 開始時刻（`00:00:00`の形式）
+    pub start_time: String,
+    /// 長さ（`00:00:00`の形式）
+    pub duration: String,
+    pub cast_id: String,
+    pub language: String,
+    /// `(Clock, BPM)`
+    pub tempos: Vec<(i64, f64)>,
+    /// `(Clock, 分子, 分母)`
+    pub beats: Vec<(i64, i64, i64)>,
+    pub notes: Vec<Note>,
+    pub parameters: Parameters,
+}
+
+/// ノート
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Note {
+    pub clock: i64,
+    pub duration: i64,
+    /// 音名（0〜11、Cが0）
+    pub pitch_step: i64,
+    /// オクターブ（中央のCが4）
+    pub pitch_octave: i64,
+    pub lyric: String,
+}
+
+/// パラメーター。編集されていないものは`None`。
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Parameters {
+    /// 基本周波数（Hz）の自然対数
+    pub log_f0: Option<Curve>,
+    pub c0: Option<Curve>,
+    /// 音量（dB）
+    pub volume: Option<Curve>,
+    /// 声質
+    pub alpha: Option<Curve>,
+}
+
+/// パラメーターの値の列
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Curve {
+    /// フレーム数
+    pub length: i64,
+    pub data: Vec<Data>,
+}
+
+/// `<Data>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Data {
+    /// 開始フレーム。`None`なら直前の`Data`の続き。
+    pub index: Option<i64>,
+    /// フレーム数。`None`なら1。
+    pub repeat: Option<i64>,
+    pub value: f64,
+}
+
+/// パラメーターの要素名
+const PARAMETER_NAMES: [&str; 4] = ["LogF0", "C0", "Volume", "Alpha"];
+
+impl Note {
+    /// MIDIノート番号
+    pub fn note_num(&self) -> i64 {
+        (self.pitch_octave + 1) * 12 + self.pitch_step
+    }
+
+    pub fn set_note_num(&mut self, note_num: i64) {
+        self.pitch_step = note_num.rem_euclid(12);
+        self.pitch_octave = note_num.div_euclid(12) - 1;
+    }
+}
+
+impl Parameters {
+    /// 要素名（`LogF0`など）でパラメーターを取り出す。
+    pub fn get(&self, name: &str) -> Option<&Curve> {
+        match name {
+            "LogF0" => self.log_f0.as_ref(),
+            "C0" => self.c0.as_ref(),
+            "Volume" => self.volume.as_ref(),
+            "Alpha" => self.alpha.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// 要素名（`LogF0`など）でパラメーターを取り出す。
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Option<Curve>> {
+        match name {
+            "LogF0" => Some(&mut self.log_f0),
+            "C0" => Some(&mut self.c0),
+            "Volume" => Some(&mut self.volume),
+            "Alpha" => Some(&mut self.alpha),
+            _ => None,
+        }
+    }
+}
+
+impl Curve {
+    /// 値を持つフレームの`(フレーム, 値)`
+    pub fn frames(&self) -> Vec<(i64, f64)> {
+        let mut frames = vec![];
+        let mut index = 0;
+
+        for d in &self.data {
+            index = d.index.unwrap_or(index);
+            for _ in 0..d.repeat.unwrap_or(1).max(1) {
+                frames.push((index, d.value));
+                index += 1;
+            }
+        }
+
+        frames
+    }
+
+    /// `(フレーム, 値)`から作る。同じ値の連続するフレームは1つの`Data`にまとめる。
+    pub fn from_frames(frames: &[(i64, f64)]) -> Self {
+        let mut data: Vec<Data> = vec![];
+        let mut next = 0;
+
+        for &(index, value) in frames {
+            match data.last_mut() {
+                Some(d) if index == next && d.value == value => {
+                    d.repeat = Some(d.repeat.unwrap_or(1) + 1);
+                }
+                _ => data.push(Data {
+                    index: if index == next && !data.is_empty() {
+                        None
+                    } else {
+                        Some(index)
+                    },
+                    repeat: None,
+                    value,
+                }),
+            }
+            next = index + 1;
+        }
+
+        Self {
+            length: frames.last().map_or(0, |f| f.0 + 1),
+            data,
+        }
+    }
+}
+
+impl Ccs {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// バイト列から読み込む。先頭のBOMは無視する。
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+
+        String::from_utf8_lossy(bytes).parse()
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        let mut bytes = vec![];
+        self.write(&mut bytes)?;
+
+        Ok(String::from_utf8_lossy(&bytes).into())
+    }
+
+    pub fn write<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        let mut writer = Writer::new_with_indent(writer, b' ', 2);
+        writer.write(br#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writer.write(b"\n")?;
+
+        write_start(&mut writer, "Scenario", &[("Code", "")])?;
+        write_start(&mut writer, "Sequence", &[("Id", "")])?;
+        write_start(&mut writer, "Scene", &[("Id", "")])?;
+
+        write_start(&mut writer, "Units", &[])?;
+        for unit in &self.units {
+            write_unit(&mut writer, unit)?;
+        }
+        write_end(&mut writer, "Units")?;
+
+        write_start(&mut writer, "Groups", &[])?;
+        for g in &self.groups {
+            write_empty(
+                &mut writer,
+                "Group",
+                &[
+                    ("Version", "1.0"),
+                    ("Id", &g.id),
+                    ("Category", "SingerSong"),
+                    ("Name", &g.name),
+                    ("Volume", &g.volume.to_string()),
+                    ("Pan", &g.pan.to_string()),
+                    ("IsSolo", &g.is_solo.to_string()),
+                    ("IsMuted", &g.is_muted.to_string()),
+                    ("CastId", &g.cast_id),
+                    ("Language", &g.language),
+                ],
+            )?;
+        }
+        write_end(&mut writer, "Groups")?;
+
+        write_end(&mut writer, "Scene")?;
+        write_end(&mut writer, "Sequence")?;
+        write_end(&mut writer, "Scenario")
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        use std::fs::File;
+        use std::io::BufWriter;
+
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    /// `Vsqx4`に変換する。
+    pub fn to_vsqx4(&self) -> Conversion<vsqx4::Vsqx4> {
+        convert::convert_ccs_to_vsqx4(self)
+    }
+
+    /// `Vpr`に変換する（`Vsqx4`を経由する）。
+    pub fn to_vpr(&self) -> Conversion<vpr::Vpr> {
+        self.to_vsqx4().map(vpr::Vpr::from)
+    }
+
+    /// `Vsqx4`から変換する。
+    pub fn from_vsqx4(v: &vsqx4::Vsqx4) -> Conversion<Self> {
+        convert::convert_vsqx4_to_ccs(v)
+    }
+
+    /// `Vpr`から変換する（`Vsqx4`を経由する）。
+    pub fn from_vpr(v: &vpr::Vpr) -> Conversion<Self> {
+        convert::convert_vsqx4_to_ccs(&v.clone().into())
+    }
+}
+
+impl std::str::FromStr for Ccs {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let root = Element::parse(s)?;
+        if root.name != "Scenario" {
+            return Err(ccs_error(format!("unsupported root <{}>", root.name)));
+        }
+
+        let mut ccs = Self::default();
+        for scene in root.children("Sequence").flat_map(|s| s.children("Scene")) {
+            for unit in scene.children("Units").flat_map(|u| u.children("Unit")) {
+                if unit.attribute("Category") == Some("SingerSong") {
+                    ccs.units.push(parse_unit(unit)?);
+                }
+            }
+
+            for g in scene.children("Groups").flat_map(|g| g.children("Group")) {
+                if g.attribute("Category") != Some("SingerSong") {
+                    continue;
+                }
+
+                let text = |name: &str| g.attribute(name).unwrap_or_default().to_string();
+                ccs.groups.push(Group {
+                    id: text("Id"),
+                    name: text("Name"),
+                    cast_id: text("CastId"),
+                    language: text("Language"),
+                    volume: parse_attribute(g, "Volume")?.unwrap_or(0.0),
+                    pan: parse_attribute(g, "Pan")?.unwrap_or(0.0),
+                    is_solo: parse_attribute(g, "IsSolo")?.unwrap_or(false),
+                    is_muted: parse_attribute(g, "IsMuted")?.unwrap_or(false),
+                });
+            }
+        }
+
+        Ok(ccs)
+    }
+}
+
+fn ccs_error<S: Into<String>>(message: S) -> Error {
+    Error::CcsError(message.into())
+}
+
+fn parse_attribute<T: std::str::FromStr>(e: &Element, name: &str) -> Result<Option<T>> {
+    match e.attribute(name) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ccs_error(format!("invalid {}: {:?}", name, value))),
+        None => Ok(None),
+    }
+}
+
+fn require<T: std::str::FromStr>(e: &Element, name: &str) -> Result<T> {
+    parse_attribute(e, name)?.ok_or_else(|| ccs_error(format!("<{}> has no {}", e.name, name)))
+}
+
+fn parse_unit(unit: &Element) -> Result<Unit> {
+    let text = |name: &str| unit.attribute(name).unwrap_or_default().to_string();
+    let mut u = Unit {
+        group: text("Group"),
+        start_time: text("StartTime"),
+        duration: text("Duration"),
+        cast_id: text("CastId"),
+        language: text("Language"),
+        ..Default::default()
+    };
+
+    let song = match unit.child("Song") {
+        Some(song) => song,
+        None => return Ok(u),
+    };
+
+    for sound in song.children("Tempo").flat_map(|t| t.children("Sound")) {
+        u.tempos
+            .push((require(sound, "Clock")?, require(sound, "Tempo")?));
+    }
+    for time in song.children("Beat").flat_map(|b| b.children("Time")) {
+        u.beats.push((
+            require(time, "Clock")?,
+            require(time, "Beats")?,
+            require(time, "BeatType")?,
+        ));
+    }
+    for note in song.children("Score").flat_map(|s| s.children("Note")) {
+        u.notes.push(Note {
+            clock: require(note, "Clock")?,
+            duration: require(note, "Duration")?,
+            pitch_step: require(note, "PitchStep")?,
+            pitch_octave: require(note, "PitchOctave")?,
+            lyric: note.attribute("Lyric").unwrap_or_default().to_string(),
+        });
+    }
+
+    if let Some(parameter) = song.child("Parameter") {
+        for &name in &PARAMETER_NAMES {
+            let curve = match parameter.child(name) {
+                Some(curve) => curve,
+                None => continue,
+            };
+
+            let data = curve
+                .children("Data")
+                .map(|d| {
+                    Ok(Data {
+                        index: parse_attribute(d, "Index")?,
+                        repeat: parse_attribute(d, "Repeat")?,
+                        value: d
+                            .text
+                            .trim()
+                            .parse()
+                            .map_err(|_| ccs_error(format!("invalid data {:?}", d.text)))?,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            *u.parameters.get_mut(name).unwrap() = Some(Curve {
+                length: parse_attribute(curve, "Length")?.unwrap_or(0),
+                data,
+            });
+        }
+    }
+
+    Ok(u)
+}
+
+fn write_unit<W: std::io::Write>(writer: &mut Writer<W>, u: &Unit) -> Result<()> {
+    write_start(
+        writer,
+        "Unit",
+        &[
+            ("Version", "1.0"),
+            ("Id", ""),
+            ("Category", "SingerSong"),
+            ("Group", &u.group),
+            ("StartTime", &u.start_time),
+            ("Duration", &u.duration),
+            ("CastId", &u.cast_id),
+            ("Language", &u.language),
+        ],
+    )?;
+    write_start(writer, "Song", &[("Version", "1.07")])?;
+
+    write_start(writer, "Tempo", &[])?;
+    for &(clock, tempo) in &u.tempos {
+        write_empty(
+            writer,
+            "Sound",
+            &[("Clock", &clock.to_string()), ("Tempo", &tempo.to_string())],
+        )?;
+    }
+    write_end(writer, "Tempo")?;
+
+    write_start(writer, "Beat", &[])?;
+    for &(clock, beats, beat_type) in &u.beats {
+        write_empty(
+            writer,
+            "Time",
+            &[
+                ("Clock", &clock.to_string()),
+                ("Beats", &beats.to_string()),
+                ("BeatType", &beat_type.to_string()),
+            ],
+        )?;
+    }
+    write_end(writer, "Beat")?;
+
+    write_start(writer, "Score", &[])?;
+    for n in &u.notes {
+        write_empty(
+            writer,
+            "Note",
+            &[
+                ("Clock", &n.clock.to_string()),
+                ("PitchStep", &n.pitch_step.to_string()),
+                ("PitchOctave", &n.pitch_octave.to_string()),
+                ("Duration", &n.duration.to_string()),
+                ("Lyric", &n.lyric),
+            ],
+        )?;
+    }
+    write_end(writer, "Score")?;
+
+    write_start(writer, "Parameter", &[])?;
+    for &name in &PARAMETER_NAMES {
+        let curve = match u.parameters.get(name) {
+            Some(curve) => curve,
+            None => continue,
+        };
+
+        write_start(writer, name, &[("Length", &curve.length.to_string())])?;
+        for d in &curve.data {
+            let index = d.index.map(|i| i.to_string());
+            let repeat = d.repeat.map(|r| r.to_string());
+            let mut attributes = vec![];
+            if let Some(index) = &index {
+                attributes.push(("Index", index.as_str()));
+            }
+            if let Some(repeat) = &repeat {
+                attributes.push(("Repeat", repeat.as_str()));
+            }
+
+            write_start(writer, "Data", &attributes)?;
+            writer.write_event(Event::Text(BytesText::from_plain_str(&d.value.to_string())))?;
+            write_end(writer, "Data")?;
+        }
+        write_end(writer, name)?;
+    }
+    write_end(writer, "Parameter")?;
+
+    write_end(writer, "Song")?;
+    write_end(writer, "Unit")
+}
+
+#[test]
+#[cfg(test)]
+/// .ccsを読み込み、書き出して読み直しても同じになるか確認する。
+fn test_ccs_roundtrip() {
+    let ccs = Ccs::from_bytes(include_bytes!("../test/test.ccs")).unwrap();
+
+    assert_eq!(ccs.units.len(), 1);
+    assert_eq!(ccs.groups[0].name, "Song 1");
+    let unit = &ccs.units[0];
+    assert_eq!(unit.tempos, vec![(0, 120.0)]);
+    assert_eq!(unit.notes[0].note_num(), 60);
+    assert_eq!(unit.notes[0].lyric, "ど");
+
+    let volume = unit.parameters.volume.as_ref().unwrap();
+    assert_eq!(volume.frames(), vec![(100, -3.0), (101, -3.0), (102, 1.5)]);
+    assert_eq!(
+        Curve::from_frames(&volume.frames()).frames(),
+        volume.frames()
+    );
+
+    let ccs2: Ccs = ccs.to_string().unwrap().parse().unwrap();
+    assert_eq!(ccs, ccs2);
+}
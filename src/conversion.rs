@@ -0,0 +1,167 @@
+//! 形式間の変換結果
+//!
+//! 形式によって表現できるデータが異なるため、変換先で表現できずに失われたデータは
+//! `Conversion::unmapped`に記録される。
+
+/// 変換先で表現できなかったデータ
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unmapped {
+    /// 変換元のトラックのインデックス
+    pub track: Option<usize>,
+    /// 変換元のパートのインデックス
+    pub part: Option<usize>,
+    /// 失われたデータの説明
+    pub description: String,
+}
+
+impl Unmapped {
+    pub(crate) fn project<S: Into<String>>(description: S) -> Self {
+        Self {
+            track: None,
+            part: None,
+            description: description.into(),
+        }
+    }
+
+    pub(crate) fn track<S: Into<String>>(track: usize, description: S) -> Self {
+        Self {
+            track: Some(track),
+            part: None,
+            description: description.into(),
+        }
+    }
+
+    pub(crate) fn part<S: Into<String>>(track: usize, part: usize, description: S) -> Self {
+        Self {
+            track: Some(track),
+            part: Some(part),
+            description: description.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Unmapped {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(track) = self.track {
+            write!(f, "track {}: ", track)?;
+        }
+        if let Some(part) = self.part {
+            write!(f, "part {}: ", part)?;
+        }
+        f.write_str(&self.description)
+    }
+}
+
+/// 変換結果
+#[derive(Clone, Debug)]
+pub struct Conversion<T> {
+    /// 変換後のプロジェクト
+    pub project: T,
+    /// 変換できなかったデータ
+    pub unmapped: Vec<Unmapped>,
+}
+
+/// パートごとにまとめて記録する、ノート単位の失われたデータ
+#[derive(Default)]
+pub(crate) struct NoteLosses(std::collections::BTreeMap<String, usize>);
+
+impl NoteLosses {
+    /// ノート1つ分の`what`が失われたことを記録する。
+    pub(crate) fn add<S: Into<String>>(&mut self, what: S) {
+        *self.0.entry(what.into()).or_default() += 1;
+    }
+
+    pub(crate) fn into_unmapped(self, track: usize, part: usize) -> Vec<Unmapped> {
+        self.0
+            .into_iter()
+            .map(|(what, count)| {
+                Unmapped::part(track, part, format!("{} of {} note(s)", what, count))
+            })
+            .collect()
+    }
+}
+
+impl<T> Conversion<T> {
+    pub(crate) fn new(project: T, unmapped: Vec<Unmapped>) -> Self {
+        Self { project, unmapped }
+    }
+
+    /// 失われたデータがなければ`true`
+    pub fn is_lossless(&self) -> bool {
+        self.unmapped.is_empty()
+    }
+
+    /// 失われたデータを捨てて、変換後のプロジェクトだけを取り出す。
+    pub fn into_project(self) -> T {
+        self.project
+    }
+
+    /// 変換後のプロジェクトをさらに変換する。
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Conversion<U> {
+        Conversion {
+            project: f(self.project),
+            unmapped: self.unmapped,
+        }
+    }
+}
+
+/// 値域`(最小, 既定, 最大)`
+pub(crate) type Range = (f64, f64, f64);
+
+/// 既定値を境に区分線形で値域を変換する。
+pub(crate) fn map_range(value: f64, from: Range, to: Range) -> f64 {
+    let (min, default, max) = from;
+    let value = value.max(min).min(max);
+
+    if value >= default {
+        if max == default {
+            to.1
+        } else {
+            to.1 + (value - default) * (to.2 - to.1) / (max - default)
+        }
+    } else {
+        to.1 - (default - value) * (to.1 - to.0) / (default - min)
+    }
+}
+
+/// 点の間を`ease`で補間して、`step`ごとの`(位置, 値)`にする。
+pub(crate) fn sample_points(
+    points: &[(i64, f64)],
+    step: i64,
+    ease: fn(f64) -> f64,
+) -> Vec<(i64, f64)> {
+    let mut events = vec![];
+
+    for pair in points.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        let mut x = x0;
+        while x < x1 {
+            let t = (x - x0) as f64 / (x1 - x0) as f64;
+            events.push((x, y0 + (y1 - y0) * ease(t)));
+            x += step;
+        }
+    }
+    if let Some(&last) = points.last() {
+        events.push(last);
+    }
+
+    events
+}
+
+/// 値が次のイベントまで保持される`(位置, 値)`を、直線で補間される点の列にする。
+pub(crate) fn hold_points<T: Copy>(events: &[(i64, T)]) -> Vec<(i64, T)> {
+    let mut points = vec![];
+
+    for (i, &(x, y)) in events.iter().enumerate() {
+        points.push((x, y));
+
+        // 次のイベントの直前まで値を保持する
+        if let Some(&(next, _)) = events.get(i + 1) {
+            if next - x > 1 {
+                points.push((next - 1, y));
+            }
+        }
+    }
+
+    points
+}
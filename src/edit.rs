@@ -0,0 +1,1093 @@
+//! パート・トラックに対する音楽的な編集操作
+//!
+//! `vsqx4::VsPart`と`vpr::Part`（およびそれぞれのトラック）に対して、
+//! 移調・時間移動・クオンタイズ・伸縮・ベロシティの調整・逆行と、
+//! パートの分割・結合を行う。
+//! コントロールチェンジ（VOCALOID5ではコントローラー）と歌手の切り替えもノートと一緒に動かす。
+//!
+//! ```
+//! use vsqx::edit::EditPart;
+//! use vsqx::vsqx4::{Note, VsPart};
+//!
+//! let mut part = VsPart::default();
+//! part.notes.push(Note {
+//!     position: 10,
+//!     duration: 470,
+//!     note_num: 60,
+//!     ..Default::default()
+//! });
+//!
+//! part.transpose(2);
+//! part.quantize(120).unwrap();
+//!
+//! assert_eq!(part.notes[0].note_num, 62);
+//! assert_eq!(part.notes[0].position, 0);
+//! ```
+
+use crate::{vpr, vsqx4, Error, Result};
+
+/// 編集操作の対象となるノート
+pub trait EditNote {
+    /// パート先頭からの位置
+    fn position(&self) -> i64;
+    fn set_position(&mut self, position: i64);
+    /// 長さ
+    fn duration(&self) -> i64;
+    fn set_duration(&mut self, duration: i64);
+    /// ノート番号
+    fn number(&self) -> i64;
+    fn set_number(&mut self, number: i64);
+    /// ベロシティ
+    fn velocity(&self) -> i64;
+    fn set_velocity(&mut self, velocity: i64);
+
+    /// 終了位置
+    fn end(&self) -> i64 {
+        self.position() + self.duration()
+    }
+}
+
+/// 編集操作の対象となるパート。
+///
+/// 必要なアクセサを実装すれば、編集操作はデフォルト実装で提供される。
+pub trait EditPart {
+    type Note: EditNote;
+
+    fn notes(&self) -> &[Self::Note];
+    fn notes_mut(&mut self) -> &mut Vec<Self::Note>;
+
+    /// パートの長さ
+    fn length(&self) -> i64;
+    fn set_length(&mut self, length: i64);
+
+    /// カーブ（コントロールチェンジを種類ごとにまとめたもの）
+    fn curves(&self) -> Vec<Curve>;
+    fn set_curves(&mut self, curves: Vec<Curve>);
+
+    /// パート固有のイベント（VOCALOID4の歌手の切り替えなど）を分割する。
+    /// `right`には`at`以降のイベントを先頭基準にして入れること。
+    fn split_attributes(&mut self, _right: &mut Self, _at: i64)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// パート固有のイベントを結合する。`other`は`offset`の位置に結合される。
+    fn merge_attributes(&mut self, _other: &Self, _offset: i64)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// `other`をこのパートに結合できるか（VOCALOID5では歌声が同じか）。
+    fn can_merge(&self, _other: &Self) -> bool
+    where
+        Self: Sized,
+    {
+        true
+    }
+
+    /// パート固有のイベントを`(位置, 値)`の配列として編集する。
+    /// 時間方向の編集操作で、カーブと同じように動かされる。
+    fn map_attributes<F: FnMut(&mut Vec<(i64, i64)>)>(&mut self, _f: F) {}
+
+    /// すべてのカーブのイベントを`(位置, 値)`の配列として編集する。
+    fn map_curves<F: FnMut(&mut Vec<(i64, i64)>)>(&mut self, mut f: F) {
+        let mut curves = self.curves();
+        for c in &mut curves {
+            f(&mut c.events);
+        }
+        self.set_curves(curves);
+    }
+
+    /// ノートの終わりまで含めたパートの長さ
+    fn effective_length(&self) -> i64 {
+        self.notes()
+            .iter()
+            .map(EditNote::end)
+            .max()
+            .unwrap_or(0)
+            .max(self.length())
+    }
+
+    /// 移調する。ノート番号は0〜127に収める。
+    fn transpose(&mut self, semitones: i64) {
+        for n in self.notes_mut() {
+            let number = (n.number() + semitones).clamp(0, 127);
+            n.set_number(number);
+        }
+    }
+
+    /// パート内でノートとカーブを`ticks`だけ動かす。
+    ///
+    /// パートの先頭より前に出たノートは切り詰められ、完全にはみ出したものは削除される。
+    /// 先頭より前に出たカーブは、最後の値だけが先頭に残る。
+    fn shift(&mut self, ticks: i64) {
+        let notes = self.notes_mut();
+        for n in notes.iter_mut() {
+            let position = n.position() + ticks;
+            if position < 0 {
+                n.set_duration(n.duration() + position);
+                n.set_position(0);
+            } else {
+                n.set_position(position);
+            }
+        }
+        notes.retain(|n| n.duration() > 0);
+
+        let mut edit = |events: &mut Vec<(i64, i64)>| {
+            for e in events.iter_mut() {
+                e.0 = (e.0 + ticks).max(0);
+            }
+            dedup_positions(events);
+        };
+        self.map_curves(&mut edit);
+        self.map_attributes(&mut edit);
+
+        extend_length(self);
+    }
+
+    /// ノートの開始位置と終了位置を`grid`ティックの格子に合わせる。
+    ///
+    /// 各ノートの範囲にあるカーブは、そのノートの開始位置の移動量だけ一緒に動く。
+    /// `grid`が正でなければエラーになる。
+    fn quantize(&mut self, grid: i64) -> Result<()> {
+        self.quantize_with_offset(grid, 0)
+    }
+
+    /// `offset`ティックずれた格子でクオンタイズする。
+    /// パートの位置が格子に乗っていない場合に使う。
+    fn quantize_with_offset(&mut self, grid: i64, offset: i64) -> Result<()> {
+        if grid <= 0 {
+            return Err(invalid_value(format!("grid {} must be positive", grid)));
+        }
+
+        let snap = |t: i64| ((t + offset) as f64 / grid as f64).round() as i64 * grid - offset;
+
+        // ノートごとの移動量
+        let mut moves = vec![];
+
+        for n in self.notes_mut().iter_mut() {
+            let (start, end) = (n.position(), n.end());
+            let mut new_start = snap(start);
+            if new_start < 0 {
+                new_start += grid;
+            }
+            let new_end = snap(end).max(new_start + grid);
+
+            moves.push((start, end, new_start - start));
+            n.set_position(new_start);
+            n.set_duration(new_end - new_start);
+        }
+
+        self.notes_mut().sort_by_key(EditNote::position);
+
+        let mut edit = |events: &mut Vec<(i64, i64)>| {
+            for e in events.iter_mut() {
+                if let Some(&(_, _, delta)) = moves.iter().find(|m| m.0 <= e.0 && e.0 < m.1) {
+                    e.0 = (e.0 + delta).max(0);
+                }
+            }
+            dedup_positions(events);
+        };
+        self.map_curves(&mut edit);
+        self.map_attributes(&mut edit);
+
+        extend_length(self);
+        Ok(())
+    }
+
+    /// 時間軸を`ratio`倍に伸縮する。`ratio`が正の有限値でなければエラーになる。
+    fn stretch(&mut self, ratio: f64) -> Result<()> {
+        if !(ratio.is_finite() && ratio > 0.0) {
+            return Err(invalid_value(format!("ratio {} must be positive", ratio)));
+        }
+
+        let scale = |t: i64| (t as f64 * ratio).round() as i64;
+
+        for n in self.notes_mut().iter_mut() {
+            let start = scale(n.position());
+            let end = scale(n.end());
+            n.set_position(start);
+            n.set_duration((end - start).max(1));
+        }
+
+        let mut edit = |events: &mut Vec<(i64, i64)>| {
+            for e in events.iter_mut() {
+                e.0 = scale(e.0);
+            }
+            dedup_positions(events);
+        };
+        self.map_curves(&mut edit);
+        self.map_attributes(&mut edit);
+
+        let length = scale(self.length());
+        self.set_length(length);
+        extend_length(self);
+        Ok(())
+    }
+
+    /// ベロシティを`factor`倍にする。ベロシティは0〜127に収める。
+    fn scale_velocity(&mut self, factor: f64) {
+        for n in self.notes_mut() {
+            let velocity = (n.velocity() as f64 * factor).round() as i64;
+            n.set_velocity(velocity.clamp(0, 127));
+        }
+    }
+
+    /// パートを時間方向に反転（逆行）する。
+    fn reverse(&mut self) {
+        let length = self.effective_length();
+
+        for n in self.notes_mut().iter_mut() {
+            let end = n.end();
+            n.set_position(length - end);
+        }
+        self.notes_mut().sort_by_key(EditNote::position);
+
+        let mut edit = |events: &mut Vec<(i64, i64)>| {
+            // [p_i, p_{i+1})の値v_iは、反転後[L - p_{i+1}, L - p_i)の値になる
+            let mut reversed = vec![];
+            for (i, &(pos, value)) in events.iter().enumerate() {
+                let next = events.get(i + 1).map(|e| e.0).unwrap_or(length);
+                if pos < length {
+                    reversed.push((length - next.min(length), value));
+                }
+            }
+            reversed.sort_by_key(|e| e.0);
+            *events = reversed;
+            dedup_positions(events);
+        };
+        self.map_curves(&mut edit);
+        self.map_attributes(&mut edit);
+
+        self.set_length(length);
+    }
+
+    /// パートを`at`ティック（パート先頭基準）で2つに分け、後半を返す。
+    ///
+    /// `at`をまたぐノートは`at`で切られる。
+    /// 後半のパートの位置は呼び出し側で設定すること（`EditTrack::split_at`を参照）。
+    fn split_off(&mut self, at: i64) -> Self
+    where
+        Self: Sized + Clone,
+    {
+        let length = self.effective_length();
+        let mut right = self.clone();
+
+        // ノート
+        let (mut left_notes, mut right_notes): (Vec<_>, Vec<_>) = std::mem::take(self.notes_mut())
+            .into_iter()
+            .partition(|n| n.position() < at);
+        for n in &mut left_notes {
+            if n.end() > at {
+                n.set_duration(at - n.position());
+            }
+        }
+        for n in &mut right_notes {
+            n.set_position(n.position() - at);
+        }
+        *self.notes_mut() = left_notes;
+        *right.notes_mut() = right_notes;
+
+        // カーブ（後半には分割位置での値を引き継ぐ）
+        let curves = self.curves();
+        self.set_curves(
+            curves
+                .iter()
+                .map(|c| Curve {
+                    name: c.name.clone(),
+                    events: c.events.iter().filter(|e| e.0 < at).cloned().collect(),
+                })
+                .filter(|c| !c.events.is_empty())
+                .collect(),
+        );
+        right.set_curves(
+            curves
+                .iter()
+                .map(|c| {
+                    let mut events: Vec<(i64, i64)> = c
+                        .events
+                        .iter()
+                        .filter(|e| e.0 >= at)
+                        .map(|&(pos, value)| (pos - at, value))
+                        .collect();
+                    if events.first().map(|e| e.0) != Some(0) {
+                        if let Some(value) = c.value_at(at) {
+                            events.insert(0, (0, value));
+                        }
+                    }
+                    Curve {
+                        name: c.name.clone(),
+                        events,
+                    }
+                })
+                .filter(|c| !c.events.is_empty())
+                .collect(),
+        );
+
+        self.split_attributes(&mut right, at);
+
+        self.set_length(at);
+        right.set_length((length - at).max(0));
+
+        right
+    }
+
+    /// `other`を`offset`ティック（このパートの先頭基準）の位置に結合する。
+    ///
+    /// `other`の範囲のカーブは`other`のもので置き換えられる。
+    /// `other`が範囲の先頭に値を持たなければ既定値（VOCALOIDの既定値が分かるものだけ）を、
+    /// 範囲の後ろにはこのパートの元の値を入れ直す。
+    /// 結合できないパート（`can_merge`を参照）はエラーになる。
+    fn merge(&mut self, mut other: Self, offset: i64) -> Result<()>
+    where
+        Self: Sized,
+    {
+        if !self.can_merge(&other) {
+            return Err(invalid_value(
+                "parts with different voices cannot be merged".into(),
+            ));
+        }
+
+        let self_length = self.effective_length();
+        let end = offset + other.effective_length();
+
+        // カーブ
+        let mut curves = self.curves();
+        let other_curves = other.curves();
+        for c in &other_curves {
+            if !curves.iter().any(|x| x.name == c.name) {
+                curves.push(Curve {
+                    name: c.name.clone(),
+                    events: vec![],
+                });
+            }
+        }
+        for x in &mut curves {
+            let other_curve = other_curves.iter().find(|c| c.name == x.name);
+            let mut events: Vec<(i64, i64)> =
+                x.events.iter().filter(|e| e.0 < offset).cloned().collect();
+
+            if !x.events.is_empty() {
+                let start = other_curve
+                    .and_then(|c| c.value_at(0))
+                    .or_else(|| default_curve_value(&x.name));
+                if let Some(start) = start {
+                    events.push((offset, start));
+                }
+            }
+            if let Some(c) = other_curve {
+                events.extend(
+                    c.events
+                        .iter()
+                        .map(|&(pos, value)| (pos + offset, value))
+                        .filter(|e| e.0 < end),
+                );
+            }
+            if end < self_length {
+                if let Some(value) = x.value_at(end) {
+                    events.push((end, value));
+                }
+            }
+            events.extend(x.events.iter().filter(|e| e.0 >= end).cloned());
+
+            dedup_positions(&mut events);
+            x.events = events;
+        }
+        curves.retain(|c| !c.events.is_empty());
+        self.set_curves(curves);
+
+        self.merge_attributes(&other, offset);
+
+        // ノート
+        let notes = std::mem::take(other.notes_mut());
+        for mut n in notes {
+            n.set_position(n.position() + offset);
+            self.notes_mut().push(n);
+        }
+        self.notes_mut().sort_by_key(EditNote::position);
+
+        let length = self.effective_length().max(end);
+        self.set_length(length);
+        Ok(())
+    }
+}
+
+/// カーブ（同じ種類のコントロールチェンジをまとめたもの）
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Curve {
+    /// 種類（VOCALOID4ではID、VOCALOID5ではコントローラー名）
+    pub name: String,
+    /// `(位置, 値)`の配列。値は次のイベントまで保持される。
+    pub events: Vec<(i64, i64)>,
+}
+
+impl Curve {
+    /// 位置`pos`での値。最初のイベントより前なら`None`。
+    pub fn value_at(&self, pos: i64) -> Option<i64> {
+        self.events
+            .iter()
+            .take_while(|e| e.0 <= pos)
+            .last()
+            .map(|e| e.1)
+    }
+}
+
+/// カーブの既定値（VOCALOID4のIDかVOCALOID5のコントローラー名で指定する）
+pub(crate) fn default_curve_value(name: &str) -> Option<i64> {
+    const DEFAULTS: &[(&str, i64)] = &[
+        ("D", 64),
+        ("P", 0),
+        ("S", 2),
+        ("B", 0),
+        ("R", 64),
+        ("C", 0),
+        ("G", 64),
+        ("T", 64),
+    ];
+
+    let id = crate::v4to5::CONTROLLER_NAMES
+        .iter()
+        .find(|(_, controller)| *controller == name)
+        .map_or(name, |(id, _)| *id);
+    DEFAULTS
+        .iter()
+        .find(|(x, _)| *x == id)
+        .map(|(_, value)| *value)
+}
+
+fn invalid_value(message: String) -> Error {
+    Error::InvalidValue {
+        message,
+        location: Box::default(),
+    }
+}
+
+/// 同じ位置のイベントを後のものだけ残すようにまとめる。
+fn dedup_positions(events: &mut Vec<(i64, i64)>) {
+    events.sort_by_key(|e| e.0);
+
+    let mut i = 0;
+    while i + 1 < events.len() {
+        if events[i].0 == events[i + 1].0 {
+            events.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// ノートがはみ出していたらパートを伸ばす。
+fn extend_length<P: EditPart + ?Sized>(part: &mut P) {
+    let length = part.effective_length();
+    part.set_length(length);
+}
+
+/// 編集操作の対象となるトラック
+pub trait EditTrack {
+    type Part: EditPart;
+
+    fn parts_mut(&mut self) -> &mut Vec<Self::Part>;
+
+    /// パートの位置
+    fn part_position(part: &Self::Part) -> i64;
+    fn set_part_position(part: &mut Self::Part, position: i64);
+
+    /// すべてのパートを移調する。
+    fn transpose(&mut self, semitones: i64) {
+        for p in self.parts_mut() {
+            p.transpose(semitones);
+        }
+    }
+
+    /// すべてのパートを`ticks`だけ動かす。位置は0より前には出ない。
+    fn shift(&mut self, ticks: i64) {
+        for p in self.parts_mut() {
+            let position = (Self::part_position(p) + ticks).max(0);
+            Self::set_part_position(p, position);
+        }
+    }
+
+    /// 各パートをトラック上の`grid`ティックの格子でクオンタイズする。
+    fn quantize(&mut self, grid: i64) -> Result<()> {
+        for p in self.parts_mut() {
+            let offset = Self::part_position(p);
+            p.quantize_with_offset(grid, offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// 最初のパートの先頭を基準に、時間軸を`ratio`倍に伸縮する。
+    fn stretch(&mut self, ratio: f64) -> Result<()> {
+        let origin = match self.parts_mut().iter().map(Self::part_position).min() {
+            Some(origin) => origin,
+            None => return Ok(()),
+        };
+
+        for p in self.parts_mut() {
+            p.stretch(ratio)?;
+            let offset = Self::part_position(p) - origin;
+            Self::set_part_position(p, origin + (offset as f64 * ratio).round() as i64);
+        }
+
+        Ok(())
+    }
+
+    /// すべてのパートのベロシティを`factor`倍にする。
+    fn scale_velocity(&mut self, factor: f64) {
+        for p in self.parts_mut() {
+            p.scale_velocity(factor);
+        }
+    }
+
+    /// 最初のパートの先頭から最後のパートの終わりまでを反転する。
+    fn reverse(&mut self) {
+        let parts = self.parts_mut();
+
+        let start = parts.iter().map(Self::part_position).min().unwrap_or(0);
+        let end = parts
+            .iter()
+            .map(|p| Self::part_position(p) + p.effective_length())
+            .max()
+            .unwrap_or(0);
+
+        for p in parts.iter_mut() {
+            p.reverse();
+            let position = start + end - (Self::part_position(p) + p.length());
+            Self::set_part_position(p, position);
+        }
+
+        parts.sort_by_key(Self::part_position);
+    }
+
+    /// トラック上の位置`at`でパートを分割する。
+    /// `at`を含むパートがなければ何もせず`false`を返す。
+    fn split_at(&mut self, at: i64) -> bool
+    where
+        Self::Part: Clone,
+    {
+        let parts = self.parts_mut();
+
+        let index = match parts.iter().position(|p| {
+            let position = Self::part_position(p);
+            position < at && at < position + p.effective_length()
+        }) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let position = Self::part_position(&parts[index]);
+        let mut right = parts[index].split_off(at - position);
+        Self::set_part_position(&mut right, at);
+        parts.insert(index + 1, right);
+
+        true
+    }
+
+    /// 位置順で`index`番目のパートとその次のパートを結合する。
+    /// 結合するパートがないか、結合できない（`EditPart::can_merge`を参照）場合は
+    /// 何もせず`false`を返す。
+    fn merge_adjacent(&mut self, index: usize) -> bool {
+        let parts = self.parts_mut();
+        parts.sort_by_key(Self::part_position);
+
+        if index + 1 >= parts.len() || !parts[index].can_merge(&parts[index + 1]) {
+            return false;
+        }
+
+        let next = parts.remove(index + 1);
+        let offset = Self::part_position(&next) - Self::part_position(&parts[index]);
+        parts[index].merge(next, offset).is_ok()
+    }
+
+    /// トラックのパートをまとめる。
+    /// 結合できないパート（`EditPart::can_merge`を参照）の手前で区切り、そこから新しくまとめ始める。
+    /// パートが重なっていた場合、ノートも重なりうるので注意（`overlap`モジュールを参照）。
+    fn consolidate(&mut self) {
+        let parts = self.parts_mut();
+        parts.sort_by_key(Self::part_position);
+
+        let mut merged: Vec<Self::Part> = vec![];
+        for p in std::mem::take(parts) {
+            match merged.last_mut() {
+                Some(last) if last.can_merge(&p) => {
+                    let offset = Self::part_position(&p) - Self::part_position(last);
+                    // `can_merge`を確かめたので失敗しない
+                    let _ = last.merge(p, offset);
+                }
+                _ => merged.push(p),
+            }
+        }
+
+        *parts = merged;
+    }
+}
+
+/* VOCALOID4 */
+
+impl EditNote for vsqx4::Note {
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    fn duration(&self) -> i64 {
+        self.duration
+    }
+
+    fn set_duration(&mut self, duration: i64) {
+        self.duration = duration;
+    }
+
+    fn number(&self) -> i64 {
+        self.note_num
+    }
+
+    fn set_number(&mut self, number: i64) {
+        self.note_num = number;
+    }
+
+    fn velocity(&self) -> i64 {
+        self.velocity
+    }
+
+    fn set_velocity(&mut self, velocity: i64) {
+        self.velocity = velocity;
+    }
+}
+
+impl EditPart for vsqx4::VsPart {
+    type Note = vsqx4::Note;
+
+    fn notes(&self) -> &[Self::Note] {
+        &self.notes
+    }
+
+    fn notes_mut(&mut self) -> &mut Vec<Self::Note> {
+        &mut self.notes
+    }
+
+    fn length(&self) -> i64 {
+        self.play_time.unwrap_or_default() as i64
+    }
+
+    fn set_length(&mut self, length: i64) {
+        self.play_time = Some(length.max(0) as u64);
+    }
+
+    fn curves(&self) -> Vec<Curve> {
+        // 出現順にIDごとにまとめる
+        let mut curves: Vec<Curve> = vec![];
+        for cc in &self.control_changes {
+            match curves.iter_mut().find(|c| c.name == cc.id) {
+                Some(c) => c.events.push((cc.pos, cc.value)),
+                None => curves.push(Curve {
+                    name: cc.id.clone(),
+                    events: vec![(cc.pos, cc.value)],
+                }),
+            }
+        }
+        curves
+    }
+
+    fn set_curves(&mut self, curves: Vec<Curve>) {
+        let mut control_changes: Vec<vsqx4::ControlChange> = curves
+            .into_iter()
+            .flat_map(|c| {
+                let id = c.name;
+                c.events
+                    .into_iter()
+                    .map(move |(pos, value)| vsqx4::ControlChange {
+                        id: id.clone(),
+                        pos,
+                        value,
+                    })
+            })
+            .collect();
+
+        // 安定ソートなので同じ位置ではIDの出現順が保たれる
+        control_changes.sort_by_key(|cc| cc.pos);
+        self.control_changes = control_changes;
+    }
+
+    fn map_attributes<F: FnMut(&mut Vec<(i64, i64)>)>(&mut self, mut f: F) {
+        // 値には歌手のインデックスを入れておく
+        let singers = std::mem::take(&mut self.singers);
+        let mut events: Vec<(i64, i64)> = singers
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.position, i as i64))
+            .collect();
+        f(&mut events);
+
+        self.singers = events
+            .into_iter()
+            .map(|(position, i)| vsqx4::Singer {
+                position,
+                ..singers[i as usize].clone()
+            })
+            .collect();
+    }
+
+    fn split_attributes(&mut self, right: &mut Self, at: i64) {
+        let active = self
+            .singers
+            .iter()
+            .take_while(|s| s.position <= at)
+            .last()
+            .cloned();
+
+        right.singers = self
+            .singers
+            .iter()
+            .filter(|s| s.position >= at)
+            .map(|s| vsqx4::Singer {
+                position: s.position - at,
+                ..s.clone()
+            })
+            .collect();
+        if right.singers.first().map(|s| s.position) != Some(0) {
+            if let Some(active) = active {
+                right.singers.insert(
+                    0,
+                    vsqx4::Singer {
+                        position: 0,
+                        ..active
+                    },
+                );
+            }
+        }
+
+        self.singers.retain(|s| s.position < at);
+    }
+
+    fn merge_attributes(&mut self, other: &Self, offset: i64) {
+        self.singers
+            .extend(other.singers.iter().map(|s| vsqx4::Singer {
+                position: s.position + offset,
+                ..s.clone()
+            }));
+        self.singers.sort_by_key(|s| s.position);
+
+        // 同じ歌手への切り替えは取り除く
+        self.singers
+            .dedup_by(|later, earlier| later.bs == earlier.bs && later.pc == earlier.pc);
+    }
+}
+
+impl EditTrack for vsqx4::VsTrack {
+    type Part = vsqx4::VsPart;
+
+    fn parts_mut(&mut self) -> &mut Vec<Self::Part> {
+        &mut self.parts
+    }
+
+    fn part_position(part: &Self::Part) -> i64 {
+        part.position
+    }
+
+    fn set_part_position(part: &mut Self::Part, position: i64) {
+        part.position = position;
+    }
+}
+
+/* VOCALOID5 */
+
+impl EditNote for vpr::Note {
+    fn position(&self) -> i64 {
+        self.pos
+    }
+
+    fn set_position(&mut self, position: i64) {
+        self.pos = position;
+    }
+
+    fn duration(&self) -> i64 {
+        self.duration as i64
+    }
+
+    fn set_duration(&mut self, duration: i64) {
+        self.duration = duration.max(0) as u64;
+    }
+
+    fn number(&self) -> i64 {
+        self.number
+    }
+
+    fn set_number(&mut self, number: i64) {
+        self.number = number;
+    }
+
+    fn velocity(&self) -> i64 {
+        self.velocity as i64
+    }
+
+    fn set_velocity(&mut self, velocity: i64) {
+        self.velocity = velocity.clamp(0, 255) as u8;
+    }
+}
+
+impl EditPart for vpr::Part {
+    type Note = vpr::Note;
+
+    fn notes(&self) -> &[Self::Note] {
+        &self.notes
+    }
+
+    fn notes_mut(&mut self) -> &mut Vec<Self::Note> {
+        &mut self.notes
+    }
+
+    fn length(&self) -> i64 {
+        self.duration as i64
+    }
+
+    fn set_length(&mut self, length: i64) {
+        self.duration = length.max(0) as u64;
+    }
+
+    fn curves(&self) -> Vec<Curve> {
+        self.controllers
+            .iter()
+            .map(|c| Curve {
+                name: c.name.clone(),
+                events: c.events.iter().map(|e| (e.pos, e.value)).collect(),
+            })
+            .collect()
+    }
+
+    fn set_curves(&mut self, curves: Vec<Curve>) {
+        self.controllers = curves
+            .into_iter()
+            .map(|c| vpr::Controller {
+                name: c.name,
+                events: c
+                    .events
+                    .into_iter()
+                    .map(|(pos, value)| vpr::ControlChange { pos, value })
+                    .collect(),
+            })
+            .collect();
+    }
+
+    fn can_merge(&self, other: &Self) -> bool {
+        self.voice == other.voice
+    }
+}
+
+impl EditTrack for vpr::Track {
+    type Part = vpr::Part;
+
+    fn parts_mut(&mut self) -> &mut Vec<Self::Part> {
+        &mut self.parts
+    }
+
+    fn part_position(part: &Self::Part) -> i64 {
+        part.pos as i64
+    }
+
+    fn set_part_position(part: &mut Self::Part, position: i64) {
+        part.pos = position.max(0) as u64;
+    }
+}
+
+#[cfg(test)]
+fn test_part() -> vsqx4::VsPart {
+    let mut part = vsqx4::VsPart {
+        play_time: Some(1920),
+        ..Default::default()
+    };
+    part.singers.push(vsqx4::Singer {
+        position: 0,
+        bs: 0,
+        pc: 0,
+    });
+
+    for (position, note_num) in &[(0, 60), (480, 62), (960, 64)] {
+        part.notes.push(vsqx4::Note {
+            position: *position,
+            duration: 480,
+            note_num: *note_num,
+            ..Default::default()
+        });
+    }
+
+    for (id, pos, value) in &[("D", 0, 64), ("P", 0, 0), ("D", 480, 100), ("P", 960, 8191)] {
+        part.control_changes.push(vsqx4::ControlChange {
+            id: (*id).into(),
+            pos: *pos,
+            value: *value,
+        });
+    }
+
+    part
+}
+
+#[test]
+#[cfg(test)]
+/// 伸縮・時間移動でカーブがノートと一緒に動くか確認する。
+fn test_edit_stretch_shift() {
+    let mut part = test_part();
+
+    part.singers.push(vsqx4::Singer {
+        position: 960,
+        bs: 0,
+        pc: 1,
+    });
+
+    part.stretch(2.0).unwrap();
+    assert_eq!(part.notes[2].position, 1920);
+    assert_eq!(part.notes[2].duration, 960);
+    assert_eq!(part.play_time, Some(3840));
+    assert_eq!(part.control_changes[2].pos, 960);
+    assert_eq!(part.singers[1].position, 1920);
+    assert!(part.stretch(0.0).is_err());
+    assert!(part.quantize(0).is_err());
+
+    part.shift(-960);
+    assert_eq!(part.notes.len(), 2);
+    assert_eq!(part.notes[0].position, 0);
+    // 先頭より前に出たカーブは最後の値だけ残る
+    let dyn_events: Vec<_> = part
+        .control_changes
+        .iter()
+        .filter(|cc| cc.id == "D")
+        .map(|cc| (cc.pos, cc.value))
+        .collect();
+    assert_eq!(dyn_events, vec![(0, 100)]);
+    // 歌手の切り替えもカーブと同じように動く
+    let singers: Vec<_> = part.singers.iter().map(|s| (s.position, s.pc)).collect();
+    assert_eq!(singers, vec![(0, 0), (960, 1)]);
+
+    // クオンタイズではノートの移動量だけ動く
+    part.notes[1].position = 970;
+    part.singers[1].position = 980;
+    part.quantize(480).unwrap();
+    assert_eq!(part.notes[1].position, 960);
+    assert_eq!(part.singers[1].position, 970);
+}
+
+#[test]
+#[cfg(test)]
+/// 逆行でノートとカーブが正しく反転されるか確認する。
+fn test_edit_reverse() {
+    let mut part = test_part();
+    part.singers.push(vsqx4::Singer {
+        position: 480,
+        bs: 0,
+        pc: 1,
+    });
+    part.reverse();
+
+    let notes: Vec<_> = part
+        .notes
+        .iter()
+        .map(|n| (n.position, n.note_num))
+        .collect();
+    assert_eq!(notes, vec![(480, 64), (960, 62), (1440, 60)]);
+
+    let pit: Vec<_> = part
+        .control_changes
+        .iter()
+        .filter(|cc| cc.id == "P")
+        .map(|cc| (cc.pos, cc.value))
+        .collect();
+    assert_eq!(pit, vec![(0, 8191), (960, 0)]);
+
+    // [0, 480)の歌手は反転後[1440, 1920)になる
+    let singers: Vec<_> = part.singers.iter().map(|s| (s.position, s.pc)).collect();
+    assert_eq!(singers, vec![(0, 1), (1440, 0)]);
+}
+
+#[test]
+#[cfg(test)]
+/// 分割・結合でノートとカーブ、長さが元に戻るか確認する。
+fn test_edit_split_merge() {
+    let mut track = vsqx4::VsTrack::default();
+    let mut part = test_part();
+    part.position = 7680;
+    track.parts.push(part.clone());
+
+    assert!(track.split_at(7680 + 720));
+    assert_eq!(track.parts.len(), 2);
+
+    let right = &track.parts[1];
+    assert_eq!(right.position, 7680 + 720);
+    assert_eq!(right.play_time, Some(1200));
+    assert_eq!(right.notes[0].position, 240);
+    // 分割位置で有効な値が引き継がれる
+    assert_eq!(right.control_changes[0].pos, 0);
+    assert_eq!(right.singers.len(), 1);
+
+    // 分割位置をまたぐノートは切られる
+    assert_eq!(track.parts[0].notes[1].duration, 240);
+
+    track.consolidate();
+    assert_eq!(track.parts.len(), 1);
+    assert_eq!(track.parts[0].notes.len(), 3);
+    assert_eq!(track.parts[0].play_time, Some(1920));
+    assert_eq!(track.parts[0].singers.len(), 1);
+}
+
+#[test]
+#[cfg(test)]
+/// 結合したパートの範囲では、後のパートのカーブと歌声が使われるか確認する。
+fn test_edit_merge_curves() {
+    let mut left = test_part();
+    left.play_time = Some(3840);
+    // 1920以降にも元のパートの値がある
+    left.control_changes.push(vsqx4::ControlChange {
+        id: "D".into(),
+        pos: 2400,
+        value: 90,
+    });
+    let mut right = vsqx4::VsPart {
+        play_time: Some(960),
+        ..Default::default()
+    };
+    right.notes.push(vsqx4::Note {
+        position: 0,
+        duration: 960,
+        ..Default::default()
+    });
+    right.control_changes.push(vsqx4::ControlChange {
+        id: "P".into(),
+        pos: 480,
+        value: -100,
+    });
+
+    left.merge(right, 960).unwrap();
+    let curve = |id: &str| -> Vec<(i64, i64)> {
+        left.control_changes
+            .iter()
+            .filter(|cc| cc.id == id)
+            .map(|cc| (cc.pos, cc.value))
+            .collect()
+    };
+    // 後のパートにDYNがなければ既定値になり、範囲の後ろで元の値に戻る
+    assert_eq!(
+        curve("D"),
+        vec![(0, 64), (480, 100), (960, 64), (1920, 100), (2400, 90)]
+    );
+    assert_eq!(
+        curve("P"),
+        vec![(0, 0), (960, 0), (1440, -100), (1920, 8191)]
+    );
+
+    // 歌声の違うVOCALOID5のパートは結合しない
+    let vpr = crate::vpr::Vpr::from_bytes(include_bytes!("test/v5.vpr")).unwrap();
+    let mut track = vpr.tracks[1].clone();
+    let mut other = track.parts[0].clone();
+    other.pos += other.duration;
+    other.voice.comp_id = "other".into();
+    assert!(track.parts[0].clone().merge(other.clone(), 0).is_err());
+    track.parts.push(other);
+    track.consolidate();
+    assert_eq!(track.parts.len(), 2);
+    assert!(!track.merge_adjacent(0));
+}
@@ -0,0 +1,215 @@
+//! XMLファイル（.vsqxなど）の文字コードの判定と変換
+//!
+//! 読み込むときは、BOM、（BOMがなければ）先頭のバイト列の並び、XML宣言の`encoding`の順に文字コードを決める。
+//! 宣言の名前は`encoding_rs`で解決するので、EUC-JPなども宣言どおりに読める。
+//! 宣言がUTF-8（または宣言なし）でもUTF-8として読めない場合は、Shift_JISとみなす。
+//! 古いツールやWindowsで編集されたファイルは、宣言と実際の文字コードが違うことがあるため。
+
+/// XMLファイルの文字コード
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XmlEncoding {
+    Utf8,
+    /// BOM付きのUTF-16（リトルエンディアン）
+    Utf16Le,
+    /// BOM付きのUTF-16（ビッグエンディアン）
+    Utf16Be,
+    /// Shift_JIS。表せない文字は文字参照（`&#...;`）になる。
+    /// 表せない文字を含む文字列は、CDATAにせずにエスケープして書き出す。
+    ShiftJis,
+    /// XML宣言で指定されたそれ以外の文字コード（EUC-JPなど）。表せない文字はShift_JISと同様に扱う。
+    Other(&'static encoding_rs::Encoding),
+}
+
+impl XmlEncoding {
+    /// XML宣言の`encoding`に書く名前
+    pub fn label(self) -> &'static str {
+        match self {
+            XmlEncoding::Utf8 => "UTF-8",
+            XmlEncoding::Utf16Le | XmlEncoding::Utf16Be => "UTF-16",
+            XmlEncoding::ShiftJis => "Shift_JIS",
+            XmlEncoding::Other(e) => e.name(),
+        }
+    }
+
+    /// バイト列の文字コードを判定する。
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"\xEF\xBB\xBF") {
+            return XmlEncoding::Utf8;
+        }
+        if bytes.starts_with(b"\xFF\xFE") || bytes.starts_with(b"<\0?\0") {
+            return XmlEncoding::Utf16Le;
+        }
+        if bytes.starts_with(b"\xFE\xFF") || bytes.starts_with(b"\0<\0?") {
+            return XmlEncoding::Utf16Be;
+        }
+
+        let declared = declared_encoding(bytes)
+            .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()));
+        let valid_utf8 = std::str::from_utf8(bytes).is_ok();
+        match declared {
+            Some(e) if e == encoding_rs::SHIFT_JIS && (!valid_utf8 || bytes.is_ascii()) => {
+                XmlEncoding::ShiftJis
+            }
+            // UTF-16はBOMかバイト列の並びで判定済みなので、ここでの宣言は当てにならない
+            Some(e)
+                if e != encoding_rs::SHIFT_JIS
+                    && e != encoding_rs::UTF_8
+                    && e != encoding_rs::UTF_16LE
+                    && e != encoding_rs::UTF_16BE
+                    && e != encoding_rs::REPLACEMENT =>
+            {
+                XmlEncoding::Other(e)
+            }
+            _ if valid_utf8 => XmlEncoding::Utf8,
+            _ => XmlEncoding::ShiftJis,
+        }
+    }
+
+    /// バイト列を文字列にする。BOMは取り除く。
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            XmlEncoding::Utf8 => {
+                let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+            XmlEncoding::Utf16Le => {
+                let bytes = bytes.strip_prefix(b"\xFF\xFE").unwrap_or(bytes);
+                encoding_rs::UTF_16LE
+                    .decode_without_bom_handling(bytes)
+                    .0
+                    .into_owned()
+            }
+            XmlEncoding::Utf16Be => {
+                let bytes = bytes.strip_prefix(b"\xFE\xFF").unwrap_or(bytes);
+                encoding_rs::UTF_16BE
+                    .decode_without_bom_handling(bytes)
+                    .0
+                    .into_owned()
+            }
+            XmlEncoding::ShiftJis => encoding_rs::SHIFT_JIS
+                .decode_without_bom_handling(bytes)
+                .0
+                .into_owned(),
+            XmlEncoding::Other(e) => e.decode_without_bom_handling(bytes).0.into_owned(),
+        }
+    }
+
+    /// `text`のすべての文字を文字参照を使わずに表せるか
+    pub fn can_encode(self, text: &str) -> bool {
+        match self {
+            XmlEncoding::Utf8 | XmlEncoding::Utf16Le | XmlEncoding::Utf16Be => true,
+            XmlEncoding::ShiftJis => !encoding_rs::SHIFT_JIS.encode(text).2,
+            XmlEncoding::Other(e) => !e.encode(text).2,
+        }
+    }
+
+    /// 文字列をバイト列にする。XML宣言の`encoding`はこの文字コードに書き換える。
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        let text = replace_declared_encoding(text, self.label());
+
+        match self {
+            XmlEncoding::Utf8 => text.into_bytes(),
+            XmlEncoding::Utf16Le => {
+                let mut bytes = vec![0xFF, 0xFE];
+                bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+                bytes
+            }
+            XmlEncoding::Utf16Be => {
+                let mut bytes = vec![0xFE, 0xFF];
+                bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+                bytes
+            }
+            XmlEncoding::ShiftJis => encoding_rs::SHIFT_JIS.encode(&text).0.into_owned(),
+            XmlEncoding::Other(e) => e.encode(&text).0.into_owned(),
+        }
+    }
+}
+
+/// 文字コードを判定して文字列にする。
+pub(crate) fn decode_xml(bytes: &[u8]) -> String {
+    XmlEncoding::detect(bytes).decode(bytes)
+}
+
+/// XML宣言の`encoding`の値（ASCIIとして読めるもののみ）
+fn declared_encoding(bytes: &[u8]) -> Option<String> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    if !bytes.starts_with(b"<?xml") {
+        return None;
+    }
+    let end = bytes.windows(2).position(|w| w == b"?>")?;
+    let declaration = std::str::from_utf8(&bytes[..end]).ok()?;
+
+    let (_, rest) = declaration.split_once("encoding")?;
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let value = &rest[1..];
+
+    Some(value[..value.find(quote)?].to_string())
+}
+
+/// XML宣言の`encoding`を`label`にする。宣言に`encoding`がなければそのまま。
+fn replace_declared_encoding(text: &str, label: &str) -> String {
+    let end = match text.find("?>") {
+        Some(end) if text.starts_with("<?xml") => end,
+        _ => return text.to_string(),
+    };
+
+    let declaration = &text[..end];
+    let start = match declaration.find("encoding") {
+        Some(i) => i,
+        None => return text.to_string(),
+    };
+    let rest = &declaration[start..];
+    let value_start = match rest.find(['"', '\'']) {
+        Some(i) => start + i + 1,
+        None => return text.to_string(),
+    };
+    let quote = &text[value_start - 1..value_start];
+    let value_end = match text[value_start..end].find(quote) {
+        Some(i) => value_start + i,
+        None => return text.to_string(),
+    };
+
+    format!("{}{}{}", &text[..value_start], label, &text[value_end..])
+}
+
+#[test]
+#[cfg(test)]
+/// 各文字コードで書き出したVsqx4を、文字コードを判定して読み直せるか確認する。
+fn test_xml_encoding() {
+    use crate::vsqx4::Vsqx4;
+
+    let mut v: Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    v.vs_track[0].parts[0].notes[0].lyric = "ら".into();
+
+    for &encoding in &[
+        XmlEncoding::Utf8,
+        XmlEncoding::Utf16Le,
+        XmlEncoding::Utf16Be,
+        XmlEncoding::ShiftJis,
+        XmlEncoding::Other(encoding_rs::EUC_JP),
+    ] {
+        let mut bytes = vec![];
+        v.write_encoded(&mut bytes, encoding).unwrap();
+        assert_eq!(XmlEncoding::detect(&bytes), encoding);
+
+        let v2 = Vsqx4::from_bytes(&bytes).unwrap();
+        assert_eq!(v, v2, "{:?}", encoding);
+    }
+
+    // UTF-8と宣言されたShift_JIS
+    let text = v.to_string().unwrap();
+    let (sjis, _, _) = encoding_rs::SHIFT_JIS.encode(&text);
+    assert_eq!(XmlEncoding::detect(&sjis), XmlEncoding::ShiftJis);
+    assert_eq!(Vsqx4::from_bytes(&sjis).unwrap(), v);
+
+    // Shift_JIS以外の宣言も尊重される
+    let text = replace_declared_encoding(&text, "EUC-JP");
+    let (euc, _, _) = encoding_rs::EUC_JP.encode(&text);
+    assert!(std::str::from_utf8(&euc).is_err());
+    assert_eq!(
+        XmlEncoding::detect(&euc),
+        XmlEncoding::Other(encoding_rs::EUC_JP)
+    );
+    assert_eq!(Vsqx4::from_bytes(&euc).unwrap(), v);
+}
@@ -0,0 +1,422 @@
+//! HTS形式のラベル（.lab）の書き出し
+//!
+//! NNSVS・ENUNUなどの歌声合成の学習に使う楽譜ラベルを、ボーカルトラックから作る。
+//! 1行が1音素で、開始・終了時刻（100ナノ秒単位）と音素（モノラベル）またはコンテキスト（フルラベル）からなる。
+//!
+//! 音素は`Note::phoneme`（空白区切り）を使う。母音より前の子音はノートの先頭に、
+//! 母音より後の子音はノートの末尾に`CONSONANT_SECONDS`秒ずつ置き、残りを母音に割り当てる。
+//! 歌詞が`-`（または音素がない）ノートは、直前のノートの母音を伸ばしたものとして扱う。
+//!
+//! フルラベルのコンテキストは次の形式（未定義の値は`xx`）。
+//!
+//! ```text
+//! p1^p2-p3+p4=p5_p6%p7/D:d1!d2/E:e1]e2^e3=e4~e5!e6@e7/F:f1#f2/G:g1_g2/H:h1
+//! ```
+//!
+//! - `p1`〜`p5`: 2つ前〜2つ後の音素（`p3`が現在の音素）
+//! - `p6`・`p7`: ノート内の音素の位置（前から・後ろから、1から数える）
+//! - `D`・`F`: 前・次のノートの音名（`C4`など）と長さ（10ミリ秒単位）
+//! - `E`: 現在のノートの音名、ノート番号、長さ（10ミリ秒単位）、長さ（ティック）、テンポ（BPM）、
+//!   フレーズ内のノートの位置（前から・後ろから）
+//! - `G`: フレーズの番号（1から）とフレーズ内のノート数
+//! - `H`: フレーズの数
+//!
+//! フレーズは休符（`pau`・`sil`）で区切られたノートの並び。
+
+use crate::{vpr, vsqx4, Result};
+
+/// 1秒あたりのラベルの時間単位数（100ナノ秒単位）
+pub const UNITS_PER_SECOND: f64 = 10_000_000.0;
+/// 子音1つの長さ（秒）。ノートの長さの半分を超える場合は縮める。
+pub const CONSONANT_SECONDS: f64 = 0.05;
+/// 最後のノートの後に置く無音の長さ（秒）
+pub const END_SILENCE_SECONDS: f64 = 0.5;
+
+/// 母音（と音節の中心になる音素）。VOCALOIDの日本語と英語の音素記号。
+const VOWELS: &[&str] = &[
+    "a", "i", "M", "e", "o", "N\\", "@", "V", "I", "i:", "{", "O:", "Q", "U", "u:", "@r", "eI",
+    "aI", "OI", "@U", "aU", "I@", "e@", "U@", "O@", "Q@",
+];
+
+const PITCH_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+];
+
+/// ラベルの形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabelFormat {
+    /// 開始・終了時刻とコンテキスト
+    Full,
+    /// 開始・終了時刻と音素
+    Mono,
+}
+
+/// 1トラック分のラベル
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Labels {
+    pub labels: Vec<Label>,
+}
+
+/// 各ラベルから見た前後のノート（を持つラベルの番号）と、フレーズの数
+#[derive(Default)]
+struct Neighbours {
+    prev: Vec<Option<usize>>,
+    next: Vec<Option<usize>>,
+    phrase_count: usize,
+}
+
+/// 1音素のラベル
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    /// 開始時刻（100ナノ秒単位）
+    pub start: i64,
+    /// 終了時刻（100ナノ秒単位）
+    pub end: i64,
+    pub phoneme: String,
+    /// 音素が属するノート。休符では`None`。
+    pub note: Option<NoteContext>,
+}
+
+/// 音素が属するノートの情報
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoteContext {
+    /// トラック内のノートの番号（0から）
+    pub index: usize,
+    pub note_num: i64,
+    /// 長さ（100ナノ秒単位）
+    pub length: i64,
+    /// 長さ（ティック）
+    pub ticks: i64,
+    /// ノートの開始位置のテンポ（BPM）
+    pub bpm: f64,
+    /// ノート内の音素の位置（0から）と音素の数
+    pub phoneme_index: usize,
+    pub phoneme_count: usize,
+    /// フレーズの番号（0から）
+    pub phrase: usize,
+    /// フレーズ内のノートの位置（0から）とノートの数
+    pub note_in_phrase: usize,
+    pub phrase_notes: usize,
+}
+
+/// ノート番号の音名（`C4`など）
+pub(crate) fn pitch_name(note_num: i64) -> String {
+    format!(
+        "{}{}",
+        PITCH_NAMES[note_num.rem_euclid(12) as usize],
+        note_num.div_euclid(12) - 1
+    )
+}
+
+fn units(seconds: f64) -> i64 {
+    (seconds * UNITS_PER_SECOND).round() as i64
+}
+
+/// 音素の列のうち、母音のインデックス（なければ最後の音素）
+fn vowel_index(phonemes: &[String]) -> usize {
+    phonemes
+        .iter()
+        .position(|p| VOWELS.contains(&p.as_str()))
+        .unwrap_or(phonemes.len() - 1)
+}
+
+impl Labels {
+    /// `Vsqx4`の`index`番目のトラックからラベルを作る。
+    /// プリメジャー中のノートは含まず、重なっているノートは後のノートの開始位置で切る。
+    pub fn from_vsqx4_track(v: &vsqx4::Vsqx4, index: usize) -> Self {
+        let notes = v.absolute_notes();
+        let tempo_map = notes.tempo_map().clone();
+
+        let mut notes: Vec<_> = notes
+            .filter(|n| n.track == index && n.tick >= 0 && n.note.duration > 0)
+            .collect();
+        notes.sort_by_key(|n| n.tick);
+
+        // `(開始ティック, 終了ティック, ノート番号, 音素)`
+        let mut events: Vec<(i64, i64, i64, Vec<String>)> = vec![];
+        let mut previous_vowel: Option<String> = None;
+        for n in &notes {
+            let mut phonemes: Vec<String> = n
+                .note
+                .phoneme
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            if n.note.lyric == "-" || phonemes.is_empty() || phonemes == ["-"] {
+                match &previous_vowel {
+                    Some(vowel) => phonemes = vec![vowel.clone()],
+                    None => continue,
+                }
+            }
+            previous_vowel = Some(phonemes[vowel_index(&phonemes)].clone());
+
+            if let Some(last) = events.last_mut() {
+                if last.1 > n.tick {
+                    last.1 = n.tick;
+                }
+            }
+            events.retain(|e| e.1 > e.0);
+            events.push((n.tick, n.tick + n.note.duration, n.note.note_num, phonemes));
+        }
+
+        // フレーズ（隙間なく続くノートの並び）
+        let mut phrases: Vec<usize> = vec![];
+        for (i, e) in events.iter().enumerate() {
+            if i == 0 || events[i - 1].1 < e.0 {
+                phrases.push(1);
+            } else {
+                *phrases.last_mut().unwrap() += 1;
+            }
+        }
+
+        let mut labels = vec![];
+        let mut time = 0;
+        let mut phrase = 0;
+        let mut note_in_phrase = 0;
+        for (i, (start_tick, end_tick, note_num, phonemes)) in events.iter().enumerate() {
+            let start = units(tempo_map.seconds(*start_tick));
+            let end = units(tempo_map.seconds(*end_tick));
+
+            if start > time {
+                labels.push(Label {
+                    start: time,
+                    end: start,
+                    phoneme: if i == 0 { "sil" } else { "pau" }.into(),
+                    note: None,
+                });
+                if i > 0 {
+                    phrase += 1;
+                    note_in_phrase = 0;
+                }
+            }
+
+            // 子音はノートの長さの半分までに収める
+            let vowel = vowel_index(phonemes);
+            let consonants = (phonemes.len() - 1) as i64;
+            let consonant = if consonants > 0 {
+                units(CONSONANT_SECONDS).min((end - start) / 2 / consonants)
+            } else {
+                0
+            };
+            let coda = (phonemes.len() - 1 - vowel) as i64;
+            let vowel_start = start + consonant * vowel as i64;
+            let vowel_end = end - consonant * coda;
+
+            for (j, p) in phonemes.iter().enumerate() {
+                let (s, e) = match j.cmp(&vowel) {
+                    std::cmp::Ordering::Less => (
+                        start + consonant * j as i64,
+                        start + consonant * (j + 1) as i64,
+                    ),
+                    std::cmp::Ordering::Equal => (vowel_start, vowel_end),
+                    std::cmp::Ordering::Greater => {
+                        let k = (j - vowel - 1) as i64;
+                        (vowel_end + consonant * k, vowel_end + consonant * (k + 1))
+                    }
+                };
+
+                labels.push(Label {
+                    start: s,
+                    end: e,
+                    phoneme: p.clone(),
+                    note: Some(NoteContext {
+                        index: i,
+                        note_num: *note_num,
+                        length: end - start,
+                        ticks: end_tick - start_tick,
+                        bpm: tempo_map.bpm_at(*start_tick),
+                        phoneme_index: j,
+                        phoneme_count: phonemes.len(),
+                        phrase,
+                        note_in_phrase,
+                        phrase_notes: phrases[phrase],
+                    }),
+                });
+            }
+
+            note_in_phrase += 1;
+            time = end;
+        }
+
+        labels.push(Label {
+            start: time,
+            end: time + units(END_SILENCE_SECONDS),
+            phoneme: "sil".into(),
+            note: None,
+        });
+
+        Self { labels }
+    }
+
+    /// `Vpr`の`index`番目のトラックからラベルを作る（`Vsqx4`を経由する）。
+    pub fn from_vpr_track(v: &vpr::Vpr, index: usize) -> Self {
+        Self::from_vsqx4_track(&v.clone().into(), index)
+    }
+
+    /// フルコンテキストで参照する前後のノートとフレーズの数を、全ラベル分まとめて求める。
+    fn neighbours(&self) -> Neighbours {
+        let labels = &self.labels;
+        let note_index = |l: &Label| l.note.as_ref().map(|n| n.index);
+
+        // 直前に現れた2つのノートの`(ノートの番号, ラベルの番号)`
+        let scan = |order: &mut dyn Iterator<Item = usize>| {
+            let mut found = vec![None; labels.len()];
+            let mut last: Option<(usize, usize)> = None;
+            let mut before_last: Option<(usize, usize)> = None;
+            for i in order {
+                let index = note_index(&labels[i]);
+                found[i] = match last {
+                    Some((n, _)) if Some(n) == index => before_last.map(|(_, j)| j),
+                    _ => last.map(|(_, j)| j),
+                };
+                if let Some(index) = index {
+                    match last {
+                        Some((n, _)) if n == index => last = Some((n, i)),
+                        _ => {
+                            before_last = last;
+                            last = Some((index, i));
+                        }
+                    }
+                }
+            }
+            found
+        };
+
+        Neighbours {
+            prev: scan(&mut (0..labels.len())),
+            next: scan(&mut (0..labels.len()).rev()),
+            phrase_count: labels
+                .iter()
+                .filter_map(|l| l.note.as_ref())
+                .map(|n| n.phrase + 1)
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// `i`番目のラベルのフルコンテキスト。
+    ///
+    /// 呼ぶたびにすべてのラベルを調べるので、全体を書き出すときは`to_string`を使うこと。
+    pub fn context(&self, i: usize) -> String {
+        self.context_with(i, &self.neighbours())
+    }
+
+    fn context_with(&self, i: usize, neighbours: &Neighbours) -> String {
+        let labels = &self.labels;
+        let phoneme = |offset: isize| {
+            let j = i as isize + offset;
+            if j < 0 {
+                return "xx";
+            }
+            labels.get(j as usize).map_or("xx", |l| l.phoneme.as_str())
+        };
+
+        let prev = neighbours.prev[i].and_then(|j| labels[j].note.as_ref());
+        let next = neighbours.next[i].and_then(|j| labels[j].note.as_ref());
+        let xx = || "xx".to_string();
+        let name = |n: Option<&NoteContext>| n.map_or_else(xx, |n| pitch_name(n.note_num));
+        let length =
+            |n: Option<&NoteContext>| n.map_or_else(xx, |n| (n.length / 100_000).to_string());
+
+        let note = labels[i].note.as_ref();
+        let field = |f: fn(&NoteContext) -> String| note.map_or_else(xx, f);
+
+        format!(
+            "{}^{}-{}+{}={}_{}%{}/D:{}!{}/E:{}]{}^{}={}~{}!{}@{}/F:{}#{}/G:{}_{}/H:{}",
+            phoneme(-2),
+            phoneme(-1),
+            phoneme(0),
+            phoneme(1),
+            phoneme(2),
+            field(|n| (n.phoneme_index + 1).to_string()),
+            field(|n| (n.phoneme_count - n.phoneme_index).to_string()),
+            name(prev),
+            length(prev),
+            name(note),
+            field(|n| n.note_num.to_string()),
+            length(note),
+            field(|n| n.ticks.to_string()),
+            field(|n| format!("{}", n.bpm)),
+            field(|n| (n.note_in_phrase + 1).to_string()),
+            field(|n| (n.phrase_notes - n.note_in_phrase).to_string()),
+            name(next),
+            length(next),
+            field(|n| (n.phrase + 1).to_string()),
+            field(|n| n.phrase_notes.to_string()),
+            neighbours.phrase_count,
+        )
+    }
+
+    pub fn to_string(&self, format: LabelFormat) -> String {
+        let neighbours = match format {
+            LabelFormat::Full => self.neighbours(),
+            LabelFormat::Mono => Neighbours::default(),
+        };
+
+        let mut s = String::new();
+        for (i, l) in self.labels.iter().enumerate() {
+            let text = match format {
+                LabelFormat::Full => self.context_with(i, &neighbours),
+                LabelFormat::Mono => l.phoneme.clone(),
+            };
+            s += &format!("{} {} {}\n", l.start, l.end, text);
+        }
+
+        s
+    }
+
+    pub fn write<W: std::io::Write>(&self, mut writer: W, format: LabelFormat) -> Result<()> {
+        writer.write_all(self.to_string(format).as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        format: LabelFormat,
+    ) -> Result<()> {
+        self.write(std::fs::File::create(path)?, format)
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// Vsqx4からモノラベルとフルラベルを作り、時刻とコンテキストを確認する。
+fn test_labels() {
+    let v: vsqx4::Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    let labels = Labels::from_vsqx4_track(&v, 0);
+
+    let mono = labels.to_string(LabelFormat::Mono);
+    let lines: Vec<&str> = mono.lines().collect();
+    // BPM 80なので1拍は0.75秒。最初のノート「gh Q d」は0秒から
+    assert_eq!(lines[0], "0 500000 gh");
+    assert_eq!(lines[1], "500000 7000000 Q");
+    assert_eq!(lines[2], "7000000 7500000 d");
+    assert!(lines.last().unwrap().ends_with(" sil"));
+
+    // 時刻は途切れずに続く
+    for w in labels.labels.windows(2) {
+        assert_eq!(w[0].end, w[1].start);
+    }
+
+    let full = labels.to_string(LabelFormat::Full);
+    let first = full.lines().next().unwrap();
+    assert!(first.starts_with("0 500000 xx^xx-gh+Q=d_1%3/D:xx!xx/E:G3]55^75=480~80!1@"));
+    assert_eq!(
+        full.lines().count(),
+        mono.lines().count(),
+        "フルラベルとモノラベルの行数は同じ"
+    );
+
+    // 前後のノートは、そのラベルのノートと違う最も近いノート
+    let neighbours = labels.neighbours();
+    let ls = &labels.labels;
+    for i in 0..ls.len() {
+        let index = ls[i].note.as_ref().map(|n| n.index);
+        let differs =
+            |j: &usize| ls[*j].note.is_some() && ls[*j].note.as_ref().map(|n| n.index) != index;
+        assert_eq!(neighbours.prev[i], (0..i).rev().find(differs));
+        assert_eq!(neighbours.next[i], (i + 1..ls.len()).find(differs));
+    }
+}
@@ -1,8 +1,30 @@
+pub mod builder;
+pub mod ccs;
+pub mod conversion;
+pub mod edit;
+pub mod encoding;
+pub mod hts;
+pub mod lyrics;
+pub mod midi;
+pub mod musicxml;
+pub mod overlap;
+pub mod schema;
+pub mod svp;
+pub mod table;
+pub mod timeline;
+pub mod ust;
+pub mod ustx;
+pub mod validate;
 pub mod vpr;
+pub mod vsq;
 pub mod vsqx3;
 pub mod vsqx4;
+pub mod write_options;
 
+pub(crate) mod locate;
+pub(crate) mod read_xml;
 pub(crate) mod write_xml;
+pub(crate) mod write_zip;
 
 // ダウングレード用プログラム
 pub(crate) mod v5to4;
@@ -10,48 +32,116 @@ pub(crate) mod v5to4;
 // アップグレード用プログラム
 pub(crate) mod v4to5;
 
-use failure::Fail;
-#[derive(Fail, Debug)]
-pub enum Error {
-    #[fail(display = "failed to serialize/deserialize JSON: {}", _0)]
-    SerdeJsonError(serde_json::Error),
-    #[fail(display = "IO Error: {}", _0)]
-    IoError(std::io::Error),
-    #[fail(display = "Zip Error: {}", _0)]
-    ZipError(zip::result::ZipError),
-    #[fail(display = "XML error: {}", _0)]
-    XmlError(quick_xml::Error),
-    #[fail(display = "XML deserialize error: {}", _0)]
-    XmlDeError(quick_xml::DeError),
-}
-
-impl From<serde_json::Error> for Error {
-    fn from(e: serde_json::Error) -> Self {
-        Self::SerdeJsonError(e)
-    }
+/// エラーの起きた場所。分かる項目だけが入る。
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// XMLの行（1から）
+    pub line: Option<usize>,
+    /// XMLの列（1から、バイト単位）
+    pub column: Option<usize>,
+    /// JSONポインタ（`/tracks/1/parts/0`など）。VSQXでは要素をたどるパス（`/vsq4/mixer/vsUnit/0`など）
+    pub pointer: Option<String>,
+    /// トラックの番号（0から）
+    pub track: Option<usize>,
+    /// トラック内のパートの番号（0から）
+    pub part: Option<usize>,
+    /// パート内のノートの番号（0から）
+    pub note: Option<usize>,
 }
 
-impl From<std::io::Error> for Error {
-    fn from(e: std::io::Error) -> Self {
-        Self::IoError(e)
-    }
-}
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut items = vec![];
+        if let Some(line) = self.line {
+            items.push(format!("line {}", line));
+        }
+        if let Some(column) = self.column {
+            items.push(format!("column {}", column));
+        }
+        if let Some(pointer) = &self.pointer {
+            items.push(pointer.clone());
+        }
+        if let Some(track) = self.track {
+            items.push(format!("track {}", track));
+        }
+        if let Some(part) = self.part {
+            items.push(format!("part {}", part));
+        }
+        if let Some(note) = self.note {
+            items.push(format!("note {}", note));
+        }
 
-impl From<zip::result::ZipError> for Error {
-    fn from(e: zip::result::ZipError) -> Self {
-        Self::ZipError(e)
+        if items.is_empty() {
+            write!(f, "unknown location")
+        } else {
+            write!(f, "{}", items.join(", "))
+        }
     }
 }
 
-impl From<quick_xml::Error> for Error {
-    fn from(e: quick_xml::Error) -> Self {
-        Self::XmlError(e)
-    }
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to serialize/deserialize JSON: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Zip Error: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("XML error: {0}")]
+    XmlError(#[from] quick_xml::Error),
+    #[error("XML deserialize error: {0}")]
+    XmlDeError(#[from] quick_xml::DeError),
+    #[error("invalid project: {0}")]
+    InvalidProject(String),
+    #[error("unknown root element <{found}> (expected <{expected}>) at {location}")]
+    UnknownRoot {
+        found: String,
+        expected: &'static str,
+        location: Box<Location>,
+    },
+    #[error("missing element {element} at {location}")]
+    MissingElement {
+        element: String,
+        location: Box<Location>,
+    },
+    #[error("invalid value at {location}: {message}")]
+    InvalidValue {
+        message: String,
+        location: Box<Location>,
+    },
+    #[error("voice {voice} is not defined (at {location})")]
+    DanglingVoice {
+        voice: String,
+        location: Box<Location>,
+    },
+    #[error("Project/sequence.json is missing in the .vpr archive")]
+    MissingSequence,
+    #[error("UST parse error: {0}")]
+    UstError(String),
+    #[error("CeVIO error: {0}")]
+    CcsError(String),
+    #[error("MIDI parse error: {0}")]
+    MidiError(String),
+    #[error("MusicXML error: {0}")]
+    MusicXmlError(String),
+    #[error("VSQ parse error: {0}")]
+    VsqError(String),
+    #[error("table error: {0}")]
+    TableError(String),
+    #[error("YAML error: {0}")]
+    YamlError(#[from] serde_yaml::Error),
 }
 
-impl From<quick_xml::DeError> for Error {
-    fn from(e: quick_xml::DeError) -> Self {
-        Self::XmlDeError(e)
+impl Error {
+    /// エラーの起きた場所（分かる場合）
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::UnknownRoot { location, .. }
+            | Error::MissingElement { location, .. }
+            | Error::InvalidValue { location, .. }
+            | Error::DanglingVoice { location, .. } => Some(location.as_ref()),
+            _ => None,
+        }
     }
 }
 
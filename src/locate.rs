@@ -0,0 +1,351 @@
+//! 読み込みエラーの場所を調べる
+//!
+//! serdeのエラーには場所が含まれない（quick-xml）か、行と列しか含まれない（serde_json）ので、
+//! 失敗したときだけ元の文字列を辿り直して、行・列、JSONポインタ、トラック・パート・ノートの番号を求める。
+
+use crate::{Error, Location, Result};
+use quick_xml::events::Event;
+use quick_xml::{DeError, Reader};
+use serde::de::DeserializeOwned;
+
+/// XMLの要素名。トラック・パート・ノートの順。
+pub(crate) type ItemNames = [&'static str; 3];
+
+/// `text`の`offset`バイト目の行と列（どちらも1から）
+pub(crate) fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text.as_bytes()[..offset.min(text.len())];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let line_start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+
+    (line, offset - line_start + 1)
+}
+
+/// XMLとしてルート要素が`root`であるか確かめてから、serdeで読み込む。
+pub(crate) fn from_xml<T, Track, Part, Note>(
+    text: &str,
+    root: &'static str,
+    items: ItemNames,
+) -> Result<T>
+where
+    T: DeserializeOwned,
+    Track: DeserializeOwned,
+    Part: DeserializeOwned,
+    Note: DeserializeOwned,
+{
+    check_root(text, root)?;
+
+    quick_xml::de::from_str(text).map_err(|e| {
+        locate_xml::<Track, Part, Note>(text, items)
+            .unwrap_or_else(|| de_error(e, Location::default()))
+    })
+}
+
+/// 最初の要素の名前を確かめる。
+fn check_root(text: &str, root: &'static str) -> Result<()> {
+    let mut reader = Reader::from_str(text);
+    let mut buf = vec![];
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) => {
+                if e.name() == root.as_bytes() {
+                    return Ok(());
+                }
+
+                let (line, column) = line_column(text, reader.buffer_position() - e.len() - 2);
+                return Err(Error::UnknownRoot {
+                    found: String::from_utf8_lossy(e.name()).into(),
+                    expected: root,
+                    location: Box::new(Location {
+                        line: Some(line),
+                        column: Some(column),
+                        ..Default::default()
+                    }),
+                });
+            }
+            Event::Eof => {
+                return Err(Error::MissingElement {
+                    element: format!("<{}>", root),
+                    location: Box::default(),
+                })
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+/// トラック・パート・ノートを1つずつ読み込み直して、最初に失敗した要素のエラーを返す。
+/// ノート、パート、トラックの順に閉じるので、最も内側の要素が見つかる。
+fn locate_xml<Track, Part, Note>(text: &str, items: ItemNames) -> Option<Error>
+where
+    Track: DeserializeOwned,
+    Part: DeserializeOwned,
+    Note: DeserializeOwned,
+{
+    let mut reader = Reader::from_str(text);
+    let mut buf = vec![];
+
+    // 開いている要素の開始位置
+    let mut stack: Vec<usize> = vec![];
+    // 次のトラック・パート・ノートの番号
+    let mut counts = [0usize; 3];
+    loop {
+        let event = reader.read_event(&mut buf).ok()?;
+        match &event {
+            Event::Start(e) => {
+                stack.push(reader.buffer_position() - e.len() - 2);
+            }
+            Event::End(e) => {
+                let start = stack.pop()?;
+                let level = match items.iter().position(|item| item.as_bytes() == e.name()) {
+                    Some(level) => level,
+                    None => {
+                        buf.clear();
+                        continue;
+                    }
+                };
+
+                let xml = &text[start..reader.buffer_position()];
+                let result = match level {
+                    0 => quick_xml::de::from_str::<Track>(xml).err(),
+                    1 => quick_xml::de::from_str::<Part>(xml).err(),
+                    _ => quick_xml::de::from_str::<Note>(xml).err(),
+                };
+                if let Some(e) = result {
+                    let (line, column) = line_column(text, start);
+                    let location = Location {
+                        line: Some(line),
+                        column: Some(column),
+                        track: Some(counts[0]),
+                        part: Some(counts[1]).filter(|_| level >= 1),
+                        note: Some(counts[2]).filter(|_| level >= 2),
+                        ..Default::default()
+                    };
+                    return Some(de_error(e, location));
+                }
+
+                // 内側の番号は外側の要素が閉じたら振り直す
+                counts[level] += 1;
+                for count in &mut counts[level + 1..] {
+                    *count = 0;
+                }
+            }
+            Event::Eof => return None,
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+/// serdeのエラーを場所付きのエラーにする。
+pub(crate) fn de_error(e: DeError, location: Location) -> Error {
+    let location = Box::new(location);
+    match e {
+        DeError::Custom(message) => match missing_field(&message) {
+            Some(field) => Error::MissingElement {
+                element: format!("<{}>", field),
+                location,
+            },
+            None => Error::InvalidValue { message, location },
+        },
+        DeError::Int(e) => Error::InvalidValue {
+            message: e.to_string(),
+            location,
+        },
+        DeError::Float(e) => Error::InvalidValue {
+            message: e.to_string(),
+            location,
+        },
+        DeError::InvalidBoolean(value) => Error::InvalidValue {
+            message: format!("invalid boolean {:?}", value),
+            location,
+        },
+        e => Error::XmlDeError(e),
+    }
+}
+
+/// serdeの`missing field `name``から`name`を取り出す。
+fn missing_field(message: &str) -> Option<&str> {
+    let field = message.strip_prefix("missing field `")?;
+    Some(&field[..field.find('`')?])
+}
+
+/// JSONをserdeで読み込み、失敗したら場所付きのエラーにする。
+pub(crate) fn from_json<T: DeserializeOwned>(text: &str) -> Result<T> {
+    use serde_json::error::Category;
+
+    serde_json::from_str(text).map_err(|e| {
+        if e.classify() != Category::Data {
+            return Error::SerdeJsonError(e);
+        }
+
+        let offset = text
+            .split_inclusive('\n')
+            .take(e.line() - 1)
+            .map(str::len)
+            .sum::<usize>()
+            + e.column();
+        let pointer = json_pointer(&text[..offset.min(text.len())]);
+
+        let mut location = Box::new(Location {
+            line: Some(e.line()),
+            column: Some(e.column()),
+            ..Default::default()
+        });
+        let segments: Vec<&str> = pointer.split('/').collect();
+        for (key, item) in [
+            ("tracks", &mut location.track),
+            ("parts", &mut location.part),
+            ("notes", &mut location.note),
+        ] {
+            *item = segments
+                .windows(2)
+                .find(|w| w[0] == key)
+                .and_then(|w| w[1].parse().ok());
+        }
+        location.pointer = Some(pointer);
+
+        // serde_jsonのメッセージの末尾には行と列が付いている
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(i) => message[..i].to_string(),
+            None => message,
+        };
+        match missing_field(&message) {
+            Some(field) => Error::MissingElement {
+                element: format!("\"{}\"", field),
+                location,
+            },
+            None => Error::InvalidValue { message, location },
+        }
+    })
+}
+
+/// JSONの先頭`text`を読んだ時点で、いまいる値のJSONポインタ
+fn json_pointer(text: &str) -> String {
+    enum Frame {
+        Object { key: Option<String>, in_key: bool },
+        Array { index: usize },
+    }
+
+    let mut stack: Vec<Frame> = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => stack.push(Frame::Object {
+                key: None,
+                in_key: true,
+            }),
+            '[' => stack.push(Frame::Array { index: 0 }),
+            '}' | ']' => {
+                stack.pop();
+            }
+            ',' => match stack.last_mut() {
+                Some(Frame::Object { in_key, .. }) => *in_key = true,
+                Some(Frame::Array { index }) => *index += 1,
+                None => {}
+            },
+            ':' => {
+                if let Some(Frame::Object { in_key, .. }) = stack.last_mut() {
+                    *in_key = false;
+                }
+            }
+            '"' => {
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            if let Some(c) = chars.next() {
+                                s.push(c);
+                            }
+                        }
+                        c => s.push(c),
+                    }
+                }
+                if let Some(Frame::Object { key, in_key: true }) = stack.last_mut() {
+                    *key = Some(s);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut pointer = String::new();
+    for frame in &stack {
+        match frame {
+            Frame::Object { key: Some(key), .. } => {
+                pointer += "/";
+                pointer += &key.replace('~', "~0").replace('/', "~1");
+            }
+            Frame::Object { key: None, .. } => {}
+            Frame::Array { index } => pointer += &format!("/{}", index),
+        }
+    }
+
+    pointer
+}
+
+#[test]
+#[cfg(test)]
+/// 壊れたVSQX4・VPRのエラーが、正しい場所を指しているか確認する。
+fn test_located_errors() {
+    use crate::{vpr::Vpr, vsqx4::Vsqx4};
+
+    let text = include_str!("test/v4.vsqx");
+    match "<vsq3></vsq3>".parse::<Vsqx4>() {
+        Err(Error::UnknownRoot { found, .. }) => assert_eq!(found, "vsq3"),
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+
+    // 3つ目のノートのノート番号を壊す
+    let note = text.match_indices("<note>").nth(2).unwrap().0;
+    let n = note + text[note..].find("<n>").unwrap();
+    let broken = format!("{}<n>x{}", &text[..n], &text[n + 3..]);
+    let e = broken.parse::<Vsqx4>().unwrap_err();
+    let location = e.location().unwrap();
+    assert!(matches!(e, Error::InvalidValue { .. }), "{}", e);
+    assert_eq!(location.line, Some(line_column(text, note).0));
+    assert_eq!(location.column, Some(4));
+    assert_eq!(
+        (location.track, location.part, location.note),
+        (Some(0), Some(0), Some(2))
+    );
+
+    // ノートの歌詞を消す
+    let vpr = include_str!("test/vpr.json");
+    let lyric = vpr.find("\"lyric\"").unwrap();
+    let end = lyric + vpr[lyric..].find(',').unwrap() + 1;
+    let broken = format!("{}{}", &vpr[..lyric], &vpr[end..]);
+    match Vpr::from_json(&broken) {
+        Err(Error::MissingElement { element, location }) => {
+            assert_eq!(element, "\"lyric\"");
+            assert_eq!(location.note, Some(0));
+            assert!(location.pointer.unwrap().ends_with("/notes/0"));
+        }
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+
+    // 音源表にない歌手
+    let mut v: Vsqx4 = text.parse().unwrap();
+    v.vs_track[0].parts[0].singers[0].pc = 99;
+    match Vpr::try_from_vsqx4(&v) {
+        Err(Error::DanglingVoice { location, .. }) => {
+            assert_eq!((location.track, location.part), (Some(0), Some(0)))
+        }
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+
+    // sequence.jsonのない.vpr
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    zip.start_file("Project/other.json", Default::default())
+        .unwrap();
+    let bytes = zip.finish().unwrap().into_inner();
+    assert!(matches!(
+        Vpr::from_bytes(&bytes),
+        Err(Error::MissingSequence)
+    ));
+}
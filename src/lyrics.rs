@@ -0,0 +1,319 @@
+//! タイムスタンプ付き歌詞（LRC・SRT・ASS）の書き出し
+//!
+//! ボーカルトラックのノートを順にたどり、休符または明示的な区切りで行に分ける。
+//! 時刻はテンポマップから計算し、曲の先頭（プリメジャーの直後）を0秒とする。
+//!
+//! 歌詞が`-`のノートは直前の音節を伸ばしたものとして扱う。英語などの歌詞は、
+//! 末尾が`-`の音節（`gra-`など）は次の音節とつなげ、それ以外は空白を挟む。
+
+use crate::{vpr, vsqx4, Result};
+
+/// 行に分けるときの設定
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LyricOptions {
+    /// この長さ（ティック）以上の休符で行を分ける。
+    pub rest_ticks: i64,
+    /// 歌詞の末尾がこの文字列のノートで行を分ける（この文字列は出力しない）。
+    pub break_mark: String,
+}
+
+impl Default for LyricOptions {
+    fn default() -> Self {
+        Self {
+            rest_ticks: 1,
+            break_mark: "/".into(),
+        }
+    }
+}
+
+/// 書き出す形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LyricFormat {
+    /// 行ごとの開始時刻
+    Lrc,
+    /// 行ごとの開始・終了時刻
+    Srt,
+    /// 行ごとの開始・終了時刻と、音節ごとのカラオケ（`\k`）タグ
+    Ass,
+}
+
+/// 1トラック分の歌詞
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct LyricLines {
+    pub lines: Vec<LyricLine>,
+}
+
+/// 1行の歌詞
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct LyricLine {
+    pub syllables: Vec<Syllable>,
+}
+
+/// 1音節（1ノート）。時刻は秒。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Syllable {
+    pub start: f64,
+    pub end: f64,
+    /// 表示する文字列（単語の区切りの空白を含む）
+    pub text: String,
+}
+
+impl LyricLine {
+    /// 開始時刻（秒）
+    pub fn start(&self) -> f64 {
+        self.syllables.first().map_or(0.0, |s| s.start)
+    }
+
+    /// 終了時刻（秒）
+    pub fn end(&self) -> f64 {
+        self.syllables.last().map_or(0.0, |s| s.end)
+    }
+
+    pub fn text(&self) -> String {
+        self.syllables
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect::<String>()
+            .trim_end()
+            .into()
+    }
+}
+
+/// 空白で区切る言語の歌詞か
+fn is_spaced(lyric: &str) -> bool {
+    lyric.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+/// `00:00.00`（LRC）
+fn lrc_time(seconds: f64) -> String {
+    let cs = (seconds.max(0.0) * 100.0).round() as i64;
+    format!("{:02}:{:02}.{:02}", cs / 6000, cs / 100 % 60, cs % 100)
+}
+
+/// `00:00:00,000`（SRT）
+fn srt_time(seconds: f64) -> String {
+    let ms = (seconds.max(0.0) * 1000.0).round() as i64;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// `0:00:00.00`（ASS）
+fn ass_time(seconds: f64) -> String {
+    let cs = (seconds.max(0.0) * 100.0).round() as i64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360_000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+fn centiseconds(seconds: f64) -> i64 {
+    (seconds * 100.0).round() as i64
+}
+
+impl LyricLines {
+    /// `Vsqx4`の`index`番目のトラックから歌詞を作る。プリメジャー中のノートは含まない。
+    pub fn from_vsqx4_track(v: &vsqx4::Vsqx4, index: usize, options: &LyricOptions) -> Self {
+        let mut notes: Vec<_> = v
+            .absolute_notes()
+            .filter(|n| n.track == index && n.tick >= 0)
+            .collect();
+        notes.sort_by_key(|n| n.tick);
+        let tempo_map = v.absolute_notes().tempo_map().clone();
+
+        let mut lines = vec![];
+        let mut line = LyricLine::default();
+        let mut end_tick = None;
+        let mut line_break = false;
+        for n in notes {
+            let start = n.tick;
+            let end = n.tick + n.note.duration;
+
+            let rest = matches!(end_tick, Some(e) if start - e >= options.rest_ticks);
+            if (line_break || rest) && !line.syllables.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            end_tick = Some(end_tick.map_or(end, |e: i64| e.max(end)));
+
+            let mut lyric = n.note.lyric.trim();
+            line_break = !options.break_mark.is_empty() && lyric.ends_with(&options.break_mark);
+            if line_break {
+                lyric = lyric[..lyric.len() - options.break_mark.len()].trim_end();
+            }
+
+            let seconds = tempo_map.seconds(end);
+            if lyric == "-" || lyric.is_empty() {
+                if let Some(last) = line.syllables.last_mut() {
+                    last.end = last.end.max(seconds);
+                }
+                continue;
+            }
+
+            let text = if !is_spaced(lyric) {
+                lyric.to_string()
+            } else if let Some(syllable) = lyric.strip_suffix('-') {
+                syllable.to_string()
+            } else {
+                format!("{} ", lyric)
+            };
+            line.syllables.push(Syllable {
+                start: n.seconds,
+                end: seconds,
+                text,
+            });
+        }
+        if !line.syllables.is_empty() {
+            lines.push(line);
+        }
+
+        Self { lines }
+    }
+
+    /// `Vpr`の`index`番目のトラックから歌詞を作る（`Vsqx4`を経由する）。
+    pub fn from_vpr_track(v: &vpr::Vpr, index: usize, options: &LyricOptions) -> Self {
+        Self::from_vsqx4_track(&v.clone().into(), index, options)
+    }
+
+    /// LRC。各行の開始時刻と、最後の行の終了時刻の空行を出力する。
+    pub fn to_lrc(&self) -> String {
+        let mut s = String::new();
+        for line in &self.lines {
+            s += &format!("[{}]{}\n", lrc_time(line.start()), line.text());
+        }
+        if let Some(last) = self.lines.last() {
+            s += &format!("[{}]\n", lrc_time(last.end()));
+        }
+
+        s
+    }
+
+    pub fn to_srt(&self) -> String {
+        let mut s = String::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            s += &format!(
+                "{}\n{} --> {}\n{}\n\n",
+                i + 1,
+                srt_time(line.start()),
+                srt_time(line.end()),
+                line.text()
+            );
+        }
+
+        s
+    }
+
+    /// ASS。音節の間の休符は文字のない`\k`タグにする。
+    pub fn to_ass(&self) -> String {
+        let mut s = String::from(concat!(
+            "[Script Info]\n",
+            "ScriptType: v4.00+\n",
+            "\n",
+            "[V4+ Styles]\n",
+            "Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, ",
+            "Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, ",
+            "Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n",
+            "Style: Default,Arial,48,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,",
+            "0,0,0,0,100,100,0,0,1,2,0,2,10,10,10,1\n",
+            "\n",
+            "[Events]\n",
+            "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        ));
+
+        for line in &self.lines {
+            let mut text = String::new();
+            // 丸めの誤差がたまらないように、行の先頭からの時刻で計算する
+            let mut time = centiseconds(line.start());
+            for syllable in &line.syllables {
+                let start = centiseconds(syllable.start);
+                if start > time {
+                    text += &format!("{{\\k{}}}", start - time);
+                }
+                let end = centiseconds(syllable.end).max(start);
+                text += &format!("{{\\k{}}}{}", end - start, syllable.text);
+                time = end;
+            }
+
+            s += &format!(
+                "Dialogue: 0,{},{},Default,,0,0,0,,{}\n",
+                ass_time(line.start()),
+                ass_time(line.end()),
+                text.trim_end()
+            );
+        }
+
+        s
+    }
+
+    pub fn to_string(&self, format: LyricFormat) -> String {
+        match format {
+            LyricFormat::Lrc => self.to_lrc(),
+            LyricFormat::Srt => self.to_srt(),
+            LyricFormat::Ass => self.to_ass(),
+        }
+    }
+
+    pub fn write<W: std::io::Write>(&self, mut writer: W, format: LyricFormat) -> Result<()> {
+        writer.write_all(self.to_string(format).as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        format: LyricFormat,
+    ) -> Result<()> {
+        self.write(std::fs::File::create(path)?, format)
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// ノートを休符と区切りで行に分け、各形式の時刻を確認する。
+fn test_lyric_lines() {
+    use crate::builder::{Length, NoteBuilder, PartBuilder, ProjectBuilder, TrackBuilder};
+
+    let note = |tick: i64, ticks: i64, number: i64, lyric: &str| {
+        NoteBuilder::new((0, 0, tick), Length::ticks(ticks), number, lyric)
+    };
+    let v = ProjectBuilder::new("Test")
+        .tempo(0, 120.0)
+        .voice("BNGW7FG7E5TRSNC3", "KAITO_V3_English")
+        .track(
+            TrackBuilder::new("Vocal").part(
+                PartBuilder::new(0)
+                    .note(note(0, 480, 60, "ど"))
+                    .note(note(480, 240, 62, "れ/"))
+                    .note(note(720, 240, 64, "み"))
+                    .note(note(960, 480, 64, "-"))
+                    // 1拍休んでから次の行
+                    .note(note(1920, 480, 65, "gra-"))
+                    .note(note(2400, 480, 67, "cious"))
+                    .note(note(2880, 480, 69, "queen")),
+            ),
+        )
+        .build_vsqx4()
+        .unwrap();
+
+    let lyrics = LyricLines::from_vsqx4_track(&v, 0, &LyricOptions::default());
+    let texts: Vec<String> = lyrics.lines.iter().map(LyricLine::text).collect();
+    assert_eq!(texts, vec!["どれ", "み", "gracious queen"]);
+
+    assert_eq!(
+        lyrics.to_lrc(),
+        "[00:00.00]どれ\n[00:00.75]み\n[00:02.00]gracious queen\n[00:03.50]\n"
+    );
+    assert!(lyrics.to_srt().starts_with(
+        "1\n00:00:00,000 --> 00:00:00,750\nどれ\n\n2\n00:00:00,750 --> 00:00:01,500\n"
+    ));
+    assert!(lyrics.to_ass().ends_with(
+        "Dialogue: 0,0:00:02.00,0:00:03.50,Default,,0,0,0,,{\\k50}gra{\\k50}cious {\\k50}queen\n"
+    ));
+}
@@ -0,0 +1,306 @@
+//! Vsqx4からSMF（フォーマット1）への書き出し
+
+use super::{EventKind, MetaEvent, MidiEvent, MidiTrack, Smf, TextEncoding};
+use crate::edit::EditPart;
+use crate::timeline::TempoMap;
+use crate::vsqx4::Vsqx4;
+
+/// ボーカルトラックに割り当てるチャンネル（リズム用の10チャンネル目は使わない）
+const CHANNELS: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
+
+/// SMFに書き出すときの設定
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// PIT・PBSからピッチベンドとピッチベンドセンシティビティ（RPN 0）を出力する。
+    pub pitch_bend: bool,
+    /// DYNからエクスプレッション（CC11）を出力する。
+    pub expression: bool,
+    /// 歌詞とトラック名の文字コード
+    pub encoding: TextEncoding,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            pitch_bend: false,
+            expression: false,
+            encoding: TextEncoding::Utf8,
+        }
+    }
+}
+
+/// 同じ位置のイベントの順序。ノートオフを先に、歌詞をノートオンの直前に置く。
+const ORDER_NOTE_OFF: u8 = 0;
+const ORDER_CONTROL: u8 = 1;
+const ORDER_LYRIC: u8 = 2;
+const ORDER_NOTE_ON: u8 = 3;
+
+/// 1つ目のトラックをテンポと拍子のトラックとし、ボーカルトラックごとにトラックを作る。
+/// 位置はプリメジャーを含まず、プリメジャー中のノートは書き出されない。
+pub(crate) fn export_vsqx4(v: &Vsqx4, options: &ExportOptions) -> Smf {
+    let master = &v.master_track;
+    let offset = master.pre_measure_ticks();
+    let encoding = options.encoding;
+
+    let mut smf = Smf::new(master.resolution as u16);
+
+    // テンポと拍子
+    let mut conductor = MidiTrack::default();
+    conductor.events.push(MidiEvent::meta(
+        0,
+        MetaEvent::TrackName(encoding.encode(&master.name)),
+    ));
+
+    let mut time_signatures = master.time_signatures.clone();
+    time_signatures.sort_by_key(|ts| ts.position);
+    let mut tick = 0;
+    for (i, ts) in time_signatures.iter().enumerate() {
+        if i > 0 {
+            let prev = &time_signatures[i - 1];
+            tick += (ts.position - prev.position) * master.resolution * 4 * prev.numerator
+                / prev.denominator;
+        }
+        push_replacing(
+            &mut conductor,
+            MidiEvent::meta(
+                (tick - offset).max(0),
+                MetaEvent::TimeSignature {
+                    numerator: ts.numerator as u8,
+                    denominator: ts.denominator as u8,
+                },
+            ),
+        );
+    }
+
+    for &(position, value) in TempoMap::from_vsqx4(v).tempos() {
+        push_replacing(
+            &mut conductor,
+            MidiEvent::meta(
+                position.max(0),
+                MetaEvent::Tempo((60_000_000 * 100 / value.max(1)) as u32),
+            ),
+        );
+    }
+
+    conductor.sort();
+    smf.tracks.push(conductor);
+
+    // ボーカルトラック
+    for (i, track) in v.vs_track.iter().enumerate() {
+        let channel = CHANNELS[i % CHANNELS.len()];
+        let mut events: Vec<(i64, u8, EventKind)> = vec![];
+
+        for part in &track.parts {
+            let start = part.position - offset;
+
+            for n in &part.notes {
+                let tick = start + n.position;
+                if tick < 0 {
+                    continue;
+                }
+
+                let key = n.note_num.clamp(0, 127) as u8;
+                events.push((
+                    tick,
+                    ORDER_LYRIC,
+                    EventKind::Meta(MetaEvent::Lyric(encoding.encode(&n.lyric))),
+                ));
+                events.push((
+                    tick,
+                    ORDER_NOTE_ON,
+                    EventKind::NoteOn {
+                        channel,
+                        key,
+                        velocity: n.velocity.clamp(1, 127) as u8,
+                    },
+                ));
+                events.push((
+                    tick + n.duration.max(1),
+                    ORDER_NOTE_OFF,
+                    EventKind::NoteOff {
+                        channel,
+                        key,
+                        velocity: 0,
+                    },
+                ));
+            }
+
+            let curves = part.curves();
+            let curve = |name: &str| curves.iter().find(|c| c.name == name);
+            let control = |pos: i64| (start + pos).max(0);
+
+            if options.pitch_bend {
+                if let Some(pbs) = curve("S") {
+                    for &(pos, value) in &pbs.events {
+                        for &(controller, value) in &[
+                            (101, 0),
+                            (100, 0),
+                            (6, value.clamp(0, 127) as u8),
+                            (38, 0),
+                            (101, 127),
+                            (100, 127),
+                        ] {
+                            events.push((
+                                control(pos),
+                                ORDER_CONTROL,
+                                EventKind::ControlChange {
+                                    channel,
+                                    controller,
+                                    value,
+                                },
+                            ));
+                        }
+                    }
+                }
+
+                if let Some(pit) = curve("P") {
+                    for &(pos, value) in &pit.events {
+                        events.push((
+                            control(pos),
+                            ORDER_CONTROL,
+                            EventKind::PitchBend {
+                                channel,
+                                value: value.clamp(-8192, 8191) as i16,
+                            },
+                        ));
+                    }
+
+                    // 次のパートに持ち越さないように戻す
+                    if matches!(pit.events.last(), Some(e) if e.1 != 0) {
+                        events.push((
+                            control(part.effective_length()),
+                            ORDER_CONTROL,
+                            EventKind::PitchBend { channel, value: 0 },
+                        ));
+                    }
+                }
+            }
+
+            if options.expression {
+                if let Some(dyn_curve) = curve("D") {
+                    for &(pos, value) in &dyn_curve.events {
+                        events.push((
+                            control(pos),
+                            ORDER_CONTROL,
+                            EventKind::ControlChange {
+                                channel,
+                                controller: 11,
+                                value: value.clamp(0, 127) as u8,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+
+        events.sort_by_key(|e| (e.0, e.1));
+
+        let mut t = MidiTrack::default();
+        t.events.push(MidiEvent::meta(
+            0,
+            MetaEvent::TrackName(encoding.encode(&track.name)),
+        ));
+        t.events.extend(
+            events
+                .into_iter()
+                .map(|(tick, _, kind)| MidiEvent::new(tick, kind)),
+        );
+        smf.tracks.push(t);
+    }
+
+    smf
+}
+
+/// 同じ位置に同じ種類のメタイベントがあれば置き換え、なければ追加する。
+fn push_replacing(track: &mut MidiTrack, event: MidiEvent) {
+    use std::mem::discriminant;
+
+    let same_kind = |e: &MidiEvent| match (&e.kind, &event.kind) {
+        (EventKind::Meta(a), EventKind::Meta(b)) => discriminant(a) == discriminant(b),
+        _ => false,
+    };
+
+    match track
+        .events
+        .iter_mut()
+        .find(|e| e.tick == event.tick && same_kind(e))
+    {
+        Some(e) => *e = event,
+        None => track.events.push(event),
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// ノートごとに歌詞とノートオン・オフが書き出されるか確認する。
+fn test_export_vsqx4() {
+    let v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+    let notes = v.vs_track[0].parts[0].notes.len();
+    let smf = Smf::from_vsqx4(&v, &ExportOptions::default());
+
+    assert_eq!(smf.tracks.len(), 2);
+    assert!(smf.tracks[0]
+        .events
+        .iter()
+        .any(|e| e.kind == EventKind::Meta(MetaEvent::Tempo(750_000))));
+
+    let count =
+        |f: fn(&EventKind) -> bool| smf.tracks[1].events.iter().filter(|e| f(&e.kind)).count();
+    assert_eq!(
+        count(|k| matches!(k, EventKind::Meta(MetaEvent::Lyric(_)))),
+        notes
+    );
+    assert_eq!(count(|k| matches!(k, EventKind::NoteOn { .. })), notes);
+    assert_eq!(count(|k| matches!(k, EventKind::NoteOff { .. })), notes);
+    assert_eq!(count(|k| matches!(k, EventKind::PitchBend { .. })), 0);
+}
+
+#[test]
+#[cfg(test)]
+/// PIT・PBS・DYNがピッチベンド・RPN・CC11になるか確認する。
+fn test_export_controls() {
+    use crate::edit::Curve;
+
+    let mut v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+    v.vs_track[0].parts[0].set_curves(vec![
+        Curve {
+            name: "S".into(),
+            events: vec![(0, 12)],
+        },
+        Curve {
+            name: "P".into(),
+            events: vec![(240, 4096)],
+        },
+        Curve {
+            name: "D".into(),
+            events: vec![(0, 100)],
+        },
+    ]);
+
+    let options = ExportOptions {
+        pitch_bend: true,
+        expression: true,
+        ..Default::default()
+    };
+    let events = &Smf::from_vsqx4(&v, &options).tracks[1].events;
+
+    let has = |kind: EventKind| events.iter().any(|e| e.kind == kind);
+    assert!(has(EventKind::ControlChange {
+        channel: 0,
+        controller: 6,
+        value: 12
+    }));
+    assert!(has(EventKind::PitchBend {
+        channel: 0,
+        value: 4096
+    }));
+    assert!(has(EventKind::PitchBend {
+        channel: 0,
+        value: 0
+    }));
+    assert!(has(EventKind::ControlChange {
+        channel: 0,
+        controller: 11,
+        value: 100
+    }));
+}
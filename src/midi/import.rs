@@ -0,0 +1,430 @@
+//! SMFからVsqx4への取り込み
+
+use super::{EventKind, MetaEvent, MidiTrack, Smf, TextEncoding};
+use crate::overlap::{self, RepairStrategy};
+use crate::vsqx4::{self, Vsqx4};
+use std::collections::{HashMap, VecDeque};
+
+/// SMFを取り込むときの設定
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// 取り込むトラックのインデックス。`None`ならノートを持つすべてのトラック。
+    pub tracks: Option<Vec<usize>>,
+    /// 歌詞とトラック名の文字コード。`None`ならUTF-8として読めなければShift_JISとみなす。
+    pub encoding: Option<TextEncoding>,
+    /// ノートの重なりの修復方法
+    pub repair: RepairStrategy,
+    /// 歌詞のないノートに付ける歌詞
+    pub default_lyric: String,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            tracks: None,
+            encoding: None,
+            repair: RepairStrategy::TrimEarlier,
+            default_lyric: vsqx4::Note::default().lyric,
+        }
+    }
+}
+
+/// 取り込み途中のノート
+struct MidiNote {
+    start: i64,
+    end: i64,
+    key: u8,
+    velocity: u8,
+}
+
+/// テンポと拍子はすべてのトラックから集める。
+/// 選んだトラックごとに1つのパートを作り、プリメジャー（4小節）の直後に置く。
+/// 歌詞はLyricメタイベントから、なければTextメタイベントから取り、
+/// 直前のノートより後でノートの開始位置以前にある最後のものをそのノートに付ける。
+pub(crate) fn import_vsqx4(smf: &Smf, options: &ImportOptions) -> Vsqx4 {
+    let mut v = Vsqx4::default();
+    let master = &mut v.master_track;
+    master.pre_measure = 4;
+
+    let division = i64::from(smf.division.max(1));
+    let resolution = master.resolution;
+    let scale = |tick: i64| (tick * resolution + division / 2) / division;
+
+    let mut time_signatures = vec![];
+    let mut tempos = vec![];
+    for track in &smf.tracks {
+        for e in &track.events {
+            match e.kind {
+                EventKind::Meta(MetaEvent::TimeSignature {
+                    numerator,
+                    denominator,
+                }) => time_signatures.push((
+                    scale(e.tick),
+                    i64::from(numerator.max(1)),
+                    i64::from(denominator.max(1)),
+                )),
+                EventKind::Meta(MetaEvent::Tempo(us)) => tempos.push((scale(e.tick), us)),
+                _ => {}
+            }
+        }
+    }
+    time_signatures.sort_by_key(|ts| ts.0);
+    tempos.sort_by_key(|t| t.0);
+
+    // 拍子（先頭の拍子はプリメジャーにも適用される）
+    let mut signatures: Vec<vsqx4::TimeSignature> = vec![];
+    for (bar, numerator, denominator) in to_bars(&time_signatures, resolution) {
+        let position = if bar == 0 {
+            0
+        } else {
+            bar + master.pre_measure
+        };
+        signatures.push(vsqx4::TimeSignature {
+            position,
+            numerator,
+            denominator,
+        });
+    }
+    if !signatures.is_empty() {
+        master.time_signatures = signatures;
+    }
+    let offset = master.pre_measure_ticks();
+
+    // テンポ
+    let mut values: Vec<vsqx4::Tempo> = vec![];
+    for (tick, us) in tempos {
+        let position = if tick == 0 { 0 } else { tick + offset };
+        values.retain(|t| t.position != position);
+        values.push(vsqx4::Tempo {
+            position,
+            value: 6_000_000_000 / i64::from(us.max(1)),
+        });
+    }
+    if !values.is_empty() {
+        if values[0].position != 0 {
+            values.insert(
+                0,
+                vsqx4::Tempo {
+                    position: 0,
+                    value: 12000,
+                },
+            );
+        }
+        master.tempos = values;
+    }
+
+    let voice = &v.voice_table.voices[0];
+    let singer = vsqx4::Singer {
+        position: 0,
+        bs: voice.bs,
+        pc: voice.pc,
+    };
+
+    let indices: Vec<usize> = match &options.tracks {
+        Some(tracks) => tracks
+            .iter()
+            .copied()
+            .filter(|&i| i < smf.tracks.len())
+            .collect(),
+        None => (0..smf.tracks.len())
+            .filter(|&i| smf.tracks[i].events.iter().any(is_note_on))
+            .collect(),
+    };
+
+    for &i in &indices {
+        let track = &smf.tracks[i];
+        let decode = |bytes: &[u8]| decode(options.encoding, bytes);
+        let name = track
+            .name()
+            .map(decode)
+            .unwrap_or_else(|| format!("Track {}", i + 1));
+
+        let notes = read_notes(track);
+        let lyrics = read_lyrics(track);
+
+        let mut k = 0;
+        let notes: Vec<vsqx4::Note> = notes
+            .into_iter()
+            .map(|n| {
+                let mut lyric = None;
+                while k < lyrics.len() && lyrics[k].0 <= n.start {
+                    lyric = Some(decode(lyrics[k].1));
+                    k += 1;
+                }
+
+                let start = scale(n.start);
+                vsqx4::Note {
+                    position: start,
+                    duration: scale(n.end) - start,
+                    note_num: i64::from(n.key),
+                    velocity: i64::from(n.velocity),
+                    lyric: lyric
+                        .map(|l| l.trim().to_string())
+                        .filter(|l| !l.is_empty())
+                        .unwrap_or_else(|| options.default_lyric.clone()),
+                    phoneme: String::new(),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let play_time = notes
+            .iter()
+            .map(|n| n.position + n.duration)
+            .max()
+            .unwrap_or(0);
+        let mut vs_track = vsqx4::VsTrack {
+            name: name.clone(),
+            ..Default::default()
+        };
+        if !notes.is_empty() {
+            vs_track.parts.push(vsqx4::VsPart {
+                position: offset,
+                play_time: Some(play_time as u64),
+                name: Some(name.clone()),
+                notes,
+                singers: vec![singer.clone()],
+                ..Default::default()
+            });
+        }
+        // 重なっていたノートは別のトラックに置く
+        let moved = overlap::repair_track(&mut vs_track, options.repair);
+        let mut vs_tracks = vec![vs_track];
+        for (k, part) in moved.into_iter().enumerate() {
+            vs_tracks.push(vsqx4::VsTrack {
+                name: format!("{} ({})", name, k + 2),
+                parts: vec![part],
+                ..Default::default()
+            });
+        }
+
+        for mut vs_track in vs_tracks {
+            vs_track.track_no = v.vs_track.len() as i64;
+            v.mixer.vs_unit.push(vsqx4::VsUnit {
+                track_no: vs_track.track_no,
+                ..Default::default()
+            });
+            v.vs_track.push(vs_track);
+        }
+    }
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    v
+}
+
+/// 位置順に並んだ拍子`(位置（ティック）, 分子, 分母)`を`(小節, 分子, 分母)`にする。
+/// 小節の途中にある拍子は次の小節に置き、同じ小節に複数あれば最後のものを使う。
+pub(crate) fn to_bars(
+    time_signatures: &[(i64, i64, i64)],
+    resolution: i64,
+) -> Vec<(i64, i64, i64)> {
+    let mut bars: Vec<(i64, i64, i64)> = vec![];
+    let (mut bar, mut bar_tick, mut bar_length) = (0, 0, resolution * 4);
+
+    for &(tick, numerator, denominator) in time_signatures {
+        let count = (tick - bar_tick + bar_length - 1) / bar_length;
+        bar += count;
+        bar_tick += count * bar_length;
+        bar_length = resolution * 4 * numerator / denominator;
+
+        bars.retain(|ts| ts.0 != bar);
+        bars.push((bar, numerator, denominator));
+    }
+
+    bars
+}
+
+fn is_note_on(e: &super::MidiEvent) -> bool {
+    matches!(e.kind, EventKind::NoteOn { velocity, .. } if velocity > 0)
+}
+
+/// ノートオンとノートオフ（ベロシティ0のノートオンを含む）を、
+/// チャンネルとキーごとに先に鳴ったものから対応させる。
+/// 閉じられていないノートはトラックの最後で終わる。
+fn read_notes(track: &MidiTrack) -> Vec<MidiNote> {
+    let mut open: HashMap<(u8, u8), VecDeque<(i64, u8)>> = HashMap::new();
+    let mut notes = vec![];
+
+    for e in &track.events {
+        match e.kind {
+            EventKind::NoteOn {
+                channel,
+                key,
+                velocity,
+            } if velocity > 0 => open
+                .entry((channel, key))
+                .or_default()
+                .push_back((e.tick, velocity)),
+            EventKind::NoteOn { channel, key, .. } | EventKind::NoteOff { channel, key, .. } => {
+                if let Some((start, velocity)) =
+                    open.get_mut(&(channel, key)).and_then(VecDeque::pop_front)
+                {
+                    notes.push(MidiNote {
+                        start,
+                        end: e.tick,
+                        key,
+                        velocity,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let end = track.events.iter().map(|e| e.tick).max().unwrap_or(0);
+    for ((_, key), rest) in open {
+        for (start, velocity) in rest {
+            notes.push(MidiNote {
+                start,
+                end,
+                key,
+                velocity,
+            });
+        }
+    }
+
+    notes.sort_by_key(|n| (n.start, n.key));
+    notes
+}
+
+/// 歌詞のメタイベント。Lyricがなければ代わりにTextを使う。
+fn read_lyrics(track: &MidiTrack) -> Vec<(i64, &[u8])> {
+    let collect = |lyric: bool| -> Vec<(i64, &[u8])> {
+        track
+            .events
+            .iter()
+            .filter_map(|e| match &e.kind {
+                EventKind::Meta(MetaEvent::Lyric(text)) if lyric => Some((e.tick, &text[..])),
+                EventKind::Meta(MetaEvent::Text(text)) if !lyric => Some((e.tick, &text[..])),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let lyrics = collect(true);
+    if lyrics.is_empty() {
+        collect(false)
+    } else {
+        lyrics
+    }
+}
+
+fn decode(encoding: Option<TextEncoding>, bytes: &[u8]) -> String {
+    match encoding {
+        Some(encoding) => encoding.decode(bytes),
+        None => match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => TextEncoding::ShiftJis.decode(bytes),
+        },
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// 書き出したSMFを取り込むとノート・歌詞・テンポが戻るか確認する。
+fn test_import_roundtrip() {
+    use super::ExportOptions;
+
+    let v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+    let options = ExportOptions {
+        encoding: TextEncoding::ShiftJis,
+        ..Default::default()
+    };
+    let bytes = Smf::from_vsqx4(&v, &options).to_bytes();
+    let imported = Smf::from_bytes(&bytes)
+        .unwrap()
+        .to_vsqx4(&ImportOptions::default());
+
+    assert_eq!(imported.vs_track.len(), 1);
+    assert_eq!(imported.vs_track[0].name, v.vs_track[0].name);
+    assert_eq!(imported.master_track.tempos[0].value, 8000);
+
+    let offset = v.master_track.pre_measure_ticks();
+    let original = &v.vs_track[0].parts[0];
+    let part = &imported.vs_track[0].parts[0];
+    assert_eq!(part.notes.len(), original.notes.len());
+    for (a, b) in part.notes.iter().zip(&original.notes) {
+        assert_eq!(a.position + part.position, b.position + original.position);
+        assert_eq!(a.note_num, b.note_num);
+        assert_eq!(a.lyric, b.lyric);
+    }
+    assert_eq!(part.position, offset);
+}
+
+#[test]
+#[cfg(test)]
+/// 重なっているノートが修復され、トラックを選べるか確認する。
+fn test_import_overlap() {
+    use super::MidiEvent;
+
+    let on = |tick, key| {
+        MidiEvent::new(
+            tick,
+            EventKind::NoteOn {
+                channel: 0,
+                key,
+                velocity: 100,
+            },
+        )
+    };
+    let off = |tick, key| {
+        MidiEvent::new(
+            tick,
+            EventKind::NoteOff {
+                channel: 0,
+                key,
+                velocity: 0,
+            },
+        )
+    };
+
+    let mut smf = Smf::new(960);
+    smf.tracks.push(MidiTrack {
+        events: vec![on(0, 60), on(960, 62), off(1920, 60), off(2880, 62)],
+    });
+    smf.tracks.push(MidiTrack {
+        events: vec![on(0, 64), off(960, 64)],
+    });
+
+    let v = smf.to_vsqx4(&ImportOptions::default());
+    assert_eq!(v.vs_track.len(), 2);
+    let part = &v.vs_track[0].parts[0];
+    assert!(overlap::find_issues(part).is_empty());
+    assert_eq!(part.notes[0].duration, 480);
+    assert_eq!(part.notes[1].position, 480);
+
+    let options = ImportOptions {
+        tracks: Some(vec![1]),
+        repair: RepairStrategy::MoveToNewPart,
+        ..Default::default()
+    };
+    let v = smf.to_vpr(&options);
+    assert_eq!(v.tracks.len(), 1);
+
+    // 重なっていたノートは別のトラックに移る
+    let options = ImportOptions {
+        tracks: Some(vec![0]),
+        repair: RepairStrategy::MoveToNewPart,
+        ..Default::default()
+    };
+    let v = smf.to_vsqx4(&options);
+    assert_eq!(v.vs_track.len(), 2);
+    assert_eq!(v.vs_track[1].track_no, 1);
+    assert_eq!(v.vs_track[1].parts[0].notes[0].position, 480);
+    assert_eq!(v.mixer.vs_unit.len(), 2);
+}
+
+#[test]
+#[cfg(test)]
+/// 小節の途中にある拍子が次の小節に置かれ、その後の小節も数え直されないか確認する。
+fn test_to_bars() {
+    // 3/4は2小節目（1920ティック～）の途中なので3小節目（3840ティック）から。
+    // 6/8は3/4の1小節後（3840 + 1440ティック）なので4小節目から。
+    let bars = to_bars(&[(0, 4, 4), (2400, 3, 4), (5280, 6, 8)], 480);
+    assert_eq!(bars, vec![(0, 4, 4), (2, 3, 4), (3, 6, 8)]);
+
+    // 同じ小節に入る拍子は後のものを使う
+    let bars = to_bars(&[(0, 4, 4), (100, 3, 4), (200, 2, 4)], 480);
+    assert_eq!(bars, vec![(0, 4, 4), (1, 2, 4)]);
+}
@@ -0,0 +1,562 @@
+//! Standard MIDI File（SMF）の読み書き
+//!
+//! イベントの位置はトラックの先頭からの絶対ティックで持ち、書き出すときにデルタタイムに直す。
+//! テキスト系のメタイベントは文字コードが決まっていないので、バイト列のまま保持する。
+
+use crate::{vpr, vsqx4, Error, Result};
+
+pub(crate) mod export;
+pub(crate) mod import;
+
+pub use export::ExportOptions;
+pub use import::ImportOptions;
+
+/// SMF
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Smf {
+    /// フォーマット（0、1、2）
+    pub format: u16,
+    /// 四分音符あたりのティック数
+    pub division: u16,
+    pub tracks: Vec<MidiTrack>,
+}
+
+/// トラック
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct MidiTrack {
+    /// 位置順に並んだイベント
+    pub events: Vec<MidiEvent>,
+}
+
+/// イベント
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiEvent {
+    /// トラックの先頭からのティック
+    pub tick: i64,
+    pub kind: EventKind,
+}
+
+/// イベントの種類
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    NoteOff {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// -8192〜8191
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+    /// その他のチャンネルメッセージ（ステータスバイトとデータ）
+    Channel {
+        status: u8,
+        data: Vec<u8>,
+    },
+    /// システムエクスクルーシブ（先頭の0xF0を除く）
+    SysEx(Vec<u8>),
+    Meta(MetaEvent),
+}
+
+/// メタイベント
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetaEvent {
+    Text(Vec<u8>),
+    TrackName(Vec<u8>),
+    Lyric(Vec<u8>),
+    /// 四分音符あたりのマイクロ秒
+    Tempo(u32),
+    TimeSignature {
+        numerator: u8,
+        /// 分母（2の累乗の指数ではなく実際の値）
+        denominator: u8,
+    },
+    EndOfTrack,
+    /// その他のメタイベント（種類とデータ）
+    Other {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// テキストの文字コード
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    ShiftJis,
+}
+
+impl TextEncoding {
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Self::Utf8 => text.as_bytes().to_vec(),
+            Self::ShiftJis => encoding_rs::SHIFT_JIS.encode(text).0.into_owned(),
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Self::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Self::ShiftJis => encoding_rs::SHIFT_JIS.decode(bytes).0.into_owned(),
+        }
+    }
+}
+
+impl MidiEvent {
+    pub fn new(tick: i64, kind: EventKind) -> Self {
+        Self { tick, kind }
+    }
+
+    pub fn meta(tick: i64, meta: MetaEvent) -> Self {
+        Self::new(tick, EventKind::Meta(meta))
+    }
+}
+
+impl MidiTrack {
+    /// イベントを位置順に並べ替える（同じ位置では元の順序を保つ）。
+    pub fn sort(&mut self) {
+        self.events.sort_by_key(|e| e.tick);
+    }
+
+    /// トラック名
+    pub fn name(&self) -> Option<&[u8]> {
+        self.events.iter().find_map(|e| match &e.kind {
+            EventKind::Meta(MetaEvent::TrackName(name)) => Some(name.as_slice()),
+            _ => None,
+        })
+    }
+}
+
+impl Smf {
+    pub fn new(division: u16) -> Self {
+        Self {
+            format: 1,
+            division,
+            tracks: vec![],
+        }
+    }
+
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader { bytes, pos: 0 };
+
+        if r.take(4)? != b"MThd" {
+            return Err(midi_error("missing MThd header"));
+        }
+        let length = r.u32()? as usize;
+        let header = r.take(length)?;
+        if header.len() < 6 {
+            return Err(midi_error("header is too short"));
+        }
+        let format = u16::from_be_bytes([header[0], header[1]]);
+        let count = u16::from_be_bytes([header[2], header[3]]);
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        if division & 0x8000 != 0 {
+            return Err(midi_error("SMPTE time division is not supported"));
+        }
+
+        let mut tracks = vec![];
+        while tracks.len() < count as usize && r.pos < bytes.len() {
+            let kind = r.take(4)?;
+            let length = r.u32()? as usize;
+            let data = r.take(length)?;
+
+            // 知らないチャンクは読み飛ばす
+            if kind == b"MTrk" {
+                tracks.push(MidiTrack::from_bytes(data)?);
+            }
+        }
+
+        Ok(Self {
+            format,
+            division,
+            tracks,
+        })
+    }
+
+    /// 書き出す。各トラックの末尾にはEnd of Trackが付けられる。
+    pub fn write<W: std::io::Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(b"MThd")?;
+        writer.write_all(&6u32.to_be_bytes())?;
+        writer.write_all(&self.format.to_be_bytes())?;
+        writer.write_all(&(self.tracks.len() as u16).to_be_bytes())?;
+        writer.write_all(&self.division.to_be_bytes())?;
+
+        for track in &self.tracks {
+            let data = track.to_bytes();
+            writer.write_all(b"MTrk")?;
+            writer.write_all(&(data.len() as u32).to_be_bytes())?;
+            writer.write_all(&data)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// `Vsqx4`を書き出す。
+    pub fn from_vsqx4(v: &vsqx4::Vsqx4, options: &ExportOptions) -> Self {
+        export::export_vsqx4(v, options)
+    }
+
+    /// `Vpr`を書き出す（`Vsqx4`を経由する）。
+    pub fn from_vpr(v: &vpr::Vpr, options: &ExportOptions) -> Self {
+        export::export_vsqx4(&v.clone().into(), options)
+    }
+
+    /// ボーカルトラックとして`Vsqx4`に取り込む。
+    pub fn to_vsqx4(&self, options: &ImportOptions) -> vsqx4::Vsqx4 {
+        import::import_vsqx4(self, options)
+    }
+
+    /// ボーカルトラックとして`Vpr`に取り込む（`Vsqx4`を経由する）。
+    pub fn to_vpr(&self, options: &ImportOptions) -> vpr::Vpr {
+        import::import_vsqx4(self, options).into()
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        use std::fs::File;
+        use std::io::BufWriter;
+
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl MidiTrack {
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader { bytes, pos: 0 };
+        let mut events = vec![];
+        let mut tick = 0;
+        let mut running = None;
+
+        while r.pos < bytes.len() {
+            tick += r.variable()? as i64;
+
+            let mut status = r.u8()?;
+            let first = if status < 0x80 {
+                // ランニングステータス
+                let first = status;
+                status = running.ok_or_else(|| midi_error("data byte without status"))?;
+                Some(first)
+            } else {
+                None
+            };
+
+            let kind = match status {
+                0xFF => {
+                    let kind = r.u8()?;
+                    let length = r.variable()? as usize;
+                    let data = r.take(length)?;
+                    let meta = read_meta(kind, data);
+                    let end = meta == MetaEvent::EndOfTrack;
+                    events.push(MidiEvent::meta(tick, meta));
+
+                    if end {
+                        break;
+                    }
+                    continue;
+                }
+                0xF0 | 0xF7 => {
+                    let length = r.variable()? as usize;
+                    EventKind::SysEx(r.take(length)?.to_vec())
+                }
+                0x80..=0xEF => {
+                    running = Some(status);
+                    let length = if let 0xC0..=0xDF = status { 1 } else { 2 };
+                    let mut data = vec![];
+                    if let Some(first) = first {
+                        data.push(first);
+                    }
+                    while data.len() < length {
+                        data.push(r.u8()?);
+                    }
+                    read_channel(status, data)
+                }
+                _ => return Err(midi_error(format!("unknown status {:#04X}", status))),
+            };
+
+            events.push(MidiEvent::new(tick, kind));
+        }
+
+        Ok(Self { events })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![];
+        let mut last = 0;
+
+        for event in &self.events {
+            if let EventKind::Meta(MetaEvent::EndOfTrack) = event.kind {
+                continue;
+            }
+
+            let tick = event.tick.max(last);
+            write_variable(&mut data, (tick - last) as u32);
+            last = tick;
+            write_event(&mut data, &event.kind);
+        }
+
+        let end = self
+            .events
+            .iter()
+            .filter(|e| e.kind == EventKind::Meta(MetaEvent::EndOfTrack))
+            .map(|e| e.tick)
+            .max()
+            .unwrap_or(last)
+            .max(last);
+        write_variable(&mut data, (end - last) as u32);
+        data.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+        data
+    }
+}
+
+fn write_event(data: &mut Vec<u8>, kind: &EventKind) {
+    match kind {
+        EventKind::NoteOff {
+            channel,
+            key,
+            velocity,
+        } => data.extend_from_slice(&[0x80 | channel, *key, *velocity]),
+        EventKind::NoteOn {
+            channel,
+            key,
+            velocity,
+        } => data.extend_from_slice(&[0x90 | channel, *key, *velocity]),
+        EventKind::ControlChange {
+            channel,
+            controller,
+            value,
+        } => data.extend_from_slice(&[0xB0 | channel, *controller, *value]),
+        EventKind::ProgramChange { channel, program } => {
+            data.extend_from_slice(&[0xC0 | channel, *program])
+        }
+        EventKind::PitchBend { channel, value } => {
+            let value = (*value as i32 + 8192).clamp(0, 16383) as u16;
+            data.extend_from_slice(&[0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8]);
+        }
+        EventKind::Channel { status, data: d } => {
+            data.push(*status);
+            data.extend_from_slice(d);
+        }
+        EventKind::SysEx(d) => {
+            data.push(0xF0);
+            write_variable(data, d.len() as u32);
+            data.extend_from_slice(d);
+        }
+        EventKind::Meta(meta) => {
+            let (kind, d) = match meta {
+                MetaEvent::Text(text) => (0x01, text.clone()),
+                MetaEvent::TrackName(name) => (0x03, name.clone()),
+                MetaEvent::Lyric(lyric) => (0x05, lyric.clone()),
+                MetaEvent::Tempo(tempo) => (0x51, tempo.to_be_bytes()[1..].to_vec()),
+                MetaEvent::TimeSignature {
+                    numerator,
+                    denominator,
+                } => {
+                    let exponent = (*denominator as f64).log2().round() as u8;
+                    (0x58, vec![*numerator, exponent, 24, 8])
+                }
+                MetaEvent::EndOfTrack => (0x2F, vec![]),
+                MetaEvent::Other { kind, data } => (*kind, data.clone()),
+            };
+
+            data.extend_from_slice(&[0xFF, kind]);
+            write_variable(data, d.len() as u32);
+            data.extend_from_slice(&d);
+        }
+    }
+}
+
+fn read_meta(kind: u8, data: &[u8]) -> MetaEvent {
+    match (kind, data.len()) {
+        (0x01, _) => MetaEvent::Text(data.to_vec()),
+        (0x03, _) => MetaEvent::TrackName(data.to_vec()),
+        (0x05, _) => MetaEvent::Lyric(data.to_vec()),
+        (0x51, 3) => MetaEvent::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]])),
+        (0x58, 4) => MetaEvent::TimeSignature {
+            numerator: data[0],
+            denominator: 1u8.checked_shl(data[1] as u32).unwrap_or(4),
+        },
+        (0x2F, _) => MetaEvent::EndOfTrack,
+        _ => MetaEvent::Other {
+            kind,
+            data: data.to_vec(),
+        },
+    }
+}
+
+fn read_channel(status: u8, data: Vec<u8>) -> EventKind {
+    let channel = status & 0x0F;
+
+    match status & 0xF0 {
+        0x80 => EventKind::NoteOff {
+            channel,
+            key: data[0],
+            velocity: data[1],
+        },
+        0x90 => EventKind::NoteOn {
+            channel,
+            key: data[0],
+            velocity: data[1],
+        },
+        0xB0 => EventKind::ControlChange {
+            channel,
+            controller: data[0],
+            value: data[1],
+        },
+        0xC0 => EventKind::ProgramChange {
+            channel,
+            program: data[0],
+        },
+        0xE0 => EventKind::PitchBend {
+            channel,
+            value: ((data[0] as i16) | ((data[1] as i16) << 7)) - 8192,
+        },
+        _ => EventKind::Channel { status, data },
+    }
+}
+
+fn midi_error<S: Into<String>>(message: S) -> Error {
+    Error::MidiError(message.into())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self.pos + length;
+        if end > self.bytes.len() {
+            return Err(midi_error("unexpected end of data"));
+        }
+
+        let data = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// 可変長数値
+    fn variable(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(midi_error("variable-length quantity is too long"))
+    }
+}
+
+/// 可変長数値を書き出す。
+fn write_variable(data: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    data.extend_from_slice(&bytes);
+}
+
+#[test]
+#[cfg(test)]
+/// 可変長数値とイベントが正しく書き出され、読み直せるか確認する。
+fn test_write_smf() {
+    let mut data = vec![];
+    write_variable(&mut data, 0x0FFF_FFFF);
+    assert_eq!(data, vec![0xFF, 0xFF, 0xFF, 0x7F]);
+
+    let mut smf = Smf::new(480);
+    smf.tracks.push(MidiTrack {
+        events: vec![
+            MidiEvent::meta(0, MetaEvent::Tempo(500_000)),
+            MidiEvent::new(
+                480,
+                EventKind::PitchBend {
+                    channel: 0,
+                    value: 0,
+                },
+            ),
+        ],
+    });
+
+    let bytes = smf.to_bytes();
+    assert_eq!(&bytes[..4], b"MThd");
+
+    // 読み直すとEnd of Trackが付く
+    let read = Smf::from_bytes(&bytes).unwrap();
+    smf.tracks[0]
+        .events
+        .push(MidiEvent::meta(480, MetaEvent::EndOfTrack));
+    assert_eq!(read, smf);
+    assert_eq!(
+        &bytes[22..],
+        &[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x83, 0x60, 0xE0, 0x00, 0x40, 0x00, 0xFF,
+            0x2F, 0x00
+        ]
+    );
+}
+
+#[test]
+#[cfg(test)]
+/// ランニングステータスとベロシティ0のノートオンを読めるか確認する。
+fn test_read_running_status() {
+    let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xE0MTrk".to_vec();
+    let track = [
+        0x00, 0x90, 0x3C, 0x40, 0x83, 0x60, 0x3C, 0x00, 0x00, 0xFF, 0x2F, 0x00,
+    ];
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&track);
+
+    let smf = Smf::from_bytes(&bytes).unwrap();
+    assert_eq!(smf.format, 0);
+    assert_eq!(
+        smf.tracks[0].events[1],
+        MidiEvent::new(
+            480,
+            EventKind::NoteOn {
+                channel: 0,
+                key: 60,
+                velocity: 0
+            }
+        )
+    );
+}
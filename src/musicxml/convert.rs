@@ -0,0 +1,525 @@
+//! MusicXMLとVsqx4の相互変換（Vprとの変換はVsqx4を経由する）
+//!
+//! 英語などの複数音節の単語は、VOCALOIDの歌詞では末尾に`-`を付けて続くことを表す
+//! （`hel-`、`lo`）。MusicXMLの`syllabic`とはこの形で相互に変換する。
+//! 歌詞のない音符はメリスマとして`-`にする。
+
+use super::{Lyric, Measure, MusicXml, Note, Part, Pitch, Syllabic};
+use crate::conversion::{Conversion, NoteLosses, Unmapped};
+use crate::edit::EditPart;
+use crate::ust::convert::bpm_to_value;
+use crate::vsqx4::{self, Vsqx4};
+
+/// 書き出すときの`divisions`（32分音符が1）
+const DIVISIONS: i64 = 8;
+/// 書き出すときに揃える単位（ティック、32分音符）
+const GRID: i64 = 480 / DIVISIONS;
+
+/// `(長さ（divisions）, 音価, 付点の数)`。長いものから順に使う。
+const NOTE_VALUES: &[(i64, &str, u8)] = &[
+    (32, "whole", 0),
+    (24, "half", 1),
+    (16, "half", 0),
+    (12, "quarter", 1),
+    (8, "quarter", 0),
+    (6, "eighth", 1),
+    (4, "eighth", 0),
+    (3, "16th", 1),
+    (2, "16th", 0),
+    (1, "32nd", 0),
+];
+
+/// MusicXMLからVsqx4への変換。
+/// パートごとに1つのトラックを作る。声部は最初の音符のものだけを使い、和音は一番上の音以外を捨てる。
+/// テンポと拍子は最初のパートから取る。
+pub(crate) fn convert_musicxml_to_vsqx4(score: &MusicXml) -> Conversion<Vsqx4> {
+    let mut unmapped = vec![];
+    let mut v = Vsqx4::default();
+    v.master_track.name = score.title.clone();
+    v.master_track.pre_measure = 4;
+    let resolution = v.master_track.resolution;
+
+    // 拍子（プリメジャーにも最初の拍子を使うので、先に決める）
+    if let Some(part) = score.parts.first() {
+        let mut time_signatures = vec![];
+        for (i, m) in part.measures.iter().enumerate() {
+            if let Some((numerator, denominator)) = m.time {
+                let position = if i == 0 {
+                    0
+                } else {
+                    i as i64 + v.master_track.pre_measure
+                };
+                time_signatures.push(vsqx4::TimeSignature {
+                    position,
+                    numerator: numerator.max(1),
+                    denominator: denominator.max(1),
+                });
+            }
+        }
+        if matches!(time_signatures.first(), Some(ts) if ts.position == 0) {
+            v.master_track.time_signatures = time_signatures;
+        } else {
+            v.master_track.time_signatures.extend(time_signatures);
+        }
+    }
+    let offset = v.master_track.pre_measure_ticks();
+
+    let voice = &v.voice_table.voices[0];
+    let singer = vsqx4::Singer {
+        position: 0,
+        bs: voice.bs,
+        pc: voice.pc,
+    };
+
+    let mut tempos: Vec<vsqx4::Tempo> = vec![];
+    for (i, part) in score.parts.iter().enumerate() {
+        let mut lost = NoteLosses::default();
+        let mut notes: Vec<vsqx4::Note> = vec![];
+        // 直前の音符が休符を挟まずに続いているか、歌詞が`-`で終わっているか
+        let mut legato = false;
+        let mut hyphen = false;
+
+        let voice = part
+            .measures
+            .iter()
+            .flat_map(|m| &m.notes)
+            .find(|n| n.pitch.is_some())
+            .and_then(|n| n.voice.clone());
+
+        let mut divisions = 1;
+        let mut numerator_denominator = (4, 4);
+        let mut measure_tick = 0;
+
+        for m in &part.measures {
+            divisions = m.divisions.unwrap_or(divisions).max(1);
+            numerator_denominator = m.time.unwrap_or(numerator_denominator);
+            let to_ticks = |d: i64| d * resolution / divisions;
+
+            if i == 0 {
+                for &(position, bpm) in &m.tempos {
+                    let tick = measure_tick + to_ticks(position);
+                    tempos.retain(|t| t.position != tick);
+                    tempos.push(vsqx4::Tempo {
+                        position: tick,
+                        value: bpm_to_value(bpm),
+                    });
+                }
+            }
+
+            for n in &m.notes {
+                if n.voice != voice && n.voice.is_some() && voice.is_some() {
+                    continue;
+                }
+                if n.chord {
+                    lost.add("chord");
+                    continue;
+                }
+
+                let pitch = match n.pitch {
+                    Some(pitch) => pitch,
+                    None => {
+                        legato = false;
+                        continue;
+                    }
+                };
+
+                let position = measure_tick + to_ticks(n.position);
+                let duration = to_ticks(n.duration);
+
+                // タイでつながった音符は1つにする
+                if n.tie_stop {
+                    if let Some(last) = notes.last_mut() {
+                        if last.note_num == pitch.note_num()
+                            && last.position + last.duration == position
+                        {
+                            last.duration += duration;
+                            continue;
+                        }
+                    }
+                }
+
+                let lyric = match &n.lyric {
+                    Some(lyric) => {
+                        let continued =
+                            matches!(lyric.syllabic, Some(Syllabic::Begin | Syllabic::Middle));
+                        hyphen = continued;
+                        if continued {
+                            format!("{}-", lyric.text)
+                        } else {
+                            lyric.text.clone()
+                        }
+                    }
+                    None if legato || hyphen => "-".into(),
+                    None => vsqx4::Note::default().lyric,
+                };
+
+                notes.push(vsqx4::Note {
+                    position,
+                    duration,
+                    note_num: pitch.note_num(),
+                    lyric,
+                    phoneme: String::new(),
+                    ..Default::default()
+                });
+                legato = true;
+            }
+
+            let (numerator, denominator) = numerator_denominator;
+            let length = m
+                .notes
+                .iter()
+                .map(|n| to_ticks(n.position + n.duration))
+                .max()
+                .filter(|&l| l > 0)
+                .unwrap_or(resolution * 4 * numerator / denominator.max(1));
+            measure_tick += length;
+        }
+
+        let play_time = notes
+            .iter()
+            .map(|n| n.position + n.duration)
+            .max()
+            .unwrap_or(0);
+        let mut vs_track = vsqx4::VsTrack {
+            track_no: i as i64,
+            name: part.name.clone(),
+            ..Default::default()
+        };
+        if !notes.is_empty() {
+            vs_track.parts.push(vsqx4::VsPart {
+                position: offset,
+                play_time: Some(play_time as u64),
+                name: Some(part.name.clone()),
+                notes,
+                singers: vec![singer.clone()],
+                ..Default::default()
+            });
+        }
+        unmapped.extend(lost.into_unmapped(i, 0));
+
+        v.vs_track.push(vs_track);
+        v.mixer.vs_unit.push(vsqx4::VsUnit {
+            track_no: i as i64,
+            ..Default::default()
+        });
+    }
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    // テンポ（先頭以外はプリメジャーの分ずらす）
+    tempos.sort_by_key(|t| t.position);
+    if !tempos.is_empty() {
+        for t in &mut tempos {
+            if t.position > 0 {
+                t.position += offset;
+            }
+        }
+        if tempos[0].position != 0 {
+            tempos.insert(
+                0,
+                vsqx4::Tempo {
+                    position: 0,
+                    value: 12000,
+                },
+            );
+        }
+        v.master_track.tempos = tempos;
+    }
+
+    Conversion::new(v, unmapped)
+}
+
+/// 小節`(開始位置, 長さ, 拍子)`の列。位置はプリメジャーを含まないティック。
+fn measures(v: &Vsqx4, end: i64) -> Vec<(i64, i64, (i64, i64))> {
+    let master = &v.master_track;
+    let mut time_signatures = master.time_signatures.clone();
+    time_signatures.sort_by_key(|ts| ts.position);
+
+    let mut measures = vec![];
+    let mut tick = -master.pre_measure_ticks();
+    let mut bar = 0;
+    let mut time = (4, 4);
+
+    while tick < end || measures.is_empty() {
+        if let Some(ts) = time_signatures.iter().rev().find(|ts| ts.position <= bar) {
+            time = (ts.numerator.max(1), ts.denominator.max(1));
+        }
+        let length = master.resolution * 4 * time.0 / time.1;
+        if bar >= master.pre_measure {
+            measures.push((tick, length.max(GRID), time));
+        }
+
+        tick += length.max(GRID);
+        bar += 1;
+    }
+
+    measures
+}
+
+/// 長さを音価に分ける。
+fn split_duration(mut duration: i64) -> Vec<(i64, &'static str, u8)> {
+    let mut values = vec![];
+    while duration > 0 {
+        let &value = NOTE_VALUES.iter().find(|v| v.0 <= duration).unwrap();
+        values.push(value);
+        duration -= value.0;
+    }
+
+    values
+}
+
+/// Vsqx4からMusicXMLへの変換。
+/// 位置と長さは32分音符単位に揃え、小節線をまたぐ音符や音価で表せない長さはタイでつなぐ。
+/// プリメジャー中のノートと、コントロールカーブは書き出さない。
+pub(crate) fn convert_vsqx4_to_musicxml(v: &Vsqx4) -> Conversion<MusicXml> {
+    let mut unmapped = vec![];
+    let offset = v.master_track.pre_measure_ticks();
+    let quantize = |tick: i64| ((tick as f64 / GRID as f64).round() as i64) * GRID;
+
+    // トラックごとの`(開始, 終了, ノート)`
+    let mut tracks = vec![];
+    for (i, track) in v.vs_track.iter().enumerate() {
+        let mut lost = NoteLosses::default();
+        let mut notes: Vec<(i64, i64, &vsqx4::Note)> = vec![];
+
+        for (j, part) in track.parts.iter().enumerate() {
+            if !part.curves().is_empty() {
+                unmapped.push(Unmapped::part(i, j, "control curves"));
+            }
+
+            for n in &part.notes {
+                let start = part.position + n.position - offset;
+                if start < 0 {
+                    lost.add("pre-measure");
+                    continue;
+                }
+
+                let (s, e) = (quantize(start), quantize(start + n.duration));
+                if s != start || e != start + n.duration {
+                    lost.add("exact timing");
+                }
+                notes.push((s, e.max(s + GRID), n));
+            }
+        }
+        unmapped.extend(lost.into_unmapped(i, 0));
+
+        // 重なりは先のノートを切る
+        notes.sort_by_key(|n| n.0);
+        for k in 1..notes.len() {
+            let next = notes[k].0;
+            let prev = &mut notes[k - 1];
+            prev.1 = prev.1.min(next);
+        }
+        notes.retain(|n| n.1 > n.0);
+
+        tracks.push(notes);
+    }
+
+    let end = tracks.iter().flatten().map(|n| n.1).max().unwrap_or(0);
+    let measures = measures(v, end);
+
+    let mut tempos: Vec<(i64, f64)> = v
+        .master_track
+        .tempos
+        .iter()
+        .map(|t| {
+            let tick = if t.position == 0 {
+                0
+            } else {
+                t.position - offset
+            };
+            (quantize(tick.max(0)), t.value as f64 / 100.0)
+        })
+        .collect();
+    tempos.sort_by_key(|t| t.0);
+
+    let mut parts = vec![];
+    for (i, (track, notes)) in v.vs_track.iter().zip(&tracks).enumerate() {
+        let mut part = Part {
+            id: format!("P{}", i + 1),
+            name: track.name.clone(),
+            measures: vec![],
+        };
+        let mut prev_time = None;
+        let mut hyphen = false;
+
+        for (k, &(start, length, time)) in measures.iter().enumerate() {
+            let end = start + length;
+            let mut measure = Measure {
+                number: (k + 1).to_string(),
+                divisions: if k == 0 { Some(DIVISIONS) } else { None },
+                time: if prev_time != Some(time) {
+                    Some(time)
+                } else {
+                    None
+                },
+                tempos: tempos
+                    .iter()
+                    .filter(|t| start <= t.0 && t.0 < end)
+                    .map(|t| ((t.0 - start) / GRID, t.1))
+                    .collect(),
+                notes: vec![],
+            };
+            prev_time = Some(time);
+
+            // 休符を挟みながら小節内の部分を並べる
+            let mut segments: Vec<(i64, i64, Option<&vsqx4::Note>, bool, bool)> = vec![];
+            let mut cursor = start;
+            for &(s, e, n) in notes.iter().filter(|n| n.0 < end && n.1 > start) {
+                if s > cursor {
+                    segments.push((cursor, s, None, false, false));
+                }
+                let (from, to) = (s.max(start), e.min(end));
+                segments.push((from, to, Some(n), s < start, e > end));
+                cursor = to;
+            }
+            if cursor < end {
+                segments.push((cursor, end, None, false, false));
+            }
+
+            for (from, to, n, tied_from, tied_to) in segments {
+                let n = match n {
+                    Some(n) => n,
+                    None if from == start && to == end => {
+                        measure.notes.push(Note {
+                            position: 0,
+                            duration: length / GRID,
+                            ..Default::default()
+                        });
+                        continue;
+                    }
+                    None => {
+                        let mut position = (from - start) / GRID;
+                        for (d, note_type, dots) in split_duration((to - from) / GRID) {
+                            measure.notes.push(Note {
+                                position,
+                                duration: d,
+                                note_type: Some(note_type.into()),
+                                dots,
+                                ..Default::default()
+                            });
+                            position += d;
+                        }
+                        continue;
+                    }
+                };
+
+                let lyric = if tied_from {
+                    None
+                } else {
+                    convert_lyric(&n.lyric, &mut hyphen)
+                };
+
+                let values = split_duration((to - from) / GRID);
+                let count = values.len();
+                let mut position = (from - start) / GRID;
+                for (j, (d, note_type, dots)) in values.into_iter().enumerate() {
+                    measure.notes.push(Note {
+                        position,
+                        duration: d,
+                        pitch: Some(Pitch::from_note_num(n.note_num)),
+                        tie_stop: j > 0 || tied_from,
+                        tie_start: j + 1 < count || tied_to,
+                        note_type: Some(note_type.into()),
+                        dots,
+                        lyric: if j == 0 { lyric.clone() } else { None },
+                        ..Default::default()
+                    });
+                    position += d;
+                }
+            }
+
+            part.measures.push(measure);
+        }
+
+        parts.push(part);
+    }
+
+    Conversion::new(
+        MusicXml {
+            title: v.master_track.name.clone(),
+            parts,
+        },
+        unmapped,
+    )
+}
+
+/// VOCALOIDの歌詞を`syllabic`付きの歌詞にする。`-`のみの歌詞は歌詞なし（メリスマ）とする。
+fn convert_lyric(lyric: &str, hyphen: &mut bool) -> Option<Lyric> {
+    if lyric == "-" {
+        return None;
+    }
+
+    let continued = lyric.len() > 1 && lyric.ends_with('-');
+    let syllabic = match (*hyphen, continued) {
+        (false, false) => Syllabic::Single,
+        (false, true) => Syllabic::Begin,
+        (true, true) => Syllabic::Middle,
+        (true, false) => Syllabic::End,
+    };
+    *hyphen = continued;
+
+    Some(Lyric {
+        text: lyric.trim_end_matches('-').to_string(),
+        syllabic: Some(syllabic),
+    })
+}
+
+#[test]
+#[cfg(test)]
+/// Vsqx4をMusicXMLに書き出し、読み直して変換すると同じノートと歌詞になるか確認する。
+fn test_musicxml_roundtrip() {
+    let v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+    let score = MusicXml::from_vsqx4(&v).project;
+    let xml = score.to_string().unwrap();
+
+    let score2: MusicXml = xml.parse().unwrap();
+    assert_eq!(score, score2);
+
+    let v2 = score2.to_vsqx4().project;
+    assert_eq!(v2.master_track.tempos[0].value, 8000);
+
+    let offset = v.master_track.pre_measure_ticks();
+    let original = &v.vs_track[0].parts[0];
+    let part = &v2.vs_track[0].parts[0];
+    let quantize = |tick: i64| (tick as f64 / GRID as f64).round() as i64 * GRID;
+    assert_eq!(part.notes.len(), original.notes.len());
+    for (a, b) in part.notes.iter().zip(&original.notes) {
+        assert_eq!(
+            a.position,
+            quantize(original.position + b.position - offset)
+        );
+        assert_eq!(a.note_num, b.note_num);
+        assert_eq!(a.lyric, b.lyric);
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// タイ・休符・音節・和音を含むMusicXMLを読み込めるか確認する。
+fn test_musicxml_import() {
+    let score: MusicXml = include_str!("../test/test.musicxml").parse().unwrap();
+    assert_eq!(score.title, "Test");
+    assert_eq!(score.parts[0].measures[0].time, Some((3, 4)));
+
+    let conversion = score.to_vsqx4();
+    let v = &conversion.project;
+    assert_eq!(v.master_track.time_signatures[0].numerator, 3);
+    assert_eq!(v.master_track.tempos[0].value, 9000);
+
+    let notes: Vec<_> = v.vs_track[0].parts[0]
+        .notes
+        .iter()
+        .map(|n| (n.position, n.duration, n.note_num, n.lyric.as_str()))
+        .collect();
+    assert_eq!(
+        notes,
+        vec![
+            (0, 480, 60, "hel-"),
+            (480, 1440, 62, "lo"),
+            (1920, 480, 61, "-"),
+            (2880, 480, 64, "あ"),
+        ]
+    );
+    assert_eq!(conversion.unmapped.len(), 1);
+}
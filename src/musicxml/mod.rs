@@ -0,0 +1,579 @@
+//! MusicXML（score-partwise）のボーカルパート
+//!
+//! 歌声合成に必要な音高・長さ・タイ・歌詞・テンポ・拍子だけを扱う。
+//! 位置と長さは`divisions`（四分音符あたりの分割数）を単位とする。
+
+use crate::conversion::Conversion;
+use crate::read_xml::Element;
+use crate::write_xml::{write_empty, WriteXml};
+use crate::{vpr, vsqx4, Error, Result};
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::Writer;
+
+pub(crate) mod convert;
+
+/// MusicXMLの楽譜
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct MusicXml {
+    /// 曲名（`work-title`）
+    pub title: String,
+    pub parts: Vec<Part>,
+}
+
+/// パート
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Part {
+    pub id: String,
+    /// パート名（`part-name`）
+    pub name: String,
+    pub measures: Vec<Measure>,
+}
+
+/// 小節
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Measure {
+    pub number: String,
+    /// 四分音符あたりの分割数。変わる小節のみ。
+    pub divisions: Option<i64>,
+    /// 拍子`(beats, beat-type)`。変わる小節のみ。
+    pub time: Option<(i64, i64)>,
+    /// テンポ`(小節の先頭からの位置, BPM)`
+    pub tempos: Vec<(i64, f64)>,
+    /// 位置順の音符と休符
+    pub notes: Vec<Note>,
+}
+
+/// 音符または休符
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Note {
+    /// 小節の先頭からの位置
+    pub position: i64,
+    pub duration: i64,
+    /// 音高。`None`なら休符。
+    pub pitch: Option<Pitch>,
+    /// 直前の音符と同時に鳴る（和音）
+    pub chord: bool,
+    pub voice: Option<String>,
+    /// 次の音符へのタイ
+    pub tie_start: bool,
+    /// 前の音符からのタイ
+    pub tie_stop: bool,
+    /// 音価（`quarter`など）。`None`の休符は小節全体の休符として書き出す。
+    pub note_type: Option<String>,
+    /// 付点の数
+    pub dots: u8,
+    pub lyric: Option<Lyric>,
+}
+
+/// 音高
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pitch {
+    /// 音名（`C`〜`B`）
+    pub step: char,
+    /// 半音単位の変化（シャープが正）
+    pub alter: i64,
+    pub octave: i64,
+}
+
+/// 歌詞
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lyric {
+    pub text: String,
+    pub syllabic: Option<Syllabic>,
+}
+
+/// 単語の中での音節の位置
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syllabic {
+    Single,
+    Begin,
+    Middle,
+    End,
+}
+
+const STEPS: [(char, i64); 7] = [
+    ('C', 0),
+    ('D', 2),
+    ('E', 4),
+    ('F', 5),
+    ('G', 7),
+    ('A', 9),
+    ('B', 11),
+];
+
+impl Pitch {
+    /// MIDIノート番号から作る。黒鍵はシャープで表す。
+    pub fn from_note_num(note_num: i64) -> Self {
+        let (octave, semitone) = (note_num.div_euclid(12) - 1, note_num.rem_euclid(12));
+        let &(step, base) = STEPS.iter().rev().find(|s| s.1 <= semitone).unwrap();
+
+        Self {
+            step,
+            alter: semitone - base,
+            octave,
+        }
+    }
+
+    /// MIDIノート番号
+    pub fn note_num(self) -> i64 {
+        let base = STEPS.iter().find(|s| s.0 == self.step).map_or(0, |s| s.1);
+
+        (self.octave + 1) * 12 + base + self.alter
+    }
+}
+
+impl Syllabic {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Begin => "begin",
+            Self::Middle => "middle",
+            Self::End => "end",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "single" => Some(Self::Single),
+            "begin" => Some(Self::Begin),
+            "middle" => Some(Self::Middle),
+            "end" => Some(Self::End),
+            _ => None,
+        }
+    }
+}
+
+impl MusicXml {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        let mut bytes = vec![];
+        self.write(&mut bytes)?;
+
+        Ok(String::from_utf8_lossy(&bytes).into())
+    }
+
+    pub fn write<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        let mut writer = Writer::new_with_indent(writer, b' ', 2);
+        writer.write(br#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#)?;
+        writer.write(b"\n")?;
+        writer.write(
+            br#"<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 3.1 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">"#,
+        )?;
+        writer.write(b"\n")?;
+        self.tagged(&mut writer, b"score-partwise")
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        use std::fs::File;
+        use std::io::BufWriter;
+
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    /// `Vsqx4`に変換する。
+    pub fn to_vsqx4(&self) -> Conversion<vsqx4::Vsqx4> {
+        convert::convert_musicxml_to_vsqx4(self)
+    }
+
+    /// `Vpr`に変換する（`Vsqx4`を経由する）。
+    pub fn to_vpr(&self) -> Conversion<vpr::Vpr> {
+        self.to_vsqx4().map(vpr::Vpr::from)
+    }
+
+    /// `Vsqx4`から変換する。
+    pub fn from_vsqx4(v: &vsqx4::Vsqx4) -> Conversion<Self> {
+        convert::convert_vsqx4_to_musicxml(v)
+    }
+
+    /// `Vpr`から変換する（`Vsqx4`を経由する）。
+    pub fn from_vpr(v: &vpr::Vpr) -> Conversion<Self> {
+        convert::convert_vsqx4_to_musicxml(&v.clone().into())
+    }
+}
+
+impl std::str::FromStr for MusicXml {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let root = Element::parse(s)?;
+        if root.name != "score-partwise" {
+            return Err(musicxml_error(format!("unsupported root <{}>", root.name)));
+        }
+
+        let title = root
+            .child("work")
+            .and_then(|w| w.text_of("work-title"))
+            .or_else(|| root.text_of("movement-title"))
+            .unwrap_or_default()
+            .to_string();
+
+        let names: Vec<(&str, &str)> = root
+            .child("part-list")
+            .map(|list| {
+                list.children("score-part")
+                    .map(|p| {
+                        (
+                            p.attribute("id").unwrap_or_default(),
+                            p.text_of("part-name").unwrap_or_default(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let parts = root
+            .children("part")
+            .map(|p| {
+                let id = p.attribute("id").unwrap_or_default();
+                Ok(Part {
+                    id: id.to_string(),
+                    name: names
+                        .iter()
+                        .find(|n| n.0 == id)
+                        .map_or("", |n| n.1)
+                        .to_string(),
+                    measures: p
+                        .children("measure")
+                        .map(parse_measure)
+                        .collect::<Result<_>>()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { title, parts })
+    }
+}
+
+fn musicxml_error<S: Into<String>>(message: S) -> Error {
+    Error::MusicXmlError(message.into())
+}
+
+fn parse_number<T: std::str::FromStr>(element: &Element) -> Result<T> {
+    element
+        .text
+        .trim()
+        .parse()
+        .map_err(|_| musicxml_error(format!("invalid <{}>: {:?}", element.name, element.text)))
+}
+
+fn parse_measure(m: &Element) -> Result<Measure> {
+    let mut measure = Measure {
+        number: m.attribute("number").unwrap_or_default().to_string(),
+        ..Default::default()
+    };
+    let mut cursor = 0;
+
+    for e in &m.children {
+        match e.name.as_str() {
+            "attributes" => {
+                if let Some(divisions) = e.child("divisions") {
+                    measure.divisions = Some(parse_number(divisions)?);
+                }
+                if let Some(time) = e.child("time") {
+                    if let (Some(beats), Some(beat_type)) =
+                        (time.child("beats"), time.child("beat-type"))
+                    {
+                        measure.time = Some((parse_number(beats)?, parse_number(beat_type)?));
+                    }
+                }
+            }
+            "direction" | "sound" => {
+                let sound = if e.name == "sound" {
+                    Some(e)
+                } else {
+                    e.child("sound")
+                };
+                if let Some(tempo) = sound.and_then(|s| s.attribute("tempo")) {
+                    if let Ok(tempo) = tempo.trim().parse() {
+                        measure.tempos.push((cursor, tempo));
+                    }
+                }
+            }
+            "backup" | "forward" => {
+                let duration: i64 = match e.child("duration") {
+                    Some(d) => parse_number(d)?,
+                    None => 0,
+                };
+                cursor += if e.name == "backup" {
+                    -duration
+                } else {
+                    duration
+                };
+            }
+            "note" => {
+                // 装飾音とキューは長さを持たないので扱わない
+                if e.child("grace").is_some() || e.child("cue").is_some() {
+                    continue;
+                }
+
+                let mut note = parse_note(e)?;
+                if note.chord {
+                    note.position = measure
+                        .notes
+                        .iter()
+                        .rev()
+                        .find(|n| !n.chord)
+                        .map_or(cursor, |n| n.position);
+                } else {
+                    note.position = cursor;
+                    cursor += note.duration;
+                }
+                measure.notes.push(note);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(measure)
+}
+
+fn parse_note(e: &Element) -> Result<Note> {
+    let pitch = match e.child("pitch") {
+        Some(p) => Some(Pitch {
+            step: p
+                .text_of("step")
+                .and_then(|s| s.chars().next())
+                .ok_or_else(|| musicxml_error("missing <step>"))?,
+            alter: p
+                .text_of("alter")
+                .and_then(|a| a.parse::<f64>().ok())
+                .map_or(0, |a| a.round() as i64),
+            octave: match p.child("octave") {
+                Some(o) => parse_number(o)?,
+                None => return Err(musicxml_error("missing <octave>")),
+            },
+        }),
+        None => None,
+    };
+
+    let ties = || e.children("tie").filter_map(|t| t.attribute("type"));
+    let lyric = e.child("lyric").and_then(|l| {
+        Some(Lyric {
+            text: l.text_of("text")?.to_string(),
+            syllabic: l.text_of("syllabic").and_then(Syllabic::parse),
+        })
+    });
+
+    Ok(Note {
+        position: 0,
+        duration: match e.child("duration") {
+            Some(d) => parse_number(d)?,
+            None => 0,
+        },
+        pitch,
+        chord: e.child("chord").is_some(),
+        voice: e.text_of("voice").map(str::to_string),
+        tie_start: ties().any(|t| t == "start"),
+        tie_stop: ties().any(|t| t == "stop"),
+        note_type: e.text_of("type").map(str::to_string),
+        dots: e.children("dot").count() as u8,
+        lyric,
+    })
+}
+
+impl WriteXml for MusicXml {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        Title(&self.title).tagged(writer, b"work")?;
+        PartList(&self.parts).tagged(writer, b"part-list")?;
+
+        for part in &self.parts {
+            part.tagged(writer, b"part")?;
+        }
+
+        Ok(())
+    }
+
+    fn props(&self) -> Vec<(&str, &str)> {
+        vec![("version", "3.1")]
+    }
+}
+
+struct Title<'a>(&'a str);
+
+impl WriteXml for Title<'_> {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        self.0.tagged(writer, b"work-title")
+    }
+}
+
+struct PartList<'a>(&'a [Part]);
+
+impl WriteXml for PartList<'_> {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        for part in self.0 {
+            ScorePart(part).tagged(writer, b"score-part")?;
+        }
+
+        Ok(())
+    }
+}
+
+struct ScorePart<'a>(&'a Part);
+
+impl WriteXml for ScorePart<'_> {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        self.0.name.tagged(writer, b"part-name")
+    }
+
+    fn props(&self) -> Vec<(&str, &str)> {
+        vec![("id", &self.0.id)]
+    }
+}
+
+impl WriteXml for Part {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        for measure in &self.measures {
+            measure.tagged(writer, b"measure")?;
+        }
+
+        Ok(())
+    }
+
+    fn props(&self) -> Vec<(&str, &str)> {
+        vec![("id", &self.id)]
+    }
+}
+
+impl WriteXml for Measure {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        if self.divisions.is_some() || self.time.is_some() {
+            Attributes(self).tagged(writer, b"attributes")?;
+        }
+
+        let mut tempos = self.tempos.iter().peekable();
+        for note in &self.notes {
+            while let Some((_, bpm)) = tempos.next_if(|t| t.0 <= note.position) {
+                Tempo(*bpm).tagged(writer, b"direction")?;
+            }
+            note.tagged(writer, b"note")?;
+        }
+        for &(_, bpm) in tempos {
+            Tempo(bpm).tagged(writer, b"direction")?;
+        }
+
+        Ok(())
+    }
+
+    fn props(&self) -> Vec<(&str, &str)> {
+        vec![("number", &self.number)]
+    }
+}
+
+/// `<attributes>`。調は常にハ長調、音部記号はト音記号として書き出す。
+struct Attributes<'a>(&'a Measure);
+
+impl WriteXml for Attributes<'_> {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        if let Some(divisions) = self.0.divisions {
+            divisions.tagged(writer, b"divisions")?;
+            writer.write_event(Event::Start(BytesStart::borrowed_name(b"key")))?;
+            0.tagged(writer, b"fifths")?;
+            writer.write_event(Event::End(BytesEnd::borrowed(b"key")))?;
+        }
+        if let Some((beats, beat_type)) = self.0.time {
+            writer.write_event(Event::Start(BytesStart::borrowed_name(b"time")))?;
+            beats.tagged(writer, b"beats")?;
+            beat_type.tagged(writer, b"beat-type")?;
+            writer.write_event(Event::End(BytesEnd::borrowed(b"time")))?;
+        }
+        if self.0.divisions.is_some() {
+            writer.write_event(Event::Start(BytesStart::borrowed_name(b"clef")))?;
+            'G'.tagged(writer, b"sign")?;
+            2.tagged(writer, b"line")?;
+            writer.write_event(Event::End(BytesEnd::borrowed(b"clef")))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// テンポの`<direction>`
+struct Tempo(f64);
+
+impl WriteXml for Tempo {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        writer.write_event(Event::Start(BytesStart::borrowed_name(b"direction-type")))?;
+        writer.write_event(Event::Start(BytesStart::borrowed_name(b"metronome")))?;
+        "quarter".tagged(writer, b"beat-unit")?;
+        self.0.tagged(writer, b"per-minute")?;
+        writer.write_event(Event::End(BytesEnd::borrowed(b"metronome")))?;
+        writer.write_event(Event::End(BytesEnd::borrowed(b"direction-type")))?;
+        write_empty(writer, "sound", &[("tempo", &self.0.to_string())])
+    }
+
+    fn props(&self) -> Vec<(&str, &str)> {
+        vec![("placement", "above")]
+    }
+}
+
+impl WriteXml for Note {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        if self.chord {
+            write_empty(writer, "chord", &[])?;
+        }
+        match self.pitch {
+            Some(pitch) => pitch.tagged(writer, b"pitch")?,
+            None if self.note_type.is_none() => write_empty(writer, "rest", &[("measure", "yes")])?,
+            None => write_empty(writer, "rest", &[])?,
+        }
+        self.duration.tagged(writer, b"duration")?;
+        if self.tie_stop {
+            write_empty(writer, "tie", &[("type", "stop")])?;
+        }
+        if self.tie_start {
+            write_empty(writer, "tie", &[("type", "start")])?;
+        }
+        if let Some(voice) = &self.voice {
+            voice.tagged(writer, b"voice")?;
+        }
+        if let Some(note_type) = &self.note_type {
+            note_type.tagged(writer, b"type")?;
+        }
+        for _ in 0..self.dots {
+            write_empty(writer, "dot", &[])?;
+        }
+
+        if self.tie_start || self.tie_stop {
+            writer.write_event(Event::Start(BytesStart::borrowed_name(b"notations")))?;
+            if self.tie_stop {
+                write_empty(writer, "tied", &[("type", "stop")])?;
+            }
+            if self.tie_start {
+                write_empty(writer, "tied", &[("type", "start")])?;
+            }
+            writer.write_event(Event::End(BytesEnd::borrowed(b"notations")))?;
+        }
+
+        if let Some(lyric) = &self.lyric {
+            lyric.tagged(writer, b"lyric")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl WriteXml for Pitch {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        self.step.tagged(writer, b"step")?;
+        if self.alter != 0 {
+            self.alter.tagged(writer, b"alter")?;
+        }
+        self.octave.tagged(writer, b"octave")
+    }
+}
+
+impl WriteXml for Lyric {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        if let Some(syllabic) = self.syllabic {
+            syllabic.as_str().tagged(writer, b"syllabic")?;
+        }
+        self.text.tagged(writer, b"text")
+    }
+
+    fn props(&self) -> Vec<(&str, &str)> {
+        vec![("number", "1")]
+    }
+}
@@ -0,0 +1,279 @@
+//! パート内のノートの重なりの検出と修復
+//!
+//! VOCALOID Editorは同じパート内でノートが重なっていると読み込みを拒否したり、
+//! 正しく発音しなかったりする。MIDIからの取り込みや自動生成したデータではよく起こるので、
+//! ここで検出・修復できるようにする。
+
+use crate::edit::{Curve, EditNote, EditPart, EditTrack};
+
+/// ノートの問題
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteIssue {
+    /// 2つのノートが重なっている。インデックスは`notes`のもの。
+    Overlap {
+        earlier: usize,
+        later: usize,
+        /// 重なっているティック数
+        ticks: i64,
+    },
+    /// 長さが0以下のノート
+    ZeroLength(usize),
+}
+
+/// 修復方法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepairStrategy {
+    /// 先のノートを後のノートの開始位置で切る。
+    /// 開始位置が同じ場合は先のノートが削除される。
+    TrimEarlier,
+    /// 重なっている2つのノートのうち短い方を削除する（同じ長さなら後のもの）。
+    DropShorter,
+    /// 重なっているノートを新しいパートに移す。
+    MoveToNewPart,
+}
+
+/// パート内の重なっているノートと長さ0のノートを探す。
+pub fn find_issues<P: EditPart + ?Sized>(part: &P) -> Vec<NoteIssue> {
+    let notes = part.notes();
+    let mut issues = vec![];
+
+    let mut order: Vec<usize> = (0..notes.len()).collect();
+    order.sort_by_key(|&i| notes[i].position());
+
+    for (k, &i) in order.iter().enumerate() {
+        if notes[i].duration() <= 0 {
+            issues.push(NoteIssue::ZeroLength(i));
+            continue;
+        }
+
+        for &j in &order[k + 1..] {
+            if notes[j].position() >= notes[i].end() {
+                break;
+            }
+            if notes[j].duration() <= 0 {
+                continue;
+            }
+
+            issues.push(NoteIssue::Overlap {
+                earlier: i,
+                later: j,
+                ticks: notes[i].end().min(notes[j].end()) - notes[j].position(),
+            });
+        }
+    }
+
+    issues
+}
+
+/// パート内の重なりを修復する。長さ0のノートは常に削除される。
+///
+/// `RepairStrategy::MoveToNewPart`の場合、移されたノートを持つパートを返す。
+/// これらは元のパートと同じ位置・設定を持ち、カーブは移されたノートの範囲の分だけ
+/// （範囲の先頭での値を含めて）写される。元のパートのカーブはそのまま残る。
+pub fn repair<P: EditPart + Clone>(part: &mut P, strategy: RepairStrategy) -> Vec<P> {
+    let mut notes = std::mem::take(part.notes_mut());
+    notes.retain(|n| n.duration() > 0);
+    notes.sort_by_key(EditNote::position);
+
+    let mut new_parts = vec![];
+
+    match strategy {
+        RepairStrategy::TrimEarlier => {
+            for i in 1..notes.len() {
+                let next = notes[i].position();
+                let prev = &mut notes[i - 1];
+                if prev.end() > next {
+                    let duration = next - prev.position();
+                    prev.set_duration(duration);
+                }
+            }
+            notes.retain(|n| n.duration() > 0);
+        }
+        RepairStrategy::DropShorter => {
+            let mut kept: Vec<P::Note> = vec![];
+            for n in notes {
+                match kept.last() {
+                    Some(last) if last.end() > n.position() => {
+                        if n.duration() > last.duration() {
+                            kept.pop();
+                            kept.push(n);
+                        }
+                    }
+                    _ => kept.push(n),
+                }
+            }
+            notes = kept;
+        }
+        RepairStrategy::MoveToNewPart => {
+            // 重ならないように層に振り分ける
+            let mut layers: Vec<Vec<P::Note>> = vec![];
+            for n in notes {
+                match layers
+                    .iter_mut()
+                    .find(|l| l.last().map(EditNote::end).unwrap_or(0) <= n.position())
+                {
+                    Some(layer) => layer.push(n),
+                    None => layers.push(vec![n]),
+                }
+            }
+
+            let mut layers = layers.into_iter();
+            notes = layers.next().unwrap_or_default();
+
+            let curves = part.curves();
+            for layer in layers {
+                let start = layer.first().map(EditNote::position).unwrap_or(0);
+                let end = layer.iter().map(EditNote::end).max().unwrap_or(0);
+
+                // 新しいパートは元と同じ位置に置くので、イベントの位置はそのままでよい
+                let mut p = part.clone();
+                p.set_curves(
+                    curves
+                        .iter()
+                        .map(|c| curve_in_range(c, start, end))
+                        .filter(|c| !c.events.is_empty())
+                        .collect(),
+                );
+                *p.notes_mut() = layer;
+                new_parts.push(p);
+            }
+        }
+    }
+
+    *part.notes_mut() = notes;
+    new_parts
+}
+
+/// `[start, end)`の範囲のイベントを取り出す。`start`にイベントがなければ、その位置での値を補う。
+fn curve_in_range(curve: &Curve, start: i64, end: i64) -> Curve {
+    let mut events: Vec<(i64, i64)> = curve
+        .events
+        .iter()
+        .filter(|e| start <= e.0 && e.0 < end)
+        .cloned()
+        .collect();
+    if events.first().map(|e| e.0) != Some(start) {
+        if let Some(value) = curve.value_at(start) {
+            events.insert(0, (start, value));
+        }
+    }
+
+    Curve {
+        name: curve.name.clone(),
+        events,
+    }
+}
+
+/// トラック内のすべてのパートの重なりを修復する。
+///
+/// `RepairStrategy::MoveToNewPart`で作られたパートは元のパートと同じ位置にあり、
+/// 同じトラックに置くとパートどうしが重なるので、トラックには入れずに返す。
+/// 別のトラックに置くこと。
+pub fn repair_track<T: EditTrack>(track: &mut T, strategy: RepairStrategy) -> Vec<T::Part>
+where
+    T::Part: Clone,
+{
+    let mut moved = vec![];
+    for p in track.parts_mut() {
+        moved.extend(repair(p, strategy));
+    }
+
+    moved
+}
+
+#[cfg(test)]
+fn test_part() -> crate::vsqx4::VsPart {
+    use crate::vsqx4::{Note, VsPart};
+
+    let mut part = VsPart::default();
+    for &(position, duration) in &[(0, 600), (480, 240), (960, 0), (960, 480)] {
+        part.notes.push(Note {
+            position,
+            duration,
+            ..Default::default()
+        });
+    }
+    part
+}
+
+#[test]
+#[cfg(test)]
+/// 重なりと長さ0のノートを検出できるか確認する。
+fn test_find_issues() {
+    let issues = find_issues(&test_part());
+
+    assert_eq!(
+        issues,
+        vec![
+            NoteIssue::Overlap {
+                earlier: 0,
+                later: 1,
+                ticks: 120
+            },
+            NoteIssue::ZeroLength(2),
+        ]
+    );
+}
+
+#[test]
+#[cfg(test)]
+/// 各修復方法で重なりがなくなるか確認する。
+fn test_repair() {
+    let mut part = test_part();
+    assert!(repair(&mut part, RepairStrategy::TrimEarlier).is_empty());
+    assert!(find_issues(&part).is_empty());
+    assert_eq!(part.notes[0].duration, 480);
+
+    let mut part = test_part();
+    repair(&mut part, RepairStrategy::DropShorter);
+    assert!(find_issues(&part).is_empty());
+    assert_eq!(part.notes.len(), 2);
+
+    let mut part = test_part();
+    for &(pos, value) in &[(0, 64), (240, 100), (600, 30), (960, 80)] {
+        part.control_changes.push(crate::vsqx4::ControlChange {
+            id: "D".into(),
+            pos,
+            value,
+        });
+    }
+    let new_parts = repair(&mut part, RepairStrategy::MoveToNewPart);
+    assert!(find_issues(&part).is_empty());
+    assert_eq!(new_parts.len(), 1);
+    assert_eq!(new_parts[0].notes[0].position, 480);
+    // 移されたノート（480～720）の範囲のカーブが、先頭での値とともに写される
+    let events: Vec<_> = new_parts[0]
+        .control_changes
+        .iter()
+        .map(|cc| (cc.pos, cc.value))
+        .collect();
+    assert_eq!(events, vec![(480, 100), (600, 30)]);
+    assert_eq!(part.control_changes.len(), 4);
+}
+
+#[test]
+#[cfg(test)]
+/// トラックの修復でパートどうしが重ならないか確認する。
+fn test_repair_track() {
+    use crate::vsqx4::VsTrack;
+
+    let mut part = test_part();
+    part.position = 1920;
+    part.play_time = Some(1440);
+    let mut track = VsTrack::default();
+    track.parts.push(part);
+
+    let moved = repair_track(&mut track, RepairStrategy::MoveToNewPart);
+    assert_eq!(track.parts.len(), 1);
+    assert_eq!(moved.len(), 1);
+    assert!(find_issues(&track.parts[0]).is_empty());
+
+    // 残ったパートどうしが重なっていないこと
+    for (i, a) in track.parts.iter().enumerate() {
+        for b in &track.parts[i + 1..] {
+            let a_end = a.position + a.play_time.unwrap_or_default() as i64;
+            let b_end = b.position + b.play_time.unwrap_or_default() as i64;
+            assert!(a_end <= b.position || b_end <= a.position);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! XMLを読み込むための要素の木
+//!
+//! 子要素の順序や知らない要素が混在する形式（MusicXML、.ccsなど）はserdeで読むと扱いにくいので、
+//! いったん木にしてから辿る。
+
+use crate::Result;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+fn unexpected_eof(message: &str) -> crate::Error {
+    quick_xml::Error::UnexpectedEof(message.into()).into()
+}
+
+/// 読み込み用の要素の木
+#[derive(Default)]
+pub(crate) struct Element {
+    pub(crate) name: String,
+    pub(crate) attributes: Vec<(String, String)>,
+    pub(crate) children: Vec<Element>,
+    pub(crate) text: String,
+    /// 開始タグの位置（バイト）
+    pub(crate) offset: usize,
+}
+
+impl Element {
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        let mut reader = Reader::from_str(s);
+        let mut buf = vec![];
+        let mut stack: Vec<Element> = vec![Element::default()];
+
+        loop {
+            let event = reader.read_event(&mut buf)?;
+            match &event {
+                Event::Start(e) | Event::Empty(e) => {
+                    // `<`と`>`（空要素は`/>`）の分を戻す
+                    let tag = e.len() + if let Event::Start(_) = event { 2 } else { 3 };
+                    let mut element = Element {
+                        name: String::from_utf8_lossy(e.name()).into(),
+                        offset: reader.buffer_position() - tag,
+                        ..Default::default()
+                    };
+                    for a in e.attributes() {
+                        let a = a?;
+                        element.attributes.push((
+                            String::from_utf8_lossy(a.key).into(),
+                            a.unescape_and_decode_value(&reader)?,
+                        ));
+                    }
+
+                    if let Event::Start(_) = event {
+                        stack.push(element);
+                    } else {
+                        stack.last_mut().unwrap().children.push(element);
+                    }
+                }
+                Event::End(_) => {
+                    let element = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Err(unexpected_eof("unbalanced tags")),
+                    }
+                }
+                Event::Text(e) | Event::CData(e) => {
+                    let text = e.unescape_and_decode(&reader)?;
+                    stack.last_mut().unwrap().text.push_str(&text);
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        stack
+            .pop()
+            .and_then(|document| document.children.into_iter().next())
+            .ok_or_else(|| unexpected_eof("no root element"))
+    }
+
+    pub(crate) fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.0 == name)
+            .map(|a| a.1.as_str())
+    }
+
+    pub(crate) fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    pub(crate) fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    /// 子要素のテキスト
+    pub(crate) fn text_of(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|c| c.text.trim())
+    }
+}
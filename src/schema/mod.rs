@@ -0,0 +1,574 @@
+//! XMLスキーマ（XSD）による.vsqxの検査
+//!
+//! .vsqxは`xsi:schemaLocation`で`vsq3.xsd`・`vsq4.xsd`を指しているが、ヤマハのXSDは配布されていない。
+//! 同梱の`vsq3.xsd`・`vsq4.xsd`は、VOCALOID3・4 Editorで保存したファイル（`src/test/v3.vsqx`・`v4.vsqx`）から起こしたもので、
+//! このクレートの書き出し結果から起こしたものではない。
+//!
+//! 検査に使うXSDの機能は、同梱のスキーマで使っている範囲に限る。
+//!
+//! * 名前付きの`xs:complexType`と、その中の`xs:sequence`（`minOccurs`・`maxOccurs`）
+//! * `xs:simpleContent`の`xs:extension`と`xs:attribute`（`use="required"`）
+//! * `xs:simpleType`の`xs:restriction`（`minInclusive`・`maxInclusive`）
+//! * 組み込み型`xs:string`・`xs:int`・`xs:long`・`xs:unsignedInt`・`xs:unsignedByte`・`xs:anyType`
+
+use crate::locate::line_column;
+use crate::read_xml::Element;
+use crate::{Location, Result};
+use std::collections::HashMap;
+
+/// 同梱のスキーマ
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schema {
+    /// VOCALOID3（`vsq3.xsd`）
+    Vsq3,
+    /// VOCALOID4（`vsq4.xsd`）
+    Vsq4,
+}
+
+/// スキーマに合わない箇所
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaIssue {
+    pub kind: SchemaIssueKind,
+    /// 要素の位置（`/vsq4/vsTrack[0]/vsPart[0]/note[3]`など、番号は同名の要素の中で0から）
+    pub path: String,
+    pub location: Location,
+}
+
+/// スキーマに合わない箇所の種類
+#[derive(Clone, Debug, PartialEq)]
+pub enum SchemaIssueKind {
+    /// ルート要素が違う
+    UnknownRoot { found: String },
+    /// ここにあってはならない要素（順序違いを含む）
+    UnexpectedElement { name: String },
+    /// 必要な要素がない
+    MissingElement { name: String },
+    /// 要素が`max`個を超えている
+    TooManyElements { name: String, max: usize },
+    /// 値が型に合わない
+    InvalidValue { type_name: String, value: String },
+    /// 必要な属性がない
+    MissingAttribute { name: String },
+    /// 定義されていない属性
+    UnexpectedAttribute { name: String },
+}
+
+impl std::fmt::Display for SchemaIssueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SchemaIssueKind::UnknownRoot { found } => write!(f, "unknown root <{}>", found),
+            SchemaIssueKind::UnexpectedElement { name } => write!(f, "unexpected <{}>", name),
+            SchemaIssueKind::MissingElement { name } => write!(f, "missing <{}>", name),
+            SchemaIssueKind::TooManyElements { name, max } => {
+                write!(f, "more than {} <{}>", max, name)
+            }
+            SchemaIssueKind::InvalidValue { type_name, value } => {
+                write!(f, "{:?} is not a valid {}", value, type_name)
+            }
+            SchemaIssueKind::MissingAttribute { name } => {
+                write!(f, "missing attribute {}", name)
+            }
+            SchemaIssueKind::UnexpectedAttribute { name } => {
+                write!(f, "unexpected attribute {}", name)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SchemaIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} in {} at {}", self.kind, self.path, self.location)
+    }
+}
+
+impl Schema {
+    /// スキーマの本文
+    pub fn xsd(self) -> &'static str {
+        match self {
+            Schema::Vsq3 => include_str!("vsq3.xsd"),
+            Schema::Vsq4 => include_str!("vsq4.xsd"),
+        }
+    }
+
+    /// ルート要素の名前
+    pub fn root(self) -> &'static str {
+        match self {
+            Schema::Vsq3 => "vsq3",
+            Schema::Vsq4 => "vsq4",
+        }
+    }
+
+    /// トラック・パート・ノートの要素名
+    fn items(self) -> [&'static str; 3] {
+        match self {
+            Schema::Vsq3 => ["vsTrack", "musicalPart", "note"],
+            Schema::Vsq4 => ["vsTrack", "vsPart", "note"],
+        }
+    }
+
+    /// `text`を検査して、合わない箇所をすべて返す。XMLとして読めないときはエラーになる。
+    pub fn validate(self, text: &str) -> Result<Vec<SchemaIssue>> {
+        let xsd = Element::parse(self.xsd())?;
+        let types = Types::new(&xsd);
+        let root = Element::parse(text)?;
+
+        let mut checker = Checker {
+            text,
+            types: &types,
+            items: self.items(),
+            issues: vec![],
+        };
+        if root.name == self.root() {
+            checker.element(&root, self.root(), &format!("/{}", root.name));
+        } else {
+            checker.issue(
+                SchemaIssueKind::UnknownRoot {
+                    found: root.name.clone(),
+                },
+                &root,
+                "",
+            );
+        }
+
+        Ok(checker.issues)
+    }
+}
+
+/// `xs:sequence`の中の`xs:element`
+struct Decl<'a> {
+    name: &'a str,
+    type_name: &'a str,
+    min: usize,
+    /// `None`は`unbounded`
+    max: Option<usize>,
+}
+
+/// `xs:simpleContent`の`xs:attribute`
+struct Attribute<'a> {
+    name: &'a str,
+    type_name: &'a str,
+    required: bool,
+}
+
+enum Type<'a> {
+    /// 子要素の並び
+    Sequence(Vec<Decl<'a>>),
+    /// 属性付きのテキスト
+    SimpleContent {
+        base: &'a str,
+        attributes: Vec<Attribute<'a>>,
+    },
+    /// 範囲を制限した値
+    Restriction {
+        base: &'a str,
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+}
+
+/// スキーマで定義された型
+struct Types<'a>(HashMap<&'a str, Type<'a>>);
+
+impl<'a> Types<'a> {
+    fn new(xsd: &'a Element) -> Self {
+        let mut types = HashMap::new();
+        for t in &xsd.children {
+            let name = match t.attribute("name") {
+                Some(name) => name,
+                None => continue,
+            };
+            let t = match t.name.as_str() {
+                "xs:complexType" => Self::complex(t),
+                "xs:simpleType" => Self::simple(t),
+                _ => continue,
+            };
+            types.insert(name, t);
+        }
+
+        Types(types)
+    }
+
+    fn complex(t: &'a Element) -> Type<'a> {
+        if let Some(extension) = t
+            .child("xs:simpleContent")
+            .and_then(|c| c.child("xs:extension"))
+        {
+            return Type::SimpleContent {
+                base: extension.attribute("base").unwrap_or("xs:string"),
+                attributes: extension
+                    .children("xs:attribute")
+                    .map(|a| Attribute {
+                        name: a.attribute("name").unwrap_or_default(),
+                        type_name: a.attribute("type").unwrap_or("xs:string"),
+                        required: a.attribute("use") == Some("required"),
+                    })
+                    .collect(),
+            };
+        }
+
+        let decls = t
+            .child("xs:sequence")
+            .map(|s| {
+                s.children("xs:element")
+                    .map(|e| Decl {
+                        name: e.attribute("name").unwrap_or_default(),
+                        type_name: e.attribute("type").unwrap_or("xs:anyType"),
+                        min: e
+                            .attribute("minOccurs")
+                            .and_then(|n| n.parse().ok())
+                            .unwrap_or(1),
+                        max: match e.attribute("maxOccurs") {
+                            Some("unbounded") => None,
+                            Some(n) => n.parse().ok(),
+                            None => Some(1),
+                        },
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Type::Sequence(decls)
+    }
+
+    fn simple(t: &'a Element) -> Type<'a> {
+        let restriction = t.child("xs:restriction");
+        let bound = |name| {
+            restriction
+                .and_then(|r| r.child(name))
+                .and_then(|b| b.attribute("value"))
+                .and_then(|v| v.parse().ok())
+        };
+
+        Type::Restriction {
+            base: restriction
+                .and_then(|r| r.attribute("base"))
+                .unwrap_or("xs:string"),
+            min: bound("xs:minInclusive"),
+            max: bound("xs:maxInclusive"),
+        }
+    }
+
+    /// 値が型に合うか
+    fn accepts(&self, type_name: &str, value: &str) -> bool {
+        match self.0.get(type_name) {
+            Some(Type::Restriction { base, min, max }) => {
+                self.accepts(base, value)
+                    && match value.trim().parse::<i64>() {
+                        Ok(v) => min.iter().all(|&m| m <= v) && max.iter().all(|&m| v <= m),
+                        Err(_) => min.is_none() && max.is_none(),
+                    }
+            }
+            Some(Type::SimpleContent { base, .. }) => self.accepts(base, value),
+            Some(Type::Sequence(_)) => true,
+            None => {
+                let value = value.trim();
+                match type_name {
+                    "xs:int" => value.parse::<i32>().is_ok(),
+                    "xs:long" => value.parse::<i64>().is_ok(),
+                    "xs:unsignedInt" => value.parse::<u32>().is_ok(),
+                    "xs:unsignedByte" => value.parse::<u8>().is_ok(),
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+struct Checker<'a, 't> {
+    text: &'a str,
+    types: &'a Types<'t>,
+    items: [&'static str; 3],
+    issues: Vec<SchemaIssue>,
+}
+
+impl<'a, 't> Checker<'a, 't> {
+    fn issue(&mut self, kind: SchemaIssueKind, element: &Element, path: &str) {
+        let (line, column) = line_column(self.text, element.offset);
+        let mut location = Location {
+            line: Some(line),
+            column: Some(column),
+            ..Default::default()
+        };
+
+        // パスの`vsTrack[i]`などから番号を取り出す
+        for segment in path.split('/') {
+            let (name, index) = match segment.find('[') {
+                Some(i) => (&segment[..i], &segment[i + 1..segment.len() - 1]),
+                None => continue,
+            };
+            let item = match self.items.iter().position(|&item| item == name) {
+                Some(0) => &mut location.track,
+                Some(1) => &mut location.part,
+                Some(_) => &mut location.note,
+                None => continue,
+            };
+            *item = index.parse().ok();
+        }
+
+        self.issues.push(SchemaIssue {
+            kind,
+            path: path.to_string(),
+            location,
+        });
+    }
+
+    fn element(&mut self, element: &Element, type_name: &str, path: &str) {
+        match self.types.0.get(type_name) {
+            Some(Type::Sequence(decls)) => {
+                self.attributes(element, &[], path);
+                self.sequence(element, decls, path);
+            }
+            Some(Type::SimpleContent { attributes, .. }) => {
+                self.attributes(element, attributes, path);
+                self.children(element, path);
+                self.value(element, type_name, path);
+            }
+            Some(Type::Restriction { .. }) | None if type_name != "xs:anyType" => {
+                self.attributes(element, &[], path);
+                self.children(element, path);
+                self.value(element, type_name, path);
+            }
+            _ => {}
+        }
+    }
+
+    /// 子要素を持たない型に子要素がないか
+    fn children(&mut self, element: &Element, path: &str) {
+        for c in &element.children {
+            self.issue(
+                SchemaIssueKind::UnexpectedElement {
+                    name: c.name.clone(),
+                },
+                c,
+                path,
+            );
+        }
+    }
+
+    fn value(&mut self, element: &Element, type_name: &str, path: &str) {
+        if !self.types.accepts(type_name, &element.text) {
+            self.issue(
+                SchemaIssueKind::InvalidValue {
+                    type_name: type_name.to_string(),
+                    value: element.text.clone(),
+                },
+                element,
+                path,
+            );
+        }
+    }
+
+    fn attributes(&mut self, element: &Element, attributes: &[Attribute], path: &str) {
+        for (name, value) in &element.attributes {
+            // 名前空間の宣言とスキーマの場所は検査しない
+            if name == "xmlns" || name.starts_with("xmlns:") || name.starts_with("xsi:") {
+                continue;
+            }
+            match attributes.iter().find(|a| a.name == name) {
+                Some(a) => {
+                    if !self.types.accepts(a.type_name, value) {
+                        self.issue(
+                            SchemaIssueKind::InvalidValue {
+                                type_name: a.type_name.to_string(),
+                                value: value.clone(),
+                            },
+                            element,
+                            &format!("{}/@{}", path, name),
+                        );
+                    }
+                }
+                None => self.issue(
+                    SchemaIssueKind::UnexpectedAttribute { name: name.clone() },
+                    element,
+                    path,
+                ),
+            }
+        }
+
+        for a in attributes {
+            if a.required && element.attribute(a.name).is_none() {
+                self.issue(
+                    SchemaIssueKind::MissingAttribute {
+                        name: a.name.to_string(),
+                    },
+                    element,
+                    path,
+                );
+            }
+        }
+    }
+
+    /// 子要素を`decls`の順序と個数に照らし合わせる。
+    fn sequence(&mut self, element: &Element, decls: &[Decl], path: &str) {
+        // いま見ている宣言と、それに合った要素の数
+        let mut current = 0;
+        let mut count = 0;
+        // 要素名ごとの番号
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+
+        for child in &element.children {
+            let index = indices.entry(child.name.as_str()).or_insert(0);
+            let child_path = format!("{}/{}[{}]", path, child.name, index);
+            *index += 1;
+
+            let found = decls[current..]
+                .iter()
+                .position(|d| d.name == child.name)
+                .map(|i| current + i);
+            let found = match found {
+                Some(found) => found,
+                None => {
+                    self.issue(
+                        SchemaIssueKind::UnexpectedElement {
+                            name: child.name.clone(),
+                        },
+                        child,
+                        &child_path,
+                    );
+                    continue;
+                }
+            };
+
+            if found == current {
+                count += 1;
+            } else {
+                // 飛ばした宣言の要素が足りているか
+                self.missing(&decls[current], count, child, path);
+                for d in &decls[current + 1..found] {
+                    self.missing(d, 0, child, path);
+                }
+                current = found;
+                count = 1;
+            }
+
+            let decl = &decls[current];
+            match decl.max {
+                Some(max) if count > max => self.issue(
+                    SchemaIssueKind::TooManyElements {
+                        name: child.name.clone(),
+                        max,
+                    },
+                    child,
+                    &child_path,
+                ),
+                _ => self.element(child, decl.type_name, &child_path),
+            }
+        }
+
+        if let Some(d) = decls.get(current) {
+            self.missing(d, count, element, path);
+            for d in &decls[current + 1..] {
+                self.missing(d, 0, element, path);
+            }
+        }
+    }
+
+    /// `decl`の要素が`count`個で足りなければ記録する。
+    fn missing(&mut self, decl: &Decl, count: usize, at: &Element, path: &str) {
+        if count < decl.min {
+            self.issue(
+                SchemaIssueKind::MissingElement {
+                    name: decl.name.to_string(),
+                },
+                at,
+                path,
+            );
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// エディターで保存したVSQXと書き出したVSQXがスキーマに合い、壊したところが見つかるか確認する。
+fn test_schema() {
+    use crate::vsqx3::Vsqx3;
+    use crate::vsqx4::Vsqx4;
+
+    // エディターで保存したファイルそのもの
+    let v3 = include_str!("../test/v3.vsqx");
+    let v4 = include_str!("../test/v4.vsqx");
+    assert_eq!(Schema::Vsq3.validate(v3).unwrap(), vec![]);
+    assert_eq!(Schema::Vsq4.validate(v4).unwrap(), vec![]);
+    // 書き出したもの
+    let vsqx3: Vsqx3 = v3.parse().unwrap();
+    assert_eq!(vsqx3.validate_schema().unwrap(), vec![]);
+    let vsqx4: Vsqx4 = v4.parse().unwrap();
+    assert_eq!(vsqx4.validate_schema().unwrap(), vec![]);
+
+    // エディターはテンポより先に拍子を書くので、逆の順序は通さない
+    let time_sig = v3.find("<timeSig>").unwrap();
+    let tempo = v3.find("<tempo>").unwrap();
+    let tempo_end = v3.rfind("</tempo>").unwrap() + "</tempo>".len();
+    let reordered = format!(
+        "{}{}{}{}",
+        &v3[..time_sig],
+        &v3[tempo..tempo_end],
+        &v3[time_sig..tempo],
+        &v3[tempo_end..]
+    );
+    let issues = Schema::Vsq3.validate(&reordered).unwrap();
+    assert!(issues.iter().any(|i| i.path == "/vsq3/masterTrack[0]/timeSig[0]"
+        && matches!(&i.kind, SchemaIssueKind::UnexpectedElement { name } if name == "timeSig")));
+
+    // VOCALOID3 Editorは`<plane>`を書かない
+    let part_end = v3.find("</musicalPart>").unwrap();
+    let with_plane = format!("{}<plane>0</plane>{}", &v3[..part_end], &v3[part_end..]);
+    assert!(Schema::Vsq3.validate(&with_plane).unwrap().iter().any(
+        |i| matches!(&i.kind, SchemaIssueKind::UnexpectedElement { name } if name == "plane")
+    ));
+
+    let issues = Schema::Vsq4.validate(v3).unwrap();
+    assert!(matches!(
+        &issues[..],
+        [SchemaIssue {
+            kind: SchemaIssueKind::UnknownRoot { .. },
+            ..
+        }]
+    ));
+
+    // 4つ目のノートの`<t>`と`<dur>`を入れ替える
+    let note = v4.match_indices("<note>").nth(3).unwrap().0;
+    let t = note + v4[note..].find("<t>").unwrap();
+    let dur = note + v4[note..].find("<dur>").unwrap();
+    let dur_end = note + v4[note..].find("</dur>").unwrap() + "</dur>".len();
+    let t_end = note + v4[note..].find("</t>").unwrap() + "</t>".len();
+    let swapped = format!(
+        "{}{}{}{}{}",
+        &v4[..t],
+        &v4[dur..dur_end],
+        &v4[t_end..dur],
+        &v4[t..t_end],
+        &v4[dur_end..]
+    );
+    let issues = Schema::Vsq4.validate(&swapped).unwrap();
+    let issue = issues
+        .iter()
+        .find(|i| matches!(&i.kind, SchemaIssueKind::UnexpectedElement { name } if name == "t"))
+        .unwrap();
+    assert_eq!(issue.path, "/vsq4/vsTrack[0]/vsPart[0]/note[3]/t[0]");
+    assert_eq!(
+        (
+            issue.location.track,
+            issue.location.part,
+            issue.location.note
+        ),
+        (Some(0), Some(0), Some(3))
+    );
+    assert_eq!(issue.location.line, Some(line_column(v4, t).0 + 1));
+
+    let mut broken = vsqx4.clone();
+    broken.vs_track[0].parts[0].play_time = None;
+    broken.vs_track[0].parts[0].notes[0].note_num = 200;
+    let kinds: Vec<_> = broken
+        .validate_schema()
+        .unwrap()
+        .into_iter()
+        .map(|i| i.kind)
+        .collect();
+    assert!(kinds.contains(&SchemaIssueKind::MissingElement {
+        name: "playTime".into()
+    }));
+    assert!(kinds.contains(&SchemaIssueKind::InvalidValue {
+        type_name: "noteNum".into(),
+        value: "200".into()
+    }));
+}
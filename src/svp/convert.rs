@@ -0,0 +1,548 @@
+//! .svpとVsqx4の相互変換（Vprとの変換はVsqx4を経由する）
+
+use super::{Group, GroupRef, Svp, BLICKS_PER_QUARTER};
+use crate::conversion::{self, Conversion, NoteLosses, Range, Unmapped};
+use crate::edit::{Curve, EditPart};
+use crate::timeline::TempoMap;
+use crate::ust::convert::{bpm_to_value, semitones_to_pit};
+use crate::vsqx4::{self, Vsqx4};
+
+/// 1ティック（四分音符 = 480）あたりのblick数
+const BLICKS_PER_TICK: i64 = BLICKS_PER_QUARTER / 480;
+/// 読み込むときに設定するピッチベンドセンシティビティ（半音）
+const PITCH_BEND_SENS: i64 = 12;
+/// 曲線を標本化する間隔（ティック）
+const CURVE_STEP: i64 = 10;
+
+/// `(.svpのパラメーター, VOCALOIDのCC, .svpの値域, VOCALOIDの値域)`
+///
+/// テンションに相当するものはないので、最も近いBRIに対応させる。
+/// `pitchDelta`はPIT・PBSから合成するのでここには含めない。
+const PARAMETER_MAPPINGS: &[(&str, &str, Range, Range)] = &[
+    ("loudness", "D", (-48.0, 0.0, 12.0), (0.0, 64.0, 127.0)),
+    ("tension", "R", (-1.0, 0.0, 1.0), (0.0, 64.0, 127.0)),
+    ("breathiness", "B", (-1.0, 0.0, 1.0), (0.0, 0.0, 127.0)),
+    ("gender", "G", (-1.0, 0.0, 1.0), (0.0, 64.0, 127.0)),
+];
+
+fn to_ticks(blicks: i64) -> i64 {
+    (blicks as f64 / BLICKS_PER_TICK as f64).round() as i64
+}
+
+fn to_blicks(ticks: i64) -> i64 {
+    ticks * BLICKS_PER_TICK
+}
+
+/// .svpからVsqx4への変換。
+/// トラックの`mainGroup`と、参照しているグループがそれぞれ1つのパートになる。
+/// VOCALOIDでは同じトラックのパートは重なれないので、重なるパートは
+/// 新しいトラック（`名前 (2)`など）に移し、`unmapped`に記録する。
+pub(crate) fn convert_svp_to_vsqx4(s: &Svp) -> Conversion<Vsqx4> {
+    let mut unmapped = vec![];
+    let mut v = Vsqx4::default();
+
+    v.master_track.pre_measure = 4;
+
+    // 拍子（先頭の拍子はプリメジャーにも適用される）
+    let mut meters = s.time.meter.clone();
+    meters.sort_by_key(|m| m.index);
+    if !meters.is_empty() {
+        v.master_track.time_signatures = meters
+            .iter()
+            .enumerate()
+            .map(|(i, m)| vsqx4::TimeSignature {
+                position: if i == 0 {
+                    0
+                } else {
+                    m.index + v.master_track.pre_measure
+                },
+                numerator: m.numerator,
+                denominator: m.denominator,
+            })
+            .collect();
+    }
+    let offset = v.master_track.pre_measure_ticks();
+
+    // テンポ
+    let mut tempos = s.time.tempo.clone();
+    tempos.sort_by_key(|t| t.position);
+    if !tempos.is_empty() {
+        v.master_track.tempos = tempos
+            .iter()
+            .enumerate()
+            .map(|(i, t)| vsqx4::Tempo {
+                position: if i == 0 {
+                    0
+                } else {
+                    to_ticks(t.position) + offset
+                },
+                value: bpm_to_value(t.bpm),
+            })
+            .collect();
+    }
+
+    let voice = &v.voice_table.voices[0];
+    let singer = vsqx4::Singer {
+        position: 0,
+        bs: voice.bs,
+        pc: voice.pc,
+    };
+
+    for (i, track) in s.tracks.iter().enumerate() {
+        if !track.main_ref.database.name.is_empty() {
+            unmapped.push(Unmapped::track(
+                i,
+                format!("voice {:?}", track.main_ref.database.name),
+            ));
+        }
+        if track.mixer.gain_decibel != 0.0 || track.mixer.pan != 0.0 {
+            unmapped.push(Unmapped::track(i, "gain and pan"));
+        }
+
+        // トラック自身のグループと、参照しているグループ
+        let mut groups = vec![(&track.main_group, &track.main_ref)];
+        for r in &track.groups {
+            if r.is_instrumental {
+                unmapped.push(Unmapped::track(i, "instrumental group"));
+                continue;
+            }
+            match s.group(&r.group_id) {
+                Some(group) => groups.push((group, r)),
+                None => unmapped.push(Unmapped::track(
+                    i,
+                    format!("missing group {:?}", r.group_id),
+                )),
+            }
+        }
+
+        // 重ならないように振り分けたパート（先頭が元のトラック）
+        let mut lanes: Vec<Vec<vsqx4::VsPart>> = vec![vec![]];
+        let mut index = 0;
+        for (group, r) in groups {
+            if group.notes.is_empty() {
+                continue;
+            }
+
+            let mut part = convert_group(group, r, i, index, &mut unmapped);
+            part.position += offset;
+            part.singers.push(singer.clone());
+
+            let end = |p: &vsqx4::VsPart| p.position + p.play_time.unwrap_or_default() as i64;
+            let lane = match lanes.iter().position(|l| {
+                l.iter()
+                    .all(|p| end(p) <= part.position || end(&part) <= p.position)
+            }) {
+                Some(lane) => lane,
+                None => {
+                    lanes.push(vec![]);
+                    lanes.len() - 1
+                }
+            };
+            if lane > 0 {
+                unmapped.push(Unmapped::part(
+                    i,
+                    index,
+                    format!(
+                        "overlapping group moved to track {:?}",
+                        format!("{} ({})", track.name, lane + 1)
+                    ),
+                ));
+            }
+            lanes[lane].push(part);
+            index += 1;
+        }
+
+        for (k, mut parts) in lanes.into_iter().enumerate() {
+            parts.sort_by_key(|p| p.position);
+            let track_no = v.vs_track.len() as i64;
+            v.vs_track.push(vsqx4::VsTrack {
+                track_no,
+                name: if k == 0 {
+                    track.name.clone()
+                } else {
+                    format!("{} ({})", track.name, k + 1)
+                },
+                parts,
+                ..Default::default()
+            });
+            v.mixer.vs_unit.push(vsqx4::VsUnit {
+                track_no,
+                mute: track.mixer.mute as i64,
+                solo: track.mixer.solo as i64,
+                ..Default::default()
+            });
+        }
+    }
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    Conversion::new(v, unmapped)
+}
+
+/// グループをパートにする。位置はプリメジャーを含まない。
+fn convert_group(
+    group: &Group,
+    r: &GroupRef,
+    track: usize,
+    index: usize,
+    unmapped: &mut Vec<Unmapped>,
+) -> vsqx4::VsPart {
+    let mut lost = NoteLosses::default();
+
+    let mut notes: Vec<_> = group.notes.iter().collect();
+    notes.sort_by_key(|n| n.onset);
+
+    let notes: Vec<vsqx4::Note> = notes
+        .into_iter()
+        .map(|n| {
+            if n.detune != 0 {
+                lost.add("detune");
+            }
+
+            vsqx4::Note {
+                position: to_ticks(n.onset),
+                duration: to_ticks(n.duration).max(1),
+                note_num: n.pitch + r.pitch_offset,
+                lyric: n.lyrics.clone(),
+                phoneme: n.phonemes.clone(),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    let mut curves = vec![];
+    let parameters = &group.parameters;
+
+    if !parameters.pitch_delta.is_empty() {
+        let mut events: Vec<(i64, i64)> = sample(&parameters.pitch_delta)
+            .into_iter()
+            .map(|(x, cents)| (x, semitones_to_pit(cents / 100.0, PITCH_BEND_SENS)))
+            .collect();
+        events.dedup_by_key(|e| e.1);
+
+        curves.push(Curve {
+            name: "S".into(),
+            events: vec![(0, PITCH_BEND_SENS)],
+        });
+        curves.push(Curve {
+            name: "P".into(),
+            events,
+        });
+    }
+
+    for &(name, id, from, to) in PARAMETER_MAPPINGS {
+        let curve = match parameters.get(name) {
+            Some(curve) if !curve.is_empty() => curve,
+            _ => continue,
+        };
+
+        let mut events: Vec<(i64, i64)> = sample(curve)
+            .into_iter()
+            .map(|(x, y)| (x, conversion::map_range(y, from, to).round() as i64))
+            .collect();
+        events.dedup_by_key(|e| e.1);
+
+        curves.push(Curve {
+            name: id.into(),
+            events,
+        });
+    }
+
+    for (name, value) in &parameters.extra {
+        let has_points = value
+            .get("points")
+            .and_then(|p| p.as_array())
+            .map(|p| !p.is_empty())
+            .unwrap_or(false);
+        if has_points {
+            unmapped.push(Unmapped::part(
+                track,
+                index,
+                format!("parameter {:?}", name),
+            ));
+        }
+    }
+
+    unmapped.extend(lost.into_unmapped(track, index));
+
+    let play_time = notes
+        .iter()
+        .map(|n| n.position + n.duration)
+        .max()
+        .unwrap_or(0);
+    let mut part = vsqx4::VsPart {
+        position: to_ticks(r.blick_offset),
+        play_time: Some(play_time as u64),
+        name: Some(group.name.clone()),
+        notes,
+        ..Default::default()
+    };
+    part.set_curves(curves);
+
+    part
+}
+
+/// 曲線を`CURVE_STEP`ごとの`(位置（ティック）, 値)`にする。
+/// `cubic`は滑らかな補間（smoothstep）で近似する。
+fn sample(curve: &super::Curve) -> Vec<(i64, f64)> {
+    let points: Vec<(i64, f64)> = curve
+        .points()
+        .into_iter()
+        .map(|(x, y)| (to_ticks(x), y))
+        .collect();
+
+    let ease: fn(f64) -> f64 = match curve.mode.as_str() {
+        "linear" => |t| t,
+        _ => |t| t * t * (3.0 - 2.0 * t),
+    };
+
+    conversion::sample_points(&points, CURVE_STEP, ease)
+}
+
+/// Vsqx4から.svpへの変換。
+/// トラックごとにすべてのパートを`mainGroup`にまとめる。
+/// 音素はSynthesizer V側で歌詞から決め直されるので書き出さない。
+pub(crate) fn convert_vsqx4_to_svp(v: &Vsqx4) -> Conversion<Svp> {
+    let mut unmapped = vec![];
+    let master = &v.master_track;
+    let offset = master.pre_measure_ticks();
+    let mut s = Svp::default();
+
+    // 拍子
+    s.time.meter.clear();
+    for (i, ts) in master.time_signatures.iter().enumerate() {
+        let index = if i == 0 {
+            0
+        } else {
+            (ts.position - master.pre_measure).max(0)
+        };
+        if s.time.meter.last().map(|m| m.index) == Some(index) {
+            s.time.meter.pop();
+        }
+        s.time.meter.push(super::Meter {
+            index,
+            numerator: ts.numerator,
+            denominator: ts.denominator,
+        });
+    }
+
+    // テンポ（プリメジャー中のものは最後のものだけを先頭に置く）
+    s.time.tempo.clear();
+    for &(position, value) in TempoMap::from_vsqx4(v).tempos() {
+        let position = to_blicks(position.max(0));
+        if s.time.tempo.last().map(|t| t.position) == Some(position) {
+            s.time.tempo.pop();
+        }
+        s.time.tempo.push(super::Tempo {
+            position,
+            bpm: value as f64 / 100.0,
+        });
+    }
+
+    for (i, track) in v.vs_track.iter().enumerate() {
+        let unit = v
+            .mixer
+            .vs_unit
+            .iter()
+            .find(|unit| unit.track_no == track.track_no);
+        let uuid = format!("00000000-0000-0000-0000-{:012x}", i);
+
+        let mut group = Group {
+            name: "main".into(),
+            uuid: uuid.clone(),
+            ..Default::default()
+        };
+
+        let mut parts: Vec<_> = track.parts.iter().enumerate().collect();
+        parts.sort_by_key(|(_, p)| p.position);
+
+        for (j, part) in parts {
+            let start = part.position - offset;
+            let mut lost = NoteLosses::default();
+
+            for n in &part.notes {
+                group.notes.push(super::Note::new(
+                    to_blicks(start + n.position),
+                    to_blicks(n.duration),
+                    n.note_num,
+                    n.lyric.clone(),
+                ));
+
+                if n.velocity != 64 {
+                    lost.add("velocity");
+                }
+                let vibrato = n.style.styles.iter().find(|s| s.id == "vibType");
+                if matches!(vibrato, Some(s) if s.value != 0) {
+                    lost.add("vibrato");
+                }
+            }
+
+            append_curves(&mut group, part, start, i, j, &mut unmapped);
+            unmapped.extend(lost.into_unmapped(i, j));
+        }
+
+        let singer = track
+            .parts
+            .iter()
+            .flat_map(|p| p.singers.first())
+            .next()
+            .and_then(|s| v.voice_table.voices.get(s.pc as usize));
+        if let Some(voice) = singer {
+            unmapped.push(Unmapped::track(i, format!("singer {:?}", voice.name)));
+        }
+
+        s.tracks.push(super::Track {
+            name: track.name.clone(),
+            disp_order: i as i64,
+            render_enabled: false,
+            mixer: super::Mixer {
+                mute: matches!(unit, Some(unit) if unit.mute != 0),
+                solo: matches!(unit, Some(unit) if unit.solo != 0),
+                ..Default::default()
+            },
+            main_group: group,
+            main_ref: GroupRef::new(uuid),
+            groups: vec![],
+            extra: Default::default(),
+        });
+    }
+
+    Conversion::new(s, unmapped)
+}
+
+/// パートのCCをグループのパラメーターに追加する。`start`はパートの位置（ティック）。
+fn append_curves(
+    group: &mut Group,
+    part: &vsqx4::VsPart,
+    start: i64,
+    track: usize,
+    index: usize,
+    unmapped: &mut Vec<Unmapped>,
+) {
+    let curves = part.curves();
+    let to_points = |events: &[(i64, f64)]| -> Vec<(i64, f64)> {
+        conversion::hold_points(events)
+            .into_iter()
+            .map(|(x, y)| (to_blicks(start + x), y))
+            .collect()
+    };
+
+    for c in &curves {
+        let (name, events) = if c.name == "P" {
+            let pbs = curves.iter().find(|c| c.name == "S");
+            let mut positions: Vec<i64> = c
+                .events
+                .iter()
+                .chain(pbs.iter().flat_map(|s| s.events.iter()))
+                .map(|e| e.0)
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+
+            let events: Vec<(i64, f64)> = positions
+                .into_iter()
+                .map(|t| {
+                    let pit = c.value_at(t).unwrap_or(0) as f64;
+                    let pbs = pbs.and_then(|s| s.value_at(t)).unwrap_or(2) as f64;
+                    (t, (pit / 8192.0 * pbs * 100.0).round())
+                })
+                .collect();
+            ("pitchDelta", events)
+        } else if c.name == "S" {
+            // PITと一緒に変換する
+            continue;
+        } else if let Some(&(name, _, to, from)) = PARAMETER_MAPPINGS.iter().find(|m| m.1 == c.name)
+        {
+            let events = c
+                .events
+                .iter()
+                .map(|&(x, y)| {
+                    let value = conversion::map_range(y as f64, from, to);
+                    (x, (value * 1000.0).round() / 1000.0)
+                })
+                .collect();
+            (name, events)
+        } else {
+            unmapped.push(Unmapped::part(
+                track,
+                index,
+                format!("control change {:?}", c.name),
+            ));
+            continue;
+        };
+
+        if let Some(curve) = group.parameters.get_mut(name) {
+            curve.mode = "linear".into();
+            let mut points = curve.points();
+            points.extend(to_points(&events));
+            curve.set_points(points);
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// .svpをVsqx4に変換し、戻したときにノート・テンポ・パラメーターが保たれるか確認する。
+fn test_svp_vsqx4() {
+    let s = Svp::from_bytes(include_bytes!("../test/test.svp")).unwrap();
+    let conv = s.to_vsqx4();
+    let v = &conv.project;
+
+    let track = &v.vs_track[0];
+    assert_eq!(track.parts.len(), 2);
+    assert_eq!(track.parts[0].notes[1].position, 480);
+    assert_eq!(track.parts[0].notes[0].phoneme, "l a");
+    // 参照しているグループは音高がずらされる
+    assert_eq!(track.parts[1].position, 1920 + 7680);
+    assert_eq!(track.parts[1].notes[0].note_num, 66);
+    assert_eq!(v.master_track.tempos[1].position, 3840 + 7680);
+
+    let curves = track.parts[0].curves();
+    let loudness = curves.iter().find(|c| c.name == "D").unwrap();
+    // 6dBは64と127の中間
+    assert_eq!(loudness.value_at(480), Some(96));
+    assert!(curves.iter().any(|c| c.name == "P"));
+
+    let lost: Vec<_> = conv.unmapped.iter().map(|u| u.to_string()).collect();
+    assert!(lost.contains(&"track 0: voice \"Eleanor Forte\"".to_string()));
+    assert!(lost.contains(&"track 0: part 0: parameter \"voicing\"".to_string()));
+    assert!(lost.contains(&"track 0: part 0: detune of 1 note(s)".to_string()));
+
+    let back = Svp::from_vsqx4(v).project;
+    assert_eq!(back.time, s.time);
+
+    let notes: Vec<_> = back.tracks[0]
+        .main_group
+        .notes
+        .iter()
+        .map(|n| (n.onset / BLICKS_PER_QUARTER, n.pitch))
+        .collect();
+    assert_eq!(notes, vec![(0, 60), (1, 62), (4, 66)]);
+
+    let loudness = back.tracks[0].main_group.parameters.loudness.points();
+    assert!(loudness.iter().any(|&(_, y)| (y - 6.0).abs() < 0.1));
+}
+
+#[test]
+#[cfg(test)]
+/// 重なるグループが別のトラックに移され、記録されるか確認する。
+fn test_svp_overlapping_groups() {
+    let mut s = Svp::from_bytes(include_bytes!("../test/test.svp")).unwrap();
+    // 既存の参照と同じ位置にもう一度置く
+    let r = s.tracks[0].groups[0].clone();
+    s.tracks[0].groups.push(r);
+
+    let conv = s.to_vsqx4();
+    let v = &conv.project;
+    assert_eq!(v.vs_track.len(), s.tracks.len() + 1);
+    assert_eq!(v.vs_track[0].parts.len(), 2);
+    assert_eq!(v.vs_track[1].name, format!("{} (2)", s.tracks[0].name));
+    assert_eq!(v.vs_track[1].parts[0].position, 1920 + 7680);
+    assert_eq!(v.mixer.vs_unit.len(), v.vs_track.len());
+    assert!(v.validate().is_empty());
+    assert!(conv
+        .unmapped
+        .iter()
+        .any(|u| (u.track, u.part) == (Some(0), Some(2))
+            && u.description.starts_with("overlapping group")));
+}
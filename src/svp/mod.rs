@@ -0,0 +1,397 @@
+//! Synthesizer V Studioの.svp形式（JSON）
+//!
+//! 時間はすべてblick（四分音符が`BLICKS_PER_QUARTER`）で表される。
+//! トラックは自身の`mainGroup`と、`library`にあるグループへの参照（`groups`）を持つ。
+//! 知らないキーは`extra`に保持されるので、読み込んで書き出しても失われない。
+
+use crate::conversion::Conversion;
+use crate::{vpr, vsqx4, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+pub(crate) mod convert;
+
+/// 四分音符あたりのblick数
+pub const BLICKS_PER_QUARTER: i64 = 705_600_000;
+
+/// 知らないキーとその値
+pub type Extra = BTreeMap<String, Value>;
+
+/// .svpのプロジェクト
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Svp {
+    pub version: i64,
+    pub time: Time,
+    /// トラックから参照されるグループ
+    #[serde(default)]
+    pub library: Vec<Group>,
+    pub tracks: Vec<Track>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl Default for Svp {
+    fn default() -> Self {
+        Self {
+            version: 113,
+            time: Time::default(),
+            library: vec![],
+            tracks: vec![],
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// 拍子とテンポ
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Time {
+    pub meter: Vec<Meter>,
+    pub tempo: Vec<Tempo>,
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self {
+            meter: vec![Meter {
+                index: 0,
+                numerator: 4,
+                denominator: 4,
+            }],
+            tempo: vec![Tempo {
+                position: 0,
+                bpm: 120.0,
+            }],
+        }
+    }
+}
+
+/// 拍子
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Meter {
+    /// 小節（0始まり）
+    pub index: i64,
+    pub numerator: i64,
+    pub denominator: i64,
+}
+
+/// テンポ
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Tempo {
+    /// 位置（blick）
+    pub position: i64,
+    pub bpm: f64,
+}
+
+/// トラック
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub name: String,
+    #[serde(default)]
+    pub disp_order: i64,
+    #[serde(default)]
+    pub render_enabled: bool,
+    #[serde(default)]
+    pub mixer: Mixer,
+    /// トラック自身のグループ
+    pub main_group: Group,
+    /// `main_group`の設定（歌声など）
+    pub main_ref: GroupRef,
+    /// `Svp::library`のグループへの参照
+    #[serde(default)]
+    pub groups: Vec<GroupRef>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// ミキサー
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Mixer {
+    #[serde(default)]
+    pub gain_decibel: f64,
+    #[serde(default)]
+    pub pan: f64,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub solo: bool,
+    #[serde(default = "default_true")]
+    pub display: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self {
+            gain_decibel: 0.0,
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            display: true,
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// ノートとパラメーターのまとまり
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    pub name: String,
+    pub uuid: String,
+    #[serde(default)]
+    pub parameters: Parameters,
+    #[serde(default)]
+    pub notes: Vec<Note>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// グループへの参照
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupRef {
+    #[serde(rename = "groupID")]
+    pub group_id: String,
+    /// グループの位置（blick）
+    #[serde(default)]
+    pub blick_offset: i64,
+    /// グループ内のノートに加える音高（半音）
+    #[serde(default)]
+    pub pitch_offset: i64,
+    /// 音声ファイルのグループ
+    #[serde(default)]
+    pub is_instrumental: bool,
+    #[serde(default)]
+    pub database: Database,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl GroupRef {
+    pub fn new<S: Into<String>>(group_id: S) -> Self {
+        Self {
+            group_id: group_id.into(),
+            blick_offset: 0,
+            pitch_offset: 0,
+            is_instrumental: false,
+            database: Database::default(),
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// 歌声データベース
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
+pub struct Database {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub phoneset: String,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// ノート
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Note {
+    /// 開始位置（blick）
+    pub onset: i64,
+    /// 長さ（blick）
+    pub duration: i64,
+    pub lyrics: String,
+    /// 音素（空白区切り）。空なら歌詞から自動で決まる。
+    #[serde(default)]
+    pub phonemes: String,
+    /// 音高（MIDIノート番号）
+    pub pitch: i64,
+    /// 音高のずれ（セント）
+    #[serde(default)]
+    pub detune: i64,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl Note {
+    pub fn new<S: Into<String>>(onset: i64, duration: i64, pitch: i64, lyrics: S) -> Self {
+        Self {
+            onset,
+            duration,
+            lyrics: lyrics.into(),
+            phonemes: String::new(),
+            pitch,
+            detune: 0,
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// パラメーターの曲線
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Parameters {
+    /// ピッチのずれ（セント）
+    #[serde(default)]
+    pub pitch_delta: Curve,
+    /// 音量（dB、-48〜12）
+    #[serde(default)]
+    pub loudness: Curve,
+    /// テンション（-1〜1）
+    #[serde(default)]
+    pub tension: Curve,
+    /// ブレシネス（-1〜1）
+    #[serde(default)]
+    pub breathiness: Curve,
+    /// ジェンダー（-1〜1）
+    #[serde(default)]
+    pub gender: Curve,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl Parameters {
+    /// 名前（`pitchDelta`など）でパラメーターを取り出す。
+    pub fn get(&self, name: &str) -> Option<&Curve> {
+        match name {
+            "pitchDelta" => Some(&self.pitch_delta),
+            "loudness" => Some(&self.loudness),
+            "tension" => Some(&self.tension),
+            "breathiness" => Some(&self.breathiness),
+            "gender" => Some(&self.gender),
+            _ => None,
+        }
+    }
+
+    /// 名前（`pitchDelta`など）でパラメーターを取り出す。
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Curve> {
+        match name {
+            "pitchDelta" => Some(&mut self.pitch_delta),
+            "loudness" => Some(&mut self.loudness),
+            "tension" => Some(&mut self.tension),
+            "breathiness" => Some(&mut self.breathiness),
+            "gender" => Some(&mut self.gender),
+            _ => None,
+        }
+    }
+}
+
+/// パラメーターの曲線。点の位置はグループの先頭からのblick。
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Curve {
+    /// 点の間の補間（`linear`、`cubic`など）
+    pub mode: String,
+    /// `[位置, 値, 位置, 値, ...]`
+    pub points: Vec<f64>,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self {
+            mode: "cubic".into(),
+            points: vec![],
+        }
+    }
+}
+
+impl Curve {
+    /// `(位置, 値)`の点の列
+    pub fn points(&self) -> Vec<(i64, f64)> {
+        self.points
+            .chunks_exact(2)
+            .map(|p| (p[0] as i64, p[1]))
+            .collect()
+    }
+
+    /// 点の列を設定する。
+    pub fn set_points<I: IntoIterator<Item = (i64, f64)>>(&mut self, points: I) {
+        self.points = points
+            .into_iter()
+            .flat_map(|(x, y)| [x as f64, y])
+            .collect();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+impl Svp {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// バイト列から読み込む。Synthesizer V Studioが末尾に付けるNUL文字は無視する。
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let end = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+
+        Ok(serde_json::from_slice(&bytes[..end])?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn write<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+
+    /// `library`から`uuid`のグループを探す。
+    pub fn group(&self, uuid: &str) -> Option<&Group> {
+        self.library.iter().find(|g| g.uuid == uuid)
+    }
+
+    /// `Vsqx4`に変換する。
+    pub fn to_vsqx4(&self) -> Conversion<vsqx4::Vsqx4> {
+        convert::convert_svp_to_vsqx4(self)
+    }
+
+    /// `Vpr`に変換する（`Vsqx4`を経由する）。
+    pub fn to_vpr(&self) -> Conversion<vpr::Vpr> {
+        self.to_vsqx4().map(vpr::Vpr::from)
+    }
+
+    /// `Vsqx4`から変換する。
+    pub fn from_vsqx4(v: &vsqx4::Vsqx4) -> Conversion<Self> {
+        convert::convert_vsqx4_to_svp(v)
+    }
+
+    /// `Vpr`から変換する（`Vsqx4`を経由する）。
+    pub fn from_vpr(v: &vpr::Vpr) -> Conversion<Self> {
+        convert::convert_vsqx4_to_svp(&v.clone().into())
+    }
+}
+
+impl std::str::FromStr for Svp {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_bytes(s.as_bytes())
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// .svpを読み込み、書き出して読み直しても同じになるか確認する。
+fn test_svp_roundtrip() {
+    let s = Svp::from_bytes(include_bytes!("../test/test.svp")).unwrap();
+
+    assert_eq!(s.tracks[0].main_group.notes.len(), 2);
+    assert_eq!(s.tracks[0].groups[0].blick_offset, 4 * BLICKS_PER_QUARTER);
+    assert!(s.group(&s.tracks[0].groups[0].group_id).is_some());
+    assert_eq!(s.tracks[0].main_group.parameters.loudness.points().len(), 2);
+    // 知らないキーも保持される
+    assert!(s.extra.contains_key("renderConfig"));
+
+    let s2: Svp = s.to_json().unwrap().parse().unwrap();
+    assert_eq!(s, s2);
+}
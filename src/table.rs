@@ -0,0 +1,760 @@
+//! ノートの表（CSV・TSV）の書き出しと読み込み
+//!
+//! 1行が1ノートで、1行目は列名。表計算ソフトで歌詞や音高を編集し、パートに書き戻すために使う。
+//!
+//! | 列名 | 内容 |
+//! |---|---|
+//! | `tick` | 曲の先頭（プリメジャーの直後）からのティック |
+//! | `position` | `小節:拍:ティック`（小節と拍は1から） |
+//! | `seconds` | 曲の先頭からの秒 |
+//! | `duration` | 長さ（ティック） |
+//! | `note` | ノート番号 |
+//! | `name` | 音名（`C4`など） |
+//! | `velocity` | ベロシティ |
+//! | `lyric` | 歌詞 |
+//! | `phoneme` | 発音記号 |
+//!
+//! 残りの列は表情パラメーターで、VOCALOID4ではノートスタイル（`accent`など）、
+//! VOCALOID5では`exp`のキーを列名とする。
+//!
+//! 読み込むときは列名で列を探すので、列の並べ替えや削除をしてもよい。
+//! `tick`・`duration`と、`note`か`name`のどちらかが必要で、`position`・`seconds`は無視する。
+//! 空の欄や削除した列の値は、既定値（またはもとのノートの値）のままにする。
+//!
+//! ファイルの文字コードはBOMとバイト列から判定する（UTF-8として読めなければShift_JIS）。
+
+use crate::edit::{EditNote, EditPart};
+use crate::hts::pitch_name;
+use crate::timeline::TempoMap;
+use crate::{vpr, vsqx4, Error, Result};
+
+/// VOCALOID4のノートスタイルのうち、表に出力するもの
+const STYLE_COLUMNS: [&str; 9] = [
+    "accent", "bendDep", "bendLen", "decay", "fallPort", "opening", "risePort", "vibLen", "vibType",
+];
+
+/// 区切り文字
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFormat {
+    /// カンマ区切り（RFC 4180）
+    Csv,
+    /// タブ区切り
+    Tsv,
+}
+
+impl TableFormat {
+    fn delimiter(self) -> char {
+        match self {
+            TableFormat::Csv => ',',
+            TableFormat::Tsv => '\t',
+        }
+    }
+}
+
+/// ノートの表
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct NoteTable {
+    /// 表情パラメーターの列名
+    pub expressions: Vec<String>,
+    pub rows: Vec<NoteRow>,
+}
+
+/// 1ノート分の行
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct NoteRow {
+    pub tick: i64,
+    /// `小節:拍:ティック`。書き出すときのみ使う。
+    pub position: String,
+    /// 書き出すときのみ使う。
+    pub seconds: f64,
+    pub duration: i64,
+    pub note_num: i64,
+    pub velocity: Option<i64>,
+    /// 空の欄や列がなければ`None`
+    pub lyric: Option<String>,
+    /// 空の欄や列がなければ`None`
+    pub phoneme: Option<String>,
+    /// `NoteTable::expressions`の順の値
+    pub expressions: Vec<Option<i64>>,
+}
+
+/// 表で読み書きするノート
+trait TableNote: EditNote + Sized {
+    fn new_note() -> Self;
+    fn lyric(&self) -> &str;
+    fn phoneme(&self) -> &str;
+    fn set_lyric(&mut self, lyric: String);
+    fn set_phoneme(&mut self, phoneme: String);
+    /// 表情パラメーターの列名
+    fn expression_names(notes: &[&Self]) -> Vec<String>;
+    fn expression(&self, name: &str) -> Option<i64>;
+    fn set_expression(&mut self, name: &str, value: i64);
+}
+
+impl TableNote for vsqx4::Note {
+    fn new_note() -> Self {
+        vsqx4::Note {
+            phoneme: String::new(),
+            ..Default::default()
+        }
+    }
+
+    fn lyric(&self) -> &str {
+        &self.lyric
+    }
+
+    fn phoneme(&self) -> &str {
+        &self.phoneme
+    }
+
+    fn set_lyric(&mut self, lyric: String) {
+        self.lyric = lyric;
+    }
+
+    fn set_phoneme(&mut self, phoneme: String) {
+        self.phoneme = phoneme;
+    }
+
+    fn expression_names(_notes: &[&Self]) -> Vec<String> {
+        STYLE_COLUMNS.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn expression(&self, name: &str) -> Option<i64> {
+        self.style
+            .styles
+            .iter()
+            .find(|s| s.id == name)
+            .map(|s| s.value)
+    }
+
+    fn set_expression(&mut self, name: &str, value: i64) {
+        crate::ust::convert::set_style(&mut self.style, name, value);
+    }
+}
+
+impl TableNote for vpr::Note {
+    fn new_note() -> Self {
+        vpr::Note {
+            lyric: String::new(),
+            phoneme: String::new(),
+            is_protected: false,
+            pos: 0,
+            duration: 0,
+            number: 60,
+            velocity: 64,
+            exp: Default::default(),
+            singing_skill: Some(vpr::SingingSkill {
+                duration: 0,
+                weight: vpr::SkillWeight { pre: 64, post: 64 },
+            }),
+            vibrato: vpr::Vibrato {
+                vibrato_type: 0,
+                duration: 0,
+            },
+        }
+    }
+
+    fn lyric(&self) -> &str {
+        &self.lyric
+    }
+
+    fn phoneme(&self) -> &str {
+        &self.phoneme
+    }
+
+    fn set_lyric(&mut self, lyric: String) {
+        self.lyric = lyric;
+    }
+
+    fn set_phoneme(&mut self, phoneme: String) {
+        self.phoneme = phoneme;
+    }
+
+    fn expression_names(notes: &[&Self]) -> Vec<String> {
+        let mut names: Vec<String> = notes.iter().flat_map(|n| n.exp.keys().cloned()).collect();
+        names.sort();
+        names.dedup();
+
+        names
+    }
+
+    fn expression(&self, name: &str) -> Option<i64> {
+        self.exp.get(name).copied()
+    }
+
+    fn set_expression(&mut self, name: &str, value: i64) {
+        self.exp.insert(name.to_string(), value);
+    }
+}
+
+fn table_error<S: Into<String>>(message: S) -> Error {
+    Error::TableError(message.into())
+}
+
+/// 音名（`C4`、`C#4`、`Db4`など）をノート番号にする。
+fn parse_pitch_name(name: &str) -> Option<i64> {
+    let mut chars = name.trim().chars().peekable();
+    let step = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut alter = 0;
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' | '♯' => alter += 1,
+            'b' | '♭' => alter -= 1,
+            _ => break,
+        }
+        chars.next();
+    }
+    let octave: i64 = chars.collect::<String>().parse().ok()?;
+
+    Some((octave + 1) * 12 + step + alter)
+}
+
+/// `小節:拍:ティック`。`bars`は`(小節, 分子, 分母)`で、小節は曲の先頭から数える。
+fn musical_position(tick: i64, bars: &[(i64, i64, i64)], resolution: i64) -> String {
+    let mut bar = 0;
+    let mut bar_tick = 0;
+    let mut time = (4, 4);
+
+    for &(b, numerator, denominator) in bars {
+        let length = resolution * 4 * time.0 / time.1;
+        let next = bar_tick + (b - bar) * length;
+        if next > tick {
+            break;
+        }
+        bar_tick = next;
+        bar = b;
+        time = (numerator.max(1), denominator.max(1));
+    }
+
+    let length = resolution * 4 * time.0 / time.1;
+    let beat_length = resolution * 4 / time.1;
+    let offset = tick - bar_tick;
+    let bar = bar + offset.div_euclid(length);
+    let offset = offset.rem_euclid(length);
+
+    format!(
+        "{}:{}:{}",
+        bar + 1,
+        offset / beat_length + 1,
+        offset % beat_length
+    )
+}
+
+/// `(パートの位置, ノート)`から表を作る。
+fn build_table<N: TableNote>(
+    parts: &[(i64, &[N])],
+    tempo_map: &TempoMap,
+    bars: &[(i64, i64, i64)],
+) -> NoteTable {
+    let notes: Vec<(i64, &N)> = parts
+        .iter()
+        .flat_map(|&(position, notes)| notes.iter().map(move |n| (position + n.position(), n)))
+        .collect();
+    let expressions = N::expression_names(&notes.iter().map(|n| n.1).collect::<Vec<_>>());
+
+    let mut rows: Vec<NoteRow> = notes
+        .into_iter()
+        .map(|(tick, n)| NoteRow {
+            tick,
+            position: musical_position(tick, bars, tempo_map.resolution()),
+            seconds: tempo_map.seconds(tick),
+            duration: n.duration(),
+            note_num: n.number(),
+            velocity: Some(n.velocity()),
+            lyric: Some(n.lyric().to_string()),
+            phoneme: Some(n.phoneme().to_string()),
+            expressions: expressions.iter().map(|e| n.expression(e)).collect(),
+        })
+        .collect();
+    rows.sort_by_key(|r| r.tick);
+
+    NoteTable { expressions, rows }
+}
+
+/// 表をパートに書き戻す。`start`はパートの位置（曲の先頭から）。
+///
+/// 位置が同じノートがもとのパートにあれば、表にない値（ビブラートなど）はそのノートから引き継ぐ。
+fn apply_table<P: EditPart>(table: &NoteTable, part: &mut P, start: i64) -> Result<()>
+where
+    P::Note: TableNote + Clone,
+{
+    let mut old: Vec<Option<P::Note>> = part.notes().iter().cloned().map(Some).collect();
+    let mut notes = vec![];
+
+    for (i, row) in table.rows.iter().enumerate() {
+        let position = row.tick - start;
+        if position < 0 {
+            return Err(table_error(format!(
+                "row {}: tick {} is before the part",
+                i + 2,
+                row.tick
+            )));
+        }
+
+        let mut note = old
+            .iter_mut()
+            .find(|n| matches!(n, Some(n) if n.position() == position))
+            .and_then(Option::take)
+            .unwrap_or_else(P::Note::new_note);
+        note.set_position(position);
+        note.set_duration(row.duration.max(1));
+        note.set_number(row.note_num.clamp(0, 127));
+        if let Some(velocity) = row.velocity {
+            note.set_velocity(velocity.clamp(0, 127));
+        }
+        if let Some(lyric) = &row.lyric {
+            note.set_lyric(lyric.clone());
+        }
+        if let Some(phoneme) = &row.phoneme {
+            note.set_phoneme(phoneme.clone());
+        }
+        for (name, value) in table.expressions.iter().zip(&row.expressions) {
+            if let Some(value) = value {
+                note.set_expression(name, *value);
+            }
+        }
+
+        notes.push(note);
+    }
+
+    notes.sort_by_key(|n| n.position());
+    let end = notes.iter().map(EditNote::end).max().unwrap_or(0);
+    *part.notes_mut() = notes;
+    if part.length() < end {
+        part.set_length(end);
+    }
+
+    Ok(())
+}
+
+/// Vsqx4の`(小節, 分子, 分母)`。小節は曲の先頭（プリメジャーの直後）から数える。
+fn vsqx4_bars(v: &vsqx4::Vsqx4) -> Vec<(i64, i64, i64)> {
+    let pre_measure = v.master_track.pre_measure;
+    let mut bars: Vec<_> = v
+        .master_track
+        .time_signatures
+        .iter()
+        .map(|ts| {
+            (
+                (ts.position - pre_measure).max(0),
+                ts.numerator,
+                ts.denominator,
+            )
+        })
+        .collect();
+    bars.sort_by_key(|b| b.0);
+
+    bars
+}
+
+fn vpr_bars(v: &vpr::Vpr) -> Vec<(i64, i64, i64)> {
+    let mut bars: Vec<_> = v
+        .master_track
+        .time_sig
+        .events
+        .iter()
+        .map(|ts| (ts.bar, ts.numerator, ts.denominator))
+        .collect();
+    bars.sort_by_key(|b| b.0);
+
+    bars
+}
+
+/// CSVの1欄を引用符で囲む（必要な場合のみ）。
+fn quote(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// CSV・TSVを欄の配列の配列にする。引用符で囲まれた欄では区切り文字・改行・`""`を扱う。
+fn split_records(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records.retain(|r| r.iter().any(|f| !f.trim().is_empty()));
+    records
+}
+
+impl NoteTable {
+    /// `Vsqx4`の`index`番目のトラックの表。プリメジャー中のノートも（負の`tick`で）含む。
+    pub fn from_vsqx4_track(v: &vsqx4::Vsqx4, index: usize) -> Self {
+        let offset = v.master_track.pre_measure_ticks();
+        let parts: Vec<(i64, &[vsqx4::Note])> = v
+            .vs_track
+            .get(index)
+            .map(|t| {
+                t.parts
+                    .iter()
+                    .map(|p| (p.position - offset, p.notes.as_slice()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        build_table(&parts, &TempoMap::from_vsqx4(v), &vsqx4_bars(v))
+    }
+
+    /// `Vsqx4`の`track`番目のトラックの`part`番目のパートの表。
+    pub fn from_vsqx4_part(v: &vsqx4::Vsqx4, track: usize, part: usize) -> Self {
+        let offset = v.master_track.pre_measure_ticks();
+        let parts: Vec<(i64, &[vsqx4::Note])> = v
+            .vs_track
+            .get(track)
+            .and_then(|t| t.parts.get(part))
+            .map(|p| vec![(p.position - offset, p.notes.as_slice())])
+            .unwrap_or_default();
+
+        build_table(&parts, &TempoMap::from_vsqx4(v), &vsqx4_bars(v))
+    }
+
+    /// `Vpr`の`index`番目のトラックの表。
+    pub fn from_vpr_track(v: &vpr::Vpr, index: usize) -> Self {
+        let parts: Vec<(i64, &[vpr::Note])> = v
+            .tracks
+            .get(index)
+            .map(|t| {
+                t.parts
+                    .iter()
+                    .map(|p| (p.pos as i64, p.notes.as_slice()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        build_table(&parts, &TempoMap::from_vpr(v), &vpr_bars(v))
+    }
+
+    /// `Vpr`の`track`番目のトラックの`part`番目のパートの表。
+    pub fn from_vpr_part(v: &vpr::Vpr, track: usize, part: usize) -> Self {
+        let parts: Vec<(i64, &[vpr::Note])> = v
+            .tracks
+            .get(track)
+            .and_then(|t| t.parts.get(part))
+            .map(|p| vec![(p.pos as i64, p.notes.as_slice())])
+            .unwrap_or_default();
+
+        build_table(&parts, &TempoMap::from_vpr(v), &vpr_bars(v))
+    }
+
+    /// `Vsqx4`の`track`番目のトラックの`part`番目のパートのノートを、この表のノートで置き換える。
+    pub fn apply_to_vsqx4(&self, v: &mut vsqx4::Vsqx4, track: usize, part: usize) -> Result<()> {
+        let offset = v.master_track.pre_measure_ticks();
+        let p = v
+            .vs_track
+            .get_mut(track)
+            .and_then(|t| t.parts.get_mut(part))
+            .ok_or_else(|| table_error(format!("no part {} in track {}", part, track)))?;
+        let start = p.position - offset;
+
+        apply_table(self, p, start)
+    }
+
+    /// `Vpr`の`track`番目のトラックの`part`番目のパートのノートを、この表のノートで置き換える。
+    pub fn apply_to_vpr(&self, v: &mut vpr::Vpr, track: usize, part: usize) -> Result<()> {
+        let p = v
+            .tracks
+            .get_mut(track)
+            .and_then(|t| t.parts.get_mut(part))
+            .ok_or_else(|| table_error(format!("no part {} in track {}", part, track)))?;
+        let start = p.pos as i64;
+
+        apply_table(self, p, start)
+    }
+
+    /// 表を読み込む。先頭のBOMは無視する。
+    pub fn parse(text: &str, format: TableFormat) -> Result<Self> {
+        let text = text.trim_start_matches('\u{feff}');
+        let mut records = split_records(text, format.delimiter()).into_iter();
+        let header: Vec<String> = match records.next() {
+            Some(header) => header.into_iter().map(|h| h.trim().to_string()).collect(),
+            None => return Ok(Self::default()),
+        };
+        let column = |name: &str| header.iter().position(|h| h == name);
+
+        let tick = column("tick").ok_or_else(|| table_error("no \"tick\" column"))?;
+        let duration = column("duration").ok_or_else(|| table_error("no \"duration\" column"))?;
+        let note = column("note");
+        let name = column("name");
+        if note.is_none() && name.is_none() {
+            return Err(table_error("no \"note\" or \"name\" column"));
+        }
+        let velocity = column("velocity");
+        let lyric = column("lyric");
+        let phoneme = column("phoneme");
+
+        const KNOWN: [&str; 9] = [
+            "tick", "position", "seconds", "duration", "note", "name", "velocity", "lyric",
+            "phoneme",
+        ];
+        let expressions: Vec<(usize, String)> = header
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| !h.is_empty() && !KNOWN.contains(&h.as_str()))
+            .map(|(i, h)| (i, h.clone()))
+            .collect();
+
+        let mut table = Self {
+            expressions: expressions.iter().map(|e| e.1.clone()).collect(),
+            rows: vec![],
+        };
+        for (i, record) in records.enumerate() {
+            let line = i + 2;
+            let cell = |index: Option<usize>| {
+                index
+                    .and_then(|i| record.get(i))
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+            };
+            let number = |index: Option<usize>, what: &str| -> Result<Option<i64>> {
+                cell(index)
+                    .map(|s| {
+                        s.parse::<f64>().map(|v| v.round() as i64).map_err(|_| {
+                            table_error(format!("row {}: invalid {} {:?}", line, what, s))
+                        })
+                    })
+                    .transpose()
+            };
+
+            let note_num = match number(note, "note")? {
+                Some(n) => n,
+                None => {
+                    let s =
+                        cell(name).ok_or_else(|| table_error(format!("row {}: no note", line)))?;
+                    parse_pitch_name(s)
+                        .ok_or_else(|| table_error(format!("row {}: invalid name {:?}", line, s)))?
+                }
+            };
+
+            table.rows.push(NoteRow {
+                tick: number(Some(tick), "tick")?
+                    .ok_or_else(|| table_error(format!("row {}: no tick", line)))?,
+                duration: number(Some(duration), "duration")?
+                    .ok_or_else(|| table_error(format!("row {}: no duration", line)))?,
+                note_num,
+                velocity: number(velocity, "velocity")?,
+                lyric: cell(lyric).map(str::to_string),
+                phoneme: cell(phoneme).map(str::to_string),
+                expressions: expressions
+                    .iter()
+                    .map(|(i, name)| number(Some(*i), name))
+                    .collect::<Result<_>>()?,
+                ..Default::default()
+            });
+        }
+
+        Ok(table)
+    }
+
+    /// ファイルから読み込む。文字コードは`encoding::XmlEncoding::detect`で判定する。
+    pub fn open<P: AsRef<std::path::Path>>(path: P, format: TableFormat) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+
+        Self::parse(&crate::encoding::decode_xml(&bytes), format)
+    }
+
+    pub fn to_string(&self, format: TableFormat) -> String {
+        let delimiter = format.delimiter();
+        let join = |fields: Vec<String>| {
+            fields
+                .iter()
+                .map(|f| quote(f, delimiter))
+                .collect::<Vec<_>>()
+                .join(&delimiter.to_string())
+                + "\n"
+        };
+
+        let mut header: Vec<String> = [
+            "tick", "position", "seconds", "duration", "note", "name", "velocity", "lyric",
+            "phoneme",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        header.extend(self.expressions.iter().cloned());
+        let mut s = join(header);
+
+        let optional = |v: Option<i64>| v.map(|v| v.to_string()).unwrap_or_default();
+        for row in &self.rows {
+            let mut fields = vec![
+                row.tick.to_string(),
+                row.position.clone(),
+                format!("{:.3}", row.seconds),
+                row.duration.to_string(),
+                row.note_num.to_string(),
+                pitch_name(row.note_num),
+                optional(row.velocity),
+                row.lyric.clone().unwrap_or_default(),
+                row.phoneme.clone().unwrap_or_default(),
+            ];
+            fields.extend(row.expressions.iter().map(|&v| optional(v)));
+            s += &join(fields);
+        }
+
+        s
+    }
+
+    pub fn write<W: std::io::Write>(&self, mut writer: W, format: TableFormat) -> Result<()> {
+        writer.write_all(self.to_string(format).as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        format: TableFormat,
+    ) -> Result<()> {
+        self.write(std::fs::File::create(path)?, format)
+    }
+}
+
+impl NoteRow {
+    /// 列名`name`の表情パラメーターの値
+    pub fn expression(&self, table: &NoteTable, name: &str) -> Option<i64> {
+        let i = table.expressions.iter().position(|e| e == name)?;
+
+        self.expressions.get(i).copied().flatten()
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// Vsqx4のトラックを表に書き出し、読み直して編集したものをパートに書き戻す。
+fn test_note_table_roundtrip() {
+    let mut v: vsqx4::Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    let table = NoteTable::from_vsqx4_track(&v, 0);
+
+    let csv = table.to_string(TableFormat::Csv);
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "tick,position,seconds,duration,note,name,velocity,lyric,phoneme,\
+         accent,bendDep,bendLen,decay,fallPort,opening,risePort,vibLen,vibType"
+    );
+    // BPM 80なので2拍目は0.75秒
+    assert!(lines
+        .nth(1)
+        .unwrap()
+        .starts_with("480,1:2:0,0.750,480,55,G3,64,save,s eI v,"));
+
+    let mut table = NoteTable::parse(&csv, TableFormat::Csv).unwrap();
+    assert_eq!(table.rows.len(), v.vs_track[0].parts[0].notes.len());
+    table.rows[1].lyric = Some("a, \"b\"".into());
+    table.rows[1].note_num = 60;
+    let accent = table
+        .expressions
+        .iter()
+        .position(|e| e == "accent")
+        .unwrap();
+    table.rows[1].expressions[accent] = Some(80);
+
+    let tsv = table.to_string(TableFormat::Tsv);
+    let table = NoteTable::parse(&tsv, TableFormat::Tsv).unwrap();
+    table.apply_to_vsqx4(&mut v, 0, 0).unwrap();
+
+    let note = &v.vs_track[0].parts[0].notes[1];
+    assert_eq!(note.lyric, "a, \"b\"");
+    assert_eq!(note.note_num, 60);
+    assert_eq!(note.expression("accent"), Some(80));
+}
+
+#[test]
+#[cfg(test)]
+/// 音名だけの表から新しいノートを作れるか確認する。
+fn test_note_table_names() {
+    let csv = "name,tick,duration,lyric\nC#4,0,480,\"ら\"\nBb3,480,240,\n";
+    let table = NoteTable::parse(csv, TableFormat::Csv).unwrap();
+    assert_eq!(table.rows[0].note_num, 61);
+    assert_eq!(table.rows[1].note_num, 58);
+
+    let mut v: vpr::Vpr = serde_json::from_str(include_str!("test/vpr.json")).unwrap();
+    table.apply_to_vpr(&mut v, 1, 0).unwrap();
+    let notes = &v.tracks[1].parts[0].notes;
+    assert_eq!(notes.len(), 2);
+    assert_eq!(notes[0].lyric, "ら");
+    assert_eq!(notes[1].duration, 240);
+
+    assert!(NoteTable::parse("tick,duration\n0,480\n", TableFormat::Csv).is_err());
+}
+
+#[test]
+#[cfg(test)]
+/// 列を削除した表や空の欄では、もとのノートの歌詞と発音記号が残るか確認する。
+fn test_note_table_deleted_columns() {
+    let mut v: vsqx4::Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    let notes = v.vs_track[0].parts[0].notes.clone();
+
+    let csv = NoteTable::from_vsqx4_track(&v, 0).to_string(TableFormat::Csv);
+    let header: Vec<&str> = csv.lines().next().unwrap().split(',').collect();
+    let phoneme = header.iter().position(|&h| h == "phoneme").unwrap();
+    let csv: String = csv
+        .lines()
+        .map(|line| {
+            let mut fields: Vec<&str> = line.split(',').collect();
+            fields.remove(phoneme);
+            fields.join(",") + "\n"
+        })
+        .collect();
+    let mut table = NoteTable::parse(&csv, TableFormat::Csv).unwrap();
+    assert!(table.rows.iter().all(|r| r.phoneme.is_none()));
+    table.rows[0].lyric = None;
+    table.rows[1].lyric = Some("ら".into());
+    table.apply_to_vsqx4(&mut v, 0, 0).unwrap();
+
+    let applied = &v.vs_track[0].parts[0].notes;
+    assert_eq!(applied[0].lyric, notes[0].lyric);
+    assert_eq!(applied[1].lyric, "ら");
+    for (a, b) in applied.iter().zip(&notes) {
+        assert_eq!(a.phoneme, b.phoneme);
+    }
+
+    // Shift_JISのファイル
+    let path = std::env::temp_dir().join("test_note_table_sjis.csv");
+    let (sjis, _, _) = encoding_rs::SHIFT_JIS.encode("tick,duration,note,lyric\n0,480,60,ら\n");
+    std::fs::write(&path, &sjis).unwrap();
+    let table = NoteTable::open(&path, TableFormat::Csv).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(table.rows[0].lyric.as_deref(), Some("ら"));
+}
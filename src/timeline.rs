@@ -0,0 +1,350 @@
+//! テンポマップと、プロジェクト全体のノートを絶対時間で走査するイテレータ
+//!
+//! ノートの位置はパート基準、パートの位置はマスタートラック基準（VOCALOID4ではプリメジャーを含む）で
+//! 保存されている。ここではこれらを曲の先頭（プリメジャーの直後）からの絶対ティックと秒に直す。
+//!
+//! ```
+//! use vsqx::vsqx4::Vsqx4;
+//!
+//! let v: Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+//! let first = v.absolute_notes().next().unwrap();
+//!
+//! assert_eq!(first.tick, 0);
+//! assert_eq!(first.singer.unwrap().name, Some("KAITO_V3_English"));
+//! ```
+
+use crate::{vpr, vsqx4};
+
+/// テンポマップ。ティックと秒を相互に変換する。
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempoMap {
+    resolution: i64,
+    /// `(位置, BPM * 100)`
+    tempos: Vec<(i64, i64)>,
+    /// 各テンポイベントの位置での秒
+    seconds: Vec<f64>,
+}
+
+impl TempoMap {
+    /// 四分音符あたり`resolution`ティック、`(位置, BPM * 100)`のテンポイベントからテンポマップを作る。
+    /// テンポイベントがなければBPM 120とみなす。
+    pub fn new<I: IntoIterator<Item = (i64, i64)>>(resolution: i64, tempos: I) -> Self {
+        let mut tempos: Vec<(i64, i64)> = tempos.into_iter().filter(|t| t.1 > 0).collect();
+        tempos.sort_by_key(|t| t.0);
+        if tempos.is_empty() {
+            tempos.push((0, 12000));
+        }
+
+        let mut seconds = vec![0.0; tempos.len()];
+        for i in 1..tempos.len() {
+            let (pos, value) = tempos[i - 1];
+            seconds[i] = seconds[i - 1] + ticks_to_seconds(tempos[i].0 - pos, value, resolution);
+        }
+
+        let mut map = Self {
+            resolution,
+            tempos,
+            seconds,
+        };
+
+        // 0ティックが0秒になるようにそろえる
+        let origin = map.seconds(0);
+        for s in &mut map.seconds {
+            *s -= origin;
+        }
+
+        map
+    }
+
+    /// `Vsqx4`のテンポマップ。位置はプリメジャーを含まない。
+    pub fn from_vsqx4(v: &vsqx4::Vsqx4) -> Self {
+        let offset = v.master_track.pre_measure_ticks();
+
+        Self::new(
+            v.master_track.resolution,
+            v.master_track
+                .tempos
+                .iter()
+                .map(|t| (t.position - offset, t.value)),
+        )
+    }
+
+    /// `Vpr`のテンポマップ。
+    pub fn from_vpr(v: &vpr::Vpr) -> Self {
+        Self::new(
+            480,
+            v.master_track.tempo.events.iter().map(|t| (t.pos, t.value)),
+        )
+    }
+
+    /// 四分音符あたりのティック数
+    pub fn resolution(&self) -> i64 {
+        self.resolution
+    }
+
+    /// `(位置, BPM * 100)`のテンポイベント
+    pub fn tempos(&self) -> &[(i64, i64)] {
+        &self.tempos
+    }
+
+    /// 位置`tick`でのテンポ（BPM）
+    pub fn bpm_at(&self, tick: i64) -> f64 {
+        self.tempos[self.index_at(tick)].1 as f64 / 100.0
+    }
+
+    /// ティックを秒に変換する。
+    pub fn seconds(&self, tick: i64) -> f64 {
+        let i = self.index_at(tick);
+        let (pos, value) = self.tempos[i];
+        self.seconds[i] + ticks_to_seconds(tick - pos, value, self.resolution)
+    }
+
+    /// 秒をティックに変換する。
+    pub fn ticks(&self, seconds: f64) -> f64 {
+        let i = self
+            .seconds
+            .iter()
+            .rposition(|&s| s <= seconds)
+            .unwrap_or(0);
+        let (pos, value) = self.tempos[i];
+
+        pos as f64
+            + (seconds - self.seconds[i]) * value as f64 / 100.0 / 60.0 * self.resolution as f64
+    }
+
+    fn index_at(&self, tick: i64) -> usize {
+        self.tempos.iter().rposition(|t| t.0 <= tick).unwrap_or(0)
+    }
+}
+
+fn ticks_to_seconds(ticks: i64, value: i64, resolution: i64) -> f64 {
+    ticks as f64 * 60.0 * 100.0 / (value as f64 * resolution as f64)
+}
+
+/// 歌声
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SingerRef<'a> {
+    /// ボイスバンクのID
+    pub comp_id: &'a str,
+    /// 歌声の名前
+    pub name: Option<&'a str>,
+    /// 言語（`bs`、`langID`）
+    pub language: Option<i64>,
+}
+
+/// 絶対時間の付いたノート
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AbsoluteNote<'a, N> {
+    /// トラックのインデックス
+    pub track: usize,
+    /// パートのインデックス
+    pub part: usize,
+    /// ノート
+    pub note: &'a N,
+    /// 曲の先頭からのティック（プリメジャーを含まない）
+    pub tick: i64,
+    /// 曲の先頭からの秒
+    pub seconds: f64,
+    /// その時点で有効な歌声
+    pub singer: Option<SingerRef<'a>>,
+}
+
+/// `Vsqx4`のノートのイテレータ
+pub struct Vsqx4Notes<'a> {
+    project: &'a vsqx4::Vsqx4,
+    tempo: TempoMap,
+    offset: i64,
+    track: usize,
+    part: usize,
+    note: usize,
+}
+
+impl<'a> Vsqx4Notes<'a> {
+    pub(crate) fn new(project: &'a vsqx4::Vsqx4) -> Self {
+        Self {
+            project,
+            tempo: TempoMap::from_vsqx4(project),
+            offset: project.master_track.pre_measure_ticks(),
+            track: 0,
+            part: 0,
+            note: 0,
+        }
+    }
+
+    /// このイテレータが使っているテンポマップ
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo
+    }
+}
+
+impl<'a> Iterator for Vsqx4Notes<'a> {
+    type Item = AbsoluteNote<'a, vsqx4::Note>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let project = self.project;
+
+        loop {
+            let track = project.vs_track.get(self.track)?;
+
+            let part = match track.parts.get(self.part) {
+                Some(part) => part,
+                None => {
+                    self.track += 1;
+                    self.part = 0;
+                    self.note = 0;
+                    continue;
+                }
+            };
+
+            let note = match part.notes.get(self.note) {
+                Some(note) => note,
+                None => {
+                    self.part += 1;
+                    self.note = 0;
+                    continue;
+                }
+            };
+
+            let tick = part.position + note.position - self.offset;
+            let singer = part
+                .singers
+                .iter()
+                .take_while(|s| s.position <= note.position)
+                .last()
+                .or_else(|| part.singers.first())
+                .and_then(|s| {
+                    let voices = &project.voice_table.voices;
+                    voices
+                        .iter()
+                        .find(|v| v.bs == s.bs && v.pc == s.pc)
+                        .or_else(|| voices.get(s.pc as usize))
+                        .map(|v| SingerRef {
+                            comp_id: &v.id,
+                            name: Some(&v.name),
+                            language: Some(s.bs),
+                        })
+                });
+
+            let item = AbsoluteNote {
+                track: self.track,
+                part: self.part,
+                note,
+                tick,
+                seconds: self.tempo.seconds(tick),
+                singer,
+            };
+
+            self.note += 1;
+            return Some(item);
+        }
+    }
+}
+
+/// `Vpr`のノートのイテレータ
+pub struct VprNotes<'a> {
+    project: &'a vpr::Vpr,
+    tempo: TempoMap,
+    track: usize,
+    part: usize,
+    note: usize,
+}
+
+impl<'a> VprNotes<'a> {
+    pub(crate) fn new(project: &'a vpr::Vpr) -> Self {
+        Self {
+            project,
+            tempo: TempoMap::from_vpr(project),
+            track: 0,
+            part: 0,
+            note: 0,
+        }
+    }
+
+    /// このイテレータが使っているテンポマップ
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo
+    }
+}
+
+impl<'a> Iterator for VprNotes<'a> {
+    type Item = AbsoluteNote<'a, vpr::Note>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let project = self.project;
+
+        loop {
+            let track = project.tracks.get(self.track)?;
+
+            let part = match track.parts.get(self.part) {
+                Some(part) => part,
+                None => {
+                    self.track += 1;
+                    self.part = 0;
+                    self.note = 0;
+                    continue;
+                }
+            };
+
+            let note = match part.notes.get(self.note) {
+                Some(note) => note,
+                None => {
+                    self.part += 1;
+                    self.note = 0;
+                    continue;
+                }
+            };
+
+            let tick = part.pos as i64 + note.pos;
+            let voice = project
+                .voices
+                .iter()
+                .find(|v| v.comp_id == part.voice.comp_id);
+            let singer = SingerRef {
+                comp_id: &part.voice.comp_id,
+                name: part
+                    .voice
+                    .name
+                    .as_deref()
+                    .or_else(|| voice.and_then(|v| v.name.as_deref())),
+                language: part.voice.lang_id.or_else(|| voice.and_then(|v| v.lang_id)),
+            };
+
+            let item = AbsoluteNote {
+                track: self.track,
+                part: self.part,
+                note,
+                tick,
+                seconds: self.tempo.seconds(tick),
+                singer: Some(singer),
+            };
+
+            self.note += 1;
+            return Some(item);
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// テンポ変化をまたいでティックと秒が正しく変換されるか確認する。
+fn test_tempo_map() {
+    let map = TempoMap::new(480, vec![(0, 12000), (1920, 6000)]);
+
+    assert!((map.seconds(1920) - 2.0).abs() < 1e-9);
+    assert!((map.seconds(2400) - 3.0).abs() < 1e-9);
+    assert!((map.ticks(3.0) - 2400.0).abs() < 1e-9);
+    assert!((map.bpm_at(2000) - 60.0).abs() < 1e-9);
+}
+
+#[test]
+#[cfg(test)]
+/// .vsqxのノートをプリメジャーを除いた絶対時間で走査できるか確認する。
+fn test_vsqx4_absolute_notes() {
+    let v: vsqx4::Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    let notes: Vec<_> = v.absolute_notes().collect();
+
+    assert_eq!(notes.len(), v.vs_track[0].parts[0].notes.len());
+    // BPM 80で480ティックは0.75秒
+    assert_eq!(notes[1].tick, 480);
+    assert!((notes[1].seconds - 0.75).abs() < 1e-9);
+}
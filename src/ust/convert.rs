@@ -0,0 +1,363 @@
+//! .ustとVsqx4の相互変換（Vprとの変換はVsqx4を経由する）
+
+use super::{Mode2Pitch, Ust, UstNote, UstVibrato};
+use crate::edit::{Curve, EditPart};
+use crate::timeline::TempoMap;
+use crate::vsqx4::{self, Vsqx4};
+
+/// ピッチ曲線を読み込むときの間隔（ティック）
+const PITCH_IMPORT_STEP: i64 = 10;
+/// ピッチ曲線を書き出すときの間隔（ティック）
+const PITCH_EXPORT_STEP: i64 = 30;
+/// 読み込むときに設定するピッチベンドセンシティビティ（半音）
+const PITCH_BEND_SENS: i64 = 12;
+
+/// .ustからVsqx4への変換。休符はノートの間の空白になる。
+pub(crate) fn convert_ust_to_vsqx4(ust: &Ust) -> Vsqx4 {
+    let mut v = Vsqx4::default();
+
+    v.master_track.name = ust.project_name.clone();
+    v.master_track.pre_measure = 4;
+    let offset = v.master_track.pre_measure_ticks();
+
+    // テンポ情報（ノートごとのテンポ変更を含む）
+    v.master_track.tempos = vec![vsqx4::Tempo {
+        position: 0,
+        value: bpm_to_value(ust.tempo),
+    }];
+    let mut tick = 0;
+    for n in &ust.notes {
+        if let Some(tempo) = n.tempo {
+            let value = bpm_to_value(tempo);
+            if tick == 0 {
+                v.master_track.tempos[0].value = value;
+            } else {
+                v.master_track.tempos.push(vsqx4::Tempo {
+                    position: tick + offset,
+                    value,
+                });
+            }
+        }
+        tick += n.length;
+    }
+
+    let tempo_map = TempoMap::from_vsqx4(&v);
+    let voice = &v.voice_table.voices[0];
+
+    let mut part = vsqx4::VsPart {
+        position: offset,
+        name: Some(ust.project_name.clone()),
+        singers: vec![vsqx4::Singer {
+            position: 0,
+            bs: voice.bs,
+            pc: voice.pc,
+        }],
+        ..Default::default()
+    };
+
+    // ノート
+    let mut pitch: Vec<(i64, i64)> = vec![];
+    let mut prev: Option<(i64, i64)> = None;
+    let mut tick = 0;
+
+    for n in &ust.notes {
+        if n.is_rest() || n.length <= 0 {
+            prev = None;
+            tick += n.length.max(0);
+            continue;
+        }
+
+        let mut note = vsqx4::Note {
+            position: tick,
+            duration: n.length,
+            note_num: n.note_num,
+            lyric: n.lyric.clone(),
+            phoneme: String::new(),
+            ..Default::default()
+        };
+
+        if let Some(vbr) = &n.vibrato {
+            let vib_len = vbr.length.round().clamp(0.0, 100.0) as i64;
+            set_style(&mut note.style, "vibLen", vib_len);
+            set_style(&mut note.style, "vibType", if vib_len > 0 { 1 } else { 0 });
+        }
+
+        if let Some(p) = &n.pitch {
+            // 直前のノートと接している場合、その音高から始まる
+            let prev_num = prev.filter(|&(end, _)| end == tick).map(|(_, num)| num);
+            let events = sample_mode2(p, tick, n.note_num, prev_num, &tempo_map);
+
+            // 後のノートの曲線が優先される
+            if let Some(&(first, _)) = events.first() {
+                pitch.retain(|e| e.0 < first);
+            }
+            // パートの先頭より前の分は捨てる
+            pitch.extend(events.into_iter().filter(|e| e.0 >= 0));
+        }
+
+        part.notes.push(note);
+        prev = Some((tick + n.length, n.note_num));
+        tick += n.length;
+    }
+
+    part.play_time = Some(tick as u64);
+
+    if !pitch.is_empty() {
+        pitch.dedup_by_key(|e| e.1);
+        part.set_curves(vec![
+            Curve {
+                name: "S".into(),
+                events: vec![(0, PITCH_BEND_SENS)],
+            },
+            Curve {
+                name: "P".into(),
+                events: pitch,
+            },
+        ]);
+    }
+
+    v.vs_track.push(vsqx4::VsTrack {
+        track_no: 0,
+        parts: vec![part],
+        ..Default::default()
+    });
+    v.mixer.vs_unit.push(vsqx4::VsUnit::default());
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    v
+}
+
+/// Mode2のピッチ曲線を`(位置, PIT)`の列に直す。
+pub(crate) fn sample_mode2(
+    pitch: &Mode2Pitch,
+    tick: i64,
+    note_num: i64,
+    prev_num: Option<i64>,
+    tempo_map: &TempoMap,
+) -> Vec<(i64, i64)> {
+    let start_height = prev_num
+        .map(|prev| (prev - note_num) as f64 * 10.0)
+        .unwrap_or(0.0);
+    let points = pitch.points(start_height);
+
+    let base = tempo_map.seconds(tick);
+    let to_tick = |ms: f64| tempo_map.ticks(base + ms / 1000.0).round() as i64;
+    let to_ms = |t: i64| (tempo_map.seconds(t) - base) * 1000.0;
+
+    let first = to_tick(points[0].0);
+    let last = to_tick(points[points.len() - 1].0).max(first);
+
+    let mut events = vec![];
+    let mut t = first;
+    while t <= last {
+        let mut semitones = pitch.height_at(to_ms(t), start_height) / 10.0;
+
+        // ノートの開始前は直前のノートが鳴っているので、その音高からの差にする
+        if t < tick {
+            if let Some(prev) = prev_num {
+                semitones += (note_num - prev) as f64;
+            }
+        }
+
+        events.push((t, semitones_to_pit(semitones, PITCH_BEND_SENS)));
+        t += PITCH_IMPORT_STEP;
+    }
+
+    if events.last().map(|e| e.0) != Some(last) {
+        let semitones = pitch.height_at(to_ms(last), start_height) / 10.0;
+        events.push((last, semitones_to_pit(semitones, PITCH_BEND_SENS)));
+    }
+
+    events
+}
+
+/// Vsqx4の`index`番目のトラックを.ustに変換する。
+/// 重なっているノートは後のノートの開始位置で切られる。
+pub(crate) fn convert_vsqx4_to_ust(v: &Vsqx4, index: usize) -> Ust {
+    let tempo_map = TempoMap::from_vsqx4(v);
+    let offset = v.master_track.pre_measure_ticks();
+
+    let mut ust = Ust {
+        project_name: v.master_track.name.clone(),
+        tempo: tempo_map.bpm_at(0),
+        ..Default::default()
+    };
+
+    // (開始位置, ノート)
+    let mut notes: Vec<(i64, UstNote)> = vec![];
+
+    if let Some(track) = v.vs_track.get(index) {
+        for part in &track.parts {
+            let curves = part.curves();
+            let curve = |name: &str| curves.iter().find(|c| c.name == name);
+            let (pit, pbs) = (curve("P"), curve("S"));
+
+            for note in &part.notes {
+                let start = part.position - offset + note.position;
+
+                let mut n = UstNote::new(note.duration, note.lyric.clone(), note.note_num);
+                n.pitch = pit.and_then(|pit| {
+                    export_pitch(pit, pbs, note.position, note.duration, start, &tempo_map)
+                });
+
+                let style = |id: &str| note.style.styles.iter().find(|s| s.id == id);
+                if let (Some(len), Some(ty)) = (style("vibLen"), style("vibType")) {
+                    if len.value > 0 && ty.value != 0 {
+                        n.vibrato = Some(UstVibrato {
+                            length: len.value as f64,
+                            ..Default::default()
+                        });
+                    }
+                }
+
+                notes.push((start, n));
+            }
+        }
+    }
+
+    notes.sort_by_key(|n| n.0);
+
+    // 重なりの解消とプリメジャー中のノートの切り詰め
+    for i in 0..notes.len() {
+        if notes[i].0 < 0 {
+            notes[i].1.length += notes[i].0;
+            notes[i].0 = 0;
+        }
+        if let Some(&(next, _)) = notes.get(i + 1) {
+            let end = notes[i].0 + notes[i].1.length;
+            if end > next {
+                notes[i].1.length -= end - next.max(notes[i].0);
+            }
+        }
+    }
+    notes.retain(|n| n.1.length > 0);
+
+    // 空白を休符で埋める
+    let mut entries: Vec<(i64, UstNote)> = vec![];
+    let mut cursor = 0;
+    for (start, n) in notes {
+        if start > cursor {
+            entries.push((cursor, UstNote::rest(start - cursor)));
+        }
+        cursor = start + n.length;
+        entries.push((start, n));
+    }
+
+    // テンポ変更をその位置から始まるノートに付ける（休符は分割する）
+    for &(pos, value) in tempo_map.tempos() {
+        if pos <= 0 {
+            continue;
+        }
+
+        if let Some(i) = entries
+            .iter()
+            .position(|(start, n)| *start < pos && pos < start + n.length && n.is_rest())
+        {
+            let (start, rest) = entries[i].clone();
+            entries[i].1.length = pos - start;
+            entries.insert(i + 1, (pos, UstNote::rest(rest.length - (pos - start))));
+        }
+
+        // 最後のノートより後のテンポ変更は失われる
+        if let Some((_, n)) = entries.iter_mut().find(|(start, _)| *start >= pos) {
+            n.tempo = Some(value as f64 / 100.0);
+        }
+    }
+
+    ust.notes = entries.into_iter().map(|(_, n)| n).collect();
+    ust
+}
+
+/// ノートの範囲のPIT・PBSをMode2のピッチ曲線にする。ピッチが変化していなければ`None`。
+fn export_pitch(
+    pit: &Curve,
+    pbs: Option<&Curve>,
+    position: i64,
+    duration: i64,
+    tick: i64,
+    tempo_map: &TempoMap,
+) -> Option<Mode2Pitch> {
+    let base = tempo_map.seconds(tick);
+    let height = |t: i64| {
+        let pit = pit.value_at(t).unwrap_or(0);
+        let pbs = pbs.and_then(|c| c.value_at(t)).unwrap_or(2);
+        (pit as f64 / 8192.0 * pbs as f64 * 100.0).round() / 10.0
+    };
+
+    let mut points = vec![];
+    let mut t = 0;
+    while t <= duration {
+        let ms = (tempo_map.seconds(tick + t) - base) * 1000.0;
+        points.push(((ms * 10.0).round() / 10.0, height(position + t)));
+        t += PITCH_EXPORT_STEP;
+    }
+
+    if points.iter().all(|p| p.1 == 0.0) {
+        return None;
+    }
+
+    Some(Mode2Pitch {
+        start: points[0].0,
+        start_height: Some(points[0].1),
+        widths: points
+            .windows(2)
+            .map(|w| ((w[1].0 - w[0].0) * 10.0).round() / 10.0)
+            .collect(),
+        heights: points[1..].iter().map(|p| p.1).collect(),
+        shapes: vec!["s".into(); points.len() - 1],
+    })
+}
+
+pub(crate) fn bpm_to_value(bpm: f64) -> i64 {
+    (bpm * 100.0).round() as i64
+}
+
+pub(crate) fn semitones_to_pit(semitones: f64, pbs: i64) -> i64 {
+    (semitones / pbs as f64 * 8192.0)
+        .round()
+        .clamp(-8192.0, 8191.0) as i64
+}
+
+pub(crate) fn set_style(style: &mut vsqx4::Style, id: &str, value: i64) {
+    match style.styles.iter_mut().find(|s| s.id == id) {
+        Some(s) => s.value = value,
+        None => style.styles.push(vsqx4::StyleKey {
+            id: id.into(),
+            value,
+        }),
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// .ustをVsqx4に変換し、休符・テンポ・ピッチ・ビブラートが反映されるか確認する。
+fn test_ust_to_vsqx4() {
+    let ust = Ust::from_bytes(include_bytes!("../test/test.ust")).unwrap();
+    let v = Vsqx4::from(ust.clone());
+
+    let part = &v.vs_track[0].parts[0];
+    let notes: Vec<_> = part
+        .notes
+        .iter()
+        .map(|n| (n.position, n.lyric.as_str()))
+        .collect();
+    assert_eq!(notes, vec![(480, "あ"), (960, "い"), (2400, "う")]);
+
+    // 2つ目の休符でのテンポ変更（プリメジャー4小節分ずれる）
+    assert_eq!(v.master_track.tempos.len(), 2);
+    assert_eq!(v.master_track.tempos[1].position, 1920 + 7680);
+    assert_eq!(v.master_track.tempos[1].value, 15000);
+
+    assert!(part.control_changes.iter().any(|cc| cc.id == "P"));
+    let vib_len = part.notes[1].style.styles.iter().find(|s| s.id == "vibLen");
+    assert_eq!(vib_len.map(|s| s.value), Some(65));
+
+    // Vsqx4から戻したときに休符とテンポが復元されること
+    let ust2 = Ust::from(v);
+    let lyrics: Vec<_> = ust2.notes.iter().map(|n| n.lyric.as_str()).collect();
+    assert_eq!(lyrics, vec!["R", "あ", "い", "R", "う"]);
+    assert_eq!(ust2.notes[3].tempo, Some(150.0));
+    assert!(ust2.notes[2].pitch.is_some());
+    assert!(ust2.notes[2].vibrato.is_some());
+}
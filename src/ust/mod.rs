@@ -0,0 +1,523 @@
+//! UTAUの.ust形式
+//!
+//! Shift_JISとUTF-8（`[#VERSION]`の`Charset=UTF-8`）の両方を読み書きできる。
+//! `Vsqx4`・`Vpr`との変換は`convert`モジュールで行う。
+
+use crate::{Error, Result};
+
+pub(crate) mod convert;
+
+/// 休符の歌詞
+pub const REST_LYRICS: &[&str] = &["R", "r"];
+
+/// 書き出すときの文字コード
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UstEncoding {
+    /// UTAU本体の既定
+    ShiftJis,
+    /// `Charset=UTF-8`を付けて書き出す
+    Utf8,
+}
+
+/// .ustファイル
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ust {
+    /// UST Version（`1.2`など）
+    pub version: String,
+    /// 読み込んだときの文字コード
+    pub encoding: UstEncoding,
+    /// プロジェクト名
+    pub project_name: String,
+    /// 先頭のテンポ（BPM）
+    pub tempo: f64,
+    /// `[#SETTING]`のその他の項目（順序を保持する）
+    pub settings: Vec<(String, String)>,
+    /// ノート（休符を含む）
+    pub notes: Vec<UstNote>,
+}
+
+impl Default for Ust {
+    fn default() -> Self {
+        Self {
+            version: "1.2".into(),
+            encoding: UstEncoding::ShiftJis,
+            project_name: "Untitled".into(),
+            tempo: 120.0,
+            settings: vec![
+                ("Tracks".into(), "1".into()),
+                ("Mode2".into(), "True".into()),
+            ],
+            notes: vec![],
+        }
+    }
+}
+
+/// ノート
+#[derive(Clone, Debug, PartialEq)]
+pub struct UstNote {
+    /// 長さ（四分音符 = 480）
+    pub length: i64,
+    /// 歌詞。休符は`R`。
+    pub lyric: String,
+    /// ノート番号
+    pub note_num: i64,
+    /// このノートからのテンポ（BPM）
+    pub tempo: Option<f64>,
+    /// Mode2のピッチ（`PBS`・`PBW`・`PBY`・`PBM`）
+    pub pitch: Option<Mode2Pitch>,
+    /// ビブラート（`VBR`）
+    pub vibrato: Option<UstVibrato>,
+    /// その他の項目（順序を保持する）
+    pub extra: Vec<(String, String)>,
+}
+
+impl UstNote {
+    pub fn new<S: Into<String>>(length: i64, lyric: S, note_num: i64) -> Self {
+        Self {
+            length,
+            lyric: lyric.into(),
+            note_num,
+            tempo: None,
+            pitch: None,
+            vibrato: None,
+            extra: vec![],
+        }
+    }
+
+    /// 休符を作る。
+    pub fn rest(length: i64) -> Self {
+        Self::new(length, "R", 60)
+    }
+
+    /// 休符かどうか
+    pub fn is_rest(&self) -> bool {
+        REST_LYRICS.contains(&self.lyric.trim())
+    }
+}
+
+/// Mode2のピッチ曲線。
+///
+/// 点の時間はノートの開始位置からのミリ秒、高さはノートの音高からの差（1/10半音）。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mode2Pitch {
+    /// 最初の点の時間（`PBS`の1つ目）
+    pub start: f64,
+    /// 最初の点の高さ（`PBS`の2つ目）。省略時は直前のノートの音高から求める。
+    pub start_height: Option<f64>,
+    /// 各区間の幅（`PBW`）
+    pub widths: Vec<f64>,
+    /// 2つ目以降の点の高さ（`PBY`）。足りない分は0。
+    pub heights: Vec<f64>,
+    /// 各区間の形（`PBM`）。空はS字、`s`は直線、`r`と`j`は曲線。
+    pub shapes: Vec<String>,
+}
+
+impl Mode2Pitch {
+    /// `(時間, 高さ)`の点の列。最初の点の高さが省略されている場合は`start_height`を使う。
+    pub fn points(&self, start_height: f64) -> Vec<(f64, f64)> {
+        let mut points = vec![(self.start, self.start_height.unwrap_or(start_height))];
+        let mut x = self.start;
+
+        for (i, w) in self.widths.iter().enumerate() {
+            x += w;
+            points.push((x, self.heights.get(i).cloned().unwrap_or(0.0)));
+        }
+
+        points
+    }
+
+    /// 時間`x`（ミリ秒）での高さ（1/10半音）。
+    pub fn height_at(&self, x: f64, start_height: f64) -> f64 {
+        let points = self.points(start_height);
+
+        if x <= points[0].0 {
+            return points[0].1;
+        }
+
+        for (i, pair) in points.windows(2).enumerate() {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if x < x1 {
+                let t = if x1 > x0 { (x - x0) / (x1 - x0) } else { 1.0 };
+                let shape = self.shapes.get(i).map(String::as_str).unwrap_or("");
+                return y0 + (y1 - y0) * interpolate(shape, t);
+            }
+        }
+
+        points.last().map(|p| p.1).unwrap_or(0.0)
+    }
+}
+
+/// 区間の形に応じた補間
+fn interpolate(shape: &str, t: f64) -> f64 {
+    use std::f64::consts::PI;
+
+    match shape {
+        "s" => t,
+        "r" => (t * PI / 2.0).sin(),
+        "j" => 1.0 - (t * PI / 2.0).cos(),
+        _ => (1.0 - (t * PI).cos()) / 2.0,
+    }
+}
+
+/// ビブラート（`VBR`）
+#[derive(Clone, Debug, PartialEq)]
+pub struct UstVibrato {
+    /// ノートの長さに対する割合（%）
+    pub length: f64,
+    /// 周期（ミリ秒）
+    pub cycle: f64,
+    /// 深さ（セント）
+    pub depth: f64,
+    /// フェードイン（%）
+    pub fade_in: f64,
+    /// フェードアウト（%）
+    pub fade_out: f64,
+    /// 位相（%）
+    pub phase: f64,
+    /// 高さのずれ（%）
+    pub shift: f64,
+}
+
+impl Default for UstVibrato {
+    fn default() -> Self {
+        Self {
+            length: 65.0,
+            cycle: 180.0,
+            depth: 35.0,
+            fade_in: 20.0,
+            fade_out: 20.0,
+            phase: 0.0,
+            shift: 0.0,
+        }
+    }
+}
+
+impl From<crate::vsqx4::Vsqx4> for Ust {
+    fn from(v: crate::vsqx4::Vsqx4) -> Self {
+        convert::convert_vsqx4_to_ust(&v, 0)
+    }
+}
+
+impl From<crate::vpr::Vpr> for Ust {
+    fn from(v: crate::vpr::Vpr) -> Self {
+        convert::convert_vsqx4_to_ust(&v.into(), 0)
+    }
+}
+
+impl Ust {
+    /// `Vsqx4`の`index`番目のトラックを変換する。.ustは1トラックのみなので、
+    /// 他のトラックを変換したい場合に使う。
+    pub fn from_vsqx4_track(v: &crate::vsqx4::Vsqx4, index: usize) -> Self {
+        convert::convert_vsqx4_to_ust(v, index)
+    }
+
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// バイト列から読み込む。UTF-8として読めない場合はShift_JISとみなす。
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+
+        let (text, encoding) = match std::str::from_utf8(bytes) {
+            Ok(text) => (text.to_string(), UstEncoding::Utf8),
+            Err(_) => {
+                let (text, _, _) = encoding_rs::SHIFT_JIS.decode(bytes);
+                (text.into_owned(), UstEncoding::ShiftJis)
+            }
+        };
+
+        let mut ust = Self::parse(&text)?;
+
+        // ASCIIのみの場合はどちらとも言えないので、Charsetの指定に従う
+        let declared_utf8 = ust.encoding == UstEncoding::Utf8;
+        if declared_utf8 || !text.is_ascii() {
+            ust.encoding = encoding;
+        }
+
+        Ok(ust)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut ust = Ust {
+            settings: vec![],
+            ..Default::default()
+        };
+
+        let mut section = String::new();
+        let mut note: Option<UstNote> = None;
+
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+
+            if line.starts_with("[#") && line.ends_with(']') {
+                if let Some(n) = note.take() {
+                    ust.notes.push(n);
+                }
+
+                section = line[2..line.len() - 1].to_string();
+                if is_note_section(&section) {
+                    note = Some(UstNote::new(0, "", 60));
+                }
+                continue;
+            }
+
+            if section == "VERSION" {
+                if let Some(version) = line.strip_prefix("UST Version") {
+                    ust.version = version.trim().into();
+                } else if line.trim().eq_ignore_ascii_case("Charset=UTF-8") {
+                    ust.encoding = UstEncoding::Utf8;
+                }
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => continue,
+            };
+
+            if section == "SETTING" {
+                match key {
+                    "Tempo" => ust.tempo = parse_number(key, value)?,
+                    "ProjectName" => ust.project_name = value.into(),
+                    _ => ust.settings.push((key.into(), value.into())),
+                }
+            } else if let Some(n) = note.as_mut() {
+                n.set(key, value)?;
+            }
+        }
+
+        if let Some(n) = note.take() {
+            ust.notes.push(n);
+        }
+
+        Ok(ust)
+    }
+
+    /// `encoding`で書き出す。改行はUTAUに合わせてCRLF。
+    pub fn write<W: std::io::Write>(&self, mut writer: W, encoding: UstEncoding) -> Result<()> {
+        let text = self.to_string_with(encoding);
+
+        match encoding {
+            UstEncoding::Utf8 => writer.write_all(text.as_bytes())?,
+            UstEncoding::ShiftJis => {
+                let (bytes, _, _) = encoding_rs::SHIFT_JIS.encode(&text);
+                writer.write_all(&bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 読み込んだときと同じ文字コードでファイルに書き出す。
+    pub fn write_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        use std::fs::File;
+        use std::io::BufWriter;
+
+        self.write(BufWriter::new(File::create(path)?), self.encoding)
+    }
+
+    fn to_string_with(&self, encoding: UstEncoding) -> String {
+        use std::fmt::Write;
+
+        let mut s = String::new();
+        let mut line = |l: &str| {
+            s.push_str(l);
+            s.push_str("\r\n");
+        };
+
+        line("[#VERSION]");
+        line(&format!("UST Version{}", self.version));
+        if encoding == UstEncoding::Utf8 {
+            line("Charset=UTF-8");
+        }
+
+        line("[#SETTING]");
+        line(&format!("Tempo={}", format_tempo(self.tempo)));
+        line(&format!("ProjectName={}", self.project_name));
+        for (key, value) in &self.settings {
+            line(&format!("{}={}", key, value));
+        }
+
+        for (i, n) in self.notes.iter().enumerate() {
+            line(&format!("[#{:04}]", i));
+            line(&format!("Length={}", n.length));
+            line(&format!("Lyric={}", n.lyric));
+            line(&format!("NoteNum={}", n.note_num));
+            if let Some(tempo) = n.tempo {
+                line(&format!("Tempo={}", format_tempo(tempo)));
+            }
+            for (key, value) in &n.extra {
+                line(&format!("{}={}", key, value));
+            }
+            if let Some(p) = &n.pitch {
+                let mut pbs = format_number(p.start);
+                if let Some(y) = p.start_height {
+                    let _ = write!(pbs, ";{}", format_number(y));
+                }
+                line(&format!("PBS={}", pbs));
+                line(&format!("PBW={}", join(&p.widths)));
+                line(&format!("PBY={}", join(&p.heights)));
+                line(&format!("PBM={}", p.shapes.join(",")));
+            }
+            if let Some(v) = &n.vibrato {
+                line(&format!(
+                    "VBR={}",
+                    join(&[
+                        v.length, v.cycle, v.depth, v.fade_in, v.fade_out, v.phase, v.shift, 0.0
+                    ])
+                ));
+            }
+        }
+
+        line("[#TRACKEND]");
+
+        s
+    }
+}
+
+impl std::str::FromStr for Ust {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        Self::parse(string)
+    }
+}
+
+impl std::fmt::Display for Ust {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_string_with(UstEncoding::Utf8))
+    }
+}
+
+impl UstNote {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "Length" => self.length = parse_number(key, value)?.round() as i64,
+            "Lyric" => self.lyric = value.into(),
+            "NoteNum" => self.note_num = parse_number(key, value)?.round() as i64,
+            "Tempo" => self.tempo = Some(parse_number(key, value)?),
+            "PBS" => {
+                let mut it = value.split([';', ',']);
+                let pitch = self.pitch_mut();
+                pitch.start = it.next().map(|x| parse_number(key, x)).unwrap_or(Ok(0.0))?;
+                pitch.start_height = match it.next() {
+                    Some(y) if !y.trim().is_empty() => Some(parse_number(key, y)?),
+                    _ => None,
+                };
+            }
+            "PBW" => self.pitch_mut().widths = parse_list(key, value)?,
+            "PBY" => self.pitch_mut().heights = parse_list(key, value)?,
+            "PBM" => {
+                self.pitch_mut().shapes = value.split(',').map(|s| s.trim().to_string()).collect()
+            }
+            "VBR" => {
+                let v = parse_list(key, value)?;
+                let get = |i: usize, default: f64| v.get(i).cloned().unwrap_or(default);
+                let d = UstVibrato::default();
+                self.vibrato = Some(UstVibrato {
+                    length: get(0, d.length),
+                    cycle: get(1, d.cycle),
+                    depth: get(2, d.depth),
+                    fade_in: get(3, d.fade_in),
+                    fade_out: get(4, d.fade_out),
+                    phase: get(5, d.phase),
+                    shift: get(6, d.shift),
+                });
+            }
+            _ => self.extra.push((key.into(), value.into())),
+        }
+
+        Ok(())
+    }
+
+    fn pitch_mut(&mut self) -> &mut Mode2Pitch {
+        self.pitch.get_or_insert_with(|| Mode2Pitch {
+            start: 0.0,
+            start_height: None,
+            widths: vec![],
+            heights: vec![],
+            shapes: vec![],
+        })
+    }
+}
+
+fn is_note_section(section: &str) -> bool {
+    !section.is_empty() && section.chars().all(|c| c.is_ascii_digit())
+}
+
+fn parse_number(key: &str, value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::UstError(format!("invalid value for {}: {:?}", key, value)))
+}
+
+fn parse_list(key: &str, value: &str) -> Result<Vec<f64>> {
+    // 空の要素は0とみなす
+    value
+        .split(',')
+        .map(|v| {
+            if v.trim().is_empty() {
+                Ok(0.0)
+            } else {
+                parse_number(key, v)
+            }
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|mut v| {
+            if value.trim().is_empty() {
+                v.clear();
+            }
+            v
+        })
+}
+
+fn format_tempo(tempo: f64) -> String {
+    format!("{:.2}", tempo)
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 {
+        format!("{}", n as i64)
+    } else {
+        format!("{:.3}", n)
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+fn join(values: &[f64]) -> String {
+    values
+        .iter()
+        .map(|&v| format_number(v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[test]
+#[cfg(test)]
+/// Shift_JISの.ustを読み込み、書き出したものが同じ内容になるか確認する。
+fn test_ust_roundtrip() {
+    let ust = Ust::from_bytes(include_bytes!("../test/test.ust")).unwrap();
+
+    assert_eq!(ust.encoding, UstEncoding::ShiftJis);
+    assert_eq!(ust.project_name, "テスト");
+    assert_eq!(ust.notes.len(), 5);
+    assert_eq!(ust.notes[1].lyric, "あ");
+    assert!(ust.notes[0].is_rest());
+
+    let pitch = ust.notes[2].pitch.as_ref().unwrap();
+    assert_eq!(pitch.start, -40.0);
+    assert_eq!(pitch.widths, vec![60.0, 40.0]);
+
+    for encoding in &[UstEncoding::ShiftJis, UstEncoding::Utf8] {
+        let mut buf = vec![];
+        ust.write(&mut buf, *encoding).unwrap();
+        let ust2 = Ust::from_bytes(&buf).unwrap();
+
+        assert_eq!(ust2.encoding, *encoding);
+        assert_eq!(ust.notes, ust2.notes);
+    }
+}
@@ -0,0 +1,599 @@
+//! .ustxとVsqx4の相互変換（Vprとの変換はVsqx4を経由する）
+
+use super::{Extra, Note, PhonemeOverride, Pitch, PitchPointShape, Ustx, VoicePart};
+use crate::conversion::{self, Conversion, NoteLosses, Range, Unmapped};
+use crate::edit::{Curve, EditPart};
+use crate::timeline::TempoMap;
+use crate::ust::convert::{bpm_to_value, sample_mode2, semitones_to_pit, set_style};
+use crate::ust::Mode2Pitch;
+use crate::vsqx4::{self, Vsqx4};
+
+/// 読み込むときに設定するピッチベンドセンシティビティ（半音）。`pitd`の範囲に合わせる。
+const PITCH_BEND_SENS: i64 = 12;
+/// 曲線を標本化する間隔（ティック）
+const CURVE_STEP: i64 = 10;
+
+/// `(.ustxの略称, VOCALOIDのCC, .ustxの値域, VOCALOIDの値域)`
+///
+/// `pitd`はPIT・PBSとピッチ点から合成するのでここには含めない。
+const CURVE_MAPPINGS: &[(&str, &str, Range, Range)] = &[
+    ("dyn", "D", (-240.0, 0.0, 120.0), (0.0, 64.0, 127.0)),
+    ("bre", "B", (0.0, 0.0, 100.0), (0.0, 0.0, 127.0)),
+    ("gen", "G", (-100.0, 0.0, 100.0), (0.0, 64.0, 127.0)),
+];
+
+/// .ustxからVsqx4への変換。
+/// 歌声はVOCALOIDのボイスに対応付けられないので、すべて既定のボイスになる。
+pub(crate) fn convert_ustx_to_vsqx4(u: &Ustx) -> Conversion<Vsqx4> {
+    let mut unmapped = vec![];
+    let mut v = Vsqx4::default();
+    let resolution = u.resolution.max(1);
+    let ticks = |t: i64| t * 480 / resolution;
+
+    v.master_track.name = u.name.clone();
+    v.master_track.pre_measure = 4;
+
+    // 拍子（先頭の拍子はプリメジャーにも適用される）
+    let mut time_signatures = u.time_signatures.clone();
+    if time_signatures.is_empty() {
+        time_signatures.push(super::TimeSignature {
+            bar_position: 0,
+            beat_per_bar: u.beat_per_bar,
+            beat_unit: u.beat_unit,
+        });
+    }
+    time_signatures.sort_by_key(|ts| ts.bar_position);
+    v.master_track.time_signatures = time_signatures
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| vsqx4::TimeSignature {
+            position: if i == 0 {
+                0
+            } else {
+                ts.bar_position + v.master_track.pre_measure
+            },
+            numerator: ts.beat_per_bar,
+            denominator: ts.beat_unit,
+        })
+        .collect();
+    let offset = v.master_track.pre_measure_ticks();
+
+    // テンポ
+    let mut tempos = u.tempos.clone();
+    if tempos.is_empty() {
+        tempos.push(super::Tempo {
+            position: 0,
+            bpm: u.bpm,
+        });
+    }
+    tempos.sort_by_key(|t| t.position);
+    v.master_track.tempos = tempos
+        .iter()
+        .enumerate()
+        .map(|(i, t)| vsqx4::Tempo {
+            position: if i == 0 {
+                0
+            } else {
+                ticks(t.position) + offset
+            },
+            value: bpm_to_value(t.bpm),
+        })
+        .collect();
+
+    let tempo_map = TempoMap::from_vsqx4(&v);
+    let voice = &v.voice_table.voices[0];
+    let singer = vsqx4::Singer {
+        position: 0,
+        bs: voice.bs,
+        pc: voice.pc,
+    };
+
+    // トラック
+    for (i, track) in u.tracks.iter().enumerate() {
+        v.vs_track.push(vsqx4::VsTrack {
+            track_no: i as i64,
+            name: track
+                .track_name
+                .clone()
+                .unwrap_or_else(|| format!("Track {}", i + 1)),
+            ..Default::default()
+        });
+        v.mixer.vs_unit.push(vsqx4::VsUnit {
+            track_no: i as i64,
+            mute: track.mute as i64,
+            solo: track.solo as i64,
+            ..Default::default()
+        });
+
+        if let Some(singer) = &track.singer {
+            unmapped.push(Unmapped::track(i, format!("singer {:?}", singer)));
+        }
+        if track.volume != 0.0 || track.pan != 0.0 {
+            unmapped.push(Unmapped::track(i, "volume and pan"));
+        }
+    }
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    for wave in &u.wave_parts {
+        unmapped.push(Unmapped::track(
+            wave.track_no,
+            format!("wave part {:?} ({})", wave.name, wave.relative_path),
+        ));
+    }
+
+    // パート
+    for (index, part) in u.voice_parts.iter().enumerate() {
+        if part.track_no >= v.vs_track.len() {
+            unmapped.push(Unmapped::part(
+                part.track_no,
+                index,
+                "part on a missing track",
+            ));
+            continue;
+        }
+
+        let start = ticks(part.position);
+        let mut p = vsqx4::VsPart {
+            position: start + offset,
+            play_time: Some(ticks(part.duration).max(0) as u64),
+            name: Some(part.name.clone()),
+            comment: Some(part.comment.clone()).filter(|c| !c.is_empty()),
+            singers: vec![singer.clone()],
+            ..Default::default()
+        };
+
+        let mut lost = NoteLosses::default();
+        let mut pitch: Vec<(i64, i64)> = vec![];
+        let mut prev: Option<(i64, i64)> = None;
+
+        let mut notes: Vec<&Note> = part.notes.iter().collect();
+        notes.sort_by_key(|n| n.position);
+
+        for note in notes {
+            let position = ticks(note.position);
+            let duration = ticks(note.duration);
+
+            let mut n = vsqx4::Note {
+                position,
+                duration,
+                note_num: note.tone,
+                lyric: note.lyric.clone(),
+                phoneme: String::new(),
+                ..Default::default()
+            };
+
+            // 音素の上書きは先頭から連続している場合のみ表現できる
+            let mut overrides: Vec<&PhonemeOverride> = note
+                .phoneme_overrides
+                .iter()
+                .filter(|o| o.phoneme.is_some())
+                .collect();
+            overrides.sort_by_key(|o| o.index);
+            if overrides.iter().enumerate().all(|(i, o)| o.index == i) {
+                n.phoneme = overrides
+                    .iter()
+                    .filter_map(|o| o.phoneme.as_deref())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+            } else {
+                lost.add("phoneme overrides");
+            }
+            if note.phoneme_overrides.iter().any(|o| !o.extra.is_empty()) {
+                lost.add("phoneme overrides");
+            }
+            for e in &note.phoneme_expressions {
+                lost.add(format!("phoneme expression {:?}", e.abbr));
+            }
+
+            let vibrato = &note.vibrato;
+            if vibrato.length > 0.0 {
+                let vib_len = vibrato.length.round().clamp(0.0, 100.0) as i64;
+                set_style(&mut n.style, "vibLen", vib_len);
+                set_style(&mut n.style, "vibType", 1);
+
+                let default = super::Vibrato::default();
+                if vibrato.period != default.period || vibrato.depth != default.depth {
+                    lost.add("vibrato depth and period");
+                }
+            }
+
+            // ピッチ点
+            let prev_num = prev.filter(|&(end, _)| end == position).map(|(_, num)| num);
+            let snapped = note.pitch.snap_first && matches!(prev_num, Some(p) if p != note.tone);
+            if note.pitch.data.len() > 1 && (snapped || note.pitch.data.iter().any(|p| p.y != 0.0))
+            {
+                let mode2 = to_mode2(&note.pitch);
+                let events =
+                    sample_mode2(&mode2, start + position, note.tone, prev_num, &tempo_map);
+
+                // 後のノートの点が優先される
+                if let Some(&(first, _)) = events.first() {
+                    pitch.retain(|e| e.0 < first - start);
+                }
+                pitch.extend(
+                    events
+                        .into_iter()
+                        .map(|(t, value)| (t - start, value))
+                        .filter(|e| e.0 >= 0),
+                );
+            }
+
+            p.notes.push(n);
+            prev = Some((position + duration, note.tone));
+        }
+
+        // 表情パラメータの曲線
+        let mut curves = vec![];
+        let mut pitd = vec![];
+        for c in &part.curves {
+            let events = sample_curve(c).into_iter().map(|(x, y)| (ticks(x), y));
+
+            if c.abbr == "pitd" {
+                pitd = events.collect();
+            } else if let Some(&(_, id, from, to)) = CURVE_MAPPINGS.iter().find(|m| m.0 == c.abbr) {
+                let mut events: Vec<_> = events.map(|(x, y)| (x, map_range(y, from, to))).collect();
+                events.dedup_by_key(|e| e.1);
+                curves.push(Curve {
+                    name: id.into(),
+                    events,
+                });
+            } else {
+                unmapped.push(Unmapped::part(
+                    part.track_no,
+                    index,
+                    format!("curve {:?}", c.abbr),
+                ));
+            }
+        }
+
+        // ピッチ点と`pitd`を合成する
+        if !pitch.is_empty() || !pitd.is_empty() {
+            let points = Curve {
+                name: "P".into(),
+                events: pitch,
+            };
+            let pitd = Curve {
+                name: "pitd".into(),
+                events: pitd,
+            };
+
+            let mut positions: Vec<i64> = points
+                .events
+                .iter()
+                .chain(pitd.events.iter())
+                .map(|e| e.0)
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+
+            let mut events: Vec<(i64, i64)> = positions
+                .into_iter()
+                .map(|t| {
+                    let cents = pitd.value_at(t).unwrap_or(0) as f64;
+                    let value = points.value_at(t).unwrap_or(0)
+                        + semitones_to_pit(cents / 100.0, PITCH_BEND_SENS);
+                    (t, value.clamp(-8192, 8191))
+                })
+                .collect();
+            events.dedup_by_key(|e| e.1);
+
+            curves.push(Curve {
+                name: "S".into(),
+                events: vec![(0, PITCH_BEND_SENS)],
+            });
+            curves.push(Curve {
+                name: "P".into(),
+                events,
+            });
+        }
+
+        p.set_curves(curves);
+        unmapped.extend(lost.into_unmapped(part.track_no, index));
+        v.vs_track[part.track_no].parts.push(p);
+    }
+
+    Conversion::new(v, unmapped)
+}
+
+/// Vsqx4から.ustxへの変換。
+/// PIT・PBSは`pitd`に書き出され、ピッチ点は平らなものになる。
+pub(crate) fn convert_vsqx4_to_ustx(v: &Vsqx4) -> Conversion<Ustx> {
+    let mut unmapped = vec![];
+    let master = &v.master_track;
+    let offset = master.pre_measure_ticks();
+
+    let mut u = Ustx {
+        name: master.name.clone(),
+        resolution: master.resolution,
+        tempos: vec![],
+        time_signatures: vec![],
+        ..Default::default()
+    };
+
+    // テンポ（プリメジャー中のものは最後のものだけを先頭に置く）
+    let tempo_map = TempoMap::from_vsqx4(v);
+    for &(position, value) in tempo_map.tempos() {
+        let position = position.max(0);
+        if u.tempos.last().map(|t| t.position) == Some(position) {
+            u.tempos.pop();
+        }
+        u.tempos.push(super::Tempo {
+            position,
+            bpm: value as f64 / 100.0,
+        });
+    }
+    u.bpm = u.tempos[0].bpm;
+
+    // 拍子
+    for (i, ts) in master.time_signatures.iter().enumerate() {
+        let bar_position = if i == 0 {
+            0
+        } else {
+            (ts.position - master.pre_measure).max(0)
+        };
+        if u.time_signatures.last().map(|t| t.bar_position) == Some(bar_position) {
+            u.time_signatures.pop();
+        }
+        u.time_signatures.push(super::TimeSignature {
+            bar_position,
+            beat_per_bar: ts.numerator,
+            beat_unit: ts.denominator,
+        });
+    }
+    if let Some(ts) = u.time_signatures.first() {
+        u.beat_per_bar = ts.beat_per_bar;
+        u.beat_unit = ts.beat_unit;
+    }
+
+    for (i, track) in v.vs_track.iter().enumerate() {
+        let unit = v
+            .mixer
+            .vs_unit
+            .iter()
+            .find(|unit| unit.track_no == track.track_no);
+        let singer = track
+            .parts
+            .iter()
+            .flat_map(|p| p.singers.first())
+            .next()
+            .and_then(|s| {
+                let voices = &v.voice_table.voices;
+                voices
+                    .iter()
+                    .find(|v| v.bs == s.bs && v.pc == s.pc)
+                    .or_else(|| voices.get(s.pc as usize))
+            })
+            .map(|voice| voice.name.clone());
+
+        u.tracks.push(super::Track {
+            singer,
+            track_name: Some(track.name.clone()),
+            mute: matches!(unit, Some(unit) if unit.mute != 0),
+            solo: matches!(unit, Some(unit) if unit.solo != 0),
+            ..Default::default()
+        });
+
+        for (j, part) in track.parts.iter().enumerate() {
+            u.voice_parts
+                .push(convert_part(part, i, j, offset, &mut unmapped));
+        }
+    }
+
+    if !v.aux.is_empty() {
+        unmapped.push(Unmapped::project("aux entries"));
+    }
+
+    Conversion::new(u, unmapped)
+}
+
+fn convert_part(
+    part: &vsqx4::VsPart,
+    track: usize,
+    index: usize,
+    offset: i64,
+    unmapped: &mut Vec<Unmapped>,
+) -> VoicePart {
+    let mut lost = NoteLosses::default();
+
+    let notes: Vec<Note> = part
+        .notes
+        .iter()
+        .map(|n| {
+            let mut note = Note::new(n.position, n.duration, n.note_num, n.lyric.clone());
+
+            note.phoneme_overrides = n
+                .phoneme
+                .split_whitespace()
+                .enumerate()
+                .map(|(index, phoneme)| PhonemeOverride {
+                    index,
+                    phoneme: Some(phoneme.into()),
+                    extra: Extra::new(),
+                })
+                .collect();
+
+            let style = |id: &str| n.style.styles.iter().find(|s| s.id == id);
+            if let (Some(len), Some(ty)) = (style("vibLen"), style("vibType")) {
+                if len.value > 0 && ty.value != 0 {
+                    note.vibrato.length = len.value as f64;
+                }
+            }
+
+            if n.velocity != 64 {
+                lost.add("velocity");
+            }
+
+            note
+        })
+        .collect();
+
+    if part.singers.len() > 1 {
+        unmapped.push(Unmapped::part(track, index, "singer changes"));
+    }
+
+    // 曲線
+    let curves = part.curves();
+    let mut ustx_curves = vec![];
+    for c in &curves {
+        if c.name == "P" {
+            let pbs = curves.iter().find(|c| c.name == "S");
+            let mut positions: Vec<i64> = c
+                .events
+                .iter()
+                .chain(pbs.iter().flat_map(|s| s.events.iter()))
+                .map(|e| e.0)
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+
+            let mut events: Vec<(i64, i64)> = positions
+                .into_iter()
+                .map(|t| {
+                    let pit = c.value_at(t).unwrap_or(0) as f64;
+                    let pbs = pbs.and_then(|s| s.value_at(t)).unwrap_or(2) as f64;
+                    (t, (pit / 8192.0 * pbs * 100.0).round() as i64)
+                })
+                .collect();
+            events.dedup_by_key(|e| e.1);
+
+            ustx_curves.push(to_ustx_curve("pitd", &events));
+        } else if c.name == "S" {
+            // PITと一緒に変換する
+        } else if let Some(&(abbr, _, to, from)) = CURVE_MAPPINGS.iter().find(|m| m.1 == c.name) {
+            let mut events: Vec<_> = c
+                .events
+                .iter()
+                .map(|&(x, y)| (x, map_range(y, from, to)))
+                .collect();
+            events.dedup_by_key(|e| e.1);
+
+            ustx_curves.push(to_ustx_curve(abbr, &events));
+        } else {
+            unmapped.push(Unmapped::part(
+                track,
+                index,
+                format!("control change {:?}", c.name),
+            ));
+        }
+    }
+
+    unmapped.extend(lost.into_unmapped(track, index));
+
+    let end = part.notes.iter().map(|n| n.position + n.duration).max();
+    VoicePart {
+        name: part.name.clone().unwrap_or_default(),
+        comment: part.comment.clone().unwrap_or_default(),
+        track_no: track,
+        position: part.position - offset,
+        duration: part.play_time.map(|t| t as i64).or(end).unwrap_or_default(),
+        notes,
+        curves: ustx_curves,
+        extra: Extra::new(),
+    }
+}
+
+/// ピッチ点をMode2のピッチ曲線にする。
+fn to_mode2(pitch: &Pitch) -> Mode2Pitch {
+    let data = &pitch.data;
+
+    Mode2Pitch {
+        start: data[0].x,
+        start_height: if pitch.snap_first {
+            None
+        } else {
+            Some(data[0].y)
+        },
+        widths: data.windows(2).map(|w| w[1].x - w[0].x).collect(),
+        heights: data[1..].iter().map(|p| p.y).collect(),
+        shapes: data[..data.len() - 1]
+            .iter()
+            .map(|p| {
+                match p.shape {
+                    PitchPointShape::InOut => "",
+                    PitchPointShape::Linear => "s",
+                    PitchPointShape::Out => "r",
+                    PitchPointShape::In => "j",
+                }
+                .to_string()
+            })
+            .collect(),
+    }
+}
+
+/// 直線で補間される曲線を`CURVE_STEP`ごとの`(位置, 値)`にする。
+fn sample_curve(curve: &super::Curve) -> Vec<(i64, i64)> {
+    let points: Vec<(i64, f64)> = curve
+        .xs
+        .iter()
+        .zip(curve.ys.iter())
+        .map(|(&x, &y)| (x, y as f64))
+        .collect();
+
+    let mut events: Vec<(i64, i64)> = conversion::sample_points(&points, CURVE_STEP, |t| t)
+        .into_iter()
+        .map(|(x, y)| (x, y.round() as i64))
+        .collect();
+    events.dedup_by_key(|e| e.1);
+    events
+}
+
+/// 値が次のイベントまで保持される`(位置, 値)`を、直線で補間される曲線にする。
+fn to_ustx_curve(abbr: &str, events: &[(i64, i64)]) -> super::Curve {
+    let (xs, ys) = conversion::hold_points(events).into_iter().unzip();
+
+    super::Curve {
+        xs,
+        ys,
+        abbr: abbr.into(),
+    }
+}
+
+fn map_range(value: i64, from: Range, to: Range) -> i64 {
+    conversion::map_range(value as f64, from, to).round() as i64
+}
+
+#[test]
+#[cfg(test)]
+/// .ustxをVsqx4に変換し、戻したときにノート・テンポ・曲線が保たれるか確認する。
+fn test_ustx_vsqx4() {
+    let u: Ustx = include_str!("../test/test.ustx").parse().unwrap();
+    let conv = u.to_vsqx4();
+    let v = &conv.project;
+
+    let part = &v.vs_track[0].parts[0];
+    assert_eq!(part.position, 480 + 7680);
+    assert_eq!(part.notes[0].phoneme, "a");
+    assert_eq!(v.master_track.tempos[1].position, 1920 + 7680);
+    assert_eq!(v.master_track.time_signatures[1].position, 6);
+
+    let curves = part.curves();
+    let dyn_curve = curves.iter().find(|c| c.name == "D").unwrap();
+    assert_eq!(dyn_curve.value_at(480), Some(127));
+    // 2つ目のノートは直前のノートの音高から始まる
+    let pit = curves.iter().find(|c| c.name == "P").unwrap();
+    assert!(pit.value_at(490).unwrap() < 0);
+
+    let lost: Vec<_> = conv
+        .unmapped
+        .iter()
+        .map(|u| u.description.as_str())
+        .collect();
+    assert!(lost.contains(&"curve \"clr\""));
+    assert!(lost.contains(&"singer \"Teto\""));
+    assert!(lost.contains(&"phoneme expression \"vel\" of 1 note(s)"));
+
+    let back = Ustx::from_vsqx4(v).project;
+    assert_eq!(back.tempos, u.tempos);
+    assert_eq!(back.time_signatures, u.time_signatures);
+
+    let part = &back.voice_parts[0];
+    assert_eq!(part.position, 480);
+    let notes: Vec<_> = part.notes.iter().map(|n| (n.position, n.tone)).collect();
+    assert_eq!(notes, vec![(0, 60), (480, 62), (1440, 64)]);
+    assert_eq!(part.notes[1].vibrato.length, 75.0);
+
+    let dyn_curve = part.curves.iter().find(|c| c.abbr == "dyn").unwrap();
+    assert!(dyn_curve.ys.contains(&120));
+    assert!(part.curves.iter().any(|c| c.abbr == "pitd"));
+}
@@ -0,0 +1,440 @@
+//! OpenUtauの.ustx形式（YAML）
+//!
+//! 知らないキーは`extra`に保持されるので、読み込んで書き出しても失われない。
+//! `Vsqx4`・`Vpr`との変換は`convert`モジュールで行い、
+//! 変換先で表現できなかったデータは`Conversion::unmapped`に記録される。
+
+use crate::conversion::Conversion;
+use crate::{vpr, vsqx4, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub(crate) mod convert;
+
+/// 知らないキーとその値
+pub type Extra = BTreeMap<String, serde_yaml::Value>;
+
+/// .ustxのプロジェクト
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Ustx {
+    pub name: String,
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default = "default_version")]
+    pub ustx_version: f64,
+    /// 四分音符あたりのティック数
+    #[serde(default = "default_resolution")]
+    pub resolution: i64,
+    /// 最初のテンポ（`tempos`がない古いファイル用）
+    #[serde(default = "default_bpm")]
+    pub bpm: f64,
+    #[serde(default = "default_beat")]
+    pub beat_per_bar: i64,
+    #[serde(default = "default_beat")]
+    pub beat_unit: i64,
+    /// 表情パラメータの定義（略称がキー）
+    #[serde(default)]
+    pub expressions: BTreeMap<String, ExpressionDescriptor>,
+    #[serde(default)]
+    pub time_signatures: Vec<TimeSignature>,
+    #[serde(default)]
+    pub tempos: Vec<Tempo>,
+    #[serde(default)]
+    pub tracks: Vec<Track>,
+    #[serde(default)]
+    pub voice_parts: Vec<VoicePart>,
+    #[serde(default)]
+    pub wave_parts: Vec<WavePart>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+fn default_version() -> f64 {
+    0.6
+}
+
+fn default_resolution() -> i64 {
+    480
+}
+
+fn default_bpm() -> f64 {
+    120.0
+}
+
+fn default_beat() -> i64 {
+    4
+}
+
+impl Default for Ustx {
+    fn default() -> Self {
+        let expressions = [
+            ("dyn", "dynamics (curve)", -240.0, 120.0),
+            ("pitd", "pitch deviation (curve)", -1200.0, 1200.0),
+            ("bre", "breathiness (curve)", 0.0, 100.0),
+            ("gen", "gender (curve)", -100.0, 100.0),
+        ]
+        .iter()
+        .map(|&(abbr, name, min, max)| {
+            (
+                abbr.to_string(),
+                ExpressionDescriptor {
+                    name: name.into(),
+                    abbr: abbr.into(),
+                    kind: "Curve".into(),
+                    min,
+                    max,
+                    default_value: 0.0,
+                    is_flag: false,
+                    flag: None,
+                    extra: Extra::new(),
+                },
+            )
+        })
+        .collect();
+
+        Self {
+            name: "New Project".into(),
+            comment: String::new(),
+            ustx_version: default_version(),
+            resolution: default_resolution(),
+            bpm: default_bpm(),
+            beat_per_bar: default_beat(),
+            beat_unit: default_beat(),
+            expressions,
+            time_signatures: vec![TimeSignature {
+                bar_position: 0,
+                beat_per_bar: 4,
+                beat_unit: 4,
+            }],
+            tempos: vec![Tempo {
+                position: 0,
+                bpm: default_bpm(),
+            }],
+            tracks: vec![],
+            voice_parts: vec![],
+            wave_parts: vec![],
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// 表情パラメータの定義
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct ExpressionDescriptor {
+    pub name: String,
+    pub abbr: String,
+    /// `Numerical`、`Options`、`Curve`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub min: f64,
+    pub max: f64,
+    pub default_value: f64,
+    #[serde(default)]
+    pub is_flag: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flag: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// 拍子
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct TimeSignature {
+    /// 小節（0始まり）
+    pub bar_position: i64,
+    pub beat_per_bar: i64,
+    pub beat_unit: i64,
+}
+
+/// テンポ
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Tempo {
+    pub position: i64,
+    pub bpm: f64,
+}
+
+/// トラック
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
+pub struct Track {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub singer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phonemizer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_name: Option<String>,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub solo: bool,
+    /// 音量（dB）
+    #[serde(default)]
+    pub volume: f64,
+    /// パン（-100〜100）
+    #[serde(default)]
+    pub pan: f64,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// 歌声のパート
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
+pub struct VoicePart {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub comment: String,
+    pub track_no: usize,
+    pub position: i64,
+    #[serde(default)]
+    pub duration: i64,
+    #[serde(default)]
+    pub notes: Vec<Note>,
+    /// 表情パラメータの曲線
+    #[serde(default)]
+    pub curves: Vec<Curve>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// 音声ファイルのパート
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct WavePart {
+    #[serde(default)]
+    pub name: String,
+    pub track_no: usize,
+    pub position: i64,
+    #[serde(default)]
+    pub relative_path: String,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// ノート。位置はパートの先頭から。
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Note {
+    pub position: i64,
+    pub duration: i64,
+    /// 音高（MIDIノート番号）
+    pub tone: i64,
+    pub lyric: String,
+    #[serde(default)]
+    pub pitch: Pitch,
+    #[serde(default)]
+    pub vibrato: Vibrato,
+    #[serde(default)]
+    pub phoneme_expressions: Vec<PhonemeExpression>,
+    #[serde(default)]
+    pub phoneme_overrides: Vec<PhonemeOverride>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl Note {
+    pub fn new<S: Into<String>>(position: i64, duration: i64, tone: i64, lyric: S) -> Self {
+        Self {
+            position,
+            duration,
+            tone,
+            lyric: lyric.into(),
+            pitch: Pitch::default(),
+            vibrato: Vibrato::default(),
+            phoneme_expressions: vec![],
+            phoneme_overrides: vec![],
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// ピッチ点
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Pitch {
+    pub data: Vec<PitchPoint>,
+    /// 最初の点を直前のノートの音高に合わせる
+    #[serde(default)]
+    pub snap_first: bool,
+}
+
+impl Default for Pitch {
+    fn default() -> Self {
+        Self {
+            data: vec![
+                PitchPoint {
+                    x: -25.0,
+                    y: 0.0,
+                    shape: PitchPointShape::InOut,
+                },
+                PitchPoint {
+                    x: 25.0,
+                    y: 0.0,
+                    shape: PitchPointShape::InOut,
+                },
+            ],
+            snap_first: false,
+        }
+    }
+}
+
+/// ピッチ点。時間はノートの開始位置からのミリ秒、高さは1/10半音。
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct PitchPoint {
+    pub x: f64,
+    pub y: f64,
+    #[serde(default)]
+    pub shape: PitchPointShape,
+}
+
+/// 次の点までの補間の形
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, Default)]
+pub enum PitchPointShape {
+    /// S字
+    #[default]
+    #[serde(rename = "io")]
+    InOut,
+    /// 直線
+    #[serde(rename = "l")]
+    Linear,
+    /// 加速
+    #[serde(rename = "i")]
+    In,
+    /// 減速
+    #[serde(rename = "o")]
+    Out,
+}
+
+/// ビブラート
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Vibrato {
+    /// ノートの長さに対する割合（%）
+    pub length: f64,
+    /// 周期（ミリ秒）
+    pub period: f64,
+    /// 深さ（セント）
+    pub depth: f64,
+    /// フェードイン（%）
+    #[serde(rename = "in")]
+    pub fade_in: f64,
+    /// フェードアウト（%）
+    #[serde(rename = "out")]
+    pub fade_out: f64,
+    /// 位相（%）
+    pub shift: f64,
+    /// 高さのずれ（%）
+    pub drift: f64,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl Default for Vibrato {
+    fn default() -> Self {
+        Self {
+            length: 0.0,
+            period: 175.0,
+            depth: 25.0,
+            fade_in: 10.0,
+            fade_out: 10.0,
+            shift: 0.0,
+            drift: 0.0,
+            extra: Extra::new(),
+        }
+    }
+}
+
+/// 音素ごとの表情パラメータ
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct PhonemeExpression {
+    pub index: usize,
+    pub abbr: String,
+    pub value: f64,
+}
+
+/// 音素の上書き
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct PhonemeOverride {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phoneme: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// 表情パラメータの曲線。位置はパートの先頭から、点の間は直線で補間される。
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Curve {
+    pub xs: Vec<i64>,
+    pub ys: Vec<i64>,
+    pub abbr: String,
+}
+
+impl Ustx {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        use std::fs::File;
+        use std::io::BufReader;
+
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_yaml::from_slice(bytes)?)
+    }
+
+    pub fn from_reader<R: std::io::Read>(reader: R) -> Result<Self> {
+        Ok(serde_yaml::from_reader(reader)?)
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    pub fn write<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        Ok(serde_yaml::to_writer(writer, self)?)
+    }
+
+    /// `Vsqx4`に変換する。
+    pub fn to_vsqx4(&self) -> Conversion<vsqx4::Vsqx4> {
+        convert::convert_ustx_to_vsqx4(self)
+    }
+
+    /// `Vpr`に変換する（`Vsqx4`を経由する）。
+    pub fn to_vpr(&self) -> Conversion<vpr::Vpr> {
+        self.to_vsqx4().map(vpr::Vpr::from)
+    }
+
+    /// `Vsqx4`から変換する。
+    pub fn from_vsqx4(v: &vsqx4::Vsqx4) -> Conversion<Self> {
+        convert::convert_vsqx4_to_ustx(v)
+    }
+
+    /// `Vpr`から変換する（`Vsqx4`を経由する）。
+    pub fn from_vpr(v: &vpr::Vpr) -> Conversion<Self> {
+        convert::convert_vsqx4_to_ustx(&v.clone().into())
+    }
+}
+
+impl std::str::FromStr for Ustx {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(s)?)
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// .ustxを読み込み、書き出して読み直しても同じになるか確認する。
+fn test_ustx_roundtrip() {
+    let u: Ustx = include_str!("../test/test.ustx").parse().unwrap();
+
+    assert_eq!(u.name, "Test");
+    assert_eq!(u.voice_parts[0].notes.len(), 3);
+    assert_eq!(
+        u.voice_parts[0].notes[1].pitch.data[0].shape,
+        PitchPointShape::Linear
+    );
+    assert_eq!(u.voice_parts[0].notes[1].vibrato.length, 75.0);
+    // 知らないキーも保持される
+    assert!(u.extra.contains_key("output_dir"));
+    assert!(u.tracks[0].extra.contains_key("renderer_settings"));
+
+    let u2: Ustx = u.to_yaml().unwrap().parse().unwrap();
+    assert_eq!(u, u2);
+}
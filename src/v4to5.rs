@@ -3,17 +3,36 @@
 use super::vpr::*;
 use super::vsqx4::{self, Vsqx4};
 
+/// VOCALOID4のコントロールチェンジのIDと、VOCALOID5のコントローラー名の対応
+pub(crate) const CONTROLLER_NAMES: &[(&str, &str)] = &[
+    ("D", "dynamics"),
+    ("P", "pitchBend"),
+    ("S", "pitchBendSens"),
+    ("B", "breathiness"),
+    ("R", "brightness"),
+    ("C", "clearness"),
+    ("G", "gender"),
+    ("T", "portamento"),
+];
+
 fn create_master_frack(v: &Vsqx4) -> MasterTrack {
+    // VOCALOID5にはプリメジャーがないので、その分をずらす
+    let offset = v.master_track.pre_measure_ticks();
+
     // テンポ情報
-    let tempo: Vec<ControlChange> = v
+    let mut tempo: Vec<ControlChange> = v
         .master_track
         .tempos
         .iter()
         .map(|t| ControlChange {
-            pos: t.position,
+            pos: (t.position - offset).max(0),
             value: t.value,
         })
         .collect();
+    // プリメジャー中のテンポは先頭に集まるので、最後のものだけ残す
+    tempo.reverse();
+    tempo.dedup_by_key(|t| t.pos);
+    tempo.reverse();
     let tempo = Tempo {
         is_folded: false,
         height: 0.0,
@@ -25,7 +44,7 @@ fn create_master_frack(v: &Vsqx4) -> MasterTrack {
     };
 
     // 拍子情報
-    let time_sig: Vec<TimeSignatureEvent> = v
+    let mut time_sig: Vec<TimeSignatureEvent> = v
         .master_track
         .time_signatures
         .iter()
@@ -35,12 +54,15 @@ fn create_master_frack(v: &Vsqx4) -> MasterTrack {
                  numerator,
                  denominator,
              }| TimeSignatureEvent {
-                bar: position,
+                bar: (position - v.master_track.pre_measure).max(0),
                 numerator,
                 denominator,
             },
         )
         .collect();
+    time_sig.reverse();
+    time_sig.dedup_by_key(|t| t.bar);
+    time_sig.reverse();
     let time_sig = TimeSignature {
         is_folded: false,
         events: time_sig,
@@ -55,11 +77,30 @@ fn create_master_frack(v: &Vsqx4) -> MasterTrack {
     }
 }
 
-fn convert_part(p: &vsqx4::VsPart, voices: &[Voice]) -> Part {
-    let pos = p.position as u64;
+/// パートの歌手がすべて音源表にあるか確かめる。
+pub(crate) fn check_voices(v: &Vsqx4) -> crate::Result<()> {
+    match crate::validate::vsqx4_voice_issues(v).into_iter().next() {
+        Some(issue) => Err(issue.into()),
+        None => Ok(()),
+    }
+}
+
+fn convert_part(p: &vsqx4::VsPart, table: &[vsqx4::Voice], voices: &[Voice], offset: i64) -> Part {
+    let pos = (p.position - offset).max(0) as u64;
     let duration = p.play_time.unwrap_or_default();
     let voice = {
-        let mut voice = voices[p.singers[0].pc as usize].clone();
+        // 音源表の`pc`が0から順に並んでいないこともある
+        let pc = p.singers[0].pc;
+        let index = table.iter().position(|v| v.pc == pc).unwrap_or(pc as usize);
+        let mut voice = voices
+            .get(index)
+            .unwrap_or_else(|| {
+                panic!(
+                    "singer pc={} is not in the voice table (use Vpr::try_from_vsqx4)",
+                    pc
+                )
+            })
+            .clone();
         voice.name = None;
         voice
     };
@@ -81,15 +122,34 @@ fn convert_part(p: &vsqx4::VsPart, voices: &[Voice]) -> Part {
                 duration: 0,
                 weight: SkillWeight { pre: 64, post: 64 },
             }),
-            vibrato: Vibrato {
-                vibrato_type: 0,
-                duration: 0,
-            },
+            vibrato: convert_vibrato(note),
         };
 
         notes.push(n);
     }
 
+    // コントロールチェンジ
+    let mut controllers: Vec<Controller> = vec![];
+    for cc in &p.control_changes {
+        let name = CONTROLLER_NAMES
+            .iter()
+            .find(|(id, _)| *id == cc.id)
+            .map(|(_, name)| *name)
+            .unwrap_or(&cc.id);
+        let event = ControlChange {
+            pos: cc.pos,
+            value: cc.value,
+        };
+
+        match controllers.iter_mut().find(|c| c.name == name) {
+            Some(c) => c.events.push(event),
+            None => controllers.push(Controller {
+                name: name.into(),
+                events: vec![event],
+            }),
+        }
+    }
+
     Part {
         name: p.name.clone(),
         pos,
@@ -97,12 +157,46 @@ fn convert_part(p: &vsqx4::VsPart, voices: &[Voice]) -> Part {
         voice,
         notes,
         midi_effects: vec![],
+        controllers,
         style_name: "No Effect".into(),
     }
 }
 
-fn convert_track(t: &vsqx4::VsTrack, voices: &[Voice]) -> Track {
-    let parts = t.parts.iter().map(|p| convert_part(p, voices)).collect();
+/// ビブラート（`vibLen`はノートの長さに対する割合）
+fn convert_vibrato(note: &vsqx4::Note) -> Vibrato {
+    let style = |id: &str| {
+        note.style
+            .styles
+            .iter()
+            .find(|s| s.id == id)
+            .map(|s| s.value)
+            .unwrap_or(0)
+    };
+
+    let vibrato_type = style("vibType");
+    let duration = if vibrato_type == 0 {
+        0
+    } else {
+        note.duration * style("vibLen") / 100
+    };
+
+    Vibrato {
+        vibrato_type,
+        duration,
+    }
+}
+
+fn convert_track(
+    t: &vsqx4::VsTrack,
+    table: &[vsqx4::Voice],
+    voices: &[Voice],
+    offset: i64,
+) -> Track {
+    let parts = t
+        .parts
+        .iter()
+        .map(|p| convert_part(p, table, voices, offset))
+        .collect();
 
     Track {
         track_type: 0, // たぶんボカロ
@@ -139,7 +233,12 @@ pub(crate) fn convert_vsqx4_to_vpr(v: &Vsqx4) -> Vpr {
     let mut tracks: Vec<Track> = vec![];
 
     for tr in &v.vs_track {
-        let track = convert_track(tr, &voices);
+        let track = convert_track(
+            tr,
+            &v.voice_table.voices,
+            &voices,
+            v.master_track.pre_measure_ticks(),
+        );
         tracks.push(track);
     }
 
@@ -152,3 +251,99 @@ pub(crate) fn convert_vsqx4_to_vpr(v: &Vsqx4) -> Vpr {
         tracks,
     }
 }
+
+#[test]
+#[cfg(test)]
+/// VSQX4とVPRを行き来して、プリメジャー、コントロールチェンジ、ビブラートが保たれるか確認する。
+fn test_conversion_roundtrip() {
+    use super::v5to4::convert_vpr_to_vsqx4;
+
+    // VSQX4 -> VPR -> VSQX4
+    let mut v: Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    let offset = v.master_track.pre_measure_ticks();
+    assert_eq!(offset, 5760);
+    {
+        let part = &mut v.vs_track[0].parts[0];
+        part.control_changes = vec![
+            vsqx4::ControlChange {
+                id: "D".into(),
+                pos: 480,
+                value: 80,
+            },
+            vsqx4::ControlChange {
+                id: "P".into(),
+                pos: 960,
+                value: -100,
+            },
+        ];
+        for s in &mut part.notes[0].style.styles {
+            match s.id.as_str() {
+                "vibType" => s.value = 1,
+                "vibLen" => s.value = 50,
+                _ => {}
+            }
+        }
+    }
+
+    let vpr = convert_vsqx4_to_vpr(&v);
+    let part = &vpr.tracks[0].parts[0];
+    assert_eq!(part.pos as i64, v.vs_track[0].parts[0].position - offset);
+    let names: Vec<_> = part.controllers.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["dynamics", "pitchBend"]);
+    assert_eq!(part.controllers[1].events[0].value, -100);
+    assert_eq!(part.notes[0].vibrato.vibrato_type, 1);
+    assert_eq!(
+        part.notes[0].vibrato.duration,
+        v.vs_track[0].parts[0].notes[0].duration / 2
+    );
+
+    let back = convert_vpr_to_vsqx4(&vpr);
+    let original = &v.vs_track[0].parts[0];
+    let part = &back.vs_track[0].parts[0];
+    assert_eq!(back.master_track.pre_measure_ticks(), offset);
+    assert_eq!(back.master_track.tempos, v.master_track.tempos);
+    assert_eq!(part.position, original.position);
+    assert_eq!(part.control_changes, original.control_changes);
+    assert_eq!(part.notes[0].style, original.notes[0].style);
+
+    // VPR -> VSQX4 -> VPR
+    let vpr = Vpr::from_bytes(include_bytes!("test/v5.vpr")).unwrap();
+    let v = convert_vpr_to_vsqx4(&vpr);
+    // 先頭の拍子（12/8）でプリメジャー4小節分ずらす
+    assert_eq!(v.master_track.pre_measure_ticks(), 4 * 2880);
+    assert_eq!(
+        v.vs_track[1].parts[0].position,
+        vpr.tracks[1].parts[0].pos as i64 + 4 * 2880
+    );
+
+    let back = convert_vsqx4_to_vpr(&v);
+    assert_eq!(
+        back.master_track.tempo.events,
+        vpr.master_track.tempo.events
+    );
+    assert_eq!(
+        back.master_track.time_sig.events,
+        vpr.master_track.time_sig.events
+    );
+    assert_eq!(back.tracks[1].parts[0].pos, vpr.tracks[1].parts[0].pos);
+
+    // プリメジャー中のテンポと曲頭のテンポは1つにまとめる
+    let mut v: Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    v.master_track.tempos.push(vsqx4::Tempo {
+        position: offset,
+        value: 12000,
+    });
+    let vpr = convert_vsqx4_to_vpr(&v);
+    assert_eq!(
+        vpr.master_track.tempo.events,
+        vec![ControlChange {
+            pos: 0,
+            value: 12000
+        }]
+    );
+
+    // 音源表にない歌手は`try_from_vsqx4`ではエラー、`From`ではパニックになる
+    v.vs_track[0].parts[0].singers[0].pc = 1;
+    assert!(Vpr::try_from_vsqx4(&v).is_err());
+    assert!(std::panic::catch_unwind(|| Vpr::from(v.clone())).is_err());
+}
@@ -1,33 +1,49 @@
 //! VOCALOID5形式からVOCALOID4形式にダウングレード
 
+use super::v4to5::CONTROLLER_NAMES;
 use super::vpr::Vpr;
 use super::vsqx4::{self, Vsqx4};
 
+/// パートの音源（`compID`）がすべて`voices`にあるか確かめる。
+pub(crate) fn check_voices(vpr: &Vpr) -> crate::Result<()> {
+    match crate::validate::vpr_voice_issues(vpr).into_iter().next() {
+        Some(issue) => Err(issue.into()),
+        None => Ok(()),
+    }
+}
+
 /// .vpr形式からvsqx4への変換をここで行う。
 pub(crate) fn convert_vpr_to_vsqx4(vpr: &Vpr) -> Vsqx4 {
     let mut v = Vsqx4::default();
 
-    // テンポ情報のコピー
-    v.master_track.tempos.clear();
-    for t in &vpr.master_track.tempo.events {
-        v.master_track.tempos.push(vsqx4::Tempo {
-            position: t.pos,
-            value: t.value,
-        });
-    }
-
-    v.master_track.pre_measure = 0;
+    // VOCALOID4 Editorの既定値に合わせてプリメジャーを入れる
+    v.master_track.pre_measure = 4;
 
-    // 拍子情報のコピー
+    // 拍子情報のコピー（先頭の拍子はプリメジャーにも適用される）
     v.master_track.time_signatures.clear();
-    for ts in &vpr.master_track.time_sig.events {
+    for (i, ts) in vpr.master_track.time_sig.events.iter().enumerate() {
         v.master_track.time_signatures.push(vsqx4::TimeSignature {
-            position: ts.bar,
+            position: if i == 0 {
+                0
+            } else {
+                ts.bar + v.master_track.pre_measure
+            },
             numerator: ts.numerator,
             denominator: ts.denominator,
         });
     }
 
+    let offset = v.master_track.pre_measure_ticks();
+
+    // テンポ情報のコピー（先頭のテンポはプリメジャーにも適用される）
+    v.master_track.tempos.clear();
+    for (i, t) in vpr.master_track.tempo.events.iter().enumerate() {
+        v.master_track.tempos.push(vsqx4::Tempo {
+            position: if i == 0 { 0 } else { t.pos + offset },
+            value: t.value,
+        });
+    }
+
     // ボイスライブラリ情報のコピー
     use std::collections::HashMap;
 
@@ -71,11 +87,15 @@ pub(crate) fn convert_vpr_to_vsqx4(vpr: &Vpr) -> Vsqx4 {
         for part in &track.parts {
             let mut p = vsqx4::VsPart::default();
 
-            // TODO: workaround
-            p.position = part.pos as i64 + 7680;
+            p.position = part.pos as i64 + offset;
             p.play_time = Some(part.duration);
 
-            let singer_id = *comp_to_pc.get(&*part.voice.comp_id).unwrap();
+            let singer_id = *comp_to_pc.get(&*part.voice.comp_id).unwrap_or_else(|| {
+                panic!(
+                    "voice {} is not defined (use Vsqx4::try_from_vpr)",
+                    part.voice.comp_id
+                )
+            });
             p.singers.push(vsqx4::Singer {
                 position: 0,
                 bs: part
@@ -86,7 +106,36 @@ pub(crate) fn convert_vpr_to_vsqx4(vpr: &Vpr) -> Vsqx4 {
                 pc: singer_id,
             });
 
+            for c in &part.controllers {
+                let id = CONTROLLER_NAMES
+                    .iter()
+                    .find(|(_, name)| *name == c.name)
+                    .map(|(id, _)| *id)
+                    .unwrap_or(&c.name);
+
+                for e in &c.events {
+                    p.control_changes.push(vsqx4::ControlChange {
+                        id: id.into(),
+                        pos: e.pos,
+                        value: e.value,
+                    });
+                }
+            }
+            p.control_changes.sort_by_key(|cc| cc.pos);
+
             for note in &part.notes {
+                let mut style = vsqx4::Style::default();
+                if note.vibrato.vibrato_type != 0 && note.duration > 0 {
+                    let vib_len = note.vibrato.duration * 100 / note.duration as i64;
+                    for s in &mut style.styles {
+                        match s.id.as_str() {
+                            "vibLen" => s.value = vib_len.clamp(0, 100),
+                            "vibType" => s.value = note.vibrato.vibrato_type,
+                            _ => {}
+                        }
+                    }
+                }
+
                 let n = vsqx4::Note {
                     position: note.pos,
                     duration: note.duration as i64,
@@ -94,7 +143,7 @@ pub(crate) fn convert_vpr_to_vsqx4(vpr: &Vpr) -> Vsqx4 {
                     velocity: note.velocity as i64,
                     lyric: note.lyric.clone(),
                     phoneme: note.phoneme.clone(),
-                    ..Default::default()
+                    style,
                 };
                 p.notes.push(n);
             }
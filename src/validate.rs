@@ -0,0 +1,416 @@
+//! VOCALOID Editorで開けるプロジェクトかどうかの検査
+//!
+//! 読み込みや変換は多少おかしなデータでも通してしまうので、書き出す前にここで検査する。
+//! 問題は見つかった順にすべて返す（最初の1つで止めない）。
+
+use crate::edit::{EditNote, EditPart};
+use crate::overlap::{self, NoteIssue};
+use crate::{vpr, vsqx4, Error, Location};
+
+/// 検査で見つかった問題
+#[derive(Clone, Debug, PartialEq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub location: Location,
+}
+
+/// 問題の種類
+#[derive(Clone, Debug, PartialEq)]
+pub enum IssueKind {
+    /// トラックに対応するミキサーの`vsUnit`がない
+    MissingUnit { track_no: i64 },
+    /// どのトラックにも対応しないミキサーの`vsUnit`
+    UnusedUnit { track_no: i64 },
+    /// 同じ番号のトラックが複数ある
+    DuplicateTrackNo { track_no: i64 },
+    /// パートに歌手がない
+    MissingSinger,
+    /// 音源表（`voices`）にない歌手
+    DanglingVoice { voice: String },
+    /// ノート番号が0～127の外にある
+    NoteNumberOutOfRange { number: i64 },
+    /// ベロシティが0～127の外にある
+    VelocityOutOfRange { velocity: i64 },
+    /// `earlier`番目のノートと重なっている
+    OverlappingNotes { earlier: usize, ticks: i64 },
+    /// 長さが0以下のノート
+    ZeroLengthNote,
+    /// テンポがない
+    MissingTempo,
+    /// 最初のテンポが0ティックにない
+    FirstTempoNotAtZero { position: i64 },
+    /// 拍子の分母が2の累乗でない
+    InvalidDenominator { denominator: i64 },
+}
+
+impl std::fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IssueKind::MissingUnit { track_no } => {
+                write!(f, "track {} has no vsUnit in the mixer", track_no)
+            }
+            IssueKind::UnusedUnit { track_no } => {
+                write!(f, "vsUnit {} does not match any track", track_no)
+            }
+            IssueKind::DuplicateTrackNo { track_no } => {
+                write!(f, "track number {} is used more than once", track_no)
+            }
+            IssueKind::MissingSinger => write!(f, "part has no singer"),
+            IssueKind::DanglingVoice { voice } => write!(f, "voice {} is not defined", voice),
+            IssueKind::NoteNumberOutOfRange { number } => {
+                write!(f, "note number {} is out of range", number)
+            }
+            IssueKind::VelocityOutOfRange { velocity } => {
+                write!(f, "velocity {} is out of range", velocity)
+            }
+            IssueKind::OverlappingNotes { earlier, ticks } => {
+                write!(f, "overlaps note {} by {} ticks", earlier, ticks)
+            }
+            IssueKind::ZeroLengthNote => write!(f, "note has no length"),
+            IssueKind::MissingTempo => write!(f, "no tempo"),
+            IssueKind::FirstTempoNotAtZero { position } => {
+                write!(f, "first tempo is at tick {} instead of 0", position)
+            }
+            IssueKind::InvalidDenominator { denominator } => {
+                write!(
+                    f,
+                    "time signature denominator {} is not a power of two",
+                    denominator
+                )
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at {}", self.kind, self.location)
+    }
+}
+
+impl From<Issue> for Error {
+    fn from(issue: Issue) -> Self {
+        let location = Box::new(issue.location);
+        match issue.kind {
+            IssueKind::MissingSinger => Error::MissingElement {
+                element: "<singer>".into(),
+                location,
+            },
+            IssueKind::DanglingVoice { voice } => Error::DanglingVoice { voice, location },
+            kind => Error::InvalidValue {
+                message: kind.to_string(),
+                location,
+            },
+        }
+    }
+}
+
+fn location(track: usize, part: Option<usize>, note: Option<usize>) -> Location {
+    Location {
+        track: Some(track),
+        part,
+        note,
+        ..Default::default()
+    }
+}
+
+/// ノート番号とベロシティの範囲、ノートの重なりを調べる。
+fn note_issues<P: EditPart>(part: &P, at: impl Fn(usize) -> Location) -> Vec<Issue> {
+    let mut issues = vec![];
+    for (i, n) in part.notes().iter().enumerate() {
+        if !(0..=127).contains(&n.number()) {
+            issues.push(Issue {
+                kind: IssueKind::NoteNumberOutOfRange { number: n.number() },
+                location: at(i),
+            });
+        }
+        if !(0..=127).contains(&n.velocity()) {
+            issues.push(Issue {
+                kind: IssueKind::VelocityOutOfRange {
+                    velocity: n.velocity(),
+                },
+                location: at(i),
+            });
+        }
+    }
+
+    for issue in overlap::find_issues(part) {
+        issues.push(match issue {
+            NoteIssue::Overlap {
+                earlier,
+                later,
+                ticks,
+            } => Issue {
+                kind: IssueKind::OverlappingNotes { earlier, ticks },
+                location: at(later),
+            },
+            NoteIssue::ZeroLength(i) => Issue {
+                kind: IssueKind::ZeroLengthNote,
+                location: at(i),
+            },
+        });
+    }
+
+    issues
+}
+
+/// 最初のテンポの位置と拍子の分母を調べる。
+///
+/// `location`にはマスタートラックの要素名（`tempo`か`timeSig`）と、その中の番号が渡される。
+fn timing_issues(
+    tempos: impl Iterator<Item = i64>,
+    denominators: impl Iterator<Item = i64>,
+    location: impl Fn(&str, Option<usize>) -> Location,
+) -> Vec<Issue> {
+    let mut issues = vec![];
+    match tempos.enumerate().min_by_key(|&(_, position)| position) {
+        None => issues.push(Issue {
+            kind: IssueKind::MissingTempo,
+            location: location("tempo", None),
+        }),
+        Some((_, 0)) => {}
+        Some((i, position)) => issues.push(Issue {
+            kind: IssueKind::FirstTempoNotAtZero { position },
+            location: location("tempo", Some(i)),
+        }),
+    }
+
+    for (i, denominator) in denominators.enumerate() {
+        if denominator <= 0 || denominator & (denominator - 1) != 0 {
+            issues.push(Issue {
+                kind: IssueKind::InvalidDenominator { denominator },
+                location: location("timeSig", Some(i)),
+            });
+        }
+    }
+
+    issues
+}
+
+/// パートの歌手が音源表にあるか調べる。
+pub(crate) fn vsqx4_voice_issues(v: &vsqx4::Vsqx4) -> Vec<Issue> {
+    let mut issues = vec![];
+    for (i, t) in v.vs_track.iter().enumerate() {
+        for (j, p) in t.parts.iter().enumerate() {
+            if p.singers.is_empty() {
+                issues.push(Issue {
+                    kind: IssueKind::MissingSinger,
+                    location: location(i, Some(j), None),
+                });
+            }
+            for s in &p.singers {
+                let found = v
+                    .voice_table
+                    .voices
+                    .iter()
+                    .any(|voice| voice.bs == s.bs && voice.pc == s.pc);
+                if !found {
+                    issues.push(Issue {
+                        kind: IssueKind::DanglingVoice {
+                            voice: format!("bs={}, pc={}", s.bs, s.pc),
+                        },
+                        location: location(i, Some(j), None),
+                    });
+                }
+            }
+        }
+    }
+
+    issues
+}
+
+/// パートの音源（`compID`）が`voices`にあるか調べる。
+pub(crate) fn vpr_voice_issues(v: &vpr::Vpr) -> Vec<Issue> {
+    let mut issues = vec![];
+    for (i, t) in v.tracks.iter().enumerate() {
+        for (j, p) in t.parts.iter().enumerate() {
+            if !v
+                .voices
+                .iter()
+                .any(|voice| voice.comp_id == p.voice.comp_id)
+            {
+                issues.push(Issue {
+                    kind: IssueKind::DanglingVoice {
+                        voice: p.voice.comp_id.clone(),
+                    },
+                    location: Location {
+                        pointer: Some(format!("/tracks/{}/parts/{}/voice/compID", i, j)),
+                        ..location(i, Some(j), None)
+                    },
+                });
+            }
+        }
+    }
+
+    issues
+}
+
+pub(crate) fn validate_vsqx4(v: &vsqx4::Vsqx4) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    // トラックとミキサー
+    for (i, t) in v.vs_track.iter().enumerate() {
+        if v.vs_track[..i].iter().any(|u| u.track_no == t.track_no) {
+            issues.push(Issue {
+                kind: IssueKind::DuplicateTrackNo {
+                    track_no: t.track_no,
+                },
+                location: location(i, None, None),
+            });
+        }
+        if !v.mixer.vs_unit.iter().any(|u| u.track_no == t.track_no) {
+            issues.push(Issue {
+                kind: IssueKind::MissingUnit {
+                    track_no: t.track_no,
+                },
+                location: location(i, None, None),
+            });
+        }
+    }
+    for (k, u) in v.mixer.vs_unit.iter().enumerate() {
+        if !v.vs_track.iter().any(|t| t.track_no == u.track_no) {
+            issues.push(Issue {
+                kind: IssueKind::UnusedUnit {
+                    track_no: u.track_no,
+                },
+                location: Location {
+                    pointer: Some(format!("/vsq4/mixer/vsUnit/{}", k)),
+                    ..Default::default()
+                },
+            });
+        }
+    }
+
+    issues.extend(vsqx4_voice_issues(v));
+
+    for (i, t) in v.vs_track.iter().enumerate() {
+        for (j, p) in t.parts.iter().enumerate() {
+            issues.extend(note_issues(p, |k| location(i, Some(j), Some(k))));
+        }
+    }
+
+    let master = &v.master_track;
+    issues.extend(timing_issues(
+        master.tempos.iter().map(|t| t.position),
+        master.time_signatures.iter().map(|t| t.denominator),
+        |name, i| Location {
+            pointer: Some(match i {
+                Some(i) => format!("/vsq4/masterTrack/{}/{}", name, i),
+                None => "/vsq4/masterTrack".into(),
+            }),
+            ..Default::default()
+        },
+    ));
+
+    issues
+}
+
+pub(crate) fn validate_vpr(v: &vpr::Vpr) -> Vec<Issue> {
+    let mut issues = vpr_voice_issues(v);
+
+    for (i, t) in v.tracks.iter().enumerate() {
+        for (j, p) in t.parts.iter().enumerate() {
+            issues.extend(note_issues(p, |k| Location {
+                pointer: Some(format!("/tracks/{}/parts/{}/notes/{}", i, j, k)),
+                ..location(i, Some(j), Some(k))
+            }));
+        }
+    }
+
+    let master = &v.master_track;
+    issues.extend(timing_issues(
+        master.tempo.events.iter().map(|t| t.pos),
+        master.time_sig.events.iter().map(|t| t.denominator),
+        |name, i| Location {
+            pointer: Some(match i {
+                Some(i) => format!("/masterTrack/{}/events/{}", name, i),
+                None => format!("/masterTrack/{}/events", name),
+            }),
+            ..Default::default()
+        },
+    ));
+
+    issues
+}
+
+#[test]
+#[cfg(test)]
+/// 正しいプロジェクトには問題がなく、壊したところが見つかるか確認する。
+fn test_validate() {
+    let v: vsqx4::Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    assert_eq!(v.validate(), vec![]);
+
+    let mut broken = v.clone();
+    broken.vs_track.push(broken.vs_track[0].clone());
+    broken.vs_track[0].parts[0].notes[1].note_num = 128;
+    broken.vs_track[0].parts[0].notes[2].position = broken.vs_track[0].parts[0].notes[1].position;
+    broken.master_track.time_signatures[0].denominator = 3;
+    let kinds: Vec<_> = broken.validate().into_iter().map(|i| i.kind).collect();
+    assert!(kinds.contains(&IssueKind::DuplicateTrackNo { track_no: 0 }));
+    assert!(kinds.contains(&IssueKind::NoteNumberOutOfRange { number: 128 }));
+    assert!(kinds.contains(&IssueKind::InvalidDenominator { denominator: 3 }));
+    assert!(kinds
+        .iter()
+        .any(|k| matches!(k, IssueKind::OverlappingNotes { earlier: 1, .. })));
+
+    // ミキサー、ノート、マスタートラックの問題はそれぞれの場所を指す
+    let mut broken = v.clone();
+    broken.vs_track[0].track_no = 5;
+    broken.vs_track[0].parts[0].notes[0].velocity = -1;
+    broken.vs_track[0].parts[0].notes[3].duration = 0;
+    broken.master_track.tempos[0].position = 480;
+    let issues = broken.validate();
+    let find = |kind: IssueKind| {
+        issues
+            .iter()
+            .find(|i| i.kind == kind)
+            .unwrap_or_else(|| panic!("{:?} is not found", kind))
+            .location
+            .clone()
+    };
+    assert_eq!(
+        find(IssueKind::MissingUnit { track_no: 5 }),
+        location(0, None, None)
+    );
+    assert_eq!(
+        find(IssueKind::UnusedUnit { track_no: 0 })
+            .pointer
+            .as_deref(),
+        Some("/vsq4/mixer/vsUnit/0")
+    );
+    assert_eq!(
+        find(IssueKind::VelocityOutOfRange { velocity: -1 }),
+        location(0, Some(0), Some(0))
+    );
+    assert_eq!(
+        find(IssueKind::ZeroLengthNote),
+        location(0, Some(0), Some(3))
+    );
+    assert_eq!(
+        find(IssueKind::FirstTempoNotAtZero { position: 480 })
+            .pointer
+            .as_deref(),
+        Some("/vsq4/masterTrack/tempo/0")
+    );
+
+    let mut vpr: vpr::Vpr = serde_json::from_str(include_str!("test/vpr.json")).unwrap();
+    assert_eq!(vpr.validate(), vec![]);
+    let mut broken = vpr.clone();
+    broken.master_track.time_sig.events[0].denominator = 6;
+    assert_eq!(
+        broken.validate(),
+        vec![Issue {
+            kind: IssueKind::InvalidDenominator { denominator: 6 },
+            location: Location {
+                pointer: Some("/masterTrack/timeSig/events/0".into()),
+                ..Default::default()
+            },
+        }]
+    );
+    vpr.voices.clear();
+    let issues = vpr.validate();
+    assert!(!issues.is_empty());
+    assert!(issues
+        .iter()
+        .all(|i| matches!(i.kind, IssueKind::DanglingVoice { .. })));
+}
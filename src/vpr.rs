@@ -3,6 +3,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::write_options::VprWriteOptions;
 use crate::Result;
 
 #[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
@@ -23,19 +24,35 @@ pub struct Vpr {
     pub tracks: Vec<Track>,
 }
 
+/// パートの歌手が音源表にない場合はパニックする。
+/// 読み込んだファイルなど、壊れているかもしれないものは`Vpr::try_from_vsqx4`で変換すること。
 impl From<super::vsqx4::Vsqx4> for Vpr {
     fn from(v: super::vsqx4::Vsqx4) -> Self {
         super::v4to5::convert_vsqx4_to_vpr(&v)
     }
 }
 
+/// パートの歌手が音源表にない場合はパニックする（`From<Vsqx4>`と同じ）。
 impl From<super::vsqx3::Vsqx3> for Vpr {
     fn from(v: super::vsqx3::Vsqx3) -> Self {
         super::v4to5::convert_vsqx4_to_vpr(&v.into())
     }
 }
 
+impl From<super::ust::Ust> for Vpr {
+    fn from(ust: super::ust::Ust) -> Self {
+        super::v4to5::convert_vsqx4_to_vpr(&ust.into())
+    }
+}
+
 impl Vpr {
+    /// `Vsqx4`から変換する。パートの歌手が音源表にない場合はエラーにする（`From`ではパニックする）。
+    pub fn try_from_vsqx4(v: &super::vsqx4::Vsqx4) -> Result<Vpr> {
+        super::v4to5::check_voices(v)?;
+
+        Ok(super::v4to5::convert_vsqx4_to_vpr(v))
+    }
+
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Vpr> {
         use std::fs::File;
 
@@ -51,19 +68,33 @@ impl Vpr {
     pub fn from_reader<R: std::io::Read + std::io::Seek>(reader: R) -> Result<Vpr> {
         use zip::ZipArchive;
 
+        use std::io::Read;
+        use zip::result::ZipError;
+
         let mut z = ZipArchive::new(reader)?;
         let seq = z.by_name("Project\\sequence.json");
 
-        let seq = if seq.is_ok() {
+        let mut seq = if seq.is_ok() {
             seq?
         } else {
             // Rust compiler is not smart enough to know seq is not be used
             // after this. A workaround is to drop seq before calling by_name.
             drop(seq);
-            z.by_name("Project/sequence.json")?
+            match z.by_name("Project/sequence.json") {
+                Ok(seq) => seq,
+                Err(ZipError::FileNotFound) => return Err(crate::Error::MissingSequence),
+                Err(e) => return Err(e.into()),
+            }
         };
 
-        Ok(serde_json::from_reader(seq)?)
+        let mut json = String::new();
+        seq.read_to_string(&mut json)?;
+        Self::from_json(&json)
+    }
+
+    /// sequence.jsonの中身から読み込む。エラーにはJSONポインタとトラック・パート・ノートの番号が付く。
+    pub fn from_json(json: &str) -> Result<Vpr> {
+        crate::locate::from_json(json)
     }
 
     pub fn to_json(&self) -> Result<String> {
@@ -86,6 +117,40 @@ impl Vpr {
 
         Ok(())
     }
+
+    /// 設定（更新日時、圧縮方式、区切り文字など）を指定して書き出す。
+    pub fn write_vpr_with_options<W: std::io::Write>(
+        &self,
+        writer: W,
+        options: &VprWriteOptions,
+    ) -> Result<()> {
+        self.write_vpr_with_files(writer, &[], options)
+    }
+
+    /// `sequence.json`のほかに`files`（`Project/Audio/1.wav`のように`/`区切りの名前と中身）も入れて書き出す。
+    /// `Project/sequence.json`など、名前が重なるファイルがあればエラーになる。
+    pub fn write_vpr_with_files<W: std::io::Write>(
+        &self,
+        writer: W,
+        files: &[(&str, &[u8])],
+        options: &VprWriteOptions,
+    ) -> Result<()> {
+        let json = self.to_json_binary()?;
+        let mut entries = vec![("Project/sequence.json", json.as_slice())];
+        entries.extend_from_slice(files);
+
+        crate::write_zip::write_zip(writer, &entries, options)
+    }
+
+    /// すべてのノートを曲の先頭からの絶対時間とともに走査する。
+    pub fn absolute_notes(&self) -> crate::timeline::VprNotes<'_> {
+        crate::timeline::VprNotes::new(self)
+    }
+
+    /// VOCALOID Editorで開けないおそれのある問題をすべて探す。
+    pub fn validate(&self) -> Vec<crate::validate::Issue> {
+        crate::validate::validate_vpr(self)
+    }
 }
 
 pub(crate) fn vpr_vender() -> String {
@@ -297,6 +362,20 @@ pub struct Part {
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub notes: Vec<Note>,
+    /// コントローラー（ピッチベンドなどのカーブ）
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub controllers: Vec<Controller>,
+}
+
+/// コントローラー
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Controller {
+    /// コントローラー名（`pitchBend`、`dynamics`など）
+    pub name: String,
+    /// イベント（の配列）
+    pub events: Vec<ControlChange>,
 }
 
 /// MIDIエフェクト
@@ -424,3 +503,91 @@ fn test_vpr_serde_full() {
     // Jsonが一致することを確認
     assert_eq!(vpr_orig, vpr_json);
 }
+
+#[test]
+#[cfg(test)]
+/// 同じ内容から同じバイト列が書き出され、zipクレートで読み戻せるか確認する。
+fn test_vpr_reproducible() {
+    use crate::write_options::{Compression, EntryOrder, PathSeparator};
+    use std::io::Read;
+
+    let vpr = Vpr::from_bytes(include_bytes!("test/v5.vpr")).unwrap();
+    let write = |files: &[(&str, &[u8])], options: &VprWriteOptions| {
+        let mut bytes = vec![];
+        vpr.write_vpr_with_files(&mut bytes, files, options)
+            .unwrap();
+        bytes
+    };
+
+    let options = VprWriteOptions::deterministic();
+    let bytes = write(&[], &options);
+    assert_eq!(bytes, write(&[], &options));
+    assert_eq!(Vpr::from_bytes(&bytes).unwrap(), vpr);
+
+    // 既定の設定でも読み戻せる
+    let mut bytes = std::io::Cursor::new(vec![]);
+    vpr.write_vpr(&mut bytes).unwrap();
+    assert_eq!(Vpr::from_bytes(bytes.get_ref()).unwrap(), vpr);
+
+    let options = VprWriteOptions {
+        compression: Compression::Stored,
+        separator: PathSeparator::Slash,
+        ..VprWriteOptions::deterministic()
+    };
+    let files: &[(&str, &[u8])] = &[("Project/b.txt", b"b"), ("Project/Audio/a.wav", b"a")];
+    let bytes = write(files, &options);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(&bytes)).unwrap();
+    let names: Vec<_> = (0..zip.len())
+        .map(|i| zip.by_index(i).unwrap().name().to_string())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "Project/Audio/a.wav",
+            "Project/b.txt",
+            "Project/sequence.json"
+        ]
+    );
+    let mut a = String::new();
+    zip.by_name("Project/Audio/a.wav")
+        .unwrap()
+        .read_to_string(&mut a)
+        .unwrap();
+    assert_eq!(a, "a");
+    assert_eq!(Vpr::from_bytes(&bytes).unwrap(), vpr);
+
+    let options = VprWriteOptions {
+        order: EntryOrder::Written,
+        ..options
+    };
+    let bytes = write(files, &options);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(&bytes)).unwrap();
+    assert_eq!(zip.len(), 3);
+    assert_eq!(zip.by_index(0).unwrap().name(), "Project/sequence.json");
+
+    // 名前が重なるファイルは入れられない
+    for name in &["Project/sequence.json", "Project\\sequence.json"] {
+        let mut bytes = vec![];
+        assert!(vpr
+            .write_vpr_with_files(&mut bytes, &[(name, b"{}")], &options)
+            .is_err());
+    }
+    let mut bytes = vec![];
+    let files: &[(&str, &[u8])] = &[("Project/a.txt", b"a"), ("Project/a.txt", b"b")];
+    assert!(vpr
+        .write_vpr_with_files(&mut bytes, files, &options)
+        .is_err());
+
+    // 圧縮レベルで大きさが変わる
+    let level = |level| {
+        write(
+            &[],
+            &VprWriteOptions {
+                level: Some(level),
+                ..VprWriteOptions::deterministic()
+            },
+        )
+        .len()
+    };
+    assert!(level(0) > level(9));
+}
@@ -0,0 +1,405 @@
+//! .vsqからVsqx4への変換
+
+use super::{Event, Vsq, VsqTrack};
+use crate::conversion::{Conversion, NoteLosses, Unmapped};
+use crate::edit::{Curve, EditPart};
+use crate::midi::import::to_bars;
+use crate::midi::{EventKind, MetaEvent};
+use crate::ust::convert::set_style;
+use crate::vsqx4::{self, Vsqx4};
+
+/// `(.vsqのコントロールカーブ, VOCALOIDのCC)`
+///
+/// どちらも次の点まで値を保つので、位置をパートの先頭からに直すだけでよい。
+const CURVE_MAPPINGS: &[(&str, &str)] = &[
+    ("PitchBendBPList", "P"),
+    ("PitchBendSensBPList", "S"),
+    ("DynamicsBPList", "D"),
+    ("EpRResidualBPList", "B"),
+    ("EpRESlopeBPList", "R"),
+    ("EpRESlopeDepthBPList", "C"),
+    ("GenderFactorBPList", "G"),
+    ("PortamentoTimingBPList", "T"),
+];
+
+/// `(.vsqのノートのプロパティ, nStyleのID)`
+const STYLE_MAPPINGS: &[(&str, &str)] = &[
+    ("PMBendDepth", "bendDep"),
+    ("PMBendLength", "bendLen"),
+    ("DEMdecGainRate", "decay"),
+    ("DEMaccent", "accent"),
+];
+
+/// .vsqからVsqx4への変換。
+/// トラックごとに1つのパートを作り、プリメジャーの直後（それより前にノートがあればそこ）に置く。
+pub(crate) fn convert_vsq_to_vsqx4(vsq: &Vsq) -> Conversion<Vsqx4> {
+    let mut unmapped = vec![];
+    let mut v = Vsqx4::default();
+    let master = &mut v.master_track;
+    master.pre_measure = vsq.pre_measure();
+
+    // 拍子とテンポ（位置はどちらもプリメジャーを含む）
+    let mut time_signatures = vec![];
+    let mut tempos = vec![];
+    for e in &vsq.master_track.events {
+        match e.kind {
+            EventKind::Meta(MetaEvent::TimeSignature {
+                numerator,
+                denominator,
+            }) => time_signatures.push((
+                e.tick,
+                i64::from(numerator.max(1)),
+                i64::from(denominator.max(1)),
+            )),
+            EventKind::Meta(MetaEvent::Tempo(us)) => tempos.push(vsqx4::Tempo {
+                position: e.tick,
+                value: 6_000_000_000 / i64::from(us.max(1)),
+            }),
+            _ => {}
+        }
+    }
+    time_signatures.sort_by_key(|ts| ts.0);
+    let time_signatures = to_bars(&time_signatures, master.resolution);
+    if !time_signatures.is_empty() {
+        master.time_signatures = time_signatures
+            .into_iter()
+            .map(|(position, numerator, denominator)| vsqx4::TimeSignature {
+                position,
+                numerator,
+                denominator,
+            })
+            .collect();
+    }
+    tempos.sort_by_key(|t| t.position);
+    tempos.dedup_by(|b, a| {
+        let same = a.position == b.position;
+        if same {
+            a.value = b.value;
+        }
+        same
+    });
+    if !tempos.is_empty() {
+        if tempos[0].position != 0 {
+            tempos.insert(
+                0,
+                vsqx4::Tempo {
+                    position: 0,
+                    value: 12000,
+                },
+            );
+        }
+        master.tempos = tempos;
+    }
+    let offset = master.pre_measure_ticks();
+
+    let mixer = vsq.tracks.iter().find_map(|t| t.mixer.as_ref());
+
+    for (i, track) in vsq.tracks.iter().enumerate() {
+        let name = track
+            .common
+            .get("Name")
+            .map(str::to_string)
+            .unwrap_or_else(|| track.name.clone());
+        let mut vs_track = vsqx4::VsTrack {
+            track_no: i as i64,
+            name: name.clone(),
+            ..Default::default()
+        };
+
+        let mut vs_unit = vsqx4::VsUnit {
+            track_no: i as i64,
+            ..Default::default()
+        };
+        if let Some(mixer) = mixer {
+            let get = |key: &str| mixer.get_i64(&format!("{}{}", key, i));
+            vs_unit.volume = get("Feder").unwrap_or(vs_unit.volume);
+            vs_unit.pan = get("Panpot").map_or(vs_unit.pan, |pan| pan + 64);
+            vs_unit.mute = get("Mute").unwrap_or(0);
+            vs_unit.solo = get("Solo").unwrap_or(0);
+        }
+        v.mixer.vs_unit.push(vs_unit);
+
+        let part = convert_track(track, i, offset, &mut v.voice_table, &mut unmapped);
+        if !part.notes.is_empty() {
+            vs_track.parts.push(vsqx4::VsPart {
+                name: Some(name),
+                ..part
+            });
+        }
+
+        v.vs_track.push(vs_track);
+    }
+    v.mixer.mono_unit.push(vsqx4::MonoUnit::default());
+    v.mixer.stereo_unit.push(vsqx4::StereoUnit::default());
+
+    Conversion::new(v, unmapped)
+}
+
+fn convert_track(
+    track: &VsqTrack,
+    index: usize,
+    offset: i64,
+    voice_table: &mut vsqx4::VoiceTable,
+    unmapped: &mut Vec<Unmapped>,
+) -> vsqx4::VsPart {
+    let mut lost = NoteLosses::default();
+
+    let position = track
+        .events
+        .iter()
+        .filter(|e| e.kind == "Anote")
+        .map(|e| e.position)
+        .min()
+        .unwrap_or(offset)
+        .min(offset);
+
+    let mut part = vsqx4::VsPart {
+        position,
+        ..Default::default()
+    };
+
+    for e in &track.events {
+        match e.kind.as_str() {
+            "Anote" => part.notes.push(convert_note(e, position, &mut lost)),
+            "Singer" => {
+                let icon = e.handle("IconHandle");
+                let get = |key: &str| icon.and_then(|h| h.get_i64(key)).unwrap_or(0);
+                let (bs, pc) = (get("Language"), get("Program"));
+                add_voice(voice_table, icon, bs, pc, index, unmapped);
+                part.singers.push(vsqx4::Singer {
+                    position: (e.position - position).max(0),
+                    bs,
+                    pc,
+                });
+            }
+            kind => unmapped.push(Unmapped::part(index, 0, format!("{} event", kind))),
+        }
+    }
+
+    part.singers.dedup_by(|b, a| {
+        let same = a.position == b.position;
+        if same {
+            *a = b.clone();
+        }
+        same
+    });
+    if !matches!(part.singers.first(), Some(s) if s.position == 0) {
+        let voice = &voice_table.voices[0];
+        part.singers.insert(
+            0,
+            vsqx4::Singer {
+                position: 0,
+                bs: voice.bs,
+                pc: voice.pc,
+            },
+        );
+    }
+
+    let mut curves = vec![];
+    for curve in &track.curves {
+        let id = match CURVE_MAPPINGS.iter().find(|(name, _)| *name == curve.name) {
+            Some(&(_, id)) => id,
+            None => {
+                if !curve.points.is_empty() {
+                    unmapped.push(Unmapped::part(index, 0, curve.name.clone()));
+                }
+                continue;
+            }
+        };
+
+        // パートより前の点はパートの先頭に寄せる
+        let mut events: Vec<(i64, i64)> = vec![];
+        for &(pos, value) in &curve.points {
+            let pos = (pos - position).max(0);
+            match events.last_mut() {
+                Some(last) if last.0 == pos => last.1 = value,
+                _ => events.push((pos, value)),
+            }
+        }
+        curves.push(Curve {
+            name: id.into(),
+            events,
+        });
+    }
+    part.set_curves(curves);
+
+    unmapped.extend(lost.into_unmapped(index, 0));
+
+    let play_time = part
+        .notes
+        .iter()
+        .map(|n| n.position + n.duration)
+        .max()
+        .unwrap_or(0);
+    part.play_time = Some(play_time as u64);
+
+    part
+}
+
+/// 歌手`(bs, pc)`が音源表になければ、`IconHandle`の`IDS`を名前にして追加する。
+/// VOCALOID2の歌手にはVOCALOID3・4のコンポーネントIDがないので、`IconID`で代用して記録する。
+fn add_voice(
+    voice_table: &mut vsqx4::VoiceTable,
+    icon: Option<&super::Properties>,
+    bs: i64,
+    pc: i64,
+    index: usize,
+    unmapped: &mut Vec<Unmapped>,
+) {
+    if voice_table
+        .voices
+        .iter()
+        .any(|voice| voice.bs == bs && voice.pc == pc)
+    {
+        return;
+    }
+
+    let get = |key: &str| icon.and_then(|h| h.get(key)).unwrap_or_default();
+    let name = get("IDS");
+    unmapped.push(Unmapped::track(
+        index,
+        format!("component ID of voice {:?}", name),
+    ));
+    voice_table.voices.push(vsqx4::Voice {
+        bs,
+        pc,
+        id: get("IconID").into(),
+        name: name.into(),
+        ..Default::default()
+    });
+}
+
+fn convert_note(e: &Event, part_position: i64, lost: &mut NoteLosses) -> vsqx4::Note {
+    let get = |key: &str| e.properties.get_i64(key);
+    let (lyric, phoneme) = e.lyric().unwrap_or_default();
+    let duration = get("Length").unwrap_or(0);
+
+    let mut note = vsqx4::Note {
+        position: e.position - part_position,
+        duration,
+        note_num: get("Note#").unwrap_or(60),
+        velocity: get("Dynamics").unwrap_or(64),
+        lyric,
+        phoneme,
+        ..Default::default()
+    };
+
+    for &(key, id) in STYLE_MAPPINGS {
+        if let Some(value) = get(key) {
+            set_style(&mut note.style, id, value);
+        }
+    }
+
+    // 1ビット目が上行、2ビット目が下行のポルタメント
+    let portamento = get("PMbPortamentoUse").unwrap_or(0);
+    set_style(&mut note.style, "risePort", portamento & 1);
+    set_style(&mut note.style, "fallPort", (portamento >> 1) & 1);
+
+    // ビブラートは`VibratoDelay`以降にかかる
+    if let Some(vibrato) = e.handle("VibratoHandle") {
+        let vib_type = vibrato
+            .get("IconID")
+            .and_then(|id| i64::from_str_radix(id.trim_start_matches('$'), 16).ok())
+            .map_or(1, |id| id & 0xFF);
+        let delay = get("VibratoDelay").unwrap_or(0).clamp(0, duration);
+        if duration > 0 && delay < duration {
+            set_style(&mut note.style, "vibType", vib_type);
+            set_style(
+                &mut note.style,
+                "vibLen",
+                (duration - delay) * 100 / duration,
+            );
+        }
+        lost.add("vibrato depth and rate");
+    }
+
+    if e.handle("NoteHeadHandle").is_some() {
+        lost.add("attack");
+    }
+
+    note
+}
+
+#[test]
+#[cfg(test)]
+/// .vsqをVsqx4に変換し、テンポ・ノート・カーブが反映されるか確認する。
+fn test_vsq_to_vsqx4() {
+    let vsq = Vsq::from_bytes(include_bytes!("../test/test.vsq")).unwrap();
+    let conversion = vsq.to_vsqx4();
+    let v = &conversion.project;
+
+    assert_eq!(v.master_track.pre_measure, 1);
+    assert_eq!(
+        v.master_track
+            .tempos
+            .iter()
+            .map(|t| (t.position, t.value))
+            .collect::<Vec<_>>(),
+        vec![(0, 12000), (3840, 15000)]
+    );
+    assert_eq!(v.mixer.vs_unit[0].pan, 74);
+
+    let part = &v.vs_track[0].parts[0];
+    assert_eq!(part.position, 1920);
+    assert_eq!(part.notes.len(), 3);
+    assert_eq!(part.notes[1].position, 480);
+    assert_eq!(part.notes[1].lyric, "か");
+    assert_eq!(part.notes[1].phoneme, "k a");
+
+    let style = |id: &str| {
+        part.notes[1]
+            .style
+            .styles
+            .iter()
+            .find(|s| s.id == id)
+            .map(|s| s.value)
+    };
+    assert_eq!(style("vibType"), Some(1));
+    assert_eq!(style("vibLen"), Some(50));
+    assert_eq!(style("fallPort"), Some(1));
+
+    let curves = part.curves();
+    let pit = curves.iter().find(|c| c.name == "P").unwrap();
+    assert_eq!(pit.events, vec![(480, 2048), (960, 0)]);
+
+    // OPEはVsqx4にない
+    assert!(conversion
+        .unmapped
+        .iter()
+        .any(|u| u.description == "OpeningBPList"));
+
+    // Vsqx3・Vprにも変換できる
+    assert_eq!(
+        vsq.to_vpr().unwrap().project.tracks[0].parts[0].notes.len(),
+        3
+    );
+
+    // 既定の音源表にない歌手は音源表に追加される
+    let mut vsq = vsq;
+    let track = &mut vsq.tracks[0];
+    let singer = track
+        .events
+        .iter_mut()
+        .find(|e| e.kind == "Singer")
+        .unwrap();
+    for (key, h) in &mut singer.handles {
+        if key == "IconHandle" {
+            h.set("Program", "1");
+            h.set("IDS", "Rin");
+        }
+    }
+    let conversion = vsq.to_vsqx4();
+    let v = &conversion.project;
+    let voice = v.voice_table.voices.iter().find(|v| v.pc == 1).unwrap();
+    assert_eq!(voice.name, "Rin");
+    assert!(v.validate().is_empty());
+    assert!(conversion
+        .unmapped
+        .iter()
+        .any(|u| u.description == "component ID of voice \"Rin\""));
+    let vpr = vsq.to_vpr().unwrap().project;
+    assert_eq!(
+        vpr.tracks[0].parts[0].voice.comp_id,
+        v.voice_table.voices[1].id
+    );
+}
@@ -0,0 +1,450 @@
+//! VOCALOID2の.vsq形式
+//!
+//! .vsqはSMF（フォーマット1、四分音符480ティック）で、1つ目のトラックがテンポと拍子を、
+//! 2つ目以降のトラックがボーカルトラックを持つ。ボーカルトラックの内容は
+//! `DM:0000:`のような番号付きのTextメタイベントに分割されたShift_JISのINI形式のテキストで、
+//! 読み込むときはこれをつなげて解析する。位置はすべてプリメジャーを含むティック。
+//!
+//! ボーカルトラックのNRPNなどのMIDIイベントは再生用にテキストから作られたものなので読み捨て、
+//! 書き出すときも出力しない。
+
+use crate::conversion::Conversion;
+use crate::midi::{EventKind, MetaEvent, MidiEvent, MidiTrack, Smf, TextEncoding};
+use crate::{vpr, vsqx3, vsqx4, Error, Result};
+use std::collections::HashMap;
+
+pub(crate) mod convert;
+
+/// 四分音符あたりのティック数
+pub const RESOLUTION: u16 = 480;
+
+/// 1つのTextメタイベントに入れるテキストの長さ（バイト）
+const CHUNK_LENGTH: usize = 119;
+
+/// .vsqのプロジェクト
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vsq {
+    /// テンポと拍子のトラック（MIDIのまま保持する）
+    pub master_track: MidiTrack,
+    pub tracks: Vec<VsqTrack>,
+}
+
+/// ボーカルトラック
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct VsqTrack {
+    /// MIDIのトラック名
+    pub name: String,
+    /// `[Common]`
+    pub common: Properties,
+    /// `[Master]`（1つ目のトラックのみ）
+    pub master: Option<Properties>,
+    /// `[Mixer]`（1つ目のトラックのみ）
+    pub mixer: Option<Properties>,
+    /// `[EventList]`のイベント（位置順）
+    pub events: Vec<Event>,
+    /// `EOS`の位置
+    pub end: i64,
+    /// `[PitchBendBPList]`などのコントロールカーブ
+    pub curves: Vec<BpList>,
+    /// その他のセクション
+    pub extra: Vec<(String, Properties)>,
+}
+
+/// `キー=値`の並び（順序を保つ）
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Properties(pub Vec<(String, String)>);
+
+/// `[ID#xxxx]`のイベント
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub position: i64,
+    /// `Type`（`Singer`、`Anote`など）
+    pub kind: String,
+    /// `Type`とハンドル以外のプロパティ（`Length`、`Note#`など）
+    pub properties: Properties,
+    /// ハンドル（`LyricHandle`などのキーと、`[h#xxxx]`の内容）
+    pub handles: Vec<(String, Properties)>,
+}
+
+/// `[...BPList]`のコントロールカーブ。値は次の点まで保たれる。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BpList {
+    /// セクション名（`PitchBendBPList`など）
+    pub name: String,
+    /// `(位置, 値)`
+    pub points: Vec<(i64, i64)>,
+}
+
+impl Properties {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 数値として取り出す。
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(|v| v.trim().parse().ok())
+    }
+
+    /// 値を設定する。キーがなければ末尾に追加する。
+    pub fn set<S: Into<String>, T: ToString>(&mut self, key: S, value: T) {
+        let key = key.into();
+        let value = value.to_string();
+
+        match self.0.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((key, value)),
+        }
+    }
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(position: i64, kind: S) -> Self {
+        Self {
+            position,
+            kind: kind.into(),
+            properties: Properties::default(),
+            handles: vec![],
+        }
+    }
+
+    /// ハンドル（`LyricHandle`など）の内容
+    pub fn handle(&self, key: &str) -> Option<&Properties> {
+        self.handles.iter().find(|(k, _)| k == key).map(|(_, h)| h)
+    }
+
+    /// `LyricHandle`の`L0`から歌詞と発音記号を取り出す。
+    pub fn lyric(&self) -> Option<(String, String)> {
+        let l0 = self.handle("LyricHandle")?.get("L0")?;
+        let mut fields = split_quoted(l0).into_iter();
+
+        Some((fields.next()?, fields.next().unwrap_or_default()))
+    }
+}
+
+impl Vsq {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_smf(&Smf::from_bytes(bytes)?)
+    }
+
+    /// 読み込んだSMFから作る。
+    pub fn from_smf(smf: &Smf) -> Result<Self> {
+        if smf.division != RESOLUTION {
+            return Err(vsq_error(format!("unsupported division {}", smf.division)));
+        }
+
+        let mut tracks = smf.tracks.iter();
+        let master_track = tracks
+            .next()
+            .cloned()
+            .ok_or_else(|| vsq_error("no master track"))?;
+
+        let tracks = tracks
+            .map(|track| {
+                let mut text = vec![];
+                for e in &track.events {
+                    if let EventKind::Meta(MetaEvent::Text(data)) = &e.kind {
+                        text.extend_from_slice(strip_prefix(data));
+                    }
+                }
+
+                let mut t = parse_track(&TextEncoding::ShiftJis.decode(&text))?;
+                t.name = track
+                    .name()
+                    .map(|name| TextEncoding::ShiftJis.decode(name))
+                    .unwrap_or_default();
+                Ok(t)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            master_track,
+            tracks,
+        })
+    }
+
+    /// SMFにする。
+    pub fn to_smf(&self) -> Smf {
+        let mut smf = Smf::new(RESOLUTION);
+        smf.tracks.push(self.master_track.clone());
+
+        for track in &self.tracks {
+            let mut t = MidiTrack::default();
+            t.events.push(MidiEvent::meta(
+                0,
+                MetaEvent::TrackName(TextEncoding::ShiftJis.encode(&track.name)),
+            ));
+
+            let text = TextEncoding::ShiftJis.encode(&write_track(track));
+            for (i, chunk) in text.chunks(CHUNK_LENGTH).enumerate() {
+                let mut data = format!("DM:{:04}:", i).into_bytes();
+                data.extend_from_slice(chunk);
+                t.events.push(MidiEvent::meta(0, MetaEvent::Text(data)));
+            }
+
+            smf.tracks.push(t);
+        }
+
+        smf
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_smf().to_bytes()
+    }
+
+    pub fn write<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        self.to_smf().write(writer)
+    }
+
+    pub fn write_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.to_smf().write_file(path)
+    }
+
+    /// プリメジャーの小節数（`[Master]`の`PreMeasure`）
+    pub fn pre_measure(&self) -> i64 {
+        self.tracks
+            .iter()
+            .find_map(|t| t.master.as_ref()?.get_i64("PreMeasure"))
+            .unwrap_or(1)
+    }
+
+    /// `Vsqx4`に変換する。
+    pub fn to_vsqx4(&self) -> Conversion<vsqx4::Vsqx4> {
+        convert::convert_vsq_to_vsqx4(self)
+    }
+
+    /// `Vsqx3`に変換する（`Vsqx4`を経由する）。
+    pub fn to_vsqx3(&self) -> Conversion<vsqx3::Vsqx3> {
+        self.to_vsqx4().map(vsqx3::Vsqx3::from)
+    }
+
+    /// `Vpr`に変換する（`Vsqx4`を経由する）。歌手が音源表にない場合はエラーになる。
+    pub fn to_vpr(&self) -> Result<Conversion<vpr::Vpr>> {
+        let conversion = self.to_vsqx4();
+        let project = vpr::Vpr::try_from_vsqx4(&conversion.project)?;
+
+        Ok(Conversion::new(project, conversion.unmapped))
+    }
+}
+
+fn vsq_error<S: Into<String>>(message: S) -> Error {
+    Error::VsqError(message.into())
+}
+
+/// `DM:0000:`のような番号を取り除く。
+fn strip_prefix(data: &[u8]) -> &[u8] {
+    if !data.starts_with(b"DM:") {
+        return data;
+    }
+
+    match data[3..].iter().position(|&b| b == b':') {
+        Some(i) => &data[3 + i + 1..],
+        None => data,
+    }
+}
+
+/// `"あ","a",0.000000`のような値を分割する。引用符は取り除く。
+fn split_quoted(value: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+fn parse_track(text: &str) -> Result<VsqTrack> {
+    // セクションを読む
+    let mut sections: Vec<(String, Properties)> = vec![];
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push((line[1..line.len() - 1].to_string(), Properties::default()));
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        match sections.last_mut() {
+            Some((_, properties)) => properties.0.push((key.into(), value.into())),
+            None => return Err(vsq_error(format!("line outside section: {}", line))),
+        }
+    }
+
+    let mut track = VsqTrack::default();
+    let mut event_list = Properties::default();
+    let mut ids: HashMap<String, Properties> = HashMap::new();
+    let mut handles: HashMap<String, Properties> = HashMap::new();
+
+    for (name, properties) in sections {
+        match name.as_str() {
+            "Common" => track.common = properties,
+            "Master" => track.master = Some(properties),
+            "Mixer" => track.mixer = Some(properties),
+            "EventList" => event_list = properties,
+            _ if name.starts_with("ID#") => {
+                ids.insert(name, properties);
+            }
+            _ if name.starts_with("h#") => {
+                handles.insert(name, properties);
+            }
+            _ if name.ends_with("BPList") => {
+                let points = properties
+                    .0
+                    .iter()
+                    .map(|(k, v)| Ok((parse_number(k)?, parse_number(v)?)))
+                    .collect::<Result<_>>()?;
+                track.curves.push(BpList { name, points });
+            }
+            _ => track.extra.push((name, properties)),
+        }
+    }
+
+    // イベントを組み立てる
+    for (position, refs) in &event_list.0 {
+        let position = parse_number(position)?;
+
+        for id in refs.split(',') {
+            if id == "EOS" {
+                track.end = position;
+                continue;
+            }
+
+            let properties = ids
+                .get(id)
+                .ok_or_else(|| vsq_error(format!("missing {}", id)))?;
+            let mut event = Event::new(position, properties.get("Type").unwrap_or_default());
+
+            for (key, value) in &properties.0 {
+                if key == "Type" {
+                    continue;
+                }
+
+                match handles.get(value) {
+                    Some(handle) if key.ends_with("Handle") => {
+                        event.handles.push((key.clone(), handle.clone()))
+                    }
+                    _ => event.properties.0.push((key.clone(), value.clone())),
+                }
+            }
+
+            track.events.push(event);
+        }
+    }
+    track.events.sort_by_key(|e| e.position);
+
+    Ok(track)
+}
+
+fn parse_number(s: &str) -> Result<i64> {
+    s.trim()
+        .parse()
+        .map_err(|_| vsq_error(format!("invalid number {:?}", s)))
+}
+
+/// INI形式のテキストにする。IDとハンドルの番号は振り直す。
+fn write_track(track: &VsqTrack) -> String {
+    use std::fmt::Write;
+
+    let mut text = String::new();
+    let mut section = |name: &str, properties: &Properties| {
+        writeln!(text, "[{}]", name).unwrap();
+        for (key, value) in &properties.0 {
+            writeln!(text, "{}={}", key, value).unwrap();
+        }
+    };
+
+    section("Common", &track.common);
+    if let Some(master) = &track.master {
+        section("Master", master);
+    }
+    if let Some(mixer) = &track.mixer {
+        section("Mixer", mixer);
+    }
+
+    let mut event_list = Properties::default();
+    let mut ids = vec![];
+    let mut handles = vec![];
+    for (i, event) in track.events.iter().enumerate() {
+        let id = format!("ID#{:04}", i);
+        event_list.0.push((event.position.to_string(), id.clone()));
+
+        let mut properties = Properties::default();
+        properties.set("Type", &event.kind);
+        properties.0.extend(event.properties.0.iter().cloned());
+        for (key, handle) in &event.handles {
+            let h = format!("h#{:04}", handles.len());
+            properties.set(key.clone(), &h);
+            handles.push((h, handle));
+        }
+        ids.push((id, properties));
+    }
+    let end = track.events.last().map_or(0, |e| e.position).max(track.end);
+    event_list.0.push((end.to_string(), "EOS".into()));
+
+    section("EventList", &event_list);
+    for (id, properties) in &ids {
+        section(id, properties);
+    }
+    for (h, handle) in handles {
+        section(&h, handle);
+    }
+    for curve in &track.curves {
+        let points = Properties(
+            curve
+                .points
+                .iter()
+                .map(|(pos, value)| (pos.to_string(), value.to_string()))
+                .collect(),
+        );
+        section(&curve.name, &points);
+    }
+    for (name, properties) in &track.extra {
+        section(name, properties);
+    }
+
+    text
+}
+
+#[test]
+#[cfg(test)]
+/// .vsqを読み込み、書き出して読み直しても同じになるか確認する。
+fn test_vsq_roundtrip() {
+    let vsq = Vsq::from_bytes(include_bytes!("../test/test.vsq")).unwrap();
+
+    assert_eq!(vsq.tracks.len(), 1);
+    let track = &vsq.tracks[0];
+    assert_eq!(track.name, "Voice1");
+    assert_eq!(track.end, 4320);
+    assert_eq!(vsq.pre_measure(), 1);
+    assert_eq!(track.events.len(), 4);
+    assert_eq!(track.events[1].properties.get_i64("Note#"), Some(60));
+    assert_eq!(
+        track.events[2].lyric(),
+        Some(("か".to_string(), "k a".to_string()))
+    );
+    assert_eq!(track.curves[0].points, vec![(2400, 2048), (2880, 0)]);
+
+    let vsq2 = Vsq::from_bytes(&vsq.to_bytes()).unwrap();
+    assert_eq!(vsq, vsq2);
+}
@@ -1,7 +1,9 @@
 //! VOCALOID3 Editorから出力される.vsqx形式
 
+use crate::encoding::XmlEncoding;
+use crate::write_options::WriteOptions;
 use crate::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub mod serializer;
 
@@ -24,7 +26,7 @@ pub mod serializer;
 ///
 /// また、特定のバージョンで必要な情報を変更するときには、
 /// `From`トレイトでサニタイズを行うように。
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "vsqx4")]
 pub struct Vsqx3 {
     /* XML関連タグ */
@@ -55,43 +57,78 @@ pub struct Vsqx3 {
 
 impl Vsqx3 {
     pub fn write<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
-        use super::write_xml::WriteXml;
-        use quick_xml::Writer;
         use std::fs::File;
         use std::io::BufWriter;
 
-        let writer = File::create(path)?;
-        let writer = BufWriter::new(writer);
-        let mut writer = Writer::new(writer);
-        writer.write(br#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#)?;
-        writer.write(b"\n")?;
-        <Self as WriteXml>::tagged(self, &mut writer, b"vsq3")?;
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
 
-        Ok(())
+    /// 任意の`Write`に書き出す。
+    pub fn write_to<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        self.write_with_options(writer, &WriteOptions::default())
+    }
+
+    /// `encoding`で書き出す。XML宣言の`encoding`もこれに合わせる。
+    pub fn write_encoded<W: std::io::Write>(&self, writer: W, encoding: XmlEncoding) -> Result<()> {
+        self.write_with_options(
+            writer,
+            &WriteOptions {
+                encoding,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// `options`に合わせて整形して書き出す。
+    pub fn write_with_options<W: std::io::Write>(
+        &self,
+        writer: W,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        crate::write_options::write_xml(writer, &self.root_string()?, options)
     }
 
     pub fn to_string(&self) -> Result<String> {
+        self.to_string_with_options(&WriteOptions::default())
+    }
+
+    /// `options`に合わせて整形した文字列。`options.encoding`はXML宣言にだけ反映する。
+    pub fn to_string_with_options(&self, options: &WriteOptions) -> Result<String> {
+        crate::write_options::format_xml(&self.root_string()?, options)
+    }
+
+    /// ルート要素を1行で書き出した文字列
+    fn root_string(&self) -> Result<String> {
         use super::write_xml::WriteXml;
         use quick_xml::Writer;
-        use std::io::Cursor;
 
-        let mut writer = Writer::new(Cursor::new(Vec::new()));
-        writer.write(br#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#)?;
-        writer.write(b"\n")?;
+        let mut writer = Writer::new(vec![]);
         <Self as WriteXml>::tagged(self, &mut writer, b"vsq3")?;
 
-        let res = writer.into_inner().into_inner();
-
-        Ok(String::from_utf8_lossy(&res).into())
+        String::from_utf8(writer.into_inner())
+            .map_err(|e| quick_xml::Error::Utf8(e.utf8_error()).into())
     }
 
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        use std::fs::File;
-        use std::io::BufReader;
-        let file = File::open(path)?;
-        let file = BufReader::new(file);
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// 任意の`BufRead`から読み込む。文字コードの扱いは`from_bytes`と同じ。
+    pub fn from_reader<R: std::io::BufRead>(mut reader: R) -> Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// 書き出したXMLを同梱のスキーマ（`schema::Schema::Vsq3`）で検査する。
+    pub fn validate_schema(&self) -> Result<Vec<crate::schema::SchemaIssue>> {
+        crate::schema::Schema::Vsq3.validate(&self.root_string()?)
+    }
 
-        Ok(quick_xml::de::from_reader(file)?)
+    /// バイト列から読み込む。文字コードはBOMとXML宣言から判定する（`encoding::XmlEncoding::detect`）。
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        crate::encoding::decode_xml(bytes).parse()
     }
 }
 
@@ -101,7 +138,11 @@ impl FromStr for Vsqx3 {
     type Err = crate::Error;
 
     fn from_str(string: &str) -> Result<Self> {
-        Ok(quick_xml::de::from_str(string)?)
+        crate::locate::from_xml::<Self, VsTrack, VsPart, Note>(
+            string,
+            "vsq3",
+            ["vsTrack", "musicalPart", "note"],
+        )
     }
 }
 
@@ -131,13 +172,13 @@ impl From<super::vpr::Vpr> for Vsqx3 {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq, Default)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
 pub struct VoiceTable {
     #[serde(rename = "vVoice")]
     pub voices: Vec<Voice>,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Voice {
     #[serde(rename = "vBS")]
     bs: i64,
@@ -151,7 +192,7 @@ pub struct Voice {
     parameters: VoiceParameters,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct VoiceParameters {
     #[serde(rename = "bre")]
     pub breathiness: i64,
@@ -165,7 +206,7 @@ pub struct VoiceParameters {
     pub openness: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Mixer {
     pub master_unit: MasterUnit,
@@ -174,7 +215,7 @@ pub struct Mixer {
     pub karaoke_unit: Vec<KaraokeUnit>,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MasterUnit {
     #[serde(rename = "outDev")]
@@ -185,7 +226,7 @@ pub struct MasterUnit {
     volume: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct VsUnit {
     #[serde(rename = "vsTrackNo")]
@@ -206,7 +247,7 @@ pub struct VsUnit {
     volume: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SeUnit {
     #[serde(rename = "inGain")]
@@ -225,7 +266,7 @@ pub struct SeUnit {
     volume: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct KaraokeUnit {
     #[serde(rename = "inGain")]
@@ -238,7 +279,7 @@ pub struct KaraokeUnit {
     volume: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MasterTrack {
     #[serde(rename = "seqName")]
@@ -252,7 +293,7 @@ pub struct MasterTrack {
     pub tempos: Vec<Tempo>,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct TimeSignature {
     #[serde(rename = "posMes")]
     pub position: i64,
@@ -262,7 +303,7 @@ pub struct TimeSignature {
     pub denominator: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Tempo {
     #[serde(rename = "posTick")]
     pub position: i64,
@@ -270,7 +311,7 @@ pub struct Tempo {
     pub value: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct VsTrack {
     #[serde(rename = "vsTrackNo")]
     pub track_no: i64,
@@ -281,7 +322,7 @@ pub struct VsTrack {
     pub parts: Vec<VsPart>,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct VsPart {
     #[serde(rename = "posTick")]
@@ -290,7 +331,7 @@ pub struct VsPart {
     pub style_plugin: StylePlugin,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub play_time: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "partName", skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
@@ -306,14 +347,14 @@ pub struct VsPart {
     pub plane: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct ControlChange<T = i64> {
     pub id: String,
     pub pos: i64,
     pub value: T,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct StylePlugin {
     #[serde(rename = "stylePluginID")]
     pub id: String,
@@ -322,7 +363,7 @@ pub struct StylePlugin {
     pub version: String,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Singer {
     #[serde(rename = "posTick")]
     position: i64,
@@ -332,7 +373,7 @@ pub struct Singer {
     pc: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Note {
     #[serde(rename = "posTick")]
     pub position: i64,
@@ -350,26 +391,26 @@ pub struct Note {
     pub style: Style,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Style {
     #[serde(rename = "attr")]
     pub styles: Vec<StyleKey>,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct StyleKey {
     pub id: String,
     #[serde(rename = "$value")]
     pub value: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct SeTrack {}
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct KaraokeTrack {}
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Aux {
     #[serde(rename = "auxID")]
     id: String,
@@ -414,3 +455,14 @@ fn test_vsqx3_convert() {
     let v: Vsqx4 = quick_xml::de::from_str(&vsqx4).unwrap();
     let _v: Vsqx3 = v.into();
 }
+
+#[test]
+#[cfg(test)]
+/// JSONに書き出して読み直しても同じになるか確認する。
+fn test_vsqx3_json() {
+    let v: Vsqx3 = quick_xml::de::from_str(include_str!("../test/v3.vsqx")).unwrap();
+
+    let json = serde_json::to_string(&v).unwrap();
+    let v2: Vsqx3 = serde_json::from_str(&json).unwrap();
+    assert_eq!(v, v2);
+}
@@ -252,9 +252,9 @@ impl WriteXml for ControlChange {
 
 impl WriteXml for StylePlugin {
     fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
-        self.id.tagged(writer, b"stylePluginID")?;
-        self.name.tagged(writer, b"stylePluginName")?;
-        self.version.tagged(writer, b"version")?;
+        self.id.cdata_tag(writer, b"stylePluginID")?;
+        self.name.cdata_tag(writer, b"stylePluginName")?;
+        self.version.cdata_tag(writer, b"version")?;
 
         Ok(())
     }
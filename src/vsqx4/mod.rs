@@ -1,15 +1,18 @@
 //! VOCALOID4 Editorから出力される.vsqx形式
 
+use crate::encoding::XmlEncoding;
+use crate::write_options::WriteOptions;
 use crate::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub mod serializer;
+pub mod stream;
 
 /// VOCALOID 4用のVsqx構造体。
 ///
 /// vsqx::Vsqx4とバイナリ互換であることを前提にしている。
 /// 詳しくはvsqx3::Vsqx3の説明を見るように。
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "vsq4")]
 pub struct Vsqx4 {
     /* XML関連タグ */
@@ -41,44 +44,95 @@ pub struct Vsqx4 {
 
 impl Vsqx4 {
     pub fn write<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
-        use super::write_xml::WriteXml;
-        use quick_xml::Writer;
         use std::fs::File;
         use std::io::BufWriter;
 
-        let writer = File::create(path)?;
-        let writer = BufWriter::new(writer);
-        let mut writer = Writer::new(writer);
-        writer.write(br#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#)?;
-        writer.write(b"\n")?;
-        <Self as WriteXml>::tagged(self, &mut writer, b"vsq4")?;
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    /// 任意の`Write`に書き出す。
+    pub fn write_to<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        self.write_with_options(writer, &WriteOptions::default())
+    }
 
-        Ok(())
+    /// `encoding`で書き出す。XML宣言の`encoding`もこれに合わせる。
+    pub fn write_encoded<W: std::io::Write>(&self, writer: W, encoding: XmlEncoding) -> Result<()> {
+        self.write_with_options(
+            writer,
+            &WriteOptions {
+                encoding,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// `options`に合わせて整形して書き出す。
+    pub fn write_with_options<W: std::io::Write>(
+        &self,
+        writer: W,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        crate::write_options::write_xml(writer, &self.root_string()?, options)
     }
 
     pub fn to_string(&self) -> Result<String> {
+        self.to_string_with_options(&WriteOptions::default())
+    }
+
+    /// `options`に合わせて整形した文字列。`options.encoding`はXML宣言にだけ反映する。
+    pub fn to_string_with_options(&self, options: &WriteOptions) -> Result<String> {
+        crate::write_options::format_xml(&self.root_string()?, options)
+    }
+
+    /// ルート要素を1行で書き出した文字列
+    fn root_string(&self) -> Result<String> {
         use super::write_xml::WriteXml;
         use quick_xml::Writer;
-        use std::io::Cursor;
 
-        let mut writer = Writer::new(Cursor::new(Vec::new()));
-        writer.write(br#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#)?;
-        writer.write(b"\n")?;
+        let mut writer = Writer::new(vec![]);
         <Self as WriteXml>::tagged(self, &mut writer, b"vsq4")?;
 
-        let res = writer.into_inner().into_inner();
-
-        Ok(String::from_utf8_lossy(&res).into())
+        String::from_utf8(writer.into_inner())
+            .map_err(|e| quick_xml::Error::Utf8(e.utf8_error()).into())
     }
 
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        use std::fs::File;
-        use std::io::BufReader;
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// 任意の`BufRead`から読み込む。文字コードの扱いは`from_bytes`と同じ。
+    pub fn from_reader<R: std::io::BufRead>(mut reader: R) -> Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// `Vpr`から変換する。パートの音源が`voices`にない場合はエラーにする（`From`ではパニックする）。
+    pub fn try_from_vpr(vpr: &super::vpr::Vpr) -> Result<Self> {
+        super::v5to4::check_voices(vpr)?;
+
+        Ok(super::v5to4::convert_vpr_to_vsqx4(vpr))
+    }
 
-        let file = File::open(path)?;
-        let file = BufReader::new(file);
+    /// バイト列から読み込む。文字コードはBOMとXML宣言から判定する（`encoding::XmlEncoding::detect`）。
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        crate::encoding::decode_xml(bytes).parse()
+    }
+
+    /// すべてのノートを曲の先頭からの絶対時間とともに走査する。
+    pub fn absolute_notes(&self) -> crate::timeline::Vsqx4Notes<'_> {
+        crate::timeline::Vsqx4Notes::new(self)
+    }
 
-        Ok(quick_xml::de::from_reader(file)?)
+    /// VOCALOID Editorで開けないおそれのある問題をすべて探す。
+    pub fn validate(&self) -> Vec<crate::validate::Issue> {
+        crate::validate::validate_vsqx4(self)
+    }
+
+    /// 書き出したXMLを同梱のスキーマ（`schema::Schema::Vsq4`）で検査する。
+    pub fn validate_schema(&self) -> Result<Vec<crate::schema::SchemaIssue>> {
+        crate::schema::Schema::Vsq4.validate(&self.root_string()?)
     }
 }
 
@@ -118,23 +172,35 @@ impl From<super::vsqx3::Vsqx3> for Vsqx4 {
     }
 }
 
+/// パートの音源が`voices`にない場合はパニックする。
+/// 読み込んだファイルなど、壊れているかもしれないものは`Vsqx4::try_from_vpr`で変換すること。
 impl From<super::vpr::Vpr> for Vsqx4 {
     fn from(vpr: super::vpr::Vpr) -> Self {
         super::v5to4::convert_vpr_to_vsqx4(&vpr)
     }
 }
 
+impl From<super::ust::Ust> for Vsqx4 {
+    fn from(ust: super::ust::Ust) -> Self {
+        super::ust::convert::convert_ust_to_vsqx4(&ust)
+    }
+}
+
 use std::str::FromStr;
 
 impl FromStr for Vsqx4 {
     type Err = crate::Error;
 
     fn from_str(string: &str) -> Result<Self> {
-        Ok(quick_xml::de::from_str(string)?)
+        crate::locate::from_xml::<Self, VsTrack, VsPart, Note>(
+            string,
+            "vsq4",
+            ["vsTrack", "vsPart", "note"],
+        )
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename = "vVoiceTable")]
 pub struct VoiceTable {
     #[serde(rename = "vVoice")]
@@ -149,7 +215,7 @@ impl Default for VoiceTable {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Voice {
     pub(crate) bs: i64,
     pub(crate) pc: i64,
@@ -172,7 +238,7 @@ impl Default for Voice {
     }
 }
 
-#[derive(Clone, Default, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Default, Deserialize, Serialize, Debug, PartialEq)]
 pub struct VoiceParameters {
     #[serde(rename = "bre")]
     pub breathiness: i64,
@@ -186,7 +252,7 @@ pub struct VoiceParameters {
     pub openness: i64,
 }
 
-#[derive(Clone, Default, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Default, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "mixer")]
 pub struct Mixer {
     pub master_unit: MasterUnit,
@@ -196,7 +262,7 @@ pub struct Mixer {
     pub stereo_unit: Vec<StereoUnit>,
 }
 
-#[derive(Clone, Default, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Default, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "masterUnit")]
 pub struct MasterUnit {
     #[serde(rename = "oDev")]
@@ -207,7 +273,7 @@ pub struct MasterUnit {
     volume: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "vsUnit")]
 pub struct VsUnit {
     #[serde(rename = "tNo")]
@@ -243,7 +309,7 @@ impl Default for VsUnit {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "monoUnit")]
 pub struct MonoUnit {
     #[serde(rename = "iGin")]
@@ -276,7 +342,7 @@ impl Default for MonoUnit {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "stUnit")]
 pub struct StereoUnit {
     #[serde(rename = "iGin")]
@@ -300,12 +366,12 @@ impl Default for StereoUnit {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "masterTrack")]
 pub struct MasterTrack {
     #[serde(rename = "seqName")]
     pub name: String,
-    #[serde(rename = "seqName", default, skip_serializing_if = "String::is_empty")]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub comment: String,
     pub resolution: i64,
     pub pre_measure: i64,
@@ -315,6 +381,19 @@ pub struct MasterTrack {
     pub tempos: Vec<Tempo>,
 }
 
+impl MasterTrack {
+    /// プリメジャーの長さ（ティック）。最初の拍子で数える。
+    pub fn pre_measure_ticks(&self) -> i64 {
+        let (numerator, denominator) = self
+            .time_signatures
+            .first()
+            .map(|ts| (ts.numerator, ts.denominator))
+            .unwrap_or((4, 4));
+
+        self.pre_measure * self.resolution * 4 * numerator / denominator
+    }
+}
+
 impl Default for MasterTrack {
     fn default() -> Self {
         Self {
@@ -328,7 +407,7 @@ impl Default for MasterTrack {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "timeSig")]
 pub struct TimeSignature {
     #[serde(rename = "m")]
@@ -349,7 +428,7 @@ impl Default for TimeSignature {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "tempo")]
 pub struct Tempo {
     #[serde(rename = "t")]
@@ -367,7 +446,7 @@ impl Default for Tempo {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "vsTrack")]
 pub struct VsTrack {
     #[serde(rename = "tNo")]
@@ -389,7 +468,7 @@ impl Default for VsTrack {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq, Default)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "camelCase", rename = "vsPart")]
 pub struct VsPart {
     #[serde(rename = "t")]
@@ -413,14 +492,14 @@ pub struct VsPart {
     pub plane: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct ControlChange<T = i64> {
     pub id: String,
     pub pos: i64,
     pub value: T,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "sPlug")]
 pub struct StylePlugin {
     pub id: String,
@@ -438,7 +517,7 @@ impl Default for StylePlugin {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "singer")]
 pub struct Singer {
     #[serde(rename = "t")]
@@ -447,7 +526,7 @@ pub struct Singer {
     pub(crate) pc: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "note")]
 pub struct Note {
     #[serde(rename = "t")]
@@ -480,7 +559,7 @@ impl Default for Note {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "nStyle")]
 pub struct Style {
     #[serde(rename = "v")]
@@ -519,7 +598,7 @@ impl Default for Style {
     }
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "v")]
 pub struct StyleKey {
     pub id: String,
@@ -527,15 +606,15 @@ pub struct StyleKey {
     pub value: i64,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq, Default)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "camelCase", rename = "monoTrack")]
 pub struct MonoTrack {}
 
-#[derive(Clone, Deserialize, Debug, PartialEq, Default)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "camelCase", rename = "stTrack")]
 pub struct StereoTrack {}
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase", rename = "aux")]
 pub struct Aux {
     id: String,
@@ -570,3 +649,28 @@ fn test_vsqx4_parse() {
     let vsqx4 = include_str!("../test/v4.vsqx");
     let _v: Vsqx4 = quick_xml::de::from_str(&vsqx4).unwrap();
 }
+
+#[test]
+#[cfg(test)]
+/// JSONに書き出して読み直しても同じになるか確認する。
+fn test_vsqx4_json() {
+    let v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+    assert_eq!(v.master_track.comment, "New VSQ File");
+
+    let json = serde_json::to_string(&v).unwrap();
+    let v2: Vsqx4 = serde_json::from_str(&json).unwrap();
+    assert_eq!(v, v2);
+}
+
+#[test]
+#[cfg(test)]
+/// メモリ上のバッファに書き出し、そこから読み直せるか確認する。
+fn test_vsqx4_reader_writer() {
+    let v: Vsqx4 = include_str!("../test/v4.vsqx").parse().unwrap();
+
+    let mut bytes = vec![];
+    v.write_to(&mut bytes).unwrap();
+    let v2 = Vsqx4::from_reader(std::io::Cursor::new(&bytes)).unwrap();
+    assert_eq!(v, v2);
+    assert_eq!(v.to_string().unwrap().as_bytes(), &bytes[..]);
+}
@@ -8,8 +8,9 @@ use crate::Result;
 
 use quick_xml::Writer;
 
-impl WriteXml for Vsqx4 {
-    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+impl Vsqx4 {
+    /// トラックより前の要素
+    pub(crate) fn write_header<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
         self.vender.cdata_tag(writer, b"vender")?;
         self.version.cdata_tag(writer, b"version")?;
 
@@ -17,6 +18,14 @@ impl WriteXml for Vsqx4 {
         self.mixer.tagged(writer, b"mixer")?;
         self.master_track.tagged(writer, b"masterTrack")?;
 
+        Ok(())
+    }
+}
+
+impl WriteXml for Vsqx4 {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        self.write_header(writer)?;
+
         for t in &self.vs_track {
             t.tagged(writer, b"vsTrack")?;
         }
@@ -183,11 +192,20 @@ impl WriteXml for Tempo {
     }
 }
 
-impl WriteXml for VsTrack {
-    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+impl VsTrack {
+    /// パートより前の要素
+    pub(crate) fn write_header<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
         self.track_no.tagged(writer, b"tNo")?;
         self.name.cdata_tag(writer, b"name")?;
         self.comment.cdata_tag(writer, b"comment")?;
+
+        Ok(())
+    }
+}
+
+impl WriteXml for VsTrack {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        self.write_header(writer)?;
         for p in &self.parts {
             p.tagged(writer, b"vsPart")?;
         }
@@ -196,8 +214,9 @@ impl WriteXml for VsTrack {
     }
 }
 
-impl WriteXml for VsPart {
-    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+impl VsPart {
+    /// CCとノートより前の要素
+    pub(crate) fn write_header<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
         self.position.tagged(writer, b"t")?;
 
         if let Some(play_time) = self.play_time {
@@ -219,6 +238,14 @@ impl WriteXml for VsPart {
             s.tagged(writer, b"singer")?;
         }
 
+        Ok(())
+    }
+}
+
+impl WriteXml for VsPart {
+    fn write_inner<W: std::io::Write>(&self, writer: &mut Writer<W>) -> Result<()> {
+        self.write_header(writer)?;
+
         for cc in &self.control_changes {
             cc.tagged(writer, b"cc")?;
         }
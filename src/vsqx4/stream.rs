@@ -0,0 +1,544 @@
+//! VSQX4を要素ごとに読み書きするストリーム
+//!
+//! `Vsqx4`はファイル全体をまとめて読み込むので、CCの多いファイルや大量のファイルを扱うとメモリを多く使う。
+//! `StreamReader`はトラック・パート・CC・ノートを1つずつイベントとして返し、
+//! `StreamWriter`は受け取ったイベントを順に書き出す。
+//!
+//! トラックより前の要素（音源表、ミキサー、マスタートラック）は最初にまとめて読み込み、
+//! `StreamReader::header`で参照できる（`vs_track`は空）。入力はUTF-8であること。
+
+use super::*;
+use crate::locate::de_error;
+use crate::write_xml::WriteXml;
+use crate::{Error, Location, Result};
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+/// ストリームのイベント
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamEvent {
+    /// トラックの開始。`parts`は空。
+    Track(VsTrack),
+    /// パートの開始。`control_changes`と`notes`は空で、`plane`は`PartEnd`で渡す。
+    Part(VsPart),
+    ControlChange(ControlChange),
+    Note(Note),
+    PartEnd {
+        plane: i64,
+    },
+    TrackEnd,
+    /// トラックの後に置かれる補助データ
+    Aux(Aux),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Header,
+    Root,
+    Track,
+    Part,
+    Done,
+}
+
+/// イベントを1つずつ返すVSQX4の読み込み
+pub struct StreamReader<R: BufRead> {
+    reader: Reader<R>,
+    header: Vsqx4,
+    state: State,
+    events: VecDeque<StreamEvent>,
+    /// まだイベントにしていないトラックとパート
+    track: Option<VsTrack>,
+    part: Option<VsPart>,
+    plane: i64,
+    /// 読み始めたトラック、パート（トラック内）、ノート（パート内）の数
+    counts: [usize; 3],
+    /// ルート要素を読んだか
+    root: bool,
+}
+
+impl StreamReader<std::io::BufReader<std::fs::File>> {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::new(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+}
+
+impl<R: BufRead> StreamReader<R> {
+    /// 最初のトラックの直前まで読み込む。
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = Reader::from_reader(reader);
+        reader.expand_empty_elements(true);
+
+        let mut stream = Self {
+            reader,
+            header: Vsqx4::default(),
+            state: State::Header,
+            events: VecDeque::new(),
+            track: None,
+            part: None,
+            plane: 0,
+            counts: [0; 3],
+            root: false,
+        };
+        while stream.state == State::Header {
+            stream.read()?;
+        }
+
+        Ok(stream)
+    }
+
+    /// トラックより前の要素。`vs_track`と`aux`は空。
+    pub fn header(&self) -> &Vsqx4 {
+        &self.header
+    }
+
+    /// 次のイベント。ファイルの終わりでは`None`。
+    pub fn next_event(&mut self) -> Result<Option<StreamEvent>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if self.state == State::Done {
+                return Ok(None);
+            }
+            if let Err(e) = self.read() {
+                self.state = State::Done;
+                return Err(e);
+            }
+        }
+    }
+
+    /// XMLのイベントを1つ読む。
+    fn read(&mut self) -> Result<()> {
+        let mut buf = vec![];
+        match self.reader.read_event(&mut buf)? {
+            Event::Start(e) => {
+                let e = e.into_owned();
+                self.start(&e)?;
+            }
+            Event::End(e) => {
+                let name = e.name().to_vec();
+                self.end(&name)?;
+            }
+            Event::Eof => {
+                if self.state == State::Header {
+                    return Err(Error::MissingElement {
+                        element: "<vsq4>".into(),
+                        location: Box::default(),
+                    });
+                }
+                self.state = State::Done;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn start(&mut self, e: &BytesStart) -> Result<()> {
+        match (self.state, e.name()) {
+            (State::Header, b"vsq4") => {
+                self.root = true;
+                for a in e.attributes() {
+                    let a = a?;
+                    let value = a.unescape_and_decode_value(&self.reader)?;
+                    match a.key {
+                        b"xmlns" => self.header.xmlns = value,
+                        b"xmlns:xsi" => self.header.xmlns_xsi = value,
+                        b"xsi:schemaLocation" => self.header.xsi_schema_location = value,
+                        _ => {}
+                    }
+                }
+            }
+            (State::Header, name) if !self.root => {
+                return Err(Error::UnknownRoot {
+                    found: String::from_utf8_lossy(name).into(),
+                    expected: "vsq4",
+                    location: Box::default(),
+                })
+            }
+            (State::Header, b"vender") => self.header.vender = self.text(e)?,
+            (State::Header, b"version") => self.header.version = self.text(e)?,
+            (State::Header, b"vVoiceTable") => self.header.voice_table = self.deserialize(e)?,
+            (State::Header, b"mixer") => self.header.mixer = self.deserialize(e)?,
+            (State::Header, b"masterTrack") => self.header.master_track = self.deserialize(e)?,
+            (State::Header, _) | (State::Root, _) => {
+                self.state = State::Root;
+                match e.name() {
+                    b"vsTrack" => {
+                        self.state = State::Track;
+                        self.counts = [self.counts[0] + 1, 0, 0];
+                        self.track = Some(VsTrack {
+                            name: String::new(),
+                            ..Default::default()
+                        });
+                    }
+                    b"aux" => {
+                        let aux = self.deserialize(e)?;
+                        self.events.push_back(StreamEvent::Aux(aux));
+                    }
+                    _ => self.skip(e)?,
+                }
+            }
+            (State::Track, name) => {
+                if name == b"vsPart" {
+                    self.flush_track();
+                    self.state = State::Part;
+                    self.counts[1] += 1;
+                    self.counts[2] = 0;
+                    self.part = Some(VsPart::default());
+                    self.plane = 0;
+                    return Ok(());
+                }
+
+                let text = self.text(e)?;
+                let location = self.location();
+                if let Some(track) = &mut self.track {
+                    match name {
+                        b"tNo" => track.track_no = parse(name, &text, location)?,
+                        b"name" => track.name = text,
+                        b"comment" => track.comment = text,
+                        _ => {}
+                    }
+                }
+            }
+            (State::Part, b"cc") => {
+                self.flush_part();
+                let cc = self.control_change(e)?;
+                self.events.push_back(StreamEvent::ControlChange(cc));
+            }
+            (State::Part, b"note") => {
+                self.flush_part();
+                self.counts[2] += 1;
+                let note = self.deserialize(e)?;
+                self.events.push_back(StreamEvent::Note(note));
+            }
+            (State::Part, b"sPlug") => {
+                let plugin = self.deserialize(e)?;
+                if let Some(p) = &mut self.part {
+                    p.style_plugin = plugin;
+                }
+            }
+            (State::Part, b"pStyle") => {
+                let style = self.deserialize(e)?;
+                if let Some(p) = &mut self.part {
+                    p.style = style;
+                }
+            }
+            (State::Part, b"singer") => {
+                let singer = self.deserialize(e)?;
+                if let Some(p) = &mut self.part {
+                    p.singers.push(singer);
+                }
+            }
+            (State::Part, name) => {
+                let text = self.text(e)?;
+                let location = self.location();
+                if name == b"plane" {
+                    self.flush_part();
+                    self.plane = parse(name, &text, location)?;
+                } else if let Some(part) = &mut self.part {
+                    match name {
+                        b"t" => part.position = parse(name, &text, location)?,
+                        b"playTime" => part.play_time = Some(parse(name, &text, location)?),
+                        b"name" => part.name = Some(text),
+                        b"comment" => part.comment = Some(text),
+                        _ => {}
+                    }
+                }
+            }
+            (State::Done, _) => {}
+        }
+
+        Ok(())
+    }
+
+    fn end(&mut self, name: &[u8]) -> Result<()> {
+        match (self.state, name) {
+            (State::Part, b"vsPart") => {
+                self.flush_part();
+                self.events
+                    .push_back(StreamEvent::PartEnd { plane: self.plane });
+                self.state = State::Track;
+            }
+            (State::Track, b"vsTrack") => {
+                self.flush_track();
+                self.events.push_back(StreamEvent::TrackEnd);
+                self.state = State::Root;
+            }
+            (_, b"vsq4") => self.state = State::Done,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn flush_track(&mut self) {
+        if let Some(track) = self.track.take() {
+            self.events.push_back(StreamEvent::Track(track));
+        }
+    }
+
+    fn flush_part(&mut self) {
+        if let Some(part) = self.part.take() {
+            self.events.push_back(StreamEvent::Part(part));
+        }
+    }
+
+    /// 要素の中の文字列（CDATAを含む）。子要素は読み飛ばす。
+    fn text(&mut self, start: &BytesStart) -> Result<String> {
+        let mut text = String::new();
+        let mut depth = 0;
+        let mut buf = vec![];
+        loop {
+            match self.reader.read_event(&mut buf)? {
+                Event::Text(e) if depth == 0 => text += &e.unescape_and_decode(&self.reader)?,
+                Event::CData(e) if depth == 0 => text += self.reader.decode(&e)?,
+                Event::Start(_) => depth += 1,
+                Event::End(_) if depth > 0 => depth -= 1,
+                Event::End(_) => break,
+                Event::Eof => return Err(unexpected_eof(start)),
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(text)
+    }
+
+    /// 要素を読み飛ばす。
+    fn skip(&mut self, start: &BytesStart) -> Result<()> {
+        let mut buf = vec![];
+        self.reader
+            .read_to_end(start.name(), &mut buf)
+            .map_err(|_| unexpected_eof(start))
+    }
+
+    /// 要素をそのまま書き出して、serdeで読み込む。
+    fn deserialize<T: serde::de::DeserializeOwned>(&mut self, start: &BytesStart) -> Result<T> {
+        let mut writer = Writer::new(vec![]);
+        writer.write_event(Event::Start(start.clone()))?;
+
+        let mut depth = 0;
+        let mut buf = vec![];
+        loop {
+            let event = self.reader.read_event(&mut buf)?;
+            let end = match &event {
+                Event::Start(_) => {
+                    depth += 1;
+                    false
+                }
+                Event::End(_) if depth > 0 => {
+                    depth -= 1;
+                    false
+                }
+                Event::End(_) => true,
+                Event::Eof => return Err(unexpected_eof(start)),
+                _ => false,
+            };
+            writer.write_event(event)?;
+            if end {
+                break;
+            }
+            buf.clear();
+        }
+
+        let xml = String::from_utf8(writer.into_inner())
+            .map_err(|e| quick_xml::Error::Utf8(e.utf8_error()))?;
+        quick_xml::de::from_str(&xml).map_err(|e| {
+            let mut location = self.location();
+            if start.name() != b"note" {
+                location.note = None;
+            }
+            de_error(e, location)
+        })
+    }
+
+    /// 読んでいるトラック・パート・ノートの番号。ノートの番号は直前に読み始めたもの。
+    fn location(&self) -> Location {
+        let track = matches!(self.state, State::Track | State::Part);
+        let part = self.state == State::Part;
+        Location {
+            track: self.counts[0].checked_sub(1).filter(|_| track),
+            part: self.counts[1].checked_sub(1).filter(|_| part),
+            note: self.counts[2].checked_sub(1).filter(|_| part),
+            ..Default::default()
+        }
+    }
+
+    /// `<cc><t>0</t><v id="S">64</v></cc>`
+    fn control_change(&mut self, start: &BytesStart) -> Result<ControlChange> {
+        let mut cc = ControlChange {
+            id: String::new(),
+            pos: 0,
+            value: 0,
+        };
+        let mut buf = vec![];
+        loop {
+            match self.reader.read_event(&mut buf)? {
+                Event::Start(e) => {
+                    let e = e.into_owned();
+                    if e.name() == b"v" {
+                        for a in e.attributes() {
+                            let a = a?;
+                            if a.key == b"id" {
+                                cc.id = a.unescape_and_decode_value(&self.reader)?;
+                            }
+                        }
+                    }
+                    let text = self.text(&e)?;
+                    match e.name() {
+                        b"t" => cc.pos = parse(b"t", &text, self.location())?,
+                        b"v" => cc.value = parse(b"v", &text, self.location())?,
+                        _ => {}
+                    }
+                }
+                Event::End(_) => break,
+                Event::Eof => return Err(unexpected_eof(start)),
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(cc)
+    }
+}
+
+impl<R: BufRead> Iterator for StreamReader<R> {
+    type Item = Result<StreamEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &[u8], text: &str, location: Location) -> Result<T> {
+    text.trim().parse().map_err(|_| Error::InvalidValue {
+        message: format!("<{}> {:?}", String::from_utf8_lossy(name), text),
+        location: Box::new(Location {
+            note: None,
+            ..location
+        }),
+    })
+}
+
+fn unexpected_eof(start: &BytesStart) -> Error {
+    quick_xml::Error::UnexpectedEof(String::from_utf8_lossy(start.name()).into()).into()
+}
+
+/// イベントを順に書き出すVSQX4の書き出し
+pub struct StreamWriter<W: Write> {
+    writer: Writer<W>,
+    /// トラックの後の要素（monoTrack、stTrack）を書き出したか
+    tracks_closed: bool,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// XML宣言と、`header`のトラックより前の要素を書き出す。`header`の`vs_track`と`aux`は使わない。
+    pub fn new(writer: W, header: &Vsqx4) -> Result<Self> {
+        let mut writer = Writer::new(writer);
+        writer.write(br#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#)?;
+        writer.write(b"\n")?;
+
+        let mut start = BytesStart::borrowed_name(b"vsq4");
+        for (key, value) in header.props() {
+            start.push_attribute((key, value));
+        }
+        writer.write_event(Event::Start(start))?;
+        header.write_header(&mut writer)?;
+
+        Ok(Self {
+            writer,
+            tracks_closed: false,
+        })
+    }
+
+    pub fn write(&mut self, event: &StreamEvent) -> Result<()> {
+        let writer = &mut self.writer;
+        match event {
+            StreamEvent::Track(track) => {
+                writer.write_event(Event::Start(BytesStart::borrowed_name(b"vsTrack")))?;
+                track.write_header(writer)?;
+            }
+            StreamEvent::Part(part) => {
+                writer.write_event(Event::Start(BytesStart::borrowed_name(b"vsPart")))?;
+                part.write_header(writer)?;
+            }
+            StreamEvent::ControlChange(cc) => cc.tagged(writer, b"cc")?,
+            StreamEvent::Note(note) => note.tagged(writer, b"note")?,
+            StreamEvent::PartEnd { plane } => {
+                plane.tagged(writer, b"plane")?;
+                writer.write_event(Event::End(BytesEnd::borrowed(b"vsPart")))?;
+            }
+            StreamEvent::TrackEnd => {
+                writer.write_event(Event::End(BytesEnd::borrowed(b"vsTrack")))?;
+            }
+            StreamEvent::Aux(aux) => {
+                self.close_tracks()?;
+                aux.tagged(&mut self.writer, b"aux")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// ルート要素を閉じて、書き出し先を返す。
+    pub fn finish(mut self) -> Result<W> {
+        self.close_tracks()?;
+        self.writer
+            .write_event(Event::End(BytesEnd::borrowed(b"vsq4")))?;
+
+        Ok(self.writer.into_inner())
+    }
+
+    fn close_tracks(&mut self) -> Result<()> {
+        if !self.tracks_closed {
+            MonoTrack::default().tagged(&mut self.writer, b"monoTrack")?;
+            StereoTrack::default().tagged(&mut self.writer, b"stTrack")?;
+            self.tracks_closed = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(test)]
+/// イベントを読んでそのまま書き出したものが、`Vsqx4`で書き出したものと同じになるか確認する。
+fn test_stream_roundtrip() {
+    let text = include_str!("../test/v4.vsqx");
+    let mut v: Vsqx4 = text.parse().unwrap();
+    v.vs_track[0].parts[0].control_changes.push(ControlChange {
+        id: "S".into(),
+        pos: 480,
+        value: 64,
+    });
+    let text = v.to_string().unwrap();
+
+    let mut reader = StreamReader::new(text.as_bytes()).unwrap();
+    assert_eq!(reader.header().master_track, v.master_track);
+
+    let mut writer = StreamWriter::new(vec![], &reader.header().clone()).unwrap();
+    let mut notes = 0;
+    let mut ccs = vec![];
+    while let Some(event) = reader.next_event().unwrap() {
+        match &event {
+            StreamEvent::Note(_) => notes += 1,
+            StreamEvent::ControlChange(cc) => ccs.push(cc.clone()),
+            _ => {}
+        }
+        writer.write(&event).unwrap();
+    }
+    let written = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+    let expected: usize = v
+        .vs_track
+        .iter()
+        .flat_map(|t| &t.parts)
+        .map(|p| p.notes.len())
+        .sum();
+    assert_eq!(notes, expected);
+    assert_eq!(ccs, v.vs_track[0].parts[0].control_changes);
+    assert_eq!(written, text);
+}
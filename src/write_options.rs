@@ -0,0 +1,458 @@
+//! XMLファイル（.vsqx）とZIPファイル（.vpr）の書き出し方の設定
+//!
+//! .vsqxは、いったん1行に書き出したXMLを要素の木にしてから、設定に合わせて整形し直す。
+//! 既定値はこれまでどおり、XML宣言の後にすべての要素を1行で書き出す。
+//! `vocaloid3`・`vocaloid4`は、VOCALOID3・4 Editorが保存するファイルと同じ形式で書き出す。
+//!
+//! .vprは`VprWriteOptions::deterministic`を使うと、同じ内容からは常に同じバイト列を書き出す。
+
+use crate::encoding::XmlEncoding;
+use crate::Result;
+use quick_xml::events::{BytesText, Event};
+use quick_xml::Reader;
+
+/// 改行コード
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// 書き出し方の設定
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// 1段ごとのインデント。`None`なら改行せずに1行で書き出す。
+    pub indent: Option<String>,
+    pub line_ending: LineEnding,
+    /// XML宣言を書き出すか
+    pub declaration: bool,
+    /// 文字列を`<![CDATA[...]]>`で書き出すか。`false`なら文字参照でエスケープする。
+    pub cdata: bool,
+    /// ルート要素の2つ目以降の属性を、1つ目の属性にそろえて改行する。
+    pub align_root_attributes: bool,
+    /// 子要素があっても1行で書き出す要素の名前
+    pub inline_elements: Vec<String>,
+    pub encoding: XmlEncoding,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            indent: None,
+            line_ending: LineEnding::Lf,
+            declaration: true,
+            cdata: true,
+            align_root_attributes: false,
+            inline_elements: vec![],
+            encoding: XmlEncoding::Utf8,
+        }
+    }
+}
+
+impl WriteOptions {
+    /// VOCALOID3 Editorと同じ形式（タブでインデント、CRLF）
+    pub fn vocaloid3() -> Self {
+        Self {
+            indent: Some("\t".into()),
+            line_ending: LineEnding::CrLf,
+            align_root_attributes: true,
+            ..Default::default()
+        }
+    }
+
+    /// VOCALOID4 Editorと同じ形式。`vocaloid3`に加えて、拍子とテンポを1行で書き出す。
+    pub fn vocaloid4() -> Self {
+        Self {
+            inline_elements: vec!["timeSig".into(), "tempo".into()],
+            ..Self::vocaloid3()
+        }
+    }
+
+    fn is_inline(&self, name: &str) -> bool {
+        self.indent.is_none() || self.inline_elements.iter().any(|e| e == name)
+    }
+}
+
+/// .vpr（ZIP）の中のファイルの圧縮方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// 圧縮しない
+    Stored,
+    /// Deflateで圧縮する
+    Deflated,
+}
+
+/// .vpr（ZIP）の中のファイル名の区切り文字
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathSeparator {
+    /// `Project\sequence.json`（VOCALOID5 Editorと同じ）
+    Backslash,
+    /// `Project/sequence.json`
+    Slash,
+}
+
+impl PathSeparator {
+    pub fn as_char(self) -> char {
+        match self {
+            PathSeparator::Backslash => '\\',
+            PathSeparator::Slash => '/',
+        }
+    }
+}
+
+/// .vpr（ZIP）の中のファイルの並び順
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryOrder {
+    /// `sequence.json`、追加したファイルの順
+    Written,
+    /// ファイル名（区切り文字を変える前）の順
+    Sorted,
+}
+
+/// ZIPに記録する更新日時（MS-DOS形式なので1980～2107年、秒は2秒単位に切り捨てる）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Timestamp {
+    /// MS-DOS形式で表せる最も古い日時（1980-01-01 00:00:00）
+    pub const DOS_EPOCH: Timestamp = Timestamp {
+        year: 1980,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    /// 現在の日時（UTC）
+    pub fn now() -> Self {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let (days, time) = (seconds / 86400, seconds % 86400);
+
+        // 1970-01-01からの日数を年月日にする（3月始まりの暦で数える）
+        let days = days as i64 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Timestamp {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// MS-DOS形式の（時刻, 日付）。範囲外の値は、年と同じく表せる範囲の端にする。
+    pub(crate) fn to_dos(self) -> (u16, u16) {
+        let year = self.year.clamp(1980, 2107) - 1980;
+        let month = self.month.clamp(1, 12) as u16;
+        let day = self.day.clamp(1, 31) as u16;
+        let hour = self.hour.min(23) as u16;
+        let minute = self.minute.min(59) as u16;
+        let second = self.second.min(59) as u16;
+        let time = hour << 11 | minute << 5 | (second / 2);
+        let date = year << 9 | month << 5 | day;
+
+        (time, date)
+    }
+}
+
+/// .vprの書き出し方の設定
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VprWriteOptions {
+    /// ファイルの更新日時。`None`なら書き出した時刻にする。
+    pub modified: Option<Timestamp>,
+    pub compression: Compression,
+    /// Deflateの圧縮レベル（0～9）。`None`なら6。
+    pub level: Option<u32>,
+    pub order: EntryOrder,
+    pub separator: PathSeparator,
+}
+
+impl Default for VprWriteOptions {
+    fn default() -> Self {
+        Self {
+            modified: None,
+            compression: Compression::Deflated,
+            level: None,
+            order: EntryOrder::Written,
+            separator: PathSeparator::Backslash,
+        }
+    }
+}
+
+impl VprWriteOptions {
+    /// 同じ内容から常に同じバイト列を書き出す設定（更新日時を`Timestamp::DOS_EPOCH`に固定し、ファイル名順に並べる）
+    pub fn deterministic() -> Self {
+        Self {
+            modified: Some(Timestamp::DOS_EPOCH),
+            order: EntryOrder::Sorted,
+            ..Default::default()
+        }
+    }
+}
+
+/// 整形用の要素の木。属性値と文字列はエスケープされたまま持つ。
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+    empty: bool,
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+    CData(String),
+}
+
+impl Element {
+    fn parse(s: &str) -> Result<Self> {
+        let mut reader = Reader::from_str(s);
+        let mut buf = vec![];
+        let mut stack: Vec<Element> = vec![Element::new(String::new())];
+
+        loop {
+            let event = reader.read_event(&mut buf)?;
+            match &event {
+                Event::Start(e) | Event::Empty(e) => {
+                    let mut element = Element::new(String::from_utf8_lossy(e.name()).into());
+                    for a in e.attributes() {
+                        let a = a?;
+                        element.attributes.push((
+                            String::from_utf8_lossy(a.key).into(),
+                            String::from_utf8_lossy(&a.value).into(),
+                        ));
+                    }
+
+                    if let Event::Start(_) = event {
+                        stack.push(element);
+                    } else {
+                        element.empty = true;
+                        stack
+                            .last_mut()
+                            .unwrap()
+                            .children
+                            .push(Node::Element(element));
+                    }
+                }
+                Event::End(_) => {
+                    let element = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(Node::Element(element)),
+                        None => {
+                            return Err(
+                                quick_xml::Error::UnexpectedEof("unbalanced tags".into()).into()
+                            )
+                        }
+                    }
+                }
+                Event::Text(e) if !e.is_empty() => {
+                    let text = String::from_utf8_lossy(e.escaped()).into();
+                    stack.last_mut().unwrap().children.push(Node::Text(text));
+                }
+                Event::CData(e) => {
+                    let text = String::from_utf8_lossy(e.escaped()).into();
+                    stack.last_mut().unwrap().children.push(Node::CData(text));
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        stack
+            .pop()
+            .and_then(|document| {
+                document.children.into_iter().find_map(|n| match n {
+                    Node::Element(e) => Some(e),
+                    _ => None,
+                })
+            })
+            .ok_or_else(|| quick_xml::Error::UnexpectedEof("no root element".into()).into())
+    }
+
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            attributes: vec![],
+            children: vec![],
+            empty: false,
+        }
+    }
+
+    /// `depth`段目の要素を書き出す。`inline`なら子要素も改行せずに書き出す。
+    fn write(&self, out: &mut String, options: &WriteOptions, depth: usize, inline: bool) {
+        let newline = options.line_ending.as_str();
+
+        *out += "<";
+        *out += &self.name;
+        for (i, (key, value)) in self.attributes.iter().enumerate() {
+            if depth == 0 && options.align_root_attributes && i > 0 {
+                *out += newline;
+                *out += &" ".repeat(self.name.len() + 2);
+            } else {
+                *out += " ";
+            }
+            *out += &format!("{}=\"{}\"", key, value);
+        }
+        if self.empty {
+            *out += "/>";
+            return;
+        }
+        *out += ">";
+
+        // 子要素も文字列もない要素は、開始タグと終了タグを別の行に書く
+        let text_only = !self.children.is_empty()
+            && self.children.iter().all(|n| !matches!(n, Node::Element(_)));
+        let indent = match &options.indent {
+            Some(indent) if !inline && !text_only && !options.is_inline(&self.name) => indent,
+            _ => {
+                for child in &self.children {
+                    child.write(out, options, depth + 1, true);
+                }
+                *out += &format!("</{}>", self.name);
+                return;
+            }
+        };
+
+        for child in &self.children {
+            *out += newline;
+            *out += &indent.repeat(depth + 1);
+            child.write(out, options, depth + 1, false);
+        }
+        *out += newline;
+        *out += &indent.repeat(depth);
+        *out += &format!("</{}>", self.name);
+    }
+}
+
+impl Node {
+    fn write(&self, out: &mut String, options: &WriteOptions, depth: usize, inline: bool) {
+        match self {
+            Node::Element(e) => e.write(out, options, depth, inline),
+            Node::Text(text) => *out += text,
+            // CDATAの中の文字参照は展開されないので、表せない文字があればエスケープする
+            Node::CData(text) if options.cdata && options.encoding.can_encode(text) => {
+                *out += &format!("<![CDATA[{}]]>", text)
+            }
+            Node::CData(text) => {
+                *out += &String::from_utf8_lossy(BytesText::from_plain_str(text).escaped())
+            }
+        }
+    }
+}
+
+/// 1行で書き出したルート要素`root`を整形し、XML宣言を付けて文字列にする。
+pub(crate) fn format_xml(root: &str, options: &WriteOptions) -> Result<String> {
+    let newline = options.line_ending.as_str();
+
+    let mut out = String::new();
+    if options.declaration {
+        out += &format!(
+            r#"<?xml version="1.0" encoding="{}" standalone="no"?>"#,
+            options.encoding.label()
+        );
+        out += newline;
+    }
+    Element::parse(root)?.write(&mut out, options, 0, false);
+    if options.indent.is_some() {
+        out += newline;
+    }
+
+    Ok(out)
+}
+
+/// 整形して`options.encoding`で書き出す。
+pub(crate) fn write_xml<W: std::io::Write>(
+    mut writer: W,
+    root: &str,
+    options: &WriteOptions,
+) -> Result<()> {
+    writer.write_all(&options.encoding.encode(&format_xml(root, options)?))?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(test)]
+/// VOCALOID Editorの形式で書き出したものが、元のファイルと同じになるか確認する。
+fn test_editor_format() {
+    use crate::{vsqx3::Vsqx3, vsqx4::Vsqx4};
+
+    // `<p>`の`lock`属性は読み込まないので、それ以外が同じになればよい
+    let text = include_str!("test/v4.vsqx");
+    let v: Vsqx4 = text.parse().unwrap();
+    let s = v
+        .to_string_with_options(&WriteOptions::vocaloid4())
+        .unwrap();
+    assert_eq!(s, text.replace(" lock=\"1\"", ""));
+
+    let text = include_str!("test/v3.vsqx");
+    let v: Vsqx3 = text.parse().unwrap();
+    let s = v
+        .to_string_with_options(&WriteOptions::vocaloid3())
+        .unwrap();
+    assert_eq!(s, text);
+
+    // CDATAを使わずにエスケープする
+    let options = WriteOptions {
+        cdata: false,
+        ..WriteOptions::vocaloid3()
+    };
+    let s = v.to_string_with_options(&options).unwrap();
+    assert!(!s.contains("<![CDATA["));
+    assert_eq!(s.parse::<Vsqx3>().unwrap(), v);
+}
+
+#[test]
+#[cfg(test)]
+/// Shift_JISで表せない文字を含む文字列が、CDATAにならずに文字参照で書き出されるか確認する。
+fn test_cdata_unencodable() {
+    use crate::vsqx4::Vsqx4;
+
+    let mut v: Vsqx4 = include_str!("test/v4.vsqx").parse().unwrap();
+    v.vs_track[0].parts[0].notes[0].lyric = "ら♫".into();
+    v.vs_track[0].parts[0].notes[1].lyric = "ら".into();
+
+    let options = WriteOptions {
+        encoding: XmlEncoding::ShiftJis,
+        ..WriteOptions::vocaloid4()
+    };
+    let mut bytes = vec![];
+    v.write_with_options(&mut bytes, &options).unwrap();
+    let text = XmlEncoding::ShiftJis.decode(&bytes);
+    assert!(text.contains("ら&#9835;"));
+    assert!(!text.contains("<![CDATA[ら&#9835;]]>"));
+    assert!(text.contains("<![CDATA[ら]]>"));
+    assert_eq!(Vsqx4::from_bytes(&bytes).unwrap(), v);
+}
@@ -61,3 +61,46 @@ where
         Ok(())
     }
 }
+
+/// 空の要素を書き出す。
+pub(crate) fn write_empty<W: std::io::Write>(
+    writer: &mut Writer<W>,
+    name: &str,
+    attributes: &[(&str, &str)],
+) -> Result<()> {
+    use quick_xml::events::{BytesStart, Event};
+
+    let mut start = BytesStart::borrowed_name(name.as_bytes());
+    for &attribute in attributes {
+        start.push_attribute(attribute);
+    }
+    writer.write_event(Event::Empty(start))?;
+
+    Ok(())
+}
+
+/// 開始タグを書き出す。
+pub(crate) fn write_start<W: std::io::Write>(
+    writer: &mut Writer<W>,
+    name: &str,
+    attributes: &[(&str, &str)],
+) -> Result<()> {
+    use quick_xml::events::{BytesStart, Event};
+
+    let mut start = BytesStart::borrowed_name(name.as_bytes());
+    for &attribute in attributes {
+        start.push_attribute(attribute);
+    }
+    writer.write_event(Event::Start(start))?;
+
+    Ok(())
+}
+
+/// 終了タグを書き出す。
+pub(crate) fn write_end<W: std::io::Write>(writer: &mut Writer<W>, name: &str) -> Result<()> {
+    use quick_xml::events::{BytesEnd, Event};
+
+    writer.write_event(Event::End(BytesEnd::borrowed(name.as_bytes())))?;
+
+    Ok(())
+}
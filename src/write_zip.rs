@@ -0,0 +1,168 @@
+//! ZIPファイル（.vpr）の書き出し
+//!
+//! zipクレート（0.5）は圧縮レベルを指定できず、更新日時も`time`機能の有無で変わってしまうので、
+//! `Vpr::write_vpr_with_options`・`write_vpr_with_files`での書き出しはここで行う。
+//! ディレクトリ、ZIP64、暗号化には対応しない。
+//! 設定を指定しない`Vpr::write_vpr`は、これまでどおりzipクレートで書き出す。
+
+use crate::write_options::{Compression, EntryOrder, VprWriteOptions};
+use crate::Result;
+use std::convert::TryFrom;
+use std::io::Write;
+use zip::result::ZipError;
+
+/// `entries`（ファイル名は`/`区切り）をZIPにして書き出す。
+///
+/// ZIP64なしで表せない大きさ（4GiB以上、65536個以上のファイル、65536バイト以上のファイル名）はエラーになる。
+/// 名前が重なるファイル（区切り文字の違いは無視する）もエラーになる。
+pub(crate) fn write_zip<W: Write>(
+    mut writer: W,
+    entries: &[(&str, &[u8])],
+    options: &VprWriteOptions,
+) -> Result<()> {
+    let mut names: Vec<String> = entries.iter().map(|e| e.0.replace('\\', "/")).collect();
+    names.sort();
+    if names.windows(2).any(|w| w[0] == w[1]) {
+        return Err(ZipError::InvalidArchive("duplicate file name").into());
+    }
+
+    let mut entries = entries.to_vec();
+    if options.order == EntryOrder::Sorted {
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+    }
+
+    let (time, date) = options
+        .modified
+        .unwrap_or_else(crate::write_options::Timestamp::now)
+        .to_dos();
+    let method: u16 = match options.compression {
+        Compression::Stored => 0,
+        Compression::Deflated => 8,
+    };
+
+    let mut offset = 0usize;
+    let mut central = vec![];
+    for (name, data) in &entries {
+        let name = name.replace('/', &options.separator.as_char().to_string());
+        let compressed = compress(data, options)?;
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        // ファイル名がASCIIでなければUTF-8であることを示す
+        let flags: u16 = if name.is_ascii() { 0 } else { 1 << 11 };
+
+        // ローカルファイルヘッダーと中央ディレクトリに共通する部分
+        let mut common = vec![];
+        put16(&mut common, 20); // 展開に必要なバージョン（2.0）
+        put16(&mut common, flags);
+        put16(&mut common, method);
+        put16(&mut common, time);
+        put16(&mut common, date);
+        put32(&mut common, crc.sum());
+        put32(&mut common, fit(compressed.len(), "file is too large")?);
+        put32(&mut common, fit(data.len(), "file is too large")?);
+        put16(&mut common, fit(name.len(), "file name is too long")?);
+        put16(&mut common, 0); // 拡張フィールド
+
+        let mut local = vec![];
+        put32(&mut local, 0x0403_4b50);
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name.as_bytes());
+        writer.write_all(&local)?;
+        writer.write_all(&compressed)?;
+
+        put32(&mut central, 0x0201_4b50);
+        put16(&mut central, 20); // 作成したバージョン（MS-DOS、2.0）
+        central.extend_from_slice(&common);
+        put16(&mut central, 0); // コメント
+        put16(&mut central, 0); // ディスク番号
+        put16(&mut central, 0); // 内部属性
+        put32(&mut central, 0); // 外部属性
+        put32(&mut central, fit(offset, "archive is too large")?);
+        central.extend_from_slice(name.as_bytes());
+
+        offset += local.len() + compressed.len();
+    }
+
+    let count: u16 = fit(entries.len(), "too many files")?;
+    writer.write_all(&central)?;
+
+    let mut end = vec![];
+    put32(&mut end, 0x0605_4b50);
+    put16(&mut end, 0); // ディスク番号
+    put16(&mut end, 0); // 中央ディレクトリの開始ディスク
+    put16(&mut end, count);
+    put16(&mut end, count);
+    put32(&mut end, fit(central.len(), "archive is too large")?);
+    put32(&mut end, fit(offset, "archive is too large")?);
+    put16(&mut end, 0); // コメント
+    writer.write_all(&end)?;
+
+    Ok(())
+}
+
+fn compress(data: &[u8], options: &VprWriteOptions) -> Result<Vec<u8>> {
+    use flate2::write::DeflateEncoder;
+
+    match options.compression {
+        Compression::Stored => Ok(data.to_vec()),
+        Compression::Deflated => {
+            let level = flate2::Compression::new(options.level.unwrap_or(6).min(9));
+            let mut encoder = DeflateEncoder::new(vec![], level);
+            encoder.write_all(data)?;
+
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+/// ZIPのヘッダーの欄に収まる整数にする。収まらなければZIP64が必要なのでエラーにする。
+fn fit<T: TryFrom<usize>>(value: usize, message: &'static str) -> Result<T> {
+    T::try_from(value).map_err(|_| ZipError::UnsupportedArchive(message).into())
+}
+
+fn put16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+#[cfg(test)]
+/// ヘッダーに収まらない大きさはエラーになり、日時は範囲内に収められるか確認する。
+fn test_write_zip_limits() {
+    use crate::write_options::Timestamp;
+
+    let options = VprWriteOptions::deterministic();
+    let name = "a".repeat(0x10000);
+    let result = write_zip(vec![], &[(&name, b"")], &options);
+    assert!(matches!(
+        result,
+        Err(crate::Error::ZipError(ZipError::UnsupportedArchive(_)))
+    ));
+    let name = "a".repeat(0xffff);
+    assert!(write_zip(vec![], &[(&name, b"")], &options).is_ok());
+
+    let time = Timestamp {
+        year: 2200,
+        month: 13,
+        day: 0,
+        hour: 24,
+        minute: 60,
+        second: 61,
+    };
+    let max = Timestamp {
+        year: 2107,
+        month: 12,
+        day: 1,
+        hour: 23,
+        minute: 59,
+        second: 59,
+    };
+    assert_eq!(time.to_dos(), max.to_dos());
+    assert_eq!(
+        max.to_dos(),
+        (23 << 11 | 59 << 5 | 29, 127 << 9 | 12 << 5 | 1)
+    );
+}